@@ -22,10 +22,16 @@ fn main() {
             let eco = record.get(0).unwrap();
             let name = record.get(1).unwrap();
             let epd = record.get(2).unwrap();
-            (epd.to_owned(), format!(r#"Opening {{ eco: "{}", name: "{}" }}"#, eco, name))
+            let ply = record.get(3).unwrap().split_whitespace().count();
+            let (family, variation) = match name.split_once(": ") {
+                Some((family, variation)) => (family, Some(variation)),
+                None => (name, None),
+            };
+            (epd.to_owned(), format!("Opening {{ eco: {:?}, name: {:?}, family: {:?}, variation: {:?}, ply: {} }}",
+                                     eco, name, family, variation, ply))
         };
         map.entry(epd, record.as_str());
     }
     map.build(&mut f).unwrap();
-    write!(&mut f, ";\n").unwrap();
+    writeln!(&mut f, ";").unwrap();
 }
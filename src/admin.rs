@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::str;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use crate::{App, Protocol, Sender};
+use crate::blocklist::Cidr;
+use crate::debug::DebugTarget;
+use crate::profile;
+use crate::model::{GameId, Sri, UserId};
+use crate::stats::SocketStatsSnapshot;
+
+/// Default and maximum duration of CPU profiles.
+const PROFILE_DURATION: Duration = Duration::from_secs(30);
+const MAX_PROFILE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Maximum size of admin requests. They have no body.
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json<T: Serialize>(value: &T) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body: serde_json::to_vec(value).expect("serialize admin response"),
+        }
+    }
+
+    fn text(status: &'static str, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn bad_request(reason: &str) -> Response {
+        Response::text("400 Bad Request", reason)
+    }
+
+    fn not_found() -> Response {
+        Response::text("404 Not Found", "not found")
+    }
+}
+
+#[derive(Deserialize)]
+struct ProfileQuery {
+    seconds: Option<u64>,
+    #[serde(default)]
+    format: profile::Format,
+}
+
+/// CPU profile over the requested duration, for looking into load during
+/// incidents.
+async fn profile(query: ProfileQuery) -> Response {
+    let duration = query.seconds.map_or(PROFILE_DURATION, Duration::from_secs);
+    if duration > MAX_PROFILE_DURATION {
+        return Response::bad_request("profile too long");
+    }
+    match profile::capture(duration, query.format).await {
+        Ok(body) => Response {
+            status: "200 OK",
+            content_type: query.format.content_type(),
+            body,
+        },
+        Err(err) => Response::text("500 Internal Server Error", &format!("profile failed: {}", err)),
+    }
+}
+
+#[derive(Deserialize)]
+struct TopGamesQuery {
+    n: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct WatchedGame<'a> {
+    id: &'a GameId,
+    watchers: usize,
+}
+
+#[derive(Deserialize)]
+struct SocketsQuery {
+    user: Option<String>,
+    sri: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SocketInfo<'a> {
+    id: u64,
+    user: Option<&'a UserId>,
+    sri: Option<&'a Sri>,
+    ip: Option<IpAddr>,
+    user_agent: Option<&'a str>,
+    single_tab: bool,
+    version: Option<u32>,
+    mobile: bool,
+    protocol: Protocol,
+    #[serde(flatten)]
+    stats: SocketStatsSnapshot,
+}
+
+fn sockets(app: &App, query: SocketsQuery) -> Response {
+    let senders: Vec<Sender> = match (query.user, query.sri) {
+        (Some(user), None) => match UserId::new(&user) {
+            Ok(uid) => app.by_user.read().get(&uid).cloned().unwrap_or_default(),
+            Err(_) => return Response::bad_request("invalid user"),
+        },
+        (None, Some(sri)) => match sri.parse::<Sri>() {
+            Ok(sri) => app.by_sri.read().get(&sri).cloned().unwrap_or_default(),
+            Err(_) => return Response::bad_request("invalid sri"),
+        },
+        _ => return Response::bad_request("expected either user or sri"),
+    };
+
+    let by_id = app.by_id.read();
+    Response::json(&senders.iter().filter_map(|sender| {
+        by_id.get(&sender.token()).map(|user_socket| SocketInfo {
+            id: sender.token().0,
+            user: user_socket.user_id(),
+            sri: user_socket.meta.sri.as_ref(),
+            ip: user_socket.meta.ip,
+            user_agent: user_socket.meta.user_agent.as_deref(),
+            single_tab: user_socket.single_tab,
+            version: user_socket.client.version,
+            mobile: user_socket.client.mobile,
+            protocol: user_socket.client.protocol,
+            stats: sender.stats().snapshot(),
+        })
+    }).collect::<Vec<_>>())
+}
+
+#[derive(Deserialize)]
+struct EchoQuery {
+    user: Option<String>,
+    sri: Option<String>,
+    enabled: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct EchoStatus {
+    sockets: usize,
+    targets: Vec<DebugTarget>,
+}
+
+/// Mirrors all messages of the sockets of a user or sri to the log, or
+/// stops doing so with `enabled=false`. Lists the current targets if
+/// neither is given.
+fn echo(app: &App, query: EchoQuery) -> Response {
+    let enabled = query.enabled.unwrap_or(true);
+    let target = match (query.user, query.sri) {
+        (Some(user), None) => match UserId::new(&user) {
+            Ok(uid) => Some(DebugTarget::User(uid)),
+            Err(_) => return Response::bad_request("invalid user"),
+        },
+        (None, Some(sri)) => match sri.parse::<Sri>() {
+            Ok(sri) => Some(DebugTarget::Sri(sri)),
+            Err(_) => return Response::bad_request("invalid sri"),
+        },
+        (None, None) => None,
+        _ => return Response::bad_request("expected either user or sri"),
+    };
+    let sockets = target.map_or(0, |target| app.set_echo(target, enabled));
+    Response::json(&EchoStatus {
+        sockets,
+        targets: app.debug.echo_targets(),
+    })
+}
+
+/// Reasons for not accepting traffic, if any.
+fn unready_reasons(app: &App) -> Vec<&'static str> {
+    let mut reasons = Vec::new();
+    if !app.subscribed.load(Ordering::Relaxed) {
+        reasons.push("not subscribed to lila");
+    }
+    if !app.session_store_ok.load(Ordering::Relaxed) {
+        reasons.push("session store unreachable");
+    }
+    if app.watchdog.is_stalled() {
+        reasons.push("event loop stalled");
+    }
+    reasons
+}
+
+async fn route(app: &App, path: &str, query: &str) -> Response {
+    match path {
+        "/live" => Response::text("200 OK", "live"),
+        "/ready" => match unready_reasons(app).as_slice() {
+            [] => Response::text("200 OK", "ready"),
+            reasons => Response::text("503 Service Unavailable", &reasons.join(", ")),
+        },
+        "/games/top" => match serde_urlencoded::from_str::<TopGamesQuery>(query) {
+            Ok(q) => {
+                let top = app.top_games(q.n.unwrap_or(10).min(1000));
+                Response::json(&top.iter().map(|(id, watchers)| WatchedGame {
+                    id,
+                    watchers: *watchers,
+                }).collect::<Vec<_>>())
+            }
+            Err(err) => Response::bad_request(&err.to_string()),
+        },
+        "/closes" => Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body: app.close_audit.to_json_string().into_bytes(),
+        },
+        "/memory" => Response::json(&app.memory_report()),
+        "/debug/state" => Response::json(&app.state_dump()),
+        "/presence" => Response::json(&app.presence().into_iter().collect::<HashMap<_, _>>()),
+        "/debug/echo" => match serde_urlencoded::from_str::<EchoQuery>(query) {
+            Ok(q) => echo(app, q),
+            Err(err) => Response::bad_request(&err.to_string()),
+        },
+        "/sockets" => match serde_urlencoded::from_str::<SocketsQuery>(query) {
+            Ok(q) => sockets(app, q),
+            Err(err) => Response::bad_request(&err.to_string()),
+        },
+        "/debug/profile" => match serde_urlencoded::from_str::<ProfileQuery>(query) {
+            Ok(q) => profile(q).await,
+            Err(err) => Response::bad_request(&err.to_string()),
+        },
+        _ => Response::not_found(),
+    }
+}
+
+struct Request {
+    target: String,
+    authorization: Option<String>,
+}
+
+/// Restrictions for the admin interface.
+#[derive(Default)]
+pub struct Access {
+    /// Addresses that may connect. Everyone if empty.
+    pub allow: Vec<Cidr>,
+    /// Required bearer token, if any.
+    pub token: Option<String>,
+}
+
+impl Access {
+    fn allows(&self, peer: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(peer))
+    }
+
+    fn authorizes(&self, req: &Request) -> bool {
+        match self.token {
+            Some(ref token) => req.authorization.as_deref()
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes())),
+            None => true,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                return Ok(req.path.map(|path| Request {
+                    target: path.to_owned(),
+                    authorization: req.headers.iter()
+                        .find(|h| h.name.eq_ignore_ascii_case("authorization"))
+                        .and_then(|h| str::from_utf8(h.value).ok())
+                        .map(|h| h.to_owned()),
+                }));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_SIZE => continue,
+            _ => return Ok(None),
+        }
+    }
+}
+
+async fn handle(app: &'static App, access: &Access, mut stream: TcpStream) -> io::Result<()> {
+    let res = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(Some(req))) if !access.authorizes(&req) => {
+            Response::text("401 Unauthorized", "unauthorized")
+        }
+        Ok(Ok(Some(req))) => {
+            let mut parts = req.target.splitn(2, '?');
+            let path = parts.next().unwrap_or("");
+            let query = parts.next().unwrap_or("");
+            route(app, path, query).await
+        }
+        Ok(Ok(None)) => Response::bad_request("invalid request"),
+        Ok(Err(err)) => return Err(err),
+        Err(_) => return Ok(()),
+    };
+
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                       res.status, res.content_type, res.body.len());
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&res.body).await?;
+    stream.shutdown().await
+}
+
+/// Serves the HTTP admin interface, which is meant to be reachable only for
+/// ops and lila.
+pub async fn serve(app: &'static App, listener: TcpListener, access: Access) -> io::Result<()> {
+    let access: &'static Access = Box::leak(Box::new(access));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        if !access.allows(peer.ip()) {
+            log::warn!("refusing admin connection from {}", peer);
+            continue;
+        }
+        tokio::spawn(async move {
+            if let Err(err) = handle(app, access, stream).await {
+                log::debug!("admin request failed: {:?}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request {
+        Request {
+            target: "/live".to_owned(),
+            authorization: authorization.map(|a| a.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_access() {
+        let open = Access::default();
+        assert!(open.allows("203.0.113.7".parse().unwrap()));
+        assert!(open.authorizes(&request(None)));
+
+        let restricted = Access {
+            allow: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+            token: Some("s3cr3t".to_owned()),
+        };
+        assert!(restricted.allows("10.1.2.3".parse().unwrap()));
+        assert!(restricted.allows("::1".parse().unwrap()));
+        assert!(!restricted.allows("203.0.113.7".parse().unwrap()));
+        assert!(restricted.authorizes(&request(Some("Bearer s3cr3t"))));
+        assert!(!restricted.authorizes(&request(Some("Bearer s3cr3"))));
+        assert!(!restricted.authorizes(&request(Some("s3cr3t"))));
+        assert!(!restricted.authorizes(&request(None)));
+    }
+}
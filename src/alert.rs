@@ -0,0 +1,130 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::panic::{self, Location};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::backend::LilaBus;
+
+/// Alert published to ops when a thread panics.
+#[derive(Serialize, Debug)]
+pub struct PanicAlert {
+    t: &'static str,
+    pid: u32,
+    thread: String,
+    message: String,
+    location: Option<String>,
+    backtrace: String,
+    suppressed: u64, // alerts skipped since the previous one
+}
+
+impl PanicAlert {
+    fn new(payload: &(dyn Any + Send), location: Option<&Location<'_>>, backtrace: &Backtrace, suppressed: u64) -> PanicAlert {
+        PanicAlert {
+            t: "panic",
+            pid: process::id(),
+            thread: thread::current().name().unwrap_or("<unnamed>").to_owned(),
+            message: payload.downcast_ref::<&str>().map(|s| (*s).to_owned())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "<non-string panic payload>".to_owned()),
+            location: location.map(|l| l.to_string()),
+            backtrace: backtrace.to_string(),
+            suppressed,
+        }
+    }
+}
+
+/// Allows at most one alert per second, so that a panic in a hot path does
+/// not flood redis.
+#[derive(Default)]
+struct AlertLimiter {
+    last_second: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl AlertLimiter {
+    /// Returns the number of previously suppressed alerts, if an alert may
+    /// be sent now.
+    fn check(&self, now_second: u64) -> Option<u64> {
+        let last = self.last_second.load(Ordering::Relaxed);
+        if last != now_second && self.last_second.compare_exchange(last, now_second, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            Some(self.suppressed.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Publishes an alert with the message and backtrace of any panic, in
+/// addition to the default output, and flushes logs. Worker threads are
+/// restarted after panics, so these would otherwise go unnoticed.
+pub fn install(bus: &'static dyn LilaBus) {
+    let limiter: &'static AlertLimiter = Box::leak(Box::default());
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        // A panic while alerting must not recurse.
+        if IN_HOOK.with(|in_hook| in_hook.replace(true)) {
+            return;
+        }
+
+        let now_second = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        if let Some(suppressed) = limiter.check(now_second) {
+            let alert = PanicAlert::new(info.payload(), info.location(), &Backtrace::force_capture(), suppressed);
+            match serde_json::to_string(&alert) {
+                Ok(msg) => {
+                    if let Err(err) = bus.alert(&msg) {
+                        log::error!("failed to publish panic alert: {}", err);
+                    }
+                }
+                Err(err) => log::error!("failed to serialize panic alert: {}", err),
+            }
+        }
+
+        log::logger().flush();
+        IN_HOOK.with(|in_hook| in_hook.set(false));
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panic_alert() {
+        let payload: Box<dyn Any + Send> = Box::new(format!("invalid {}", "state"));
+        let alert = PanicAlert::new(payload.as_ref(), Some(Location::caller()), &Backtrace::disabled(), 2);
+        let json = serde_json::to_value(&alert).unwrap();
+        assert_eq!(json["t"], "panic");
+        assert_eq!(json["message"], "invalid state");
+        assert!(json["location"].as_str().unwrap().starts_with("src/alert.rs:"));
+        assert_eq!(json["thread"], "alert::tests::test_panic_alert");
+        assert_eq!(json["suppressed"], 2);
+
+        let payload: Box<dyn Any + Send> = Box::new("static");
+        assert_eq!(PanicAlert::new(payload.as_ref(), None, &Backtrace::disabled(), 0).message, "static");
+        let payload: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!(PanicAlert::new(payload.as_ref(), None, &Backtrace::disabled(), 0).message, "<non-string panic payload>");
+    }
+
+    #[test]
+    fn test_alert_limiter() {
+        let limiter = AlertLimiter::default();
+        assert_eq!(limiter.check(100), Some(0));
+        assert_eq!(limiter.check(100), None);
+        assert_eq!(limiter.check(100), None);
+        assert_eq!(limiter.check(101), Some(2));
+        assert_eq!(limiter.check(101), None);
+    }
+}
@@ -0,0 +1,126 @@
+#[cfg(any(feature = "alloc-stats", test))]
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(any(feature = "alloc-stats", test))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+/// System allocator that keeps track of live and peak heap usage. Compared
+/// with the totals of the system allocator, this shows fragmentation.
+#[cfg(any(feature = "alloc-stats", test))]
+pub struct Counting {
+    live: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+}
+
+#[cfg(any(feature = "alloc-stats", test))]
+impl Counting {
+    pub const fn new() -> Counting {
+        Counting {
+            live: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+        }
+    }
+
+    fn grow(&self, bytes: usize) {
+        let live = self.live.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(live, Ordering::Relaxed);
+    }
+
+    fn shrink(&self, bytes: usize) {
+        self.live.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            live: self.live.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(any(feature = "alloc-stats", test))]
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            self.grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            self.grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        self.shrink(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            self.shrink(layout.size());
+            self.grow(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Heap usage as seen by `Counting`.
+#[derive(Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HeapStats {
+    /// Bytes currently allocated.
+    pub live: usize,
+    /// Maximum of live bytes so far.
+    pub peak: usize,
+    /// Number of allocations so far.
+    pub allocations: usize,
+}
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static GLOBAL: Counting = Counting::new();
+
+/// Heap usage, if built with the `alloc-stats` feature.
+#[cfg(feature = "alloc-stats")]
+pub fn heap_stats() -> Option<HeapStats> {
+    Some(GLOBAL.stats())
+}
+
+#[cfg(not(feature = "alloc-stats"))]
+pub fn heap_stats() -> Option<HeapStats> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counting() {
+        let counting = Counting::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = counting.alloc(layout);
+            assert_eq!(counting.stats(), HeapStats { live: 100, peak: 100, allocations: 1 });
+            let ptr = counting.realloc(ptr, layout, 300);
+            assert_eq!(counting.stats(), HeapStats { live: 300, peak: 300, allocations: 1 });
+            counting.dealloc(ptr, Layout::from_size_align(300, 8).unwrap());
+        }
+        assert_eq!(counting.stats(), HeapStats { live: 0, peak: 300, allocations: 1 });
+
+        #[cfg(feature = "alloc-stats")]
+        assert!(heap_stats().is_some_and(|stats| stats.live > 0));
+    }
+}
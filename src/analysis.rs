@@ -4,25 +4,52 @@ use serde::{Deserialize, Serialize};
 
 use arrayvec::ArrayString;
 
-use shakmaty::{Square, Castles, PositionError, Setup, Position, MoveList, Role, IllegalMoveError, File, MaterialSide, Material};
+use shakmaty::{Square, Castles, PositionError, Setup, Position, MoveList, Role, IllegalMoveError, File, MaterialSide, Material, RemainingChecks};
 use shakmaty::variants::{Variant, VariantPosition};
 use shakmaty::fen::{Fen, FenOpts, ParseFenError};
-use shakmaty::san::SanPlus;
+use shakmaty::san::{ParseSanError, SanError, SanPlus};
 use shakmaty::uci::Uci;
 use shakmaty::attacks;
 
+use crate::model::VariantKey;
+use crate::pgn::{self, InvalidPgn};
 use crate::util;
 
-#[derive(Serialize)]
 pub struct Opening {
     eco: &'static str,
     name: &'static str,
+    family: &'static str, // name up to the colon
+    variation: Option<&'static str>, // name after the colon
+    ply: u32, // of the main line
+}
+
+/// Opening as sent to clients.
+#[derive(Serialize)]
+pub struct OpeningInfo {
+    eco: &'static str,
+    name: &'static str,
+    family: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variation: Option<&'static str>,
+    ply: u32,
 }
 
-fn lookup_opening(mut fen: Fen) -> Option<&'static Opening> {
+impl From<&'static Opening> for OpeningInfo {
+    fn from(opening: &'static Opening) -> OpeningInfo {
+        OpeningInfo {
+            eco: opening.eco,
+            name: opening.name,
+            family: opening.family,
+            variation: opening.variation,
+            ply: opening.ply,
+        }
+    }
+}
+
+fn lookup_opening(mut fen: Fen) -> Option<OpeningInfo> {
     fen.pockets = None;
     fen.remaining_checks = None;
-    OPENING_DB.get(FenOpts::new().epd(&fen).as_str())
+    OPENING_DB.get(FenOpts::new().epd(&fen).as_str()).map(OpeningInfo::from)
 }
 
 fn uci_char_pair(uci: &Uci) -> ArrayString<[u8; 3]> {
@@ -122,7 +149,7 @@ fn dests(pos: &VariantPosition) -> String {
 fn drops(pos: &VariantPosition) -> Option<String> {
     let checkers = pos.checkers();
 
-    if checkers.is_empty() || pos.pockets().map_or(true, |p| p.by_color(pos.turn()).is_empty()) {
+    if checkers.is_empty() || pos.pockets().is_none_or(|p| p.by_color(pos.turn()).is_empty()) {
         None
     } else if let Some(checker) = checkers.single_square() {
         let king = pos.board().king_of(pos.turn()).expect("king in crazyhouse");
@@ -140,6 +167,14 @@ fn fix_castles(variant: Variant, fen: &mut Fen) {
     }
 }
 
+/// Sets up a position from a FEN sent by a client, ignoring invalid
+/// castling rights.
+pub fn position(variant: Variant, fen: &str) -> Result<VariantPosition, StepFailure> {
+    let mut fen: Fen = fen.parse()?;
+    fix_castles(variant, &mut fen);
+    Ok(VariantPosition::from_setup(variant, &fen)?)
+}
+
 #[derive(Deserialize)]
 enum PromotableRole {
     #[serde(rename = "knight")]
@@ -151,7 +186,7 @@ enum PromotableRole {
     #[serde(rename = "queen")]
     Queen,
     #[serde(rename = "king")]
-    King,
+    King, // antichess only, rejected as illegal otherwise
 }
 
 #[derive(Deserialize)]
@@ -192,30 +227,6 @@ impl From<DroppableRole> for Role {
     }
 }
 
-#[derive(Deserialize, Copy, Clone)]
-enum VariantKey {
-    #[serde(rename = "standard")]
-    Standard,
-    #[serde(rename = "fromPosition")]
-    FromPosition,
-    #[serde(rename = "chess960")]
-    Chess960,
-    #[serde(rename = "antichess")]
-    Antichess,
-    #[serde(rename = "kingOfTheHill")]
-    KingOfTheHill,
-    #[serde(rename = "threeCheck")]
-    ThreeCheck,
-    #[serde(rename = "atomic")]
-    Atomic,
-    #[serde(rename = "horde")]
-    Horde,
-    #[serde(rename = "racingKings")]
-    RacingKings,
-    #[serde(rename = "crazyhouse")]
-    Crazyhouse,
-}
-
 impl From<VariantKey> for Variant {
     fn from(variant: VariantKey) -> Variant {
         match variant {
@@ -232,10 +243,7 @@ impl From<VariantKey> for Variant {
 }
 
 fn is_opening_sensible(variant: Variant) -> bool {
-    match variant {
-        Variant::Chess | Variant::Crazyhouse | Variant::ThreeCheck | Variant::KingOfTheHill => true,
-        _ => false,
-    }
+    matches!(variant, Variant::Chess | Variant::Crazyhouse | Variant::ThreeCheck | Variant::KingOfTheHill)
 }
 
 #[derive(Deserialize)]
@@ -261,9 +269,10 @@ impl GetOpening {
 #[derive(Serialize)]
 pub struct OpeningResponse {
     path: String,
-    opening: &'static Opening,
+    opening: OpeningInfo,
 }
 
+
 #[derive(Deserialize)]
 pub struct GetDests {
     variant: Option<VariantKey>,
@@ -274,6 +283,10 @@ pub struct GetDests {
 }
 
 impl GetDests {
+    pub fn fen(&self) -> &str {
+        &self.fen
+    }
+
     pub fn respond(self) -> Result<DestsResponse, StepFailure> {
         let variant = Variant::from(self.variant.unwrap_or(VariantKey::Standard));
         let mut fen: Fen = self.fen.parse()?;
@@ -290,6 +303,43 @@ impl GetDests {
     }
 }
 
+/// Maximum number of positions in a batch of dests requests. Sized so that
+/// a full batch of typical positions with paths of 16 plies still fits in a
+/// client message.
+pub const MAX_DESTS_BATCH: usize = 16;
+
+/// Dests for a single position, or a batch of positions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum DestsRequest {
+    Single(GetDests),
+    Batch(Vec<GetDests>),
+}
+
+/// Dests for a position of a batch, or the failure to compute them.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BatchedDests {
+    Dests(DestsResponse),
+    Failure {
+        path: String,
+        failure: bool,
+    },
+}
+
+pub fn respond_batch(batch: Vec<GetDests>) -> Vec<BatchedDests> {
+    batch.into_iter().map(|d| {
+        let path = d.path.clone();
+        match d.respond() {
+            Ok(res) => BatchedDests::Dests(res),
+            Err(err) => {
+                log::warn!("analysis dests failure in batch {:?}: {}", err, path);
+                BatchedDests::Failure { path, failure: true }
+            }
+        }
+    }).collect()
+}
+
 #[derive(Serialize)]
 pub struct DestsResponse {
     path: String,
@@ -297,7 +347,7 @@ pub struct DestsResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     drops: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    opening: Option<&'static Opening>,
+    opening: Option<OpeningInfo>,
     #[serde(rename = "ch", skip_serializing_if = "Option::is_none")]
     chapter_id: Option<String>,
 }
@@ -368,35 +418,146 @@ impl From<PlayDrop> for PlayStep {
 }
 
 impl PlayStep {
+    pub fn fen(&self) -> &str {
+        &self.fen
+    }
+
     pub fn respond(self) -> Result<Node, StepFailure> {
         let variant = Variant::from(self.variant.unwrap_or(VariantKey::Standard));
-        let mut fen: Fen = self.fen.parse()?;
-        fix_castles(variant, &mut fen);
-        let mut pos = VariantPosition::from_setup(variant, &fen)?;
+        let mut pos = position(variant, &self.fen)?;
 
         let m = self.uci.to_move(&pos)?;
         let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
 
         Ok(Node {
-            node: Branch {
-                children: Vec::new(),
-                san: san.to_string(),
-                uci: self.uci.to_string(),
-                id: uci_char_pair(&self.uci),
-                dests: dests(&pos),
-                drops: drops(&pos),
-                check: pos.is_check(),
-                fen: FenOpts::default().scid(true).promoted(variant == Variant::Crazyhouse).fen(&pos),
-                ply: (pos.fullmoves() - 1) * 2 + pos.turn().fold(0, 1),
-                opening: lookup_opening(Fen::from_setup(&pos)).filter(|_| is_opening_sensible(variant)),
-                crazy: pos.pockets().map(CrazyData::from)
-            },
+            node: Branch::new(variant, &pos, &self.uci, &san),
             path: self.path,
             chapter_id: self.chapter_id
         })
     }
 }
 
+/// Maximum number of moves of a line.
+const MAX_LINE_PLIES: usize = 100;
+
+#[derive(Deserialize)]
+pub struct PlayLine {
+    variant: Option<VariantKey>,
+    fen: String,
+    path: String,
+    #[serde(deserialize_with = "util::space_separated")]
+    ucis: Vec<Uci>,
+    #[serde(rename = "ch")]
+    chapter_id: Option<String>,
+}
+
+impl PlayLine {
+    pub fn respond(self) -> Result<Line, StepFailure> {
+        if self.ucis.len() > MAX_LINE_PLIES {
+            return Err(StepFailure::TooManyMoves);
+        }
+
+        let variant = Variant::from(self.variant.unwrap_or(VariantKey::Standard));
+        let mut pos = position(variant, &self.fen)?;
+
+        let mut nodes = Vec::with_capacity(self.ucis.len());
+        for uci in &self.ucis {
+            let m = uci.to_move(&pos)?;
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            nodes.push(Branch::new(variant, &pos, uci, &san));
+        }
+
+        Ok(Line {
+            path: self.path,
+            nodes,
+            chapter_id: self.chapter_id,
+        })
+    }
+}
+
+/// Nodes reached by playing a line, each a child of the previous one.
+#[derive(Serialize)]
+pub struct Line {
+    path: String,
+    nodes: Vec<Branch>,
+    #[serde(rename = "ch", skip_serializing_if = "Option::is_none")]
+    chapter_id: Option<String>,
+}
+
+/// Maximum number of moves of imported games.
+const MAX_IMPORT_PLIES: usize = 600;
+
+#[derive(Deserialize)]
+pub struct ImportPgn {
+    variant: Option<VariantKey>,
+    pgn: String,
+    #[serde(rename = "ch")]
+    chapter_id: Option<String>,
+}
+
+/// Variant from the PGN tag, which is a human readable name like
+/// `King of the Hill`.
+fn variant_from_tag(tag: &str) -> Option<VariantKey> {
+    let name: String = tag.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_lowercase()).collect();
+    Some(match name.as_str() {
+        "standard" => VariantKey::Standard,
+        "fromposition" => VariantKey::FromPosition,
+        "chess960" => VariantKey::Chess960,
+        "antichess" => VariantKey::Antichess,
+        "kingofthehill" => VariantKey::KingOfTheHill,
+        "threecheck" => VariantKey::ThreeCheck,
+        "atomic" => VariantKey::Atomic,
+        "horde" => VariantKey::Horde,
+        "racingkings" => VariantKey::RacingKings,
+        "crazyhouse" => VariantKey::Crazyhouse,
+        _ => return None,
+    })
+}
+
+impl ImportPgn {
+    pub fn respond(self) -> Result<ImportedPgn, StepFailure> {
+        let variant = Variant::from(self.variant.unwrap_or(VariantKey::Standard));
+        let game = pgn::parse(&self.pgn)?;
+        if let Some(tag) = game.tag("Variant") {
+            if variant_from_tag(tag).map(Variant::from) != Some(variant) {
+                return Err(StepFailure::VariantMismatch);
+            }
+        }
+        if game.sans.len() > MAX_IMPORT_PLIES {
+            return Err(StepFailure::TooManyMoves);
+        }
+
+        let mut pos = match game.tag("FEN") {
+            Some(fen) => position(variant, fen)?,
+            None => VariantPosition::new(variant),
+        };
+        let fen = FenOpts::default().scid(true).promoted(variant == Variant::Crazyhouse).fen(&pos);
+
+        let mut nodes = Vec::with_capacity(game.sans.len());
+        for san in game.sans {
+            let m = SanPlus::from_ascii(san.as_bytes())?.san.to_move(&pos)?;
+            let uci = Uci::from_move(&pos, &m);
+            let san = SanPlus::from_move_and_play_unchecked(&mut pos, &m);
+            nodes.push(Branch::new(variant, &pos, &uci, &san));
+        }
+
+        Ok(ImportedPgn {
+            fen,
+            nodes,
+            chapter_id: self.chapter_id,
+        })
+    }
+}
+
+/// Mainline of an imported game.
+#[derive(Serialize)]
+pub struct ImportedPgn {
+    fen: String, // initial position
+    nodes: Vec<Branch>,
+    #[serde(rename = "ch", skip_serializing_if = "Option::is_none")]
+    chapter_id: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct Node {
     node: Branch,
@@ -417,11 +578,33 @@ pub struct Branch {
     check: bool,
     dests: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    opening: Option<&'static Opening>,
+    opening: Option<OpeningInfo>,
     #[serde(skip_serializing_if = "Option::is_none")]
     drops: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     crazy: Option<CrazyData>,
+    #[serde(rename = "checkCount", skip_serializing_if = "Option::is_none")]
+    check_count: Option<CheckCount>,
+}
+
+impl Branch {
+    /// Describes the position `pos` reached with a move.
+    fn new(variant: Variant, pos: &VariantPosition, uci: &Uci, san: &SanPlus) -> Branch {
+        Branch {
+            children: Vec::new(),
+            san: san.to_string(),
+            uci: uci.to_string(),
+            id: uci_char_pair(uci),
+            dests: dests(pos),
+            drops: drops(pos),
+            check: pos.is_check(),
+            fen: FenOpts::default().scid(true).promoted(variant == Variant::Crazyhouse).fen(pos),
+            ply: (pos.fullmoves() - 1) * 2 + pos.turn().fold(0, 1),
+            opening: lookup_opening(Fen::from_setup(pos)).filter(|_| is_opening_sensible(variant)),
+            crazy: pos.pockets().map(CrazyData::from),
+            check_count: pos.remaining_checks().map(CheckCount::from),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -437,6 +620,22 @@ impl<'a> From<&'a Material> for CrazyData {
     }
 }
 
+/// Checks given by each side in three-check.
+#[derive(Serialize)]
+pub struct CheckCount {
+    white: u8,
+    black: u8,
+}
+
+impl<'a> From<&'a RemainingChecks> for CheckCount {
+    fn from(remaining: &'a RemainingChecks) -> CheckCount {
+        CheckCount {
+            white: 3u8.saturating_sub(remaining.white),
+            black: 3u8.saturating_sub(remaining.black),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct CrazyPocket {
     #[serde(skip_serializing_if = "util::is_zero_u8")]
@@ -464,10 +663,16 @@ impl<'a> From<&'a MaterialSide> for CrazyPocket {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names, dead_code)] // errors are only logged
 pub enum StepFailure {
     ParseFenError(ParseFenError),
     PositionError(PositionError),
     IllegalMoveError(IllegalMoveError),
+    InvalidPgn(InvalidPgn),
+    ParseSanError(ParseSanError),
+    SanError(SanError),
+    VariantMismatch,
+    TooManyMoves,
 }
 
 impl From<ParseFenError> for StepFailure {
@@ -488,6 +693,24 @@ impl From<IllegalMoveError> for StepFailure {
     }
 }
 
+impl From<InvalidPgn> for StepFailure {
+    fn from(err: InvalidPgn) -> StepFailure {
+        StepFailure::InvalidPgn(err)
+    }
+}
+
+impl From<ParseSanError> for StepFailure {
+    fn from(err: ParseSanError) -> StepFailure {
+        StepFailure::ParseSanError(err)
+    }
+}
+
+impl From<SanError> for StepFailure {
+    fn from(err: SanError) -> StepFailure {
+        StepFailure::SanError(err)
+    }
+}
+
 include!(concat!(env!("OUT_DIR"), "/opening_db.rs"));
 
 #[cfg(test)]
@@ -517,9 +740,176 @@ mod tests {
         assert_eq!(&uci_char_pair(&Uci::Normal { from: Square::B7, to: Square::B8, promotion: Some(Role::Queen) }), "Td");
         assert_eq!(&uci_char_pair(&Uci::Normal { from: Square::B7, to: Square::C8, promotion: Some(Role::Queen) }), "Te");
         assert_eq!(&uci_char_pair(&Uci::Normal { from: Square::B7, to: Square::C8, promotion: Some(Role::Knight) }), "T}");
+        assert_eq!(&uci_char_pair(&Uci::Normal { from: Square::B7, to: Square::B8, promotion: Some(Role::King) }), "T\u{84}");
 
         // drops
         assert_eq!(&uci_char_pair(&Uci::Put { to: Square::A1, role: Role::Pawn }), "#\u{8f}");
         assert_eq!(&uci_char_pair(&Uci::Put { to: Square::H8, role: Role::Queen }), "b\u{8b}");
     }
+
+    #[test]
+    fn test_opening_response() {
+        let get = |fen: &str| GetOpening {
+            variant: None,
+            path: "path".to_owned(),
+            fen: fen.to_owned(),
+        };
+
+        let res = get("rnbqkbnr/pppppppp/8/8/8/7N/PPPPPPPP/RNBQKB1R b KQkq - 1 1").respond().unwrap();
+        assert_eq!(serde_json::to_string(&res).unwrap(),
+                   r#"{"path":"path","opening":{"eco":"A00","name":"Amar Opening","family":"Amar Opening","ply":1}}"#);
+
+        let res = get("rn1qkbnr/ppp2ppp/8/3p4/8/6PB/PPPPP3/RNBQ1RK1 b kq - 0 6").respond().unwrap();
+        assert_eq!(serde_json::to_string(&res).unwrap(),
+                   r#"{"path":"path","opening":{"eco":"A00","name":"Amar Opening: Gent Gambit","family":"Amar Opening","variation":"Gent Gambit","ply":11}}"#);
+
+        assert!(get("8/8/8/8/8/8/8/8 w - - 0 1").respond().is_none());
+    }
+
+    #[test]
+    fn test_antichess_promotion() {
+        let play = |variant: &str, promotion: &str| -> Result<Node, StepFailure> {
+            let d: PlayMove = serde_json::from_value(serde_json::json!({
+                "orig": "b7",
+                "dest": "b8",
+                "variant": variant,
+                "fen": "8/1P6/8/8/8/8/8/k6K w - - 0 1",
+                "path": "",
+                "promotion": promotion,
+            })).unwrap();
+            PlayStep::from(d).respond()
+        };
+
+        let node = play("antichess", "king").unwrap().node;
+        assert_eq!(node.uci, "b7b8k");
+        assert_eq!(node.san, "b8=K");
+        assert_eq!(&node.id, "T\u{84}");
+        assert_eq!(node.fen, "1K6/8/8/8/8/8/8/k6K b - - 0 1");
+
+        // Kings can be promoted to only in antichess.
+        assert!(matches!(play("standard", "king"), Err(StepFailure::IllegalMoveError(_))));
+        assert!(play("standard", "queen").is_ok());
+
+        // Also in lines and imported games.
+        let line: PlayLine = serde_json::from_value(serde_json::json!({
+            "variant": "antichess",
+            "fen": "8/1P6/8/8/8/8/8/k6K w - - 0 1",
+            "path": "",
+            "ucis": "b7b8k",
+        })).unwrap();
+        assert_eq!(line.respond().unwrap().nodes[0].san, "b8=K");
+        let imported = ImportPgn {
+            variant: Some(VariantKey::Antichess),
+            pgn: "[Variant \"Antichess\"]\n[FEN \"8/1P6/8/8/8/8/8/k6K w - - 0 1\"]\n\n1. b8=K".to_owned(),
+            chapter_id: None,
+        }.respond().unwrap();
+        assert_eq!(imported.nodes[0].uci, "b7b8k");
+    }
+
+    #[test]
+    fn test_three_check() {
+        let play = |fen: &str, ucis: &str| -> Line {
+            serde_json::from_value::<PlayLine>(serde_json::json!({
+                "variant": "threeCheck",
+                "fen": fen,
+                "path": "",
+                "ucis": ucis,
+            })).unwrap().respond().unwrap()
+        };
+
+        // Counters default to no checks given, and are carried through moves.
+        let line = play("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "e2e4 d7d5 f1b5");
+        assert_eq!(line.nodes[2].fen, "rnbqkbnr/ppp1pppp/8/1B1p4/4P3/8/PPPP1PPP/RNBQK1NR b KQkq - 1 2 +1+0");
+        assert_eq!(serde_json::to_string(&line.nodes[2].check_count).unwrap(), r#"{"white":1,"black":0}"#);
+        assert!(line.nodes[0].check_count.is_some());
+
+        // Node fens are accepted back, as are remaining checks.
+        for fen in &["rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2 +2+1",
+                     "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 1+2 0 2"] {
+            let line = play(fen, "f1b5");
+            let node = &line.nodes[0];
+            assert_eq!(node.fen, "rnbqkbnr/ppp1pppp/8/1B1p4/4P3/8/PPPP1PPP/RNBQK1NR b KQkq - 1 2 +3+1");
+            assert!(node.check);
+
+            // The third check wins.
+            assert_eq!(node.dests, "");
+        }
+
+        // Not tracked in other variants.
+        let line = serde_json::from_value::<PlayLine>(serde_json::json!({
+            "fen": "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 2 +2+1",
+            "path": "",
+            "ucis": "f1b5",
+        })).unwrap().respond().unwrap();
+        assert_eq!(line.nodes[0].fen, "rnbqkbnr/ppp1pppp/8/1B1p4/4P3/8/PPPP1PPP/RNBQK1NR b KQkq - 1 2");
+        assert!(line.nodes[0].check_count.is_none());
+        assert_ne!(line.nodes[0].dests, "");
+    }
+
+    #[test]
+    fn test_dests_batch() {
+        let req: DestsRequest = serde_json::from_str(r##"[
+            {"fen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "path": ""},
+            {"fen": "invalid", "path": "#+"},
+            {"fen": "7k/6Q1/6K1/8/8/8/8/8 b - - 0 1", "path": "#+#+"}
+        ]"##).unwrap();
+        let batch = match req {
+            DestsRequest::Batch(batch) => batch,
+            DestsRequest::Single(_) => panic!("expected batch"),
+        };
+        assert_eq!(serde_json::to_string(&respond_batch(batch)).unwrap(),
+                   r##"[{"path":"","dests":"bqs gvx iqy jrz ksA ltB muC nvD owE pxF"},{"path":"#+","failure":true},{"path":"#+#+","dests":""}]"##);
+
+        let req: DestsRequest = serde_json::from_str(r#"{"fen": "8/8/8/8/8/8/8/8 w - - 0 1", "path": ""}"#).unwrap();
+        assert!(matches!(req, DestsRequest::Single(_)));
+    }
+
+    #[test]
+    fn test_line() {
+        let play = |ucis: &str| -> Result<Line, StepFailure> {
+            serde_json::from_value::<PlayLine>(serde_json::json!({
+                "fen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "path": "",
+                "ucis": ucis,
+            })).unwrap().respond()
+        };
+
+        let line = play("e2e4 e7e5 g1f3").unwrap();
+        assert_eq!(line.nodes.iter().map(|n| n.san.as_str()).collect::<Vec<_>>(), ["e4", "e5", "Nf3"]);
+        assert_eq!(line.nodes[2].ply, 3);
+        assert_eq!(line.nodes[2].fen, "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2");
+
+        assert!(matches!(play("e2e4 e2e4"), Err(StepFailure::IllegalMoveError(_))));
+        assert!(matches!(play(&vec!["g1f3 f3g1 g8f6 f6g8"; 26].join(" ")), Err(StepFailure::TooManyMoves)));
+    }
+
+    #[test]
+    fn test_import_pgn() {
+        let import = |variant: Option<VariantKey>, pgn: &str| ImportPgn {
+            variant,
+            pgn: pgn.to_owned(),
+            chapter_id: None,
+        }.respond();
+
+        let res = import(None, "1. Nh3 d5 2. g3 e5 3. f4 Bxh3 4. Bxh3 exf4 5. O-O fxg3 6. Rxf7 Qh4 7. Rxf8+").unwrap();
+        assert_eq!(res.fen, "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        assert_eq!(res.nodes.len(), 13);
+        assert_eq!(res.nodes[0].opening.as_ref().unwrap().name, "Amar Opening");
+        assert_eq!(res.nodes[8].uci, "e1g1");
+        assert_eq!(res.nodes[12].san, "Rxf8+");
+        assert!(res.nodes[12].check);
+        assert_eq!(res.nodes[12].ply, 13);
+
+        // Starting from a position in the selected variant.
+        let res = import(Some(VariantKey::Crazyhouse), r#"[Variant "Crazyhouse"]
+[FEN "rnbqkbnr/ppp1pppp/8/8/8/8/PPPP1PPP/RNBQKBNR/Pp w KQkq - 0 3"]
+
+3. P@d5 *"#).unwrap();
+        assert_eq!(res.nodes[0].uci, "P@d5");
+        assert!(res.nodes[0].crazy.is_some());
+
+        assert!(matches!(import(None, r#"[Variant "Atomic"] 1. e4"#), Err(StepFailure::VariantMismatch)));
+        assert!(matches!(import(None, "1. e4 e5 2. Ke3"), Err(StepFailure::SanError(_))));
+        assert!(matches!(import(None, "1. e4 {"), Err(StepFailure::InvalidPgn(_))));
+    }
 }
@@ -0,0 +1,144 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::model::UserId;
+
+/// Number of recent closes to remember.
+const CAPACITY: usize = 1000;
+
+/// Why a socket was closed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CloseReason {
+    /// No messages from the client for too long.
+    IdleTimeout,
+    /// Nothing but pings from the client for too long.
+    UserIdle,
+    /// Invalid or binary message from the client.
+    Protocol,
+    /// Persistently exceeding the rate limit.
+    RateLimited,
+    /// Message exceeding the size limit.
+    Oversized,
+    /// Client was not reading its messages.
+    Stale,
+    /// Outgoing queue was full for too long.
+    SlowConsumer,
+    /// Requested by lila.
+    Kicked,
+    /// Server is shutting down.
+    Shutdown,
+    /// Closed by the client, with the given close code.
+    Client(Option<u16>),
+    /// Connection dropped without close handshake.
+    Dropped,
+    /// Transport or protocol level error.
+    Error,
+}
+
+impl CloseReason {
+    fn kind(&self) -> &'static str {
+        match self {
+            CloseReason::IdleTimeout => "idleTimeout",
+            CloseReason::UserIdle => "userIdle",
+            CloseReason::Protocol => "protocol",
+            CloseReason::RateLimited => "rateLimited",
+            CloseReason::Oversized => "oversized",
+            CloseReason::Stale => "stale",
+            CloseReason::SlowConsumer => "slowConsumer",
+            CloseReason::Kicked => "kicked",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::Client(_) => "client",
+            CloseReason::Dropped => "dropped",
+            CloseReason::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Client(Some(code)) => write!(f, "client:{}", code),
+            reason => f.write_str(reason.kind()),
+        }
+    }
+}
+
+impl Serialize for CloseReason {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CloseRecord {
+    pub socket: u64,
+    pub at: u64,
+    pub reason: CloseReason,
+    pub user: Option<UserId>,
+    pub ip: Option<IpAddr>,
+}
+
+/// Recent socket closes and totals per reason.
+#[derive(Default)]
+pub struct CloseAudit {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default, Serialize)]
+struct Inner {
+    totals: BTreeMap<&'static str, u64>,
+    recent: VecDeque<CloseRecord>,
+}
+
+impl CloseAudit {
+    pub fn record(&self, socket: u64, reason: CloseReason, user: Option<UserId>, ip: Option<IpAddr>) {
+        let mut inner = self.inner.lock();
+        *inner.totals.entry(reason.kind()).or_insert(0) += 1;
+        if inner.recent.len() >= CAPACITY {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(CloseRecord {
+            socket,
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            reason,
+            user,
+            ip,
+        });
+    }
+
+    /// Totals per reason, like `client=12 idleTimeout=3`.
+    pub fn report(&self) -> String {
+        let inner = self.inner.lock();
+        let totals: Vec<String> = inner.totals.iter().map(|(kind, n)| format!("{}={}", kind, n)).collect();
+        format!("closes: {}", totals.join(" "))
+    }
+
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(&*self.inner.lock()).expect("serialize close audit")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_audit() {
+        let audit = CloseAudit::default();
+        for i in 0..(CAPACITY as u64 + 5) {
+            audit.record(i, CloseReason::Client(Some(1001)), None, None);
+        }
+        audit.record(0, CloseReason::IdleTimeout, None, None);
+        assert_eq!(audit.report(), "closes: client=1005 idleTimeout=1");
+
+        let inner = audit.inner.lock();
+        assert_eq!(inner.recent.len(), CAPACITY);
+        assert_eq!(inner.recent.front().unwrap().socket, 6);
+        assert_eq!(serde_json::to_string(&inner.recent.front().unwrap().reason).unwrap(), r#""client:1001""#);
+    }
+}
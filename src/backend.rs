@@ -0,0 +1,333 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel;
+
+use mongodb::ThreadedClient as _;
+use mongodb::db::ThreadedDatabase as _;
+use mongodb::coll::Collection;
+use mongodb::coll::options::FindOptions;
+use bson::{doc, bson};
+
+use redis::Commands as _;
+
+use crate::model::UserId;
+
+#[derive(Debug)]
+pub enum BackendError {
+    Redis(redis::RedisError),
+    Mongo(Box<mongodb::Error>),
+    /// No answer in time. The attempt may still complete in the background.
+    Timeout,
+    /// A previous attempt has not completed yet.
+    Busy,
+    #[cfg(test)]
+    Closed,
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Redis(err) => write!(f, "redis: {}", err),
+            BackendError::Mongo(err) => write!(f, "mongodb: {}", err),
+            BackendError::Timeout => f.write_str("timed out"),
+            BackendError::Busy => f.write_str("previous attempt still pending"),
+            #[cfg(test)]
+            BackendError::Closed => f.write_str("closed"),
+        }
+    }
+}
+
+impl From<redis::RedisError> for BackendError {
+    fn from(err: redis::RedisError) -> BackendError {
+        BackendError::Redis(err)
+    }
+}
+
+impl From<mongodb::Error> for BackendError {
+    fn from(err: mongodb::Error) -> BackendError {
+        BackendError::Mongo(Box::new(err))
+    }
+}
+
+/// Message transport between lila and this server.
+pub trait LilaBus: Send + Sync {
+    /// Opens a connection for messages to lila.
+    fn publisher(&self) -> Result<Box<dyn Publisher>, BackendError>;
+
+    /// Subscribes to messages from lila and passes them to the handler,
+    /// until the connection fails. Calls `subscribed` once the subscription
+    /// is confirmed.
+    fn subscribe(&self, subscribed: &mut dyn FnMut(), handler: &mut dyn FnMut(&str)) -> Result<(), BackendError>;
+
+    /// Verifies that publishing and subscribing is possible.
+    fn check(&self) -> Result<(), BackendError>;
+
+    /// Publishes an alert for ops, on a fresh connection. Called from the
+    /// panic hook, so it must not block for long.
+    fn alert(&self, msg: &str) -> Result<(), BackendError>;
+
+    /// Stores a snapshot of connected users for lila, on a fresh
+    /// connection. Must not block for long either.
+    fn store_presence(&self, snapshot: &str) -> Result<(), BackendError>;
+}
+
+pub trait Publisher {
+    /// Publishes a message to lila. Returns the number of receivers.
+    fn publish(&mut self, msg: &str) -> Result<u32, BackendError>;
+}
+
+/// Lookup of authenticated users by session id.
+pub trait SessionStore: Send + Sync {
+    fn user_id(&self, session_id: &str) -> Result<Option<UserId>, BackendError>;
+
+    /// Verifies that the store is reachable.
+    fn check(&self) -> Result<(), BackendError>;
+}
+
+/// Timeout for publishing alerts, which happens on the way down.
+const ALERT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Timeout for storing presence snapshots, which blocks handling messages
+/// from lila.
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Presence snapshots expire after this many seconds, so that a stale
+/// snapshot is never mistaken for a fresh one.
+const PRESENCE_TTL: usize = 5 * 60;
+
+/// Redis pub/sub on the site-in and site-out channels, alerts on the
+/// socket-alert channel, and presence snapshots in the socket-presence key.
+pub struct RedisBus {
+    client: redis::Client,
+    alert_pending: Arc<AtomicBool>,
+    presence_pending: Arc<AtomicBool>,
+}
+
+impl RedisBus {
+    pub fn new(uri: &str) -> Result<RedisBus, BackendError> {
+        Ok(RedisBus {
+            client: redis::Client::open(uri)?,
+            alert_pending: Arc::new(AtomicBool::new(false)),
+            presence_pending: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Runs a command on a fresh connection in a dedicated thread, and
+    /// waits at most `timeout` for it. The redis client has no connect
+    /// timeout, so this is the only way to bound the wait. At most one
+    /// attempt per `pending` flag is in flight, so that hanging attempts
+    /// do not pile up.
+    fn run_with_timeout<F>(&self, pending: &Arc<AtomicBool>, timeout: Duration, f: F) -> Result<(), BackendError>
+    where
+        F: FnOnce(&mut redis::Connection) -> Result<(), BackendError> + Send + 'static,
+    {
+        if pending.swap(true, Ordering::AcqRel) {
+            return Err(BackendError::Busy);
+        }
+        let (tx, rx) = channel::bounded(1);
+        let client = self.client.clone();
+        let done = Arc::clone(pending);
+        let spawned = thread::Builder::new().name("redis-oneshot".to_owned()).spawn(move || {
+            let res = client.get_connection().map_err(BackendError::from).and_then(|mut con| {
+                con.set_read_timeout(Some(timeout))?;
+                con.set_write_timeout(Some(timeout))?;
+                f(&mut con)
+            });
+            done.store(false, Ordering::Release);
+            let _ = tx.send(res);
+        });
+        if let Err(err) = spawned {
+            pending.store(false, Ordering::Release);
+            log::error!("failed to spawn redis thread: {}", err);
+            return Err(BackendError::Busy);
+        }
+        rx.recv_timeout(timeout).unwrap_or(Err(BackendError::Timeout))
+    }
+}
+
+struct RedisPublisher {
+    con: redis::Connection,
+}
+
+impl Publisher for RedisPublisher {
+    fn publish(&mut self, msg: &str) -> Result<u32, BackendError> {
+        Ok(self.con.publish("site-in", msg)?)
+    }
+}
+
+impl LilaBus for RedisBus {
+    fn publisher(&self) -> Result<Box<dyn Publisher>, BackendError> {
+        Ok(Box::new(RedisPublisher {
+            con: self.client.get_connection()?,
+        }))
+    }
+
+    fn subscribe(&self, subscribed: &mut dyn FnMut(), handler: &mut dyn FnMut(&str)) -> Result<(), BackendError> {
+        let mut con = self.client.get_connection()?;
+        let mut incoming = con.as_pubsub();
+        incoming.subscribe("site-out")?;
+        subscribed();
+
+        loop {
+            match incoming.get_message()?.get_payload::<String>() {
+                Ok(msg) => handler(&msg),
+                Err(err) => log::error!("invalid payload from lila: {:?}", err),
+            }
+        }
+    }
+
+    fn check(&self) -> Result<(), BackendError> {
+        let mut con = self.client.get_connection()?;
+        redis::cmd("PING").query::<String>(&mut con)?;
+        let mut pubsub = con.as_pubsub();
+        pubsub.subscribe("site-out")?;
+        pubsub.unsubscribe("site-out")?;
+        Ok(())
+    }
+
+    fn alert(&self, msg: &str) -> Result<(), BackendError> {
+        let msg = msg.to_owned();
+        self.run_with_timeout(&self.alert_pending, ALERT_TIMEOUT, move |con| {
+            con.publish::<_, _, u32>("socket-alert", msg)?;
+            Ok(())
+        })
+    }
+
+    fn store_presence(&self, snapshot: &str) -> Result<(), BackendError> {
+        let snapshot = snapshot.to_owned();
+        self.run_with_timeout(&self.presence_pending, PRESENCE_TIMEOUT, move |con| {
+            con.set_ex::<_, _, ()>("socket-presence", snapshot, PRESENCE_TTL)?;
+            Ok(())
+        })
+    }
+}
+
+/// Sessions in the security collection of the lila database.
+pub struct MongoSessionStore {
+    coll: Collection,
+}
+
+impl MongoSessionStore {
+    pub fn new(uri: &str) -> Result<MongoSessionStore, BackendError> {
+        Ok(MongoSessionStore {
+            coll: mongodb::Client::with_uri(uri)?.db("lichess").collection("security"),
+        })
+    }
+}
+
+impl SessionStore for MongoSessionStore {
+    fn user_id(&self, session_id: &str) -> Result<Option<UserId>, BackendError> {
+        let query = doc! { "_id": session_id, "up": true, };
+        let mut opts = FindOptions::new();
+        opts.projection = Some(doc! { "user": true });
+
+        Ok(self.coll.find_one(Some(query), Some(opts))?
+            .and_then(|doc| doc.get_str("user").ok().and_then(|s| UserId::new(s).ok())))
+    }
+
+    fn check(&self) -> Result<(), BackendError> {
+        let mut opts = FindOptions::new();
+        opts.projection = Some(doc! { "_id": true });
+        self.coll.find_one(None, Some(opts))?;
+        Ok(())
+    }
+}
+
+/// In-memory implementations for tests.
+#[cfg(test)]
+pub mod fake {
+    use std::collections::HashMap;
+
+    use crossbeam::channel;
+
+    use super::*;
+
+    /// Collects messages to lila in a channel and replays messages from lila
+    /// that are sent to the other end of a channel. Alerts and presence
+    /// snapshots are collected with the messages to lila, prefixed with
+    /// `alert` and `presence`. Injecting
+    /// `fake/disconnect` ends the subscription like a lost connection.
+    pub struct FakeBus {
+        site_in: channel::Sender<String>,
+        site_out: channel::Receiver<String>,
+    }
+
+    impl FakeBus {
+        /// Returns the bus, a sender to inject messages from lila, and a
+        /// receiver for messages published to lila.
+        pub fn new() -> (FakeBus, channel::Sender<String>, channel::Receiver<String>) {
+            let (site_in, site_in_recv) = channel::unbounded();
+            let (site_out_send, site_out) = channel::unbounded();
+            (FakeBus { site_in, site_out }, site_out_send, site_in_recv)
+        }
+    }
+
+    struct FakePublisher {
+        site_in: channel::Sender<String>,
+    }
+
+    impl Publisher for FakePublisher {
+        fn publish(&mut self, msg: &str) -> Result<u32, BackendError> {
+            self.site_in.send(msg.to_owned()).map_err(|_| BackendError::Closed)?;
+            Ok(1)
+        }
+    }
+
+    impl LilaBus for FakeBus {
+        fn publisher(&self) -> Result<Box<dyn Publisher>, BackendError> {
+            Ok(Box::new(FakePublisher {
+                site_in: self.site_in.clone(),
+            }))
+        }
+
+        fn subscribe(&self, subscribed: &mut dyn FnMut(), handler: &mut dyn FnMut(&str)) -> Result<(), BackendError> {
+            subscribed();
+            loop {
+                let msg = self.site_out.recv().map_err(|_| BackendError::Closed)?;
+                if msg == "fake/disconnect" {
+                    return Err(BackendError::Closed);
+                }
+                handler(&msg);
+            }
+        }
+
+        fn check(&self) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn alert(&self, msg: &str) -> Result<(), BackendError> {
+            self.site_in.send(format!("alert {}", msg)).map_err(|_| BackendError::Closed)
+        }
+
+        fn store_presence(&self, snapshot: &str) -> Result<(), BackendError> {
+            self.site_in.send(format!("presence {}", snapshot)).map_err(|_| BackendError::Closed)
+        }
+    }
+
+    /// Fixed mapping of session ids to users.
+    #[derive(Default)]
+    pub struct FakeSessionStore {
+        sessions: HashMap<String, UserId>,
+    }
+
+    impl FakeSessionStore {
+        pub fn with_session(mut self, session_id: &str, uid: &str) -> FakeSessionStore {
+            self.sessions.insert(session_id.to_owned(), UserId::new(uid).expect("valid uid"));
+            self
+        }
+    }
+
+    impl SessionStore for FakeSessionStore {
+        fn user_id(&self, session_id: &str) -> Result<Option<UserId>, BackendError> {
+            Ok(self.sessions.get(session_id).cloned())
+        }
+
+        fn check(&self) -> Result<(), BackendError> {
+            Ok(())
+        }
+    }
+}
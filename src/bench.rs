@@ -0,0 +1,339 @@
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures_util::{SinkExt as _, StreamExt as _};
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest as _;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+#[derive(StructOpt, Clone)]
+pub struct BenchOpt {
+    /// Websocket endpoint to benchmark
+    #[structopt(long = "target", default_value = "ws://127.0.0.1:9664/")]
+    target: String,
+    /// Number of simultaneous connections
+    #[structopt(long = "connections", default_value = "100")]
+    connections: u32,
+    /// Duration of the benchmark in seconds
+    #[structopt(long = "duration", default_value = "30")]
+    duration: u64,
+    /// Delay between requests on each connection in milliseconds
+    #[structopt(long = "interval", default_value = "1000")]
+    interval: u64,
+    /// File with session ids (one per line) used to authenticate connections
+    #[structopt(long = "sessions")]
+    sessions: Option<String>,
+    /// Fraction of connections to authenticate with a session id
+    #[structopt(long = "auth-ratio", default_value = "0.5")]
+    auth_ratio: f64,
+    /// Space separated game ids to watch on each connection
+    #[structopt(long = "watch", default_value = "")]
+    watch: String,
+    /// Flag to subscribe to (tournament or simul)
+    #[structopt(long = "flag")]
+    flag: Option<String>,
+    /// Weighted message mix, for example ping:8,dests:1,opening:1
+    #[structopt(long = "mix", default_value = "ping:8,dests:1,opening:1")]
+    mix: Mix,
+}
+
+/// Requests that are answered by the server, so that round trip latencies
+/// can be measured.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Kind {
+    Ping,
+    Dests,
+    Opening,
+}
+
+const KINDS: [Kind; 3] = [Kind::Ping, Kind::Dests, Kind::Opening];
+
+impl Kind {
+    fn request(self) -> &'static str {
+        match self {
+            Kind::Ping => "null",
+            Kind::Dests => r#"{"t":"anaDests","d":{"fen":"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1","path":""}}"#,
+            Kind::Opening => r#"{"t":"opening","d":{"fen":"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1","path":""}}"#,
+        }
+    }
+
+    fn is_response(self, msg: &str) -> bool {
+        match self {
+            Kind::Ping => msg == "0",
+            Kind::Dests => msg.starts_with(r#"{"t":"dests""#) || msg.starts_with(r#"{"t":"destsFailure""#),
+            Kind::Opening => msg.starts_with(r#"{"t":"opening""#),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Kind::Ping => "ping",
+            Kind::Dests => "dests",
+            Kind::Opening => "opening",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidMix;
+
+impl fmt::Display for InvalidMix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid message mix, expected for example ping:8,dests:1")
+    }
+}
+
+/// Relative weights of the request kinds.
+#[derive(Clone, Debug, PartialEq)]
+struct Mix(Vec<(Kind, u32)>);
+
+impl FromStr for Mix {
+    type Err = InvalidMix;
+
+    fn from_str(s: &str) -> Result<Mix, InvalidMix> {
+        let mut mix = Vec::new();
+        for part in s.split(',') {
+            let mut kv = part.splitn(2, ':');
+            let kind = match kv.next() {
+                Some("ping") => Kind::Ping,
+                Some("dests") => Kind::Dests,
+                Some("opening") => Kind::Opening,
+                _ => return Err(InvalidMix),
+            };
+            let weight = kv.next().ok_or(InvalidMix)?.parse().map_err(|_| InvalidMix)?;
+            mix.push((kind, weight));
+        }
+        if mix.iter().map(|&(_, w)| w).sum::<u32>() == 0 {
+            return Err(InvalidMix);
+        }
+        Ok(Mix(mix))
+    }
+}
+
+impl Mix {
+    fn pick(&self, rng: &mut XorShift) -> Kind {
+        let total: u32 = self.0.iter().map(|&(_, w)| w).sum();
+        let mut n = rng.next() % total;
+        for &(kind, weight) in &self.0 {
+            if n < weight {
+                return kind;
+            }
+            n -= weight;
+        }
+        unreachable!("weights sum to total")
+    }
+}
+
+/// Tiny deterministic random number generator, good enough to pick
+/// requests.
+struct XorShift(u32);
+
+impl XorShift {
+    fn new(seed: u32) -> XorShift {
+        XorShift(seed.wrapping_mul(2_654_435_761) | 1)
+    }
+
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
+
+/// Returns the value at the given percentile of a sorted slice.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let idx = ((sorted.len() - 1) as f64 * p / 100.0).round() as usize;
+    sorted[idx]
+}
+
+enum Sample {
+    Latency(Kind, Duration),
+    Timeout(Kind),
+    ConnectFailed,
+    Disconnected,
+}
+
+async fn run_connection(opt: BenchOpt, idx: u32, session: Option<String>, samples: mpsc::UnboundedSender<Sample>) -> Result<(), tungstenite::Error> {
+    let mut url = format!("{}?sri=bench{}", opt.target, idx);
+    if let Some(ref flag) = opt.flag {
+        url.push_str("&flag=");
+        url.push_str(flag);
+    }
+
+    let mut req = url.into_client_request()?;
+    // Spread connections over many addresses to avoid the rate limiter.
+    let ip = format!("10.{}.{}.{}", (idx >> 16) & 0xff, (idx >> 8) & 0xff, idx & 0xff);
+    req.headers_mut().insert("x-forwarded-for", HeaderValue::from_str(&ip).expect("valid ip header"));
+    if let Some(session) = session {
+        let cookie = format!("lila2=bench-sessionId={}", session);
+        req.headers_mut().insert("cookie", HeaderValue::from_str(&cookie).expect("valid cookie header"));
+    }
+
+    let (mut ws, _) = match tokio_tungstenite::connect_async(req).await {
+        Ok(ws) => ws,
+        Err(err) => {
+            let _ = samples.send(Sample::ConnectFailed);
+            return Err(err);
+        }
+    };
+
+    if !opt.watch.is_empty() {
+        ws.send(Message::text(format!(r#"{{"t":"startWatching","d":"{}"}}"#, opt.watch))).await?;
+    }
+
+    let mut rng = XorShift::new(idx);
+    let deadline = Instant::now() + Duration::from_secs(opt.duration);
+
+    // Stagger requests.
+    time::sleep(Duration::from_millis(u64::from(rng.next()) % opt.interval.max(1))).await;
+
+    while Instant::now() < deadline {
+        let kind = opt.mix.pick(&mut rng);
+        let sent = Instant::now();
+        ws.send(Message::text(kind.request())).await?;
+
+        let answered = time::timeout(Duration::from_secs(10), async {
+            while let Some(msg) = ws.next().await {
+                if let Message::Text(text) = msg? {
+                    if kind.is_response(text.as_str()) {
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok::<_, tungstenite::Error>(false)
+        }).await;
+
+        match answered {
+            Ok(Ok(true)) => {
+                let _ = samples.send(Sample::Latency(kind, sent.elapsed()));
+            }
+            Ok(Ok(false)) => {
+                let _ = samples.send(Sample::Disconnected);
+                return Ok(());
+            }
+            Ok(Err(err)) => {
+                let _ = samples.send(Sample::Disconnected);
+                return Err(err);
+            }
+            Err(_) => {
+                let _ = samples.send(Sample::Timeout(kind));
+            }
+        }
+
+        time::sleep(Duration::from_millis(opt.interval)).await;
+    }
+
+    ws.close(None).await
+}
+
+async fn run_bench(opt: BenchOpt) {
+    let sessions: Vec<String> = match opt.sessions {
+        Some(ref path) => fs::read_to_string(path)
+            .expect("read sessions file")
+            .lines()
+            .map(|l| l.trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    for idx in 0..opt.connections {
+        let authenticate = !sessions.is_empty() && f64::from(idx % 1000) < opt.auth_ratio * 1000.0;
+        let session = if authenticate {
+            Some(sessions[idx as usize % sessions.len()].clone())
+        } else {
+            None
+        };
+        let opt = opt.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = run_connection(opt, idx, session, tx).await {
+                log::debug!("bench connection {} failed: {:?}", idx, err);
+            }
+        });
+    }
+    drop(tx);
+
+    let mut stats: Vec<(Kind, Vec<Duration>, u32)> = KINDS.iter().map(|&k| (k, Vec::new(), 0)).collect();
+    let mut connect_failed = 0;
+    let mut disconnected = 0;
+
+    while let Some(sample) = rx.recv().await {
+        match sample {
+            Sample::Latency(kind, latency) => {
+                if let Some((_, latencies, _)) = stats.iter_mut().find(|(k, _, _)| *k == kind) {
+                    latencies.push(latency);
+                }
+            }
+            Sample::Timeout(kind) => {
+                if let Some((_, _, timeouts)) = stats.iter_mut().find(|(k, _, _)| *k == kind) {
+                    *timeouts += 1;
+                }
+            }
+            Sample::ConnectFailed => connect_failed += 1,
+            Sample::Disconnected => disconnected += 1,
+        }
+    }
+
+    println!("connections: {} (failed: {}, disconnected: {})", opt.connections, connect_failed, disconnected);
+    for (kind, mut ls, timeouts) in stats {
+        if ls.is_empty() && timeouts == 0 {
+            continue;
+        }
+        ls.sort();
+        println!("{:>8}: n={} timeouts={} p50={:?} p90={:?} p99={:?} max={:?}",
+                 kind, ls.len(), timeouts,
+                 percentile(&ls, 50.0), percentile(&ls, 90.0), percentile(&ls, 99.0),
+                 ls.last().cloned().unwrap_or_default());
+    }
+}
+
+/// Opens many Websocket connections against a running server and reports
+/// latency percentiles.
+pub fn run(opt: BenchOpt) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+
+    runtime.block_on(run_bench(opt));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix() {
+        assert_eq!("ping:8,dests:1".parse::<Mix>().unwrap(), Mix(vec![(Kind::Ping, 8), (Kind::Dests, 1)]));
+        assert!("ping".parse::<Mix>().is_err());
+        assert!("ping:0".parse::<Mix>().is_err());
+        assert!("pong:1".parse::<Mix>().is_err());
+
+        let mix: Mix = "opening:1".parse().unwrap();
+        let mut rng = XorShift::new(1);
+        assert!((0..100).all(|_| mix.pick(&mut rng) == Kind::Opening));
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(51));
+        assert_eq!(percentile(&sorted, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 50.0), Duration::default());
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use parking_lot::RwLock;
+
+/// Range of IP addresses, like `192.0.2.0/24` or `2001:db8::/32`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug)]
+pub struct InvalidCidr;
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid cidr")
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Cidr, InvalidCidr> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| InvalidCidr)?;
+                (addr, prefix_len.parse().map_err(|_| InvalidCidr)?)
+            }
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| InvalidCidr)?;
+                (addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        if prefix_len > if addr.is_ipv4() { 32 } else { 128 } {
+            return Err(InvalidCidr);
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Blocked ranges of client addresses, from a file with one CIDR per line.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Default)]
+pub struct Blocklist {
+    path: Option<String>,
+    ranges: RwLock<Vec<Cidr>>,
+}
+
+impl Blocklist {
+    pub fn open(path: Option<&str>) -> io::Result<Blocklist> {
+        let blocklist = Blocklist {
+            path: path.map(|p| p.to_owned()),
+            ranges: RwLock::new(Vec::new()),
+        };
+        blocklist.reload()?;
+        Ok(blocklist)
+    }
+
+    /// Reads the file again. Returns the number of ranges.
+    pub fn reload(&self) -> io::Result<usize> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(0),
+        };
+        let ranges = parse(&fs::read_to_string(path)?)
+            .map_err(|line| io::Error::new(io::ErrorKind::InvalidData, format!("invalid cidr in {}: {}", path, line)))?;
+        let len = ranges.len();
+        *self.ranges.write() = ranges;
+        Ok(len)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.ranges.read().iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+fn parse(s: &str) -> Result<Vec<Cidr>, &str> {
+    s.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.parse().map_err(|_| line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "192.0.2.0/24".parse().unwrap();
+        assert!(net.contains("192.0.2.77".parse().unwrap()));
+        assert!(!net.contains("192.0.3.1".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.7".parse().unwrap()));
+
+        let single: Cidr = "203.0.113.7".parse().unwrap();
+        assert!(single.contains("203.0.113.7".parse().unwrap()));
+        assert!(!single.contains("203.0.113.8".parse().unwrap()));
+
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let ranges = parse("# datacenters\n192.0.2.0/24\n\n  2001:db8::/32  \n").unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(parse("192.0.2.0/24\nnonsense"), Err("nonsense"));
+    }
+}
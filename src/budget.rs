@@ -0,0 +1,78 @@
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+/// Estimate of the rate limit credits left to a single connection.
+///
+/// Mirrors the leaky bucket that is shared by all connections of an
+/// address, counting only the messages of this connection. Clients with
+/// multiple connections from the same address may have less left.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    credits: u32,
+    interval: Duration,
+    empty_at: Instant, // when all spent credits have been restored
+}
+
+impl Budget {
+    pub fn new(credits: u32, interval: Duration) -> Budget {
+        Budget {
+            credits,
+            interval,
+            empty_at: Instant::now(),
+        }
+    }
+
+    /// Time to restore a single credit.
+    fn credit_interval(&self) -> Duration {
+        self.interval / self.credits.max(1)
+    }
+
+    /// Records credits spent on accepted messages.
+    pub fn spend(&mut self, n: u32, now: Instant) {
+        self.empty_at = self.empty_at.max(now) + self.credit_interval() * n;
+    }
+
+    /// Records that the shared limiter will not accept messages for `wait`.
+    pub fn exhaust(&mut self, wait: Duration, now: Instant) {
+        self.empty_at = now + wait + self.interval.saturating_sub(self.credit_interval());
+    }
+
+    /// Credits left at `now`.
+    pub fn remaining(&self, now: Instant) -> u32 {
+        let pending = self.empty_at.saturating_duration_since(now);
+        let spent = pending.as_nanos().div_ceil(self.credit_interval().as_nanos().max(1));
+        self.credits.saturating_sub(spent.try_into().unwrap_or(u32::MAX))
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let now = Instant::now();
+        let mut budget = Budget::new(40, Duration::from_secs(10));
+        budget.empty_at = now;
+        assert_eq!(budget.remaining(now), 40);
+
+        budget.spend(1, now);
+        budget.spend(9, now);
+        assert_eq!(budget.remaining(now), 30);
+
+        // Credits are restored over time.
+        assert_eq!(budget.remaining(now + Duration::from_millis(250)), 31);
+        assert_eq!(budget.remaining(now + Duration::from_secs(10)), 40);
+
+        // Spending more than available saturates.
+        budget.spend(100, now);
+        assert_eq!(budget.remaining(now), 0);
+
+        budget.exhaust(Duration::from_secs(1), now);
+        assert_eq!(budget.remaining(now + Duration::from_secs(1)), 1);
+    }
+}
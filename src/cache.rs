@@ -0,0 +1,189 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ipc::MoveMeta;
+use crate::model::GameId;
+
+/// Latest known state of a watched game, sent to new watchers.
+#[derive(Debug)]
+pub struct WatchedGame {
+    pub fen: String,
+    pub lm: String,
+    pub meta: Option<MoveMeta>,
+    pub finished: bool,
+    pub win: Option<char>,
+}
+
+impl WatchedGame {
+    /// Approximate memory usage in bytes.
+    fn size(&self) -> usize {
+        self.fen.len() + self.lm.len()
+    }
+}
+
+struct Entry {
+    state: WatchedGame,
+    seq: u64, // of last insert
+}
+
+/// Bounded cache of watched games. When full, the game that has not seen
+/// a move for the longest time is evicted.
+pub struct GameCache {
+    games: HashMap<GameId, Entry>,
+    order: VecDeque<(u64, GameId)>, // may contain outdated entries
+    seq: u64,
+    capacity: usize,
+    bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Occupancy of `GameCache`, for metrics.
+#[derive(Debug)]
+pub struct GameCacheStats {
+    pub len: usize,
+    pub capacity: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl fmt::Display for GameCacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lookups = self.hits + self.misses;
+        let hit_rate = if lookups > 0 { self.hits as f64 / lookups as f64 } else { 0.0 };
+        write!(f, "game cache: {}/{} games, {} bytes, {} hits, {} misses (hit rate {:.3})",
+               self.len, self.capacity, self.bytes, self.hits, self.misses, hit_rate)
+    }
+}
+
+impl GameCache {
+    pub fn new(capacity: usize) -> GameCache {
+        GameCache {
+            games: HashMap::new(),
+            order: VecDeque::new(),
+            seq: 0,
+            capacity,
+            bytes: 0,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Looks up a game for a new watcher. Counts towards the hit rate.
+    pub fn get(&self, game: &GameId) -> Option<&WatchedGame> {
+        let res = self.games.get(game).map(|e| &e.state);
+        if res.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Mutable access for updates that do not change the size of the
+    /// state.
+    pub fn get_mut(&mut self, game: &GameId) -> Option<&mut WatchedGame> {
+        self.games.get_mut(game).map(|e| &mut e.state)
+    }
+
+    pub fn insert(&mut self, game: GameId, state: WatchedGame) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.seq += 1;
+        self.bytes += state.size();
+        self.order.push_back((self.seq, game.clone()));
+        if let Some(old) = self.games.insert(game, Entry { state, seq: self.seq }) {
+            self.bytes -= old.state.size();
+        }
+
+        while self.games.len() > self.capacity {
+            self.evict_oldest();
+        }
+
+        // Keep outdated entries in order from piling up.
+        if self.order.len() > 2 * self.games.len() + 16 {
+            let games = &self.games;
+            self.order.retain(|(seq, game)| games.get(game).is_some_and(|e| e.seq == *seq));
+        }
+    }
+
+    pub fn remove(&mut self, game: &GameId) {
+        if let Some(entry) = self.games.remove(game) {
+            self.bytes -= entry.state.size();
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        while let Some((seq, game)) = self.order.pop_front() {
+            if self.games.get(&game).is_some_and(|e| e.seq == seq) {
+                self.remove(&game);
+                return;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> GameCacheStats {
+        GameCacheStats {
+            len: self.games.len(),
+            capacity: self.capacity,
+            bytes: self.bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game(id: &str) -> GameId {
+        id.parse().unwrap()
+    }
+
+    fn state(lm: &str) -> WatchedGame {
+        WatchedGame {
+            fen: "8/8/8/8/8/8/8/8".to_owned(),
+            lm: lm.to_owned(),
+            meta: None,
+            finished: false,
+            win: None,
+        }
+    }
+
+    #[test]
+    fn test_game_cache() {
+        let mut cache = GameCache::new(2);
+        cache.insert(game("aaaaaaaa"), state("e2e4"));
+        cache.insert(game("bbbbbbbb"), state("d2d4"));
+        cache.insert(game("aaaaaaaa"), state("e7e5"));
+        cache.insert(game("cccccccc"), state("c2c4"));
+
+        // The game without recent moves was evicted.
+        assert!(cache.get(&game("bbbbbbbb")).is_none());
+        assert_eq!(cache.get(&game("aaaaaaaa")).unwrap().lm, "e7e5");
+        assert!(cache.get(&game("cccccccc")).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.len, 2);
+        assert_eq!(stats.bytes, 2 * (15 + 4));
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+
+        cache.remove(&game("aaaaaaaa"));
+        cache.remove(&game("cccccccc"));
+        assert_eq!(cache.stats().bytes, 0);
+
+        for _ in 0..100 {
+            cache.insert(game("dddddddd"), state("g1f3"));
+        }
+        assert!(cache.order.len() <= 2 + 16 + 1);
+
+        let mut disabled = GameCache::new(0);
+        disabled.insert(game("aaaaaaaa"), state("e2e4"));
+        assert_eq!(disabled.stats().len, 0);
+    }
+}
@@ -0,0 +1,37 @@
+use std::fmt::Display;
+use std::net::ToSocketAddrs as _;
+
+use crate::Opt;
+use crate::backend::{LilaBus, MongoSessionStore, RedisBus, SessionStore};
+
+fn report<T, E: Display>(what: &str, res: Result<T, E>) -> bool {
+    match res {
+        Ok(_) => {
+            println!("ok      {}", what);
+            true
+        }
+        Err(err) => {
+            println!("FAILED  {}: {}", what, err);
+            false
+        }
+    }
+}
+
+/// Validates the configuration and verifies that redis and mongodb are
+/// reachable. Returns `false` if any check failed.
+pub fn run(opt: &Opt) -> bool {
+    let mut ok = true;
+
+    ok &= report(&format!("bind address {}", opt.bind), opt.bind.to_socket_addrs());
+    ok &= report("rate limiter credits", if opt.rate_limiter_credits > 0 { Ok(()) } else { Err("must be positive") });
+    ok &= report("max connections", if opt.max_connections > 0 { Ok(()) } else { Err("must be positive") });
+    ok &= report("queue sizes", if opt.redis_queue_size > 0 && opt.auth_queue_size > 0 { Ok(()) } else { Err("must be positive") });
+
+    ok &= report(&format!("redis at {} (site-out subscription)", opt.redis),
+                 RedisBus::new(&opt.redis).and_then(|bus| bus.check()));
+
+    ok &= report(&format!("mongodb at {} (security collection)", opt.mongodb),
+                 MongoSessionStore::new(&opt.mongodb).and_then(|store| store.check()));
+
+    ok
+}
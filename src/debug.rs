@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::mem;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use serde::Serialize;
+
+use crate::model::{Sri, UserId};
+use crate::trace::{now_ms, MAX_TRACE_DURATION};
+
+/// Connections selected for debugging.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DebugTarget {
+    User(UserId),
+    Sri(Sri),
+}
+
+/// What is enabled for a target.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Debugging {
+    pub echo: bool, // mirror all messages to the log
+    pub trace_until_ms: u64, // 0 if not traced
+}
+
+impl Debugging {
+    fn is_active(&self, now_ms: u64) -> bool {
+        self.echo || now_ms < self.trace_until_ms
+    }
+}
+
+/// Users and sris selected for debugging, either by mirroring their
+/// messages to the log, to diagnose protocol issues of specific clients,
+/// or by verbose tracing. Tracing expires on its own, so that it is not
+/// accidentally left on.
+#[derive(Default)]
+pub struct DebugTargets {
+    targets: RwLock<HashMap<DebugTarget, Debugging>>,
+}
+
+impl DebugTargets {
+    /// Starts or stops mirroring messages to the log. Returns `true` if
+    /// that changed anything.
+    pub fn set_echo(&self, target: DebugTarget, enabled: bool) -> bool {
+        self.update(target, |debugging| mem::replace(&mut debugging.echo, enabled) != enabled)
+    }
+
+    /// Starts tracing for the given duration, or stops tracing for a zero
+    /// duration. Returns the end as for `TraceFlag::set_until`.
+    pub fn set_trace(&self, target: DebugTarget, duration: Duration) -> u64 {
+        let until_ms = if duration.is_zero() {
+            0
+        } else {
+            now_ms() + duration.min(MAX_TRACE_DURATION).as_millis() as u64
+        };
+        self.update(target, |debugging| debugging.trace_until_ms = until_ms);
+        until_ms
+    }
+
+    fn update<T>(&self, target: DebugTarget, f: impl FnOnce(&mut Debugging) -> T) -> T {
+        let mut targets = self.targets.write();
+        let res = f(targets.entry(target).or_default());
+        let now = now_ms();
+        targets.retain(|_, debugging| debugging.is_active(now));
+        res
+    }
+
+    /// What is currently enabled for the target. Expired tracing is off.
+    pub fn get(&self, target: &DebugTarget) -> Debugging {
+        let targets = self.targets.read();
+        if targets.is_empty() {
+            return Debugging::default();
+        }
+        let mut debugging = targets.get(target).copied().unwrap_or_default();
+        if debugging.trace_until_ms <= now_ms() {
+            debugging.trace_until_ms = 0;
+        }
+        debugging
+    }
+
+    /// Targets with messages mirrored to the log.
+    pub fn echo_targets(&self) -> Vec<DebugTarget> {
+        self.targets.read().iter()
+            .filter(|(_, debugging)| debugging.echo)
+            .map(|(target, _)| target.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo() {
+        let targets = DebugTargets::default();
+        let user = DebugTarget::User(UserId::new("thibault").unwrap());
+        let sri = DebugTarget::Sri("t3st".parse().unwrap());
+        assert!(!targets.get(&user).echo);
+        assert!(targets.set_echo(user.clone(), true));
+        assert!(!targets.set_echo(user.clone(), true));
+        assert!(targets.get(&user).echo);
+        assert!(!targets.get(&sri).echo);
+        assert!(targets.echo_targets().contains(&user));
+        assert!(targets.set_echo(user.clone(), false));
+        assert!(!targets.get(&user).echo);
+        assert!(targets.echo_targets().is_empty());
+    }
+
+    #[test]
+    fn test_trace() {
+        let targets = DebugTargets::default();
+        let user = DebugTarget::User(UserId::new("thibault").unwrap());
+        assert_eq!(targets.get(&user).trace_until_ms, 0);
+
+        let until_ms = targets.set_trace(user.clone(), Duration::from_secs(60));
+        assert_eq!(targets.get(&user).trace_until_ms, until_ms);
+
+        // Independent of echo.
+        targets.set_echo(user.clone(), true);
+        assert_eq!(targets.set_trace(user.clone(), Duration::ZERO), 0);
+        assert_eq!(targets.get(&user), Debugging { echo: true, trace_until_ms: 0 });
+        assert!(targets.echo_targets().contains(&user));
+
+        // Capped.
+        let until_ms = targets.set_trace(user, Duration::from_secs(365 * 24 * 60 * 60));
+        assert!(until_ms <= now_ms() + MAX_TRACE_DURATION.as_millis() as u64);
+    }
+}
@@ -0,0 +1,93 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::ServerLoad;
+use crate::memory::MapUsage;
+use crate::model::{GameId, RoomId};
+use crate::penalty::PenaltyCounts;
+
+/// Number of largest games and rooms to include.
+const LARGEST: usize = 10;
+
+/// Key state of the server for post-incident analysis, logged on SIGUSR1
+/// and available from the admin interface.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StateDump {
+    pub at: u64,
+    #[serde(flatten)]
+    pub load: ServerLoad,
+    pub users: usize,
+    pub maps: BTreeMap<&'static str, MapUsage>,
+    pub largest_games: Vec<Largest<GameId>>,
+    pub largest_rooms: Vec<Largest<RoomId>>,
+    pub penalties: PenaltyCounts,
+    pub queues: BTreeMap<&'static str, QueueDepth>,
+    pub client_queues: ClientQueues,
+}
+
+impl StateDump {
+    pub fn new(load: ServerLoad, users: usize, maps: BTreeMap<&'static str, MapUsage>) -> StateDump {
+        StateDump {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            load,
+            users,
+            maps,
+            largest_games: Vec::new(),
+            largest_rooms: Vec::new(),
+            penalties: PenaltyCounts::default(),
+            queues: BTreeMap::new(),
+            client_queues: ClientQueues::default(),
+        }
+    }
+}
+
+/// A game or room with many sockets.
+#[derive(Serialize, Debug, Eq, PartialEq)]
+pub struct Largest<K> {
+    pub id: K,
+    pub sockets: usize,
+}
+
+/// Picks the entries with the most sockets.
+pub fn largest<'a, K: Clone + 'a>(entries: impl Iterator<Item = (&'a K, usize)>) -> Vec<Largest<K>> {
+    let mut entries: Vec<(&K, usize)> = entries.collect();
+    entries.sort_by_key(|&(_, sockets)| Reverse(sockets));
+    entries.into_iter().take(LARGEST).map(|(id, sockets)| Largest {
+        id: id.clone(),
+        sockets,
+    }).collect()
+}
+
+/// Messages waiting in an internal queue.
+#[derive(Serialize, Debug, Copy, Clone)]
+pub struct QueueDepth {
+    pub len: usize,
+    pub capacity: Option<usize>, // if bounded
+}
+
+/// Messages waiting to be sent to Websocket clients.
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientQueues {
+    pub queued: usize,
+    pub fullest: usize,
+    pub slow_consumers: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_largest() {
+        let games: Vec<(GameId, usize)> = (0..20).map(|i| (format!("game{:04}", i).parse().unwrap(), i)).collect();
+        let top = largest(games.iter().map(|(id, n)| (id, *n)));
+        assert_eq!(top.len(), LARGEST);
+        assert_eq!(top[0], Largest { id: "game0019".parse().unwrap(), sockets: 19 });
+        assert_eq!(top[LARGEST - 1].sockets, 10);
+    }
+}
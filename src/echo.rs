@@ -0,0 +1,29 @@
+/// Messages are cut off after this many bytes when mirrored to the log.
+const MAX_ECHO_SIZE: usize = 1024;
+
+/// Message as it is mirrored to the log, cut off at a character boundary
+/// if it is too long.
+pub fn truncate(msg: &str) -> String {
+    if msg.len() <= MAX_ECHO_SIZE {
+        return msg.to_owned();
+    }
+    let mut end = MAX_ECHO_SIZE;
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes)", &msg[..end], msg.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate(r#"{"t":"p"}"#), r#"{"t":"p"}"#);
+        let long = "é".repeat(MAX_ECHO_SIZE);
+        let truncated = truncate(&long);
+        assert!(truncated.starts_with(&"é".repeat(MAX_ECHO_SIZE / 2)));
+        assert!(truncated.ends_with(&format!("... ({} bytes)", 2 * MAX_ECHO_SIZE)));
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read as _, Write as _};
+use std::net::{TcpStream, ToSocketAddrs as _};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam::channel;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shakmaty::fen::{Fen, FenOpts};
+use shakmaty::variants::{Variant, VariantPosition};
+
+use crate::model::VariantKey;
+
+/// Timeout for connecting to the explorer, and for each read and write.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Responses larger than this are discarded.
+const MAX_RESPONSE_SIZE: u64 = 512 * 1024;
+
+/// Base URL of the explorer HTTP service, like `http://127.0.0.1:9002`.
+/// Only plain HTTP is supported.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Endpoint {
+    authority: String, // host:port
+    host: String,
+}
+
+#[derive(Debug)]
+pub struct InvalidEndpoint;
+
+impl fmt::Display for InvalidEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid explorer endpoint, expected http://host:port")
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = InvalidEndpoint;
+
+    fn from_str(s: &str) -> Result<Endpoint, InvalidEndpoint> {
+        let authority = s.strip_prefix("http://").ok_or(InvalidEndpoint)?.trim_end_matches('/');
+        if authority.is_empty() || authority.contains('/') {
+            return Err(InvalidEndpoint);
+        }
+        let host = authority.to_owned();
+        let authority = if authority.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            authority.to_owned()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok(Endpoint { authority, host })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Db {
+    Masters,
+    Lichess,
+}
+
+/// Explorer request from a client.
+#[derive(Deserialize)]
+pub struct GetExplorer {
+    db: Db,
+    variant: Option<VariantKey>,
+    fen: String,
+    path: String,
+}
+
+#[derive(Serialize)]
+struct Params<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<VariantKey>,
+    fen: &'a str,
+}
+
+impl GetExplorer {
+    /// Path and query for the explorer, which is also the cache key. The
+    /// position is validated and normalized, so that equivalent requests
+    /// share an entry.
+    fn uri(&self) -> Option<String> {
+        let variant = match self.db {
+            Db::Masters => None,
+            Db::Lichess => self.variant,
+        };
+        let fen: Fen = self.fen.parse().ok()?;
+        let pos = VariantPosition::from_setup(Variant::from(variant.unwrap_or(VariantKey::Standard)), &fen).ok()?;
+        let fen = FenOpts::default().fen(&pos);
+        let query = serde_urlencoded::to_string(Params { variant, fen: &fen }).ok()?;
+        Some(match self.db {
+            Db::Masters => format!("/masters?{}", query),
+            Db::Lichess => format!("/lichess?{}", query),
+        })
+    }
+
+    pub fn db(&self) -> Db {
+        self.db
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// Called with the explorer response, or `None` on failure.
+pub type Callback = Box<dyn FnOnce(Option<Arc<Value>>) + Send>;
+
+struct Job {
+    uri: String,
+    callback: Callback,
+}
+
+/// Least recently used explorer responses.
+struct Lru {
+    entries: HashMap<String, (Arc<Value>, u64)>,
+    order: VecDeque<(u64, String)>, // may contain outdated entries
+    seq: u64,
+    capacity: usize,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            seq: 0,
+            capacity,
+        }
+    }
+
+    fn touch(&mut self, key: &str) -> u64 {
+        self.seq += 1;
+        self.order.push_back((self.seq, key.to_owned()));
+        self.seq
+    }
+
+    fn get(&mut self, key: &str) -> Option<Arc<Value>> {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        let seq = self.touch(key);
+        let entry = self.entries.get_mut(key).expect("entry");
+        entry.1 = seq;
+        let value = entry.0.clone();
+        self.compact();
+        Some(value)
+    }
+
+    fn insert(&mut self, key: String, value: Arc<Value>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let seq = self.touch(&key);
+        self.entries.insert(key, (value, seq));
+
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some((seq, key)) if self.entries.get(&key).is_some_and(|e| e.1 == seq) => {
+                    self.entries.remove(&key);
+                }
+                Some(_) => continue,
+                None => break,
+            }
+        }
+        self.compact();
+    }
+
+    /// Keeps outdated entries in order from piling up.
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.entries.len() + 16 {
+            let entries = &self.entries;
+            self.order.retain(|(seq, key)| entries.get(key).is_some_and(|e| e.1 == *seq));
+        }
+    }
+}
+
+/// Proxy for the opening explorer, with a cache of recent responses.
+pub struct Explorer {
+    endpoint: Endpoint,
+    cache: Mutex<Lru>,
+    jobs: channel::Sender<Job>,
+    queue: channel::Receiver<Job>,
+}
+
+impl Explorer {
+    pub fn new(endpoint: Endpoint, cache_size: usize, queue_size: usize) -> Explorer {
+        let (jobs, queue) = channel::bounded(queue_size);
+        Explorer {
+            endpoint,
+            cache: Mutex::new(Lru::new(cache_size)),
+            jobs,
+            queue,
+        }
+    }
+
+    /// Answers from the cache right away, or queues a request to the
+    /// explorer. Fails without calling back if the request is invalid or
+    /// too many requests are pending.
+    pub fn query(&self, req: &GetExplorer, callback: Callback) -> Result<(), Callback> {
+        let uri = match req.uri() {
+            Some(uri) => uri,
+            None => return Err(callback),
+        };
+        if let Some(value) = self.cache.lock().get(&uri) {
+            callback(Some(value));
+            return Ok(());
+        }
+        self.jobs.try_send(Job { uri, callback }).map_err(|err| err.into_inner().callback)
+    }
+
+    /// Works through queued requests. Run on multiple threads.
+    pub fn work(&self) -> Result<(), channel::RecvError> {
+        loop {
+            let job = self.queue.recv()?;
+            match self.fetch(&job.uri) {
+                Ok(value) => {
+                    let value = Arc::new(value);
+                    self.cache.lock().insert(job.uri, value.clone());
+                    (job.callback)(Some(value));
+                }
+                Err(err) => {
+                    log::warn!("explorer request {} failed: {}", job.uri, err);
+                    (job.callback)(None);
+                }
+            }
+        }
+    }
+
+    fn fetch(&self, uri: &str) -> io::Result<Value> {
+        let addr = self.endpoint.authority.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "explorer host not found"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        // HTTP/1.0 to get a plain body that ends with the connection.
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n\r\n", uri, self.endpoint.host)?;
+        let mut buf = Vec::new();
+        stream.take(MAX_RESPONSE_SIZE + 1).read_to_end(&mut buf)?;
+        if buf.len() as u64 > MAX_RESPONSE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "explorer response too large"));
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut res = httparse::Response::new(&mut headers);
+        let body_start = match res.parse(&buf) {
+            Ok(httparse::Status::Complete(n)) => n,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid explorer response")),
+        };
+        if res.code != Some(200) {
+            return Err(io::Error::other(format!("explorer responded with status {:?}", res.code)));
+        }
+        Ok(serde_json::from_slice(&buf[body_start..])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        let endpoint: Endpoint = "http://127.0.0.1:9002/".parse().unwrap();
+        assert_eq!(endpoint.authority, "127.0.0.1:9002");
+        let endpoint: Endpoint = "http://explorer.internal".parse().unwrap();
+        assert_eq!(endpoint.authority, "explorer.internal:80");
+        assert_eq!(endpoint.host, "explorer.internal");
+        assert!("https://explorer.lichess.ovh".parse::<Endpoint>().is_err());
+        assert!("http://127.0.0.1:9002/masters".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_uri() {
+        let req = |db, variant| GetExplorer {
+            db,
+            variant,
+            fen: "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1".to_owned(),
+            path: "/?".to_owned(),
+        };
+        assert_eq!(req(Db::Masters, Some(VariantKey::Atomic)).uri().unwrap(),
+                   "/masters?fen=rnbqkbnr%2Fpppppppp%2F8%2F8%2F4P3%2F8%2FPPPP1PPP%2FRNBQKBNR+b+KQkq+-+0+1");
+        assert_eq!(req(Db::Lichess, Some(VariantKey::KingOfTheHill)).uri().unwrap(),
+                   "/lichess?variant=kingOfTheHill&fen=rnbqkbnr%2Fpppppppp%2F8%2F8%2F4P3%2F8%2FPPPP1PPP%2FRNBQKBNR+b+KQkq+-+0+1");
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(2);
+        lru.insert("a".to_owned(), Arc::new(Value::from(1)));
+        lru.insert("b".to_owned(), Arc::new(Value::from(2)));
+        assert!(lru.get("a").is_some());
+        lru.insert("c".to_owned(), Arc::new(Value::from(3)));
+
+        // The least recently used entry was evicted.
+        assert!(lru.get("b").is_none());
+        assert_eq!(lru.get("a").as_deref(), Some(&Value::from(1)));
+        assert_eq!(lru.get("c").as_deref(), Some(&Value::from(3)));
+
+        for _ in 0..100 {
+            lru.get("a");
+        }
+        assert!(lru.order.len() <= 2 * 2 + 16 + 1);
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt::Write as _;
+
+use crate::ipc::MoveMeta;
+use crate::model::GameId;
+use crate::v2::Shape;
+
+/// Formats the `fen` message for watchers of a game, exactly like
+/// serializing `SocketIn::Fen` (or `SocketInV2::Fen`) with serde_json would.
+/// This is by far the most frequent message, so it is worth skipping the
+/// generic machinery.
+pub fn fen_json(id: &GameId, fen: &str, lm: &str, meta: Option<&MoveMeta>, shape: Shape) -> String {
+    let mut json = String::with_capacity(48 + fen.len() + lm.len() + if meta.is_some() { 64 } else { 0 });
+    json.push_str(r#"{"t":"fen","d":{"id":"#);
+    push_str(&mut json, id.as_str());
+    json.push_str(r#","fen":"#);
+    push_str(&mut json, fen);
+    json.push_str(match shape {
+        Shape::Legacy => r#","lm":"#,
+        Shape::V2 => r#","lastMove":"#,
+    });
+    push_str(&mut json, lm);
+    if let Some(meta) = meta {
+        write!(json, r#","ply":{},"turn":"{}","variant":"{}""#, meta.ply, meta.turn.char(), meta.variant.as_str()).expect("write to string");
+        if let Some(ref pockets) = meta.pockets {
+            json.push_str(r#","pockets":"#);
+            push_str(&mut json, pockets.as_str());
+        }
+    }
+    json.push_str("}}");
+    json
+}
+
+/// Appends a JSON string literal, escaped like serde_json does.
+fn push_str(json: &mut String, s: &str) {
+    json.push('"');
+    let mut start = 0;
+    for (i, b) in s.bytes().enumerate() {
+        let escape = match b {
+            b'"' => "\\\"",
+            b'\\' => "\\\\",
+            b'\n' => "\\n",
+            b'\r' => "\\r",
+            b'\t' => "\\t",
+            0x08 => "\\b",
+            0x0c => "\\f",
+            0x00..=0x1f => "",
+            _ => continue,
+        };
+        json.push_str(&s[start..i]);
+        if escape.is_empty() {
+            write!(json, "\\u{:04x}", b).expect("write to string");
+        } else {
+            json.push_str(escape);
+        }
+        start = i + 1;
+    }
+    json.push_str(&s[start..]);
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shakmaty::Color;
+
+    use crate::model::VariantKey;
+    use crate::v2::SocketInV2;
+    use crate::SocketIn;
+
+    fn serde_fen(id: &GameId, fen: &str, lm: &str, meta: Option<MoveMeta>) -> String {
+        SocketIn::Fen { id, fen, lm, meta }.to_json_string()
+    }
+
+    fn serde_fen_v2(id: &GameId, fen: &str, last_move: &str, meta: Option<MoveMeta>) -> String {
+        SocketInV2::Fen { id, fen, last_move, meta }.to_json_string()
+    }
+
+    #[test]
+    fn test_fen_json() {
+        let id: GameId = "5iL3vzAw".parse().unwrap();
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR";
+        assert_eq!(fen_json(&id, fen, "e2e4", None, Shape::Legacy), serde_fen(&id, fen, "e2e4", None));
+        assert_eq!(fen_json(&id, fen, "e2e4", None, Shape::V2), serde_fen_v2(&id, fen, "e2e4", None));
+
+        for (variant, pockets) in [(VariantKey::Standard, None), (VariantKey::Crazyhouse, Some("PQ")), (VariantKey::Crazyhouse, Some("-"))] {
+            let meta = MoveMeta {
+                ply: 5,
+                turn: Color::White,
+                variant,
+                pockets: pockets.map(|p| p.parse().unwrap()),
+            };
+            assert_eq!(fen_json(&id, fen, "P@e6", Some(&meta), Shape::Legacy), serde_fen(&id, fen, "P@e6", Some(meta)));
+            assert_eq!(fen_json(&id, fen, "P@e6", Some(&meta), Shape::V2), serde_fen_v2(&id, fen, "P@e6", Some(meta)));
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        let id: GameId = "5iL3vzAw".parse().unwrap();
+        for s in ["", "\"quoted\"", "back\\slash", "line\nbreak\r\t", "\u{1}\u{8}\u{c}\u{1f}\u{7f}", "ünïcödé ♞"] {
+            assert_eq!(fen_json(&id, s, s, None, Shape::Legacy), serde_fen(&id, s, s, None));
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+
+use once_cell::sync::Lazy;
+
+/// Maps keyed by ids that are validated before they are used as keys, so
+/// that there is no need to pay for SipHash. Clients still choose some of
+/// the keys (game ids), so the hasher is keyed randomly per process.
+pub type FxHashMap<K, V> = HashMap<K, V, FxBuildHasher>;
+
+pub type FxHashSet<T> = HashSet<T, FxBuildHasher>;
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+/// Random key of this process, so that colliding keys can not be chosen
+/// ahead of time.
+static KEY: Lazy<u64> = Lazy::new(|| RandomState::new().build_hasher().finish());
+
+/// Builds `FxHasher`s starting from the random key of this process.
+#[derive(Copy, Clone, Debug)]
+pub struct FxBuildHasher {
+    key: u64,
+}
+
+impl Default for FxBuildHasher {
+    fn default() -> FxBuildHasher {
+        FxBuildHasher { key: *KEY }
+    }
+}
+
+impl BuildHasher for FxBuildHasher {
+    type Hasher = FxHasher;
+
+    #[inline]
+    fn build_hasher(&self) -> FxHasher {
+        FxHasher { hash: self.key }
+    }
+}
+
+/// The hash function used in rustc and Firefox, with a final mix. Much
+/// faster than SipHash on short keys, but trivial to attack with chosen
+/// keys if the initial state is known.
+#[derive(Copy, Clone)]
+pub struct FxHasher {
+    hash: u64,
+}
+
+impl FxHasher {
+    #[inline]
+    fn add_to_hash(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add_to_hash(u64::from_le_bytes(chunk.try_into().expect("8 bytes")));
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut word = [0; 8];
+            word[..rest.len()].copy_from_slice(rest);
+            self.add_to_hash(u64::from_le_bytes(word));
+        }
+    }
+
+    #[inline]
+    fn write_u8(&mut self, i: u8) {
+        self.add_to_hash(u64::from(i));
+    }
+
+    #[inline]
+    fn write_u32(&mut self, i: u32) {
+        self.add_to_hash(u64::from(i));
+    }
+
+    #[inline]
+    fn write_u64(&mut self, i: u64) {
+        self.add_to_hash(i);
+    }
+
+    #[inline]
+    fn write_usize(&mut self, i: usize) {
+        self.add_to_hash(i as u64);
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        // The multiplication only carries entropy towards the high bits,
+        // but the hash table picks buckets by the low bits. So finish with
+        // the final mix of MurmurHash3.
+        let mut hash = self.hash;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::hash::Hash;
+
+    use crate::model::{GameId, UserId};
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        FxBuildHasher::default().hash_one(value)
+    }
+
+    #[test]
+    fn test_distinct() {
+        let games: FxHashSet<u64> = ["5iL3vzAw", "5iL3vzAx", "Kn8YNzSq", "Hn4s0Qa1"].iter()
+            .map(|id| hash(&id.parse::<GameId>().unwrap()))
+            .collect();
+        assert_eq!(games.len(), 4);
+
+        // Users that differ only after the first 8 bytes, and prefixes.
+        let users: FxHashSet<u64> = ["thibault", "thibault1", "thibault2", "thib"].iter()
+            .map(|uid| hash(&UserId::new(uid).unwrap()))
+            .collect();
+        assert_eq!(users.len(), 4);
+    }
+
+    #[test]
+    fn test_low_bits() {
+        // Hash tables pick buckets by the low bits, so these must differ
+        // even for keys that only differ in their last bytes.
+        let buckets: FxHashSet<u64> = (0..256)
+            .map(|i| hash(&format!("g{:07}", i).parse::<GameId>().unwrap()) & 0xff)
+            .collect();
+        assert!(buckets.len() > 128, "{} buckets", buckets.len());
+    }
+
+    #[test]
+    fn test_keyed() {
+        // Same key within the process, but not a fixed one.
+        let game = "5iL3vzAw".parse::<GameId>().unwrap();
+        assert_eq!(hash(&game), hash(&game));
+        let unkeyed = {
+            let mut hasher = FxHasher { hash: 0 };
+            game.hash(&mut hasher);
+            hasher.finish()
+        };
+        assert_ne!(hash(&game), unkeyed);
+    }
+
+    #[test]
+    fn test_map() {
+        let mut map = FxHashMap::default();
+        map.insert(UserId::new("thibault").unwrap(), 1);
+        map.insert(UserId::new("revoof").unwrap(), 2);
+        assert_eq!(map.get(&UserId::new("Thibault").unwrap()), Some(&1));
+        assert_eq!(map.get(&UserId::new("neio").unwrap()), None);
+    }
+}
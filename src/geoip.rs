@@ -0,0 +1,136 @@
+use std::cmp::Reverse;
+use std::fmt;
+use std::net::IpAddr;
+use std::collections::HashMap;
+
+use arrayvec::ArrayString;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use parking_lot::Mutex;
+
+/// ISO 3166-1 alpha-2 country code.
+pub type CountryCode = ArrayString<[u8; 2]>;
+
+/// Origin of a client address, as far as known.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct GeoInfo {
+    pub country: Option<CountryCode>,
+    pub asn: Option<u32>,
+}
+
+impl fmt::Display for GeoInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.country {
+            Some(country) => write!(f, "{}", country)?,
+            None => f.write_str("--")?,
+        }
+        match self.asn {
+            Some(asn) => write!(f, "/AS{}", asn),
+            None => f.write_str("/AS?"),
+        }
+    }
+}
+
+/// Optional MaxMind country and ASN databases.
+#[derive(Default)]
+pub struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    pub fn open(country_db: Option<&str>, asn_db: Option<&str>) -> Result<GeoIp, MaxMindDBError> {
+        Ok(GeoIp {
+            country: country_db.map(Reader::open_readfile).transpose()?,
+            asn: asn_db.map(Reader::open_readfile).transpose()?,
+        })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        GeoInfo {
+            country: self.country.as_ref()
+                .and_then(|db| db.lookup::<geoip2::Country>(ip).ok())
+                .and_then(|c| c.country)
+                .and_then(|c| c.iso_code)
+                .and_then(|code| CountryCode::from(code).ok()),
+            asn: self.asn.as_ref()
+                .and_then(|db| db.lookup::<geoip2::Asn>(ip).ok())
+                .and_then(|a| a.autonomous_system_number),
+        }
+    }
+}
+
+/// Number of open connections by country and by autonomous system.
+#[derive(Default)]
+pub struct GeoConnections {
+    by_country: Mutex<HashMap<Option<CountryCode>, u32>>,
+    by_asn: Mutex<HashMap<Option<u32>, u32>>,
+}
+
+impl GeoConnections {
+    pub fn connected(&self, geo: &GeoInfo) {
+        *self.by_country.lock().entry(geo.country).or_insert(0) += 1;
+        *self.by_asn.lock().entry(geo.asn).or_insert(0) += 1;
+    }
+
+    pub fn disconnected(&self, geo: &GeoInfo) {
+        decrement(&mut self.by_country.lock(), &geo.country);
+        decrement(&mut self.by_asn.lock(), &geo.asn);
+    }
+
+    /// Summary of the countries and autonomous systems with the most
+    /// connections.
+    pub fn report(&self, n: usize) -> String {
+        let countries = top(&self.by_country.lock(), n);
+        let asns = top(&self.by_asn.lock(), n);
+
+        let mut report = "countries:".to_owned();
+        for (country, count) in countries {
+            report.push_str(&format!(" {}={}", country.as_ref().map_or("--", |c| c.as_str()), count));
+        }
+        report.push_str(" asns:");
+        for (asn, count) in asns {
+            match asn {
+                Some(asn) => report.push_str(&format!(" AS{}={}", asn, count)),
+                None => report.push_str(&format!(" AS?={}", count)),
+            }
+        }
+        report
+    }
+}
+
+fn decrement<K: Eq + std::hash::Hash>(map: &mut HashMap<K, u32>, key: &K) {
+    if let Some(count) = map.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+fn top<K: Clone>(map: &HashMap<K, u32>, n: usize) -> Vec<(K, u32)> {
+    let mut entries: Vec<(K, u32)> = map.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    entries.sort_by_key(|&(_, count)| Reverse(count));
+    entries.truncate(n);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo_connections() {
+        let de = GeoInfo { country: Some(CountryCode::from("DE").unwrap()), asn: Some(3320) };
+        let unknown = GeoInfo::default();
+
+        let connections = GeoConnections::default();
+        connections.connected(&de);
+        connections.connected(&de);
+        connections.connected(&unknown);
+        assert_eq!(connections.report(5), "countries: DE=2 --=1 asns: AS3320=2 AS?=1");
+
+        connections.disconnected(&de);
+        connections.disconnected(&de);
+        assert_eq!(connections.report(5), "countries: --=1 asns: AS?=1");
+    }
+}
@@ -0,0 +1,1251 @@
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crossbeam::channel;
+use futures_util::{SinkExt as _, StreamExt as _};
+use structopt::StructOpt as _;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest as _;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+use crate::backend::fake::{FakeBus, FakeSessionStore};
+use crate::{admin, analysis, await_lila, batch, batch_msgpack, serve, start, App, Opt, Priority, Sender, SendError, SocketId, AWAY_TIMEOUT, FINISHED_GAME_RETENTION, MAX_CLIENT_MESSAGE_SIZE, MAX_SEND_FAILURES, MAX_WATCHED_GAMES, QUEUE_SIZE, SLOW_CONSUMER_TIMEOUT, USER_IDLE};
+use crate::debug::DebugTarget;
+use crate::memory::MapUsage;
+use crate::model::UserId;
+use crate::v2::Shape;
+
+const FEN: &str = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR";
+
+fn expect_site_in(site_in: &channel::Receiver<String>, expected: &str) {
+    let msg = site_in.recv_timeout(Duration::from_secs(5)).expect(expected);
+    assert_eq!(msg, expected);
+}
+
+/// Statistics published in response to mlat.
+fn expect_stats(site_in: &channel::Receiver<String>) {
+    expect_site_in(site_in, "connections 1");
+    expect_site_in(site_in, "connections/anon 0");
+    let visitors = site_in.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(visitors.starts_with("visitors "), "{}", visitors);
+    expect_site_in(site_in, "lags ");
+}
+
+async fn admin_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    res
+}
+
+struct TestServer {
+    app: &'static App,
+    addr: SocketAddr,
+    site_out: channel::Sender<String>,
+    site_in: channel::Receiver<String>,
+}
+
+/// Starts a server with the given command line arguments, once lila is
+/// available.
+async fn start_server(args: &[&str]) -> TestServer {
+    let (bus, site_out, site_in) = FakeBus::new();
+    let session_store = FakeSessionStore::default().with_session("s3ss10n", "thibault");
+
+    let opt = Opt::from_iter(["lila-websocket"].iter().chain(args));
+    let bus = Box::leak(Box::new(bus));
+    let (app, rate_limiter) = start(&opt, bus, Box::leak(Box::new(session_store)));
+    await_lila(app, bus);
+    expect_site_in(&site_in, "disconnect/all");
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { serve(app, listener, &opt, rate_limiter).await });
+
+    TestServer { app, addr, site_out, site_in }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_connect_auth_watch_move() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&[]).await;
+
+    // Connect and authenticate.
+    let mut req = format!("ws://{}/?sri=t3st&proto=5", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    req.headers_mut().insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+    req.headers_mut().insert("user-agent", HeaderValue::from_static("test client"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault 203.0.113.7 t3st test client");
+
+    // Watch a game.
+    ws.send(Message::text(r#"{"t":"startWatching","d":"5iL3vzAw"}"#)).await.unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"watching","d":{"accepted":[{"id":"5iL3vzAw","cached":false}],"rejected":[]}}"#);
+
+    // Moves are relayed to watchers.
+    site_out.send(format!("move 5iL3vzAw e2e4 {}", FEN)).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), format!(r#"{{"t":"fen","d":{{"id":"5iL3vzAw","fen":"{}","lm":"e2e4"}}}}"#, FEN));
+
+    // Including details of the position, if given.
+    site_out.send(format!("move 5iL3vzAw e2e4 {} 1 b standard", FEN)).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), format!(r#"{{"t":"fen","d":{{"id":"5iL3vzAw","fen":"{}","lm":"e2e4","ply":1,"turn":"b","variant":"standard"}}}}"#, FEN));
+
+    // Other messages to watchers are relayed.
+    site_out.send(r#"tell/game 5iL3vzAw {"t":"message","d":"gg"}"#.to_owned()).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"message","d":"gg"}"#);
+
+    // Game results are relayed.
+    site_out.send("finish 5iL3vzAw w".to_owned()).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"finish","d":{"id":"5iL3vzAw","win":"w"}}"#);
+
+    // Messages to the user are relayed.
+    site_out.send("counts 100 50 20".to_owned()).unwrap();
+    site_out.send(r#"tell/users thibault {"t":"notifications","d":1}"#.to_owned()).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"notifications","d":1}"#);
+
+    // Online counts include this server and the totals from lila (received
+    // before the message relayed above).
+    ws.send(Message::text(r#"{"t":"counts","d":true}"#)).await.unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"counts","d":{"connections":101,"members":51,"rounds":20}}"#);
+
+    // Move latency comes with the load of this server.
+    ws.send(Message::text(r#"{"t":"moveLat","d":true}"#)).await.unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"mlatHistory","d":[]}"#);
+    ws.next().await.unwrap().unwrap(); // mlat
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"serverLoad","d":{"connections":1,"load":"normal"}}"#);
+    site_out.send("mlat 42".to_owned()).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"mlat","d":42}"#);
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"serverLoad","d":{"connections":1,"load":"normal"}}"#);
+    expect_stats(&site_in);
+
+    // Statistics are paused while the app is in the background.
+    ws.send(Message::text(r#"{"t":"bg"}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    site_out.send("mlat 43".to_owned()).unwrap();
+    expect_stats(&site_in);
+    site_out.send(r#"tell/sri t3st {"t":"reload","d":null}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"reload","d":null}"#);
+    ws.send(Message::text(r#"{"t":"fg"}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    site_out.send("mlat 44".to_owned()).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"mlat","d":44}"#);
+    ws.next().await.unwrap().unwrap(); // serverLoad
+    expect_stats(&site_in);
+
+    // Sockets of the user can be inspected.
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    tokio::spawn(admin::serve(app, admin_listener, admin::Access::default()));
+    let res = admin_get(admin_addr, "/sockets?user=thibault").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+    assert!(res.contains(r#""sri":"t3st","ip":"203.0.113.7","userAgent":"test client""#), "{}", res);
+    assert!(res.contains(r#""singleTab":false,"version":5,"mobile":false"#), "{}", res);
+    assert!(res.contains(r#""watching":1"#), "{}", res);
+
+    // Disconnect.
+    ws.close(None).await.unwrap();
+    expect_site_in(&site_in, "disconnect thibault");
+    expect_site_in(&site_in, "unwatch 5iL3vzAw");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_msgpack_protocol() {
+    let TestServer { addr, site_out, .. } = start_server(&[]).await;
+
+    // The first supported protocol is chosen.
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_static("lichess-v3-cbor, lichess-v2-msgpack, lichess-v2-json"));
+    let (mut ws, res) = tokio_tungstenite::connect_async(req).await.unwrap();
+    assert_eq!(res.headers().get("sec-websocket-protocol").unwrap(), "lichess-v2-msgpack");
+
+    // Ping.
+    ws.send(Message::binary(&b"\xc0"[..])).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::binary(&b"\x00"[..]));
+
+    // Messages are encoded.
+    site_out.send(r#"tell/sri t3st {"t":"reload","d":null}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::binary(&b"\x82\xa1t\xa6reload\xa1d\xc0"[..]));
+
+    // Also when broadcast to many clients.
+    site_out.send(r#"tell/all {"t":"reload","d":null}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::binary(&b"\x82\xa1t\xa6reload\xa1d\xc0"[..]));
+
+    // And decoded.
+    ws.send(Message::binary(&b"\x82\xa1t\xa1p\xa1l\x05"[..])).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap(), Message::binary(&b"\x00"[..]));
+
+    // Invalid messages drop the connection.
+    ws.send(Message::binary(&b"\xc4\x00"[..])).await.unwrap();
+    assert!(!matches!(ws.next().await, Some(Ok(_))));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_client_policy() {
+    let TestServer { app, addr, site_in, .. } = start_server(&["--allowed-origin", "https://lichess.org"]).await;
+
+    let connect = |headers: &[(&'static str, &'static str)]| {
+        let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+        for (name, value) in headers {
+            req.headers_mut().insert(*name, HeaderValue::from_static(value));
+        }
+        tokio_tungstenite::connect_async(req)
+    };
+
+    // Browsers need an allowed origin.
+    assert!(connect(&[]).await.is_err());
+    assert!(connect(&[("origin", "https://evil.example")]).await.is_err());
+    assert!(connect(&[("origin", "https://lichess.org")]).await.is_ok());
+
+    // Session cookies that are stale or garbled are counted, but their
+    // clients still connect anonymously.
+    assert!(connect(&[("origin", "https://lichess.org"), ("cookie", "lila2=s1gn4ture-sid=x")]).await.is_ok());
+    assert_eq!(app.invalid_cookies.load(Ordering::Relaxed), 0);
+    for cookie in &["lila2=", "lila2=sessionId=x", "lila2=garbage"] {
+        let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+        req.headers_mut().insert("origin", HeaderValue::from_static("https://lichess.org"));
+        req.headers_mut().insert("cookie", HeaderValue::from_static(cookie));
+        let (mut ws, _) = tokio_tungstenite::connect_async(req).await.expect(cookie);
+        ws.send(Message::text("null")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    }
+    assert_eq!(app.invalid_cookies.load(Ordering::Relaxed), 3);
+    assert!(site_in.try_recv().is_err());
+
+    // API clients need neither.
+    assert!(connect(&[("authorization", "Bearer t0k3n")]).await.is_ok());
+    assert!(connect(&[("authorization", "Bearer ")]).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_server_full() {
+    let TestServer { addr, .. } = start_server(&["--max-connections", "1", "--admission-queue", "0"]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // Websocket clients are told to retry later.
+    let (mut full, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=s3c0nd", addr)).await.unwrap();
+    match full.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => {
+            assert_eq!(frame.code, CloseCode::Again);
+            assert_eq!(frame.reason, "server full, retry after 10 seconds");
+        }
+        msg => panic!("expected close frame, got {:?}", msg),
+    }
+
+    // Other clients get a status code.
+    let res = admin_get(addr, "/").await;
+    assert!(res.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", res);
+    assert!(res.contains("\r\nRetry-After: 10\r\n"), "{}", res);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_admission_queue() {
+    let TestServer { addr, .. } = start_server(&["--max-connections", "1", "--admission-queue", "1"]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // The second connection waits for a free slot.
+    let queued = tokio::spawn(tokio_tungstenite::connect_async(format!("ws://{}/?sri=s3c0nd", addr)));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // The third is rejected right away, because the queue is full.
+    let res = admin_get(addr, "/").await;
+    assert!(res.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", res);
+
+    // The slot is freed.
+    ws.close(None).await.unwrap();
+    let (mut queued, _) = queued.await.unwrap().unwrap();
+    queued.send(Message::text("null")).await.unwrap();
+    assert_eq!(queued.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_rate_limit_notice() {
+    let TestServer { addr, .. } = start_server(&["--rate-limiter-credits", "2"]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+
+    // Clients can ask for the remaining credits.
+    ws.send(Message::text(r#"{"t":"rateLimit"}"#)).await.unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"rateLimit","d":{"remaining":1,"interval":10000}}"#);
+
+    for _ in 0..3 {
+        ws.send(Message::text("null")).await.unwrap();
+    }
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // A single notice with the time until messages are accepted again.
+    let msg = ws.next().await.unwrap().unwrap();
+    let notice: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+    assert_eq!(notice["t"], "error");
+    assert_eq!(notice["d"]["code"], "rateLimited");
+    let retry_in = notice["d"]["retryIn"].as_u64().unwrap();
+    assert!(retry_in > 0 && retry_in <= 10_000, "{}", retry_in);
+
+    ws.send(Message::text("null")).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(200), ws.next()).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_explorer_proxy() {
+    // Explorer that answers a single request.
+    let explorer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let explorer_url = format!("http://{}", explorer.local_addr().unwrap());
+    let explorer_task = tokio::spawn(async move {
+        let (mut stream, _) = explorer.accept().await.unwrap();
+        let mut req = vec![0; 1024];
+        let n = stream.read(&mut req).await.unwrap();
+        stream.write_all(b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{\"white\":10,\"draws\":5,\"black\":3}").await.unwrap();
+        String::from_utf8(req[..n].to_vec()).unwrap()
+    });
+
+    let TestServer { addr, .. } = start_server(&["--explorer", &explorer_url]).await;
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+
+    let query = format!(r#"{{"t":"explorer","d":{{"db":"masters","fen":"{} b KQkq - 0 1","path":"/?"}}}}"#, FEN);
+    let expected = r#"{"t":"explorer","d":{"path":"/?","db":"masters","data":{"white":10,"draws":5,"black":3}}}"#;
+    ws.send(Message::text(query.clone())).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), expected);
+    let req = explorer_task.await.unwrap();
+    assert!(req.starts_with("GET /masters?fen=rnbqkbnr%2Fpppppppp%2F8%2F8%2F4P3%2F8%2FPPPP1PPP%2FRNBQKBNR+b+KQkq+-+0+1 HTTP/1.0\r\n"), "{}", req);
+
+    // The second request is answered from the cache.
+    ws.send(Message::text(query)).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), expected);
+
+    // Invalid positions are not proxied.
+    ws.send(Message::text(r#"{"t":"explorer","d":{"db":"lichess","fen":"8/8/8/8/8/8/8/8 w - - 0 1","path":""}}"#)).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"explorerFailure","d":{"path":""}}"#);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_share_chapter_evals() {
+    let TestServer { addr, site_out, site_in, .. } = start_server(&[]).await;
+
+    let (mut viewer, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=v13w3r", addr)).await.unwrap();
+    let (mut member, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=m3mb3r", addr)).await.unwrap();
+
+    // Eval requests ask to join the chapter.
+    let get = r#"{"t":"evalGet","d":{"fen":"8/8/8/8/8/8/8/8 w - - 0 1","path":"","ch":"kN8BVgDS"}}"#;
+    viewer.send(Message::text(get)).await.unwrap();
+    expect_site_in(&site_in, &format!("tell/sri v13w3r - {}", get));
+
+    // Evals are only relayed to lila, not shared directly.
+    let put = r##"{"t":"evalPut","d":{"fen":"8/8/8/8/8/8/8/8 w - - 0 1","knodes":100,"depth":20,"pvs":[{"moves":"e2e4","cp":20}],"ch":"kN8BVgDS","path":"#+"}}"##;
+    member.send(Message::text(put)).await.unwrap();
+    expect_site_in(&site_in, &format!("tell/sri m3mb3r - {}", put));
+
+    // Evals that lila accepted go to the viewers that lila confirmed.
+    let hit = r##"{"t":"evalHit","d":{"fen":"8/8/8/8/8/8/8/8 w - - 0 1","knodes":100,"depth":20,"pvs":[{"moves":"e2e4","cp":20}],"path":"#+"}}"##;
+    site_out.send(format!("tell/chapter kN8BVgDS {}", hit)).unwrap();
+    site_out.send("chapter/viewer v13w3r kN8BVgDS".to_owned()).unwrap();
+    site_out.send(format!("tell/chapter kN8BVgDS {}", hit)).unwrap();
+    assert_eq!(viewer.next().await.unwrap().unwrap().to_text().unwrap(), hit);
+    viewer.send(Message::text("null")).await.unwrap();
+    assert_eq!(viewer.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    member.send(Message::text("null")).await.unwrap();
+    assert_eq!(member.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_unknown_lila_message() {
+    let TestServer { app, addr, site_out, .. } = start_server(&["--pass-unknown-messages"]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+
+    // Messages of unknown types are counted and do not disturb the ones
+    // that follow.
+    site_out.send(r#"tell/someday thibault {"t":"new"}"#.to_owned()).unwrap();
+    site_out.send("tell/someday".to_owned()).unwrap();
+    site_out.send(r#"tell/users thibault {"t":"notifications","d":1}"#.to_owned()).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"notifications","d":1}"#);
+    assert_eq!(app.unknown_messages.report(), "unknown lila messages: tell/someday=2");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_compressed_lila_message() {
+    let TestServer { addr, site_out, .. } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st&flag=tournament", addr)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // Compressed messages are relayed decompressed.
+    site_out.send("lz4 invalid".to_owned()).unwrap();
+    site_out.send(include_str!("../tests/fixtures/site-out-compressed.txt").trim_end().to_owned()).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert!(msg.to_text().unwrap().starts_with(r#"{"t":"reload","d":{"standing":{"page":1,"players":[{"name":"player0""#));
+    assert_eq!(msg.len(), 922 - "tell/flag tournament ".len());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_memory() {
+    let TestServer { app, .. } = start_server(&[]).await;
+
+    // Maps are shrunk after a spike.
+    let users: Vec<UserId> = (0..5000).map(|i| UserId::new(&format!("user{}", i)).unwrap()).collect();
+    app.last_notified.lock().extend(users.iter().map(|u| (u.clone(), Instant::now())));
+    app.last_notified.lock().clear();
+    assert!(app.map_usage()["last_notified"].capacity >= 5000);
+    assert_eq!(app.shrink_maps(), 1);
+    assert_eq!(app.map_usage()["last_notified"], MapUsage { len: 0, capacity: 0 });
+    assert_eq!(app.shrink_maps(), 0);
+
+    // Usage is reported.
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    tokio::spawn(admin::serve(app, admin_listener, admin::Access::default()));
+    let res = admin_get(admin_addr, "/memory").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+    assert!(res.contains(r#""last_notified":{"len":0,"capacity":0}"#), "{}", res);
+    assert!(app.memory_report().summary().starts_with("memory: by_chapter=0/0 by_game=0/0 "));
+
+    // State is dumped for debugging.
+    let res = admin_get(admin_addr, "/debug/state").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", res);
+    assert!(res.contains(r#""connections":0,"load":"normal","users":0,"#), "{}", res);
+    assert!(res.contains(r#""penalties":{"offenders":0,"banned":0}"#), "{}", res);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_profile() {
+    let TestServer { app, .. } = start_server(&[]).await;
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    tokio::spawn(admin::serve(app, admin_listener, admin::Access::default()));
+
+    // Keep a thread busy, so that there are samples.
+    let busy = std::thread::spawn(|| {
+        let started = std::time::Instant::now();
+        while started.elapsed() < Duration::from_secs(3) {
+            std::hint::black_box(started.elapsed());
+        }
+    });
+
+    let res = admin_get(admin_addr, "/debug/profile?seconds=1").await;
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\nContent-Type: image/svg+xml\r\n"), "{}", res);
+    assert!(res.contains("<svg"), "{}", res);
+
+    let mut stream = TcpStream::connect(admin_addr).await.unwrap();
+    stream.write_all(b"GET /debug/profile?seconds=1&format=pprof HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res).await.unwrap();
+    assert!(res.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n"));
+    busy.join().unwrap();
+
+    let res = admin_get(admin_addr, "/debug/profile?seconds=3600").await;
+    assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", res);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_republish_watches() {
+    let TestServer { addr, site_out, site_in, .. } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"startWatching","d":"5iL3vzAw"}"#)).await.unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+    ws.next().await.unwrap().unwrap(); // watching
+
+    // After reconnecting to redis.
+    site_out.send("fake/disconnect".to_owned()).unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+
+    // After lila restarted.
+    site_out.send("boot".to_owned()).unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+
+    // Moves are still relayed.
+    site_out.send(format!("move 5iL3vzAw e2e4 {}", FEN)).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert!(msg.to_text().unwrap().starts_with(r#"{"t":"fen""#));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_watch_many_cached_games() {
+    let TestServer { app, addr, site_out, .. } = start_server(&[]).await;
+
+    let games: Vec<String> = (0..20).map(|i| format!("g4m3{:04}", i)).collect();
+    for game in &games {
+        site_out.send(format!("move {} e2e4 {}", game, FEN)).unwrap();
+        site_out.send(format!("finish {} w", game)).unwrap();
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !games.iter().all(|game| app.watched_games.read().get(&game.parse().unwrap()).is_some_and(|state| state.finished)) {
+        assert!(Instant::now() < deadline, "games not cached");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // All cached positions and results are sent at once.
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text(format!(r#"{{"t":"startWatching","d":"{}"}}"#, games.join(" ")))).await.unwrap();
+    for game in &games {
+        let fen: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(fen["t"], "fen");
+        assert_eq!(fen["d"]["id"], game.as_str());
+        let finish: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(finish["t"], "finish");
+    }
+    let ack: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(ack["t"], "watching");
+    assert_eq!(ack["d"]["accepted"].as_array().unwrap().len(), games.len());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_invalid_game_ids() {
+    let TestServer { addr, site_in, .. } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"startWatching","d":"5iL3vzAw n0pe"}"#)).await.unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+    expect_site_in(&site_in, "abuse invalidGameId - -");
+
+    // Valid ids are watched anyway.
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), r#"{"t":"watching","d":{"accepted":[{"id":"5iL3vzAw","cached":false}],"rejected":[],"invalid":1}}"#);
+
+    // Asking for more games than can be watched at once is a protocol
+    // violation.
+    let ids: Vec<String> = (0..=MAX_WATCHED_GAMES).map(|i| format!("g4m3{:04}", i)).collect();
+    ws.send(Message::text(format!(r#"{{"t":"startWatching","d":"{}"}}"#, ids.join(" ")))).await.unwrap();
+    expect_site_in(&site_in, "abuse tooManyGames - -");
+    match ws.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Protocol),
+        msg => panic!("expected close, got {:?}", msg),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_watch_finished_games() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    let games: Vec<String> = (0..MAX_WATCHED_GAMES).map(|i| format!("g4m3{:04}", i)).collect();
+    ws.send(Message::text(format!(r#"{{"t":"startWatching","d":"{}"}}"#, games.join(" ")))).await.unwrap();
+    for game in &games {
+        expect_site_in(&site_in, &format!("watch {}", game));
+    }
+    let ack: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(ack["d"]["accepted"].as_array().unwrap().len(), MAX_WATCHED_GAMES);
+
+    for game in &games {
+        site_out.send(format!("move {} e2e4 {}", game, FEN)).unwrap();
+        let fen: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(fen["t"], "fen");
+        site_out.send(format!("finish {} w", game)).unwrap();
+        let finish: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(finish["t"], "finish");
+    }
+
+    // Finished games do not count towards the limit.
+    ws.send(Message::text(r#"{"t":"startWatching","d":"n3wG4m3x"}"#)).await.unwrap();
+    expect_site_in(&site_in, "watch n3wG4m3x");
+    let ack: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(ack["d"]["accepted"].as_array().unwrap().len(), 1);
+    assert_eq!(ack["d"]["rejected"].as_array().unwrap().len(), 0);
+
+    // Finished games are unwatched once retention expired.
+    for (finished_at, _) in app.finished_games.lock().iter_mut() {
+        *finished_at -= FINISHED_GAME_RETENTION;
+    }
+    app.cleanup_finished_games();
+    for game in &games {
+        expect_site_in(&site_in, &format!("unwatch {}", game));
+        assert!(app.watched_games.read().get(&game.parse().unwrap()).is_none());
+    }
+    assert!(app.by_game.read().contains_key(&"n3wG4m3x".parse().unwrap()));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_import_long_pgn() {
+    let TestServer { addr, site_in, .. } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    let pgn: String = (1..=100).map(|n| format!("{}. Nf3 Nf6 {}. Ng1 Ng8 ", 2 * n - 1, 2 * n)).collect();
+    let msg = serde_json::json!({"t": "importPgn", "d": {"pgn": pgn}}).to_string();
+    assert!(msg.len() > MAX_CLIENT_MESSAGE_SIZE);
+    ws.send(Message::text(msg)).await.unwrap();
+    let res: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(res["t"], "importedPgn");
+    assert_eq!(res["d"]["nodes"].as_array().unwrap().len(), 400);
+
+    // Other messages of that size are still abuse.
+    ws.send(Message::text(format!(r#"{{"t":"talk","d":"{}"}}"#, "a".repeat(MAX_CLIENT_MESSAGE_SIZE)))).await.unwrap();
+    expect_site_in(&site_in, "abuse oversized - -");
+    match ws.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Size),
+        msg => panic!("expected close, got {:?}", msg),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_resync() {
+    let TestServer { addr, site_out, site_in, .. } = start_server(&[]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st&flag=simul", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+
+    ws.send(Message::text(r#"{"t":"startWatching","d":"5iL3vzAw"}"#)).await.unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+    ws.next().await.unwrap().unwrap(); // watching
+    site_out.send(format!("move 5iL3vzAw e2e4 {}", FEN)).unwrap();
+    ws.next().await.unwrap().unwrap(); // fen
+    site_out.send(r#"tell/flag simul {"t":"simul","d":1}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"simul","d":1}"#);
+
+    // Current state is sent again in one message, and lila is asked for
+    // notifications.
+    ws.send(Message::text(r#"{"t":"resync"}"#)).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(),
+               format!(r#"{{"t":"state","d":{{"games":[{{"id":"5iL3vzAw","fen":"{}","lm":"e2e4"}}]}}}}"#, FEN));
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"simul","d":1}"#);
+    expect_site_in(&site_in, "resync thibault");
+
+    // Asking again right away is ignored.
+    ws.send(Message::text(r#"{"t":"resync"}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    assert!(site_in.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_slow_consumer() {
+    let (tx, _rx) = mpsc::channel(QUEUE_SIZE);
+    let sender = Sender::new(SocketId(1), tx);
+    for _ in 0..QUEUE_SIZE {
+        sender.send(Message::text("{}")).unwrap();
+    }
+    assert!(matches!(sender.send(Message::text("{}")), Err(SendError::QueueFull)));
+
+    // Dropping messages for a while is tolerated.
+    let start = Instant::now();
+    sender.queue_full(start + SLOW_CONSUMER_TIMEOUT / 2);
+    assert!(!sender.is_slow_consumer());
+
+    // As long as the queue drains in between.
+    sender.queue_drained();
+    sender.queue_full(start + SLOW_CONSUMER_TIMEOUT);
+    assert!(!sender.is_slow_consumer());
+
+    sender.queue_full(start + 2 * SLOW_CONSUMER_TIMEOUT);
+    assert!(sender.is_slow_consumer());
+    tokio::time::timeout(Duration::from_secs(1), sender.stale()).await.expect("stale");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_slow_spectator() {
+    let TestServer { app, addr, site_out, .. } = start_server(&[]).await;
+
+    // A client that stops reading, with a small receive buffer, so that
+    // the writer stalls soon.
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let stream = socket.connect(addr).await.unwrap();
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}/?sri=t3st&flag=simul", addr), stream).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // It only gets normal priority broadcasts, which are dropped before
+    // the queue is completely full. Still it is closed as a slow consumer.
+    let padding = "x".repeat(256 * 1024);
+    let start = Instant::now();
+    for i in 0.. {
+        if app.close_audit.report().contains("slowConsumer=1") {
+            break;
+        }
+        assert!(start.elapsed() < 3 * SLOW_CONSUMER_TIMEOUT, "not closed: {}", app.close_audit.report());
+        site_out.send(format!(r#"tell/flag simul {{"t":"simul","d":{},"p":"{}"}}"#, i, padding)).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(start.elapsed() >= SLOW_CONSUMER_TIMEOUT);
+}
+
+#[tokio::test]
+async fn test_reserved_slots() {
+    let (tx, _rx) = mpsc::channel(QUEUE_SIZE);
+    let sender = Sender::new(SocketId(1), tx);
+    for _ in 0..QUEUE_SIZE / 2 {
+        sender.send(Message::text("{}")).unwrap();
+    }
+
+    // Low priority messages are dropped to keep the other half free, which
+    // is routine and does not make the connection stale.
+    for _ in 0..2 * MAX_SEND_FAILURES {
+        sender.send_with(Priority::Low, Message::text("{}")).unwrap();
+    }
+    assert_eq!(sender.queued(), QUEUE_SIZE / 2);
+    assert!(!sender.is_slow_consumer());
+    assert!(tokio::time::timeout(Duration::from_millis(100), sender.stale()).await.is_err());
+
+    // More important messages still get through.
+    sender.send_with(Priority::Normal, Message::text("{}")).unwrap();
+    assert_eq!(sender.queued(), QUEUE_SIZE / 2 + 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_online_query() {
+    let TestServer { addr, site_out, site_in, .. } = start_server(&[]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (_ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+
+    site_out.send("online/query 3 neio,thibault".to_owned()).unwrap();
+    expect_site_in(&site_in, "online/answer 3 thibault");
+
+    site_out.send("online/query 4 neio".to_owned()).unwrap();
+    expect_site_in(&site_in, "online/answer 4 ");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_presence_dump() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&[]).await;
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    tokio::spawn(admin::serve(app, admin_listener, admin::Access::default()));
+
+    let mut clients = Vec::new();
+    for sri in ["t3st", "t3st2"] {
+        let mut req = format!("ws://{}/?sri={}", addr, sri).into_client_request().unwrap();
+        req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+        clients.push(tokio_tungstenite::connect_async(req).await.unwrap());
+    }
+    expect_site_in(&site_in, "connect thibault - t3st -");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while app.presence() != [(UserId::new("thibault").unwrap(), 2)] {
+        assert!(Instant::now() < deadline, "second connection not authenticated");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    site_out.send("presence/dump".to_owned()).unwrap();
+    expect_site_in(&site_in, "presence thibault");
+    expect_site_in(&site_in, "presence/snapshot 1");
+
+    site_out.send("presence/dump counts".to_owned()).unwrap();
+    expect_site_in(&site_in, "presence thibault:2");
+    expect_site_in(&site_in, "presence/snapshot 1");
+
+    let res = admin_get(admin_addr, "/presence").await;
+    assert!(res.ends_with(r#"{"thibault":2}"#), "{}", res);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_debug_echo() {
+    let TestServer { app, addr, .. } = start_server(&[]).await;
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+    tokio::spawn(admin::serve(app, admin_listener, admin::Access::default()));
+
+    let res = admin_get(admin_addr, "/debug/echo?sri=t3st").await;
+    assert!(res.ends_with(r#"{"sockets":0,"targets":[{"sri":"t3st"}]}"#), "{}", res);
+
+    // Connections opened later are mirrored, too.
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    let sender = app.by_sri.read().values().next().unwrap()[0].clone();
+    assert!(sender.health.echo.load(Ordering::Relaxed));
+
+    let res = admin_get(admin_addr, "/debug/echo?sri=t3st&enabled=false").await;
+    assert!(res.ends_with(r#"{"sockets":1,"targets":[]}"#), "{}", res);
+    assert!(!sender.health.echo.load(Ordering::Relaxed));
+
+    let res = admin_get(admin_addr, "/debug/echo?user=thibault&sri=t3st").await;
+    assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", res);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_away() {
+    let TestServer { app, addr, site_in, .. } = start_server(&[]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+
+    // Recently active.
+    app.detect_away();
+    assert!(site_in.try_recv().is_err());
+
+    // Pings do not count as activity.
+    let sender = app.by_user.read().values().next().unwrap()[0].clone();
+    sender.health.last_activity_ms.fetch_sub(AWAY_TIMEOUT.as_millis() as u64, Ordering::Relaxed);
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    app.detect_away();
+    expect_site_in(&site_in, "away thibault");
+
+    // Other messages do.
+    ws.send(Message::text(r#"{"t":"moveLat","d":false}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    expect_site_in(&site_in, "back thibault");
+    app.detect_away();
+    assert!(site_in.try_recv().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_trace_user() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&["--trace-duration", "60"]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (_ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+    let sender = app.by_user.read().values().next().unwrap()[0].clone();
+    assert!(!sender.health.trace.is_active());
+
+    // Lila messages are handled in order.
+    site_out.send("trace/user thibault".to_owned()).unwrap();
+    site_out.send("online/query 1 thibault".to_owned()).unwrap();
+    expect_site_in(&site_in, "online/answer 1 thibault");
+    assert!(sender.health.trace.is_active());
+    assert_ne!(app.debug.get(&DebugTarget::User(UserId::new("thibault").unwrap())).trace_until_ms, 0);
+
+    site_out.send("trace/user thibault 0".to_owned()).unwrap();
+    site_out.send("online/query 2 thibault".to_owned()).unwrap();
+    expect_site_in(&site_in, "online/answer 2 thibault");
+    assert!(!sender.health.trace.is_active());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_signals() {
+    let TestServer { addr, site_out, site_in, .. } = start_server(&[]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    ws.send(Message::text(r#"{"t":"startWatching","d":"5iL3vzAw"}"#)).await.unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+    ws.next().await.unwrap().unwrap(); // watching
+    site_out.send(format!("move 5iL3vzAw e2e4 {}", FEN)).unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    // Analysis of the current position of the game in progress.
+    ws.send(Message::text(format!(r#"{{"t":"anaDests","d":{{"fen":"{} b KQkq - 0 1","path":""}}}}"#, FEN))).await.unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    // Impossible lag.
+    ws.send(Message::text(r#"{"t":"p","l":-20}"#)).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    ws.close(None).await.unwrap();
+    expect_site_in(&site_in, r#"signals 203.0.113.7 - {"messages":2,"timingCv":null,"analysisInGame":1,"impossibleLags":1}"#);
+    expect_site_in(&site_in, "unwatch 5iL3vzAw");
+}
+
+#[tokio::test]
+async fn test_dests_batch() {
+    let TestServer { addr, .. } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+
+    // A full batch of positions from the middle of a study chapter.
+    let position = r#"{"fen":"r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4","path":"/?UE)8VN*7/?UE)8VN*7/?UE)8VN*7/?"}"#;
+    let msg = format!(r#"{{"t":"anaDests","d":[{}]}}"#, vec![position; analysis::MAX_DESTS_BATCH].join(","));
+    assert!(msg.len() <= MAX_CLIENT_MESSAGE_SIZE, "{} bytes", msg.len());
+    ws.send(Message::text(msg)).await.unwrap();
+    let msg: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(msg["t"], "destsBatch");
+    assert_eq!(msg["d"].as_array().unwrap().len(), analysis::MAX_DESTS_BATCH);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_round_trips() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&["--rtt-probe-interval", "1"]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+
+    // The client answers the probe with its next write.
+    let msg = ws.next().await.unwrap().unwrap();
+    assert!(msg.is_ping(), "{:?}", msg);
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    assert!(app.rtts.read().contains_key(&UserId::new("thibault").unwrap()));
+
+    site_out.send("mlat 42".to_owned()).unwrap();
+    loop {
+        let msg = site_in.recv_timeout(Duration::from_secs(5)).unwrap();
+        if msg.starts_with("rtts ") {
+            assert!(msg.starts_with("rtts thibault:"), "{}", msg);
+            break;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_watcher_talk() {
+    let TestServer { addr, site_out, site_in, .. } = start_server(&[]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+    let (mut other, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=0th3r", addr)).await.unwrap();
+
+    // Only to watched games.
+    ws.send(Message::text(r#"{"t":"talk","d":{"id":"5iL3vzAw","text":"hi"}}"#)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"startWatching","d":"5iL3vzAw"}"#)).await.unwrap();
+    expect_site_in(&site_in, "watch 5iL3vzAw");
+    ws.next().await.unwrap().unwrap(); // watching
+    ws.send(Message::text(r#"{"t":"talk","d":{"id":"5iL3vzAw","text":" gg "}}"#)).await.unwrap();
+    expect_site_in(&site_in, "watcher/talk 5iL3vzAw thibault gg");
+
+    // Anonymous spectators can not talk.
+    other.send(Message::text(r#"{"t":"startWatching","d":"5iL3vzAw"}"#)).await.unwrap();
+    other.send(Message::text(r#"{"t":"talk","d":{"id":"5iL3vzAw","text":"hi"}}"#)).await.unwrap();
+    other.send(Message::text("null")).await.unwrap();
+    other.next().await.unwrap().unwrap(); // watching
+    assert_eq!(other.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // Accepted lines are relayed to all watchers.
+    site_out.send(r#"tell/game 5iL3vzAw {"t":"message","d":{"u":"thibault","t":"gg"}}"#.to_owned()).unwrap();
+    for ws in [&mut ws, &mut other] {
+        let msg = ws.next().await.unwrap().unwrap();
+        assert_eq!(msg.to_text().unwrap(), r#"{"t":"message","d":{"u":"thibault","t":"gg"}}"#);
+    }
+    assert!(site_in.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_rooms() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&[]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+    let (mut anon, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=0th3r", addr)).await.unwrap();
+
+    // Pending until lila pushed the rooms of the user.
+    ws.send(Message::text(r#"{"t":"joinRoom","d":"team:coders"}"#)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"joinRoom","d":"team:secret"}"#)).await.unwrap();
+    anon.send(Message::text(r#"{"t":"joinRoom","d":"team:coders"}"#)).await.unwrap();
+    for ws in [&mut ws, &mut anon] {
+        ws.send(Message::text("null")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    }
+    assert!(app.by_topic.read().is_empty());
+
+    // Only rooms the user is a member of.
+    site_out.send("rooms thibault team:coders,team:lichess-swiss".to_owned()).unwrap();
+    site_out.send(r#"tell/room team:secret {"t":"secret"}"#.to_owned()).unwrap();
+    site_out.send(r#"tell/room team:coders {"t":"chat"}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"chat"}"#);
+
+    ws.send(Message::text(r#"{"t":"joinRoom","d":"team:lichess-swiss"}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    assert_eq!(app.by_topic.read().len(), 2);
+    ws.send(Message::text(r#"{"t":"leaveRoom","d":"team:lichess-swiss"}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    assert_eq!(app.by_topic.read().len(), 1);
+
+    // Leaving a team unsubscribes.
+    site_out.send("rooms thibault".to_owned()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !app.by_topic.read().is_empty() {
+        assert!(Instant::now() < deadline, "still subscribed");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(site_in.try_recv().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_lila_ws_protocol() {
+    let TestServer { addr, site_out, site_in, .. } = start_server(&["--lila-protocol", "lila-ws"]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect/user thibault - t3st -");
+
+    // Versioned room messages.
+    site_out.send("rooms thibault team:coders".to_owned()).unwrap();
+    ws.send(Message::text(r#"{"t":"joinRoom","d":"team:coders","v":3}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    site_out.send(r#"tell/version team:coders 4 true {"t":"troll"}"#.to_owned()).unwrap();
+    site_out.send(r#"tell/version team:coders 5 false {"t":"chat","d":"hi"}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"v":5,"t":"chat","d":"hi"}"#);
+    site_out.send(r#"tell/rooms team:coders,team:secret {"t":"reload"}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"reload"}"#);
+    site_out.send(r#"tell/sris 0th3r,t3st {"t":"sri"}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"sri"}"#);
+
+    // Trolls get the messages only for them.
+    site_out.send("roles thibault troll".to_owned()).unwrap();
+    site_out.send(r#"tell/version team:coders 6 true {"t":"troll"}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"v":6,"t":"troll"}"#);
+
+    // Versions are recorded even without sockets in the room, and do not
+    // go back.
+    site_out.send("rooms thibault team:coders,team:empty".to_owned()).unwrap();
+    site_out.send(r#"tell/version team:empty 7 false {"t":"chat","d":"hi"}"#.to_owned()).unwrap();
+    site_out.send(r#"tell/version team:empty 3 false {"t":"chat","d":"late"}"#.to_owned()).unwrap();
+    site_out.send(r#"tell/sris t3st {"t":"sri"}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"sri"}"#);
+
+    // Clients that missed a version are told to reload, whether they join
+    // in the handshake or later.
+    let mut req = format!("ws://{}/?sri=0th3r&room=team:empty&v=6", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut other, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    assert_eq!(other.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"reload"}"#);
+    other.send(Message::text(r#"{"t":"joinRoom","d":"team:coders","v":5}"#)).await.unwrap();
+    assert_eq!(other.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"reload"}"#);
+
+    ws.close(None).await.unwrap();
+    other.close(None).await.unwrap();
+    expect_site_in(&site_in, "disconnect/users thibault");
+}
+
+#[tokio::test]
+async fn test_relay() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"startRelay","d":"Qa1bR2c3"}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // Positions of many boards in a single frame.
+    site_out.send(format!("relay/fens Qa1bR2c3 kN8BVgDS:e2e4:{},Xf9a0Lq2::{}", FEN, FEN)).unwrap();
+    let msg = ws.next().await.unwrap().unwrap();
+    assert_eq!(msg.to_text().unwrap(), format!(r#"{{"t":"fens","d":[{{"id":"kN8BVgDS","fen":"{}","lm":"e2e4"}},{{"id":"Xf9a0Lq2","fen":"{}","lm":""}}]}}"#, FEN, FEN));
+
+    site_out.send(r#"tell/relay Qa1bR2c3 {"t":"addChapter"}"#.to_owned()).unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"addChapter"}"#);
+
+    ws.send(Message::text(r#"{"t":"stopRelay"}"#)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    assert!(app.by_topic.read().is_empty());
+    assert!(site_in.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_swiss() {
+    let TestServer { app, addr, site_out, site_in } = start_server(&[]).await;
+
+    let mut viewers = Vec::new();
+    for sri in ["t3st", "0th3r"] {
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri={}", addr, sri)).await.unwrap();
+        ws.send(Message::text(r#"{"t":"startSwiss","d":"w5XbKq1Z"}"#)).await.unwrap();
+        ws.send(Message::text("null")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+        viewers.push(ws);
+    }
+
+    site_out.send(r#"tell/swiss w5XbKq1Z {"t":"reload"}"#.to_owned()).unwrap();
+    for ws in &mut viewers {
+        assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), r#"{"t":"reload"}"#);
+    }
+
+    // Reloads for a new round are spread out.
+    site_out.send("swiss/round w5XbKq1Z 3 2000".to_owned()).unwrap();
+    let mut delays = Vec::new();
+    for ws in &mut viewers {
+        let msg: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(msg["t"], "swissRound");
+        assert_eq!(msg["d"]["round"], 3);
+        delays.push(msg["d"]["delay"].as_u64().unwrap());
+    }
+    delays.sort_unstable();
+    assert_eq!(delays, [0, 1000]);
+
+    for ws in &mut viewers {
+        ws.send(Message::text(r#"{"t":"stopSwiss"}"#)).await.unwrap();
+        ws.send(Message::text("null")).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    }
+    assert!(app.by_topic.read().is_empty());
+    assert!(site_in.try_recv().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_ping_lag() {
+    let TestServer { app, addr, site_in, .. } = start_server(&[]).await;
+
+    let mut req = format!("ws://{}/?sri=t3st", addr).into_client_request().unwrap();
+    req.headers_mut().insert("cookie", HeaderValue::from_static("lila2=s1gn4ture-sessionId=s3ss10n"));
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    expect_site_in(&site_in, "connect thibault - t3st -");
+
+    // Lags are recorded for the user the socket authenticated as.
+    ws.send(Message::text(r#"{"t":"p","l":42}"#)).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+    assert!(app.lags.read().contains_key(&UserId::new("thibault").unwrap()));
+
+    ws.close(None).await.unwrap();
+    expect_site_in(&site_in, "disconnect thibault");
+    assert!(app.lags.read().is_empty());
+}
+
+#[test]
+fn test_batch() {
+    let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+
+    // Single messages are sent as they are.
+    let (msg, held) = batch("0".into(), &mut rx, Shape::Legacy);
+    assert_eq!(msg, Message::text("0"));
+    assert_eq!(held, None);
+
+    // Up to the next message that can not be batched.
+    tx.try_send(Message::text(r#"{"t":"mlat","d":42}"#)).unwrap();
+    tx.try_send(Message::Close(None)).unwrap();
+    tx.try_send(Message::text("0")).unwrap();
+    let (msg, held) = batch("0".into(), &mut rx, Shape::Legacy);
+    assert_eq!(msg, Message::text(r#"[0,{"t":"mlat","d":42}]"#));
+    assert_eq!(held, Some(Message::Close(None)));
+    assert_eq!(rx.try_recv().unwrap(), Message::text("0"));
+
+    // V2 always sends arrays. Messages are already shaped, and opaque
+    // payloads from lila are left alone.
+    let (msg, held) = batch(r#"{"t":"mlat","d":42}"#.into(), &mut rx, Shape::V2);
+    assert_eq!(msg, Message::text(r#"[{"t":"mlat","d":42}]"#));
+    assert_eq!(held, None);
+}
+
+#[test]
+fn test_batch_msgpack() {
+    let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+
+    // Single messages are sent as they are, but encoded.
+    let (msg, held) = batch_msgpack(Message::text("0"), &mut rx, Shape::Legacy);
+    assert_eq!(msg, Message::binary(&b"\x00"[..]));
+    assert_eq!(held, None);
+
+    // Broadcasts are queued encoded already.
+    tx.try_send(Message::binary(&b"\x2a"[..])).unwrap();
+    tx.try_send(Message::Close(None)).unwrap();
+    let (msg, held) = batch_msgpack(Message::text("[]"), &mut rx, Shape::Legacy);
+    assert_eq!(msg, Message::binary(&b"\x92\x90\x2a"[..]));
+    assert_eq!(held, Some(Message::Close(None)));
+
+    // V2 always sends arrays.
+    let (msg, held) = batch_msgpack(Message::binary(&b"\x00"[..]), &mut rx, Shape::V2);
+    assert_eq!(msg, Message::binary(&b"\x91\x00"[..]));
+    assert_eq!(held, None);
+}
+
+#[tokio::test]
+async fn test_batch_frames() {
+    let TestServer { addr, .. } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st&batch=1", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"moveLat","d":true}"#)).await.unwrap();
+    let msg: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    let types: Vec<&str> = msg.as_array().unwrap().iter().map(|m| m["t"].as_str().unwrap()).collect();
+    assert_eq!(types, ["mlatHistory", "mlat", "serverLoad"]);
+
+    // Without negotiation, one frame per message.
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=0th3r", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"moveLat","d":true}"#)).await.unwrap();
+    let msg: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(msg["t"], "mlatHistory");
+}
+
+#[tokio::test]
+async fn test_v2_shape() {
+    let TestServer { addr, .. } = start_server(&[]).await;
+
+    // Clients that did not migrate yet keep getting the legacy shape.
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st&proto=5", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"moveLat","d":true}"#)).await.unwrap();
+    let msg: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(msg["t"], "mlatHistory");
+
+    // The room version does not select the protocol.
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st1&room=team:empty&v=6", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"moveLat","d":true}"#)).await.unwrap();
+    let msg: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    assert_eq!(msg["t"], "mlatHistory");
+
+    // Migrated clients get renamed types, always in batches.
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st2&proto=6", addr)).await.unwrap();
+    ws.send(Message::text(r#"{"t":"moveLat","d":true}"#)).await.unwrap();
+    let msg: serde_json::Value = serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap()).unwrap();
+    let types: Vec<&str> = msg.as_array().unwrap().iter().map(|m| m["t"].as_str().unwrap()).collect();
+    assert_eq!(types, ["moveLatencyHistory", "moveLatency", "serverLoad"]);
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let TestServer { app, addr, .. } = start_server(&[]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    ws.send(Message::text("null")).await.unwrap();
+    assert_eq!(ws.next().await.unwrap().unwrap().to_text().unwrap(), "0");
+
+    // Clients are told to go away, and the server waits for them.
+    let shutdown = tokio::spawn(app.shutdown());
+    match ws.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+        msg => panic!("expected close, got {:?}", msg),
+    }
+    assert!(ws.next().await.is_none());
+    shutdown.await.unwrap();
+    assert_eq!(app.connection_count.load(Ordering::Relaxed), 0);
+    assert_eq!(app.close_audit.report(), "closes: shutdown=1");
+}
+
+#[tokio::test]
+async fn test_user_idle() {
+    let TestServer { addr, .. } = start_server(&["--user-idle-timeout", "1"]).await;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/?sri=t3st", addr)).await.unwrap();
+    let start = Instant::now();
+
+    // Pings keep the connection alive, but do not count as activity.
+    let msg = loop {
+        ws.send(Message::text("null")).await.unwrap();
+        match ws.next().await.unwrap().unwrap() {
+            Message::Text(pong) => assert_eq!(pong.as_str(), "0"),
+            msg => break msg,
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    };
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    match msg {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::from(USER_IDLE)),
+        msg => panic!("expected close, got {:?}", msg),
+    }
+}
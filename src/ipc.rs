@@ -1,20 +1,58 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
 
+use parking_lot::Mutex;
+use serde::{Serialize, Serializer};
+use shakmaty::Color;
 use smallvec::SmallVec;
-use std::collections::HashMap;
 
-use crate::model::{Flag, GameId, Sri, UserId, InvalidUserId};
+use crate::model::{ChapterId, Flag, GameId, RelayId, Role, RoomId, Sri, SwissId, UserId, InvalidUserId, Pockets, VariantKey};
+use crate::lag::LagPercentiles;
+use crate::lz4;
+use crate::signals::SignalReport;
+use crate::visitors::VisitorCounts;
 
 #[derive(Debug)]
 pub struct IpcError;
 
+/// Wire protocol spoken with lila. Messages from lila are understood in
+/// either dialect, so that lila can be migrated without a flag-day
+/// cutover. The dialect only selects how messages to lila are framed.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Dialect {
+    #[default]
+    Legacy,
+    /// The newer lila-ws protocol.
+    LilaWs,
+}
+
+impl FromStr for Dialect {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Dialect, &'static str> {
+        match s {
+            "legacy" => Ok(Dialect::Legacy),
+            "lila-ws" => Ok(Dialect::LilaWs),
+            _ => Err("expected legacy or lila-ws"),
+        }
+    }
+}
+
 /// Messages we receive from lila.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum LilaOut<'a> {
     Move {
         game: GameId,
         last_uci: &'a str,
         fen: &'a str,
+        meta: Option<MoveMeta>,
+    },
+    Finish {
+        game: GameId,
+        winner: Option<Color>,
     },
     TellUsers {
         users: SmallVec<[UserId; 1]>,
@@ -23,6 +61,12 @@ pub enum LilaOut<'a> {
     TellAll {
         payload: &'a str,
     },
+    TellAnon {
+        payload: &'a str,
+    },
+    TellAuth {
+        payload: &'a str,
+    },
     TellFlag {
         flag: Flag,
         payload: &'a str,
@@ -31,10 +75,247 @@ pub enum LilaOut<'a> {
         sri: Sri,
         payload: &'a str,
     },
+    /// Message for any number of sris at once, as framed by lila-ws.
+    TellSris {
+        sris: SmallVec<[Sri; 1]>,
+        payload: &'a str,
+    },
+    TellGame {
+        game: GameId,
+        payload: &'a str,
+    },
+    TellRole {
+        role: Role,
+        payload: &'a str,
+    },
+    UserRoles {
+        uid: UserId,
+        roles: SmallVec<[Role; 2]>,
+    },
+    /// Message for all sockets subscribed to a team or private channel.
+    TellRoom {
+        room: RoomId,
+        payload: &'a str,
+    },
+    /// Message for the sockets of any number of rooms, as framed by
+    /// lila-ws.
+    TellRooms {
+        rooms: SmallVec<[RoomId; 1]>,
+        payload: &'a str,
+    },
+    /// Versioned message for a room, as framed by lila-ws. Clients that
+    /// missed a version need to resync. Messages only for trolls are
+    /// marked as such.
+    TellVersion {
+        room: RoomId,
+        version: u32,
+        troll: bool,
+        payload: &'a str,
+    },
+    /// Rooms a connected user may subscribe to. Replaces any previous
+    /// list of the user.
+    UserRooms {
+        uid: UserId,
+        rooms: Vec<RoomId>,
+    },
     DisconnectUser {
         uid: UserId,
     },
     MoveLatency(u32),
+    Counts(Counts),
+    DeployPre,
+    DeployPost,
+    /// Lila (re)started and may have lost track of watched games.
+    Boot,
+    /// Lila asks which of the users are connected to this server, for
+    /// example to show online badges in a user list.
+    OnlineQuery {
+        id: u32,
+        users: Vec<UserId>,
+    },
+    /// Lila asks for a snapshot of all connected users, optionally with
+    /// their number of connections, to resynchronize after a restart.
+    PresenceDump {
+        counts: bool,
+    },
+    /// Verbose tracing of the connections of a user, for the given number
+    /// of seconds or a default duration. Zero stops tracing.
+    TraceUser {
+        uid: UserId,
+        seconds: Option<u64>,
+    },
+    /// Eval that lila accepted, for the confirmed viewers of a study
+    /// chapter.
+    TellChapter {
+        chapter: ChapterId,
+        payload: &'a str,
+    },
+    /// Lila confirms that the sockets with the sri may see evals of the
+    /// study chapter they asked about.
+    ChapterViewer {
+        sri: Sri,
+        chapter: ChapterId,
+    },
+    /// Message for all viewers of a broadcast, like PGN updates.
+    TellRelay {
+        relay: RelayId,
+        payload: &'a str,
+    },
+    /// Positions of any number of boards of a broadcast, sent to viewers
+    /// in a single frame.
+    RelayFens {
+        relay: RelayId,
+        fens: Vec<RelayFen<'a>>,
+    },
+    /// Message for all viewers of a swiss tournament page.
+    TellSwiss {
+        swiss: SwissId,
+        payload: &'a str,
+    },
+    /// Pairings of a new round of a swiss tournament are ready. Viewers
+    /// are told to reload, spread over the given number of milliseconds,
+    /// rather than all at once.
+    SwissRound {
+        swiss: SwissId,
+        round: u32,
+        spread: u32,
+    },
+    /// Well-formed message of a type we do not know, probably from a newer
+    /// version of lila.
+    Unknown {
+        tag: &'a str,
+        args: Option<&'a str>,
+    },
+}
+
+/// Message types we understand. Messages of these types with invalid
+/// arguments are rejected rather than treated as unknown.
+const KNOWN_TAGS: &[&str] = &[
+    "move", "finish", "tell/users", "tell/user", "tell/all", "tell/anon", "tell/auth", "tell/flag", "tell/sri",
+    "tell/game", "tell/role", "roles", "disconnect/user", "mlat", "counts", "deploy/pre", "deploy/post", "boot", "lz4",
+    "online/query", "presence/dump", "trace/user", "tell/room", "rooms", "tell/sris", "tell/rooms", "tell/version",
+    "tell/relay", "relay/fens", "tell/swiss", "swiss/round", "tell/chapter", "chapter/viewer",
+];
+
+/// Maximum number of users lila may ask about in a single online query.
+pub const MAX_ONLINE_QUERY_USERS: usize = 500;
+
+/// Tags are path-like, for example `tell/users`.
+fn is_well_formed_tag(tag: &str) -> bool {
+    tag.split('/').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Upper bound for the size of decompressed messages.
+const MAX_DECOMPRESSED_SIZE: usize = 4 * 1024 * 1024;
+
+/// Lila may compress large messages (like tournament standings) as
+/// `lz4 <size> <base64>`, with the base64 encoded LZ4 block of the
+/// original message and its size in bytes. Returns other messages as they
+/// are.
+pub fn decompress(msg: &str) -> Result<Cow<'_, str>, IpcError> {
+    let args = match msg.strip_prefix("lz4 ") {
+        Some(args) => args,
+        None => return Ok(Cow::Borrowed(msg)),
+    };
+    let (size, data) = args.split_once(' ').ok_or(IpcError)?;
+    let size: usize = size.parse().map_err(|_| IpcError)?;
+    if size > MAX_DECOMPRESSED_SIZE {
+        return Err(IpcError);
+    }
+    let block = base64::decode(data).map_err(|_| IpcError)?;
+    let decompressed = lz4::decompress_block(&block, size).map_err(|_| IpcError)?;
+    String::from_utf8(decompressed).map(Cow::Owned).map_err(|_| IpcError)
+}
+
+/// Number of distinct unknown message types to count separately. Any
+/// further types are counted as `other`.
+const MAX_UNKNOWN_TAGS: usize = 50;
+
+/// Totals of messages of unknown types received from lila, by type.
+#[derive(Default)]
+pub struct UnknownCounts {
+    totals: Mutex<BTreeMap<String, u64>>,
+}
+
+impl UnknownCounts {
+    /// Returns `true` for the first message of a type that is counted
+    /// separately.
+    pub fn record(&self, tag: &str) -> bool {
+        let mut totals = self.totals.lock();
+        let key = if totals.len() < MAX_UNKNOWN_TAGS || totals.contains_key(tag) { tag } else { "other" };
+        let n = totals.entry(key.to_owned()).or_insert(0);
+        *n += 1;
+        *n == 1 && key == tag
+    }
+
+    /// Totals per type, like `tell/new=3 other=1`.
+    pub fn report(&self) -> String {
+        let totals: Vec<String> = self.totals.lock().iter().map(|(tag, n)| format!("{}={}", tag, n)).collect();
+        format!("unknown lila messages: {}", totals.join(" "))
+    }
+}
+
+/// Position details accompanying a move, so that watchers joining mid-game
+/// can render it without waiting for the next move.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct MoveMeta {
+    pub ply: u32,
+    #[serde(serialize_with = "serialize_color")]
+    pub turn: Color,
+    pub variant: VariantKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pockets: Option<Pockets>,
+}
+
+/// Position of a board of a broadcast, as `<chapter>:<uci>:<fen>`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct RelayFen<'a> {
+    pub id: ChapterId,
+    pub fen: &'a str,
+    pub lm: &'a str,
+}
+
+impl<'a> RelayFen<'a> {
+    fn parse(s: &'a str) -> Result<RelayFen<'a>, IpcError> {
+        let mut parts = s.splitn(3, ':');
+        let id = parts.next().unwrap().parse().map_err(|_| IpcError)?;
+        let lm = parts.next().ok_or(IpcError)?;
+        let fen = parts.next().ok_or(IpcError)?;
+        if fen.is_empty() || fen.contains(' ') {
+            return Err(IpcError);
+        }
+        Ok(RelayFen { id, fen, lm })
+    }
+}
+
+fn serialize_color<S: Serializer>(color: &Color, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_char(color.char())
+}
+
+/// Online counts, as shown on the lichess homepage.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct Counts {
+    pub connections: u32,
+    pub members: u32,
+    pub rounds: u32,
+}
+
+impl MoveMeta {
+    /// Parses `ply turn variant [pockets]`, like `12 w standard` or
+    /// `12 w crazyhouse Qpp`, with `-` for empty pockets.
+    fn parse(s: &str) -> Result<MoveMeta, IpcError> {
+        let mut args = s.split(' ');
+        let meta = MoveMeta {
+            ply: args.next().unwrap().parse().map_err(|_| IpcError)?,
+            turn: Color::from_char(args.next().ok_or(IpcError)?.parse().map_err(|_| IpcError)?).ok_or(IpcError)?,
+            variant: args.next().ok_or(IpcError)?.parse().map_err(|_| IpcError)?,
+            pockets: args.next().map(|p| p.parse()).transpose().map_err(|_| IpcError)?,
+        };
+        if args.next().is_some() || (meta.pockets.is_some() && meta.variant != VariantKey::Crazyhouse) {
+            return Err(IpcError);
+        }
+        Ok(meta)
+    }
 }
 
 impl<'a> LilaOut<'a> {
@@ -42,11 +323,22 @@ impl<'a> LilaOut<'a> {
         let mut tag_and_args = s.splitn(2, ' ');
         Ok(match (tag_and_args.next().unwrap(), tag_and_args.next()) {
             ("move", Some(args)) => {
-                let mut args = args.splitn(3, ' ');
+                let mut args = args.splitn(4, ' ');
                 LilaOut::Move {
                     game: args.next().unwrap().parse().map_err(|_| IpcError)?,
                     last_uci: args.next().ok_or(IpcError)?,
                     fen: args.next().ok_or(IpcError)?,
+                    meta: args.next().map(MoveMeta::parse).transpose()?,
+                }
+            },
+            ("finish", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::Finish {
+                    game: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    winner: match args.next().ok_or(IpcError)? {
+                        "-" => None,
+                        w => Some(Color::from_char(w.parse().map_err(|_| IpcError)?).ok_or(IpcError)?),
+                    },
                 }
             },
             ("tell/user", Some(args)) | ("tell/users", Some(args)) => {
@@ -60,6 +352,12 @@ impl<'a> LilaOut<'a> {
             ("tell/all", Some(payload)) => {
                 LilaOut::TellAll { payload }
             },
+            ("tell/anon", Some(payload)) => {
+                LilaOut::TellAnon { payload }
+            },
+            ("tell/auth", Some(payload)) => {
+                LilaOut::TellAuth { payload }
+            },
             ("tell/flag", Some(args)) => {
                 let mut args = args.splitn(2, ' ');
                 LilaOut::TellFlag {
@@ -74,6 +372,123 @@ impl<'a> LilaOut<'a> {
                     payload: args.next().ok_or(IpcError)?,
                 }
             },
+            ("tell/sris", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellSris {
+                    sris: args.next().unwrap().split(',').map(|s| s.parse().map_err(|_| IpcError)).collect::<Result<_, _>>()?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("tell/game", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellGame {
+                    game: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("tell/role", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellRole {
+                    role: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("roles", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::UserRoles {
+                    uid: UserId::new(args.next().unwrap()).map_err(|_| IpcError)?,
+                    roles: match args.next() {
+                        Some(roles) => roles.split(',').map(|r| r.parse().map_err(|_| IpcError)).collect::<Result<_, _>>()?,
+                        None => SmallVec::new(),
+                    },
+                }
+            },
+            ("tell/room", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellRoom {
+                    room: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("tell/rooms", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellRooms {
+                    rooms: args.next().unwrap().split(',').map(|r| r.parse().map_err(|_| IpcError)).collect::<Result<_, _>>()?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("tell/version", Some(args)) => {
+                let mut args = args.splitn(4, ' ');
+                LilaOut::TellVersion {
+                    room: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    version: args.next().ok_or(IpcError)?.parse().map_err(|_| IpcError)?,
+                    troll: match args.next().ok_or(IpcError)? {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(IpcError),
+                    },
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("rooms", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::UserRooms {
+                    uid: UserId::new(args.next().unwrap()).map_err(|_| IpcError)?,
+                    rooms: match args.next() {
+                        Some(rooms) => rooms.split(',').map(|r| r.parse().map_err(|_| IpcError)).collect::<Result<_, _>>()?,
+                        None => Vec::new(),
+                    },
+                }
+            },
+            ("tell/chapter", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellChapter {
+                    chapter: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("chapter/viewer", Some(args)) => {
+                let mut args = args.split(' ');
+                let msg = LilaOut::ChapterViewer {
+                    sri: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    chapter: args.next().ok_or(IpcError)?.parse().map_err(|_| IpcError)?,
+                };
+                if args.next().is_some() {
+                    return Err(IpcError);
+                }
+                msg
+            },
+            ("tell/relay", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellRelay {
+                    relay: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("relay/fens", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::RelayFens {
+                    relay: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    fens: args.next().ok_or(IpcError)?.split(',').map(RelayFen::parse).collect::<Result<_, _>>()?,
+                }
+            },
+            ("tell/swiss", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TellSwiss {
+                    swiss: args.next().unwrap().parse().map_err(|_| IpcError)?,
+                    payload: args.next().ok_or(IpcError)?,
+                }
+            },
+            ("swiss/round", Some(args)) => {
+                let mut args = args.split(' ');
+                let swiss = args.next().unwrap().parse().map_err(|_| IpcError)?;
+                let round = args.next().ok_or(IpcError)?.parse().map_err(|_| IpcError)?;
+                let spread = args.next().ok_or(IpcError)?.parse().map_err(|_| IpcError)?;
+                if args.next().is_some() {
+                    return Err(IpcError);
+                }
+                LilaOut::SwissRound { swiss, round, spread }
+            },
             ("disconnect/user", Some(uid)) => {
                 LilaOut::DisconnectUser {
                     uid: UserId::new(uid).map_err(|_| IpcError)?,
@@ -82,46 +497,706 @@ impl<'a> LilaOut<'a> {
             ("mlat", Some(value)) => {
                 LilaOut::MoveLatency(value.parse().map_err(|_| IpcError)?)
             },
+            ("counts", Some(args)) => {
+                let mut args = args.split(' ').map(|v| v.parse().map_err(|_| IpcError));
+                let counts = Counts {
+                    connections: args.next().ok_or(IpcError)??,
+                    members: args.next().ok_or(IpcError)??,
+                    rounds: args.next().ok_or(IpcError)??,
+                };
+                if args.next().is_some() {
+                    return Err(IpcError);
+                }
+                LilaOut::Counts(counts)
+            },
+            ("deploy/pre", None) => LilaOut::DeployPre,
+            ("deploy/post", None) => LilaOut::DeployPost,
+            ("boot", None) => LilaOut::Boot,
+            ("online/query", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                let id = args.next().unwrap().parse().map_err(|_| IpcError)?;
+                let users: Vec<UserId> = args.next().ok_or(IpcError)?.split(',').map(UserId::new).collect::<Result<_, _>>().map_err(|_| IpcError)?;
+                if users.len() > MAX_ONLINE_QUERY_USERS {
+                    return Err(IpcError);
+                }
+                LilaOut::OnlineQuery { id, users }
+            },
+            ("presence/dump", None) => LilaOut::PresenceDump { counts: false },
+            ("presence/dump", Some("counts")) => LilaOut::PresenceDump { counts: true },
+            ("trace/user", Some(args)) => {
+                let mut args = args.splitn(2, ' ');
+                LilaOut::TraceUser {
+                    uid: UserId::new(args.next().unwrap()).map_err(|_| IpcError)?,
+                    seconds: args.next().map(|s| s.parse().map_err(|_| IpcError)).transpose()?,
+                }
+            },
+            (tag, args) if !KNOWN_TAGS.contains(&tag) && is_well_formed_tag(tag) => LilaOut::Unknown { tag, args },
             _ => return Err(IpcError),
         })
     }
 }
 
+/// Details about a new connection of a user, for lila's security
+/// subsystem.
+#[derive(Debug, Default)]
+pub struct ConnectMeta {
+    pub ip: Option<IpAddr>,
+    pub sri: Option<Sri>,
+    pub user_agent: Option<String>,
+}
+
+impl fmt::Display for ConnectMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{} ", ip)?,
+            None => f.write_str("- ")?,
+        }
+        match self.sri {
+            Some(ref sri) => write!(f, "{} ", sri)?,
+            None => f.write_str("- ")?,
+        }
+        f.write_str(&self.user_agent.as_deref().map_or(Cow::Borrowed("-"), escape))
+    }
+}
+
+/// Kinds of misbehavior reported to lila.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AbuseKind {
+    /// Socket closed for persistently exceeding the rate limit.
+    RateLimited,
+    /// Address banned for repeatedly exceeding the rate limit.
+    Banned,
+    /// Message exceeding the size limit.
+    Oversized,
+    /// Trying to watch too many games.
+    TooManyGames,
+    /// Persistently reporting impossible lag.
+    ImpossibleLag,
+    /// Trying to watch games with malformed ids.
+    InvalidGameId,
+}
+
+impl fmt::Display for AbuseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AbuseKind::RateLimited => "rateLimited",
+            AbuseKind::Banned => "banned",
+            AbuseKind::Oversized => "oversized",
+            AbuseKind::TooManyGames => "tooManyGames",
+            AbuseKind::ImpossibleLag => "impossibleLag",
+            AbuseKind::InvalidGameId => "invalidGameId",
+        })
+    }
+}
+
+/// Adds the room version to a payload like `{"t":"message","d":"hi"}`, as
+/// clients of versioned rooms expect. Returns `None` if the payload is not
+/// a JSON object.
+pub fn with_version(payload: &str, version: u32) -> Option<String> {
+    let rest = payload.strip_prefix('{')?.trim_start();
+    Some(if rest.starts_with('}') {
+        format!("{{\"v\":{}{}", version, rest)
+    } else {
+        format!("{{\"v\":{},{}", version, rest)
+    })
+}
+
+/// Escapes free text for the last field of a message to lila, so that it
+/// stays on a single line and can not be confused with `-` for a missing
+/// value. Backslashes, line breaks and a lone `-` are escaped with a
+/// backslash.
+fn escape(s: &str) -> Cow<'_, str> {
+    if s == "-" {
+        return Cow::Borrowed("\\-");
+    }
+    if !s.contains(['\\', '\n', '\r']) {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Reverses `escape`, as done by lila.
+#[cfg(test)]
+fn unescape(s: &str) -> Result<String, IpcError> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next() {
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('-') if s == "\\-" => '-',
+                _ => return Err(IpcError),
+            },
+            c => c,
+        });
+    }
+    Ok(unescaped)
+}
+
+/// Puts a JSON payload on a single line. Line breaks can only be
+/// insignificant whitespace in valid JSON, so this is lossless.
+fn single_line(json: &str) -> Cow<'_, str> {
+    if json.contains(['\n', '\r']) {
+        Cow::Owned(json.replace(['\n', '\r'], " "))
+    } else {
+        Cow::Borrowed(json)
+    }
+}
+
 /// Messages we send to lila.
 #[derive(Debug)]
 pub enum LilaIn<'a> {
-    Connect(&'a UserId),
+    Connect(&'a UserId, Option<&'a ConnectMeta>),
     Disconnect(&'a UserId),
     DisconnectAll,
     Notified(&'a UserId),
+    Away(&'a UserId),
+    Back(&'a UserId),
     Watch(&'a GameId),
     Unwatch(&'a GameId),
     Connections(u32),
-    Lags(&'a HashMap::<UserId, u32>),
+    AnonConnections(u32),
+    Visitors(VisitorCounts),
+    Lags(&'a [(UserId, LagPercentiles)]),
+    /// Round trip times of users as measured by this server.
+    RoundTrips(&'a [(UserId, LagPercentiles)]),
+    TopGames(&'a [(GameId, usize)]),
     Friends(&'a UserId),
+    /// A client of the user recovered from suspend and needs its unread
+    /// notification count again.
+    Resync(&'a UserId),
     TellSri(&'a Sri, Option<&'a UserId>, &'a str),
+    Abuse(AbuseKind, Option<IpAddr>, Option<&'a UserId>),
+    /// Answer to an online query, with the connected subset of the users.
+    Online(u32, &'a [&'a UserId]),
+    /// A presence snapshot with this many users has been stored.
+    PresenceSnapshot(usize),
+    /// Chat line of a spectator of a game.
+    WatcherTalk(&'a GameId, &'a UserId, &'a str),
+    /// Behavioral signals of a connection that stand out.
+    Signals(Option<IpAddr>, Option<&'a UserId>, &'a SignalReport),
+}
+
+impl<'a> LilaIn<'a> {
+    /// Statistics that may be dropped if lila can not keep up.
+    pub fn is_low_priority(&self) -> bool {
+        matches!(self, LilaIn::Connections(_) | LilaIn::AnonConnections(_) | LilaIn::Visitors(_) | LilaIn::Lags(_) | LilaIn::RoundTrips(_) | LilaIn::TopGames(_) | LilaIn::Abuse(..) | LilaIn::Signals(..))
+    }
+
+    /// State changes that lila would otherwise lose track of. Retried for a
+    /// while if no one is subscribed.
+    pub fn is_retried(&self) -> bool {
+        matches!(self, LilaIn::Connect(..) | LilaIn::Disconnect(_) | LilaIn::Watch(_))
+    }
+
+    /// Formats the message for lila in the given dialect.
+    pub fn to_string_in(&self, dialect: Dialect) -> String {
+        match (dialect, self) {
+            (Dialect::LilaWs, LilaIn::Connect(uid, None)) => format!("connect/user {}", uid),
+            (Dialect::LilaWs, LilaIn::Connect(uid, Some(meta))) => format!("connect/user {} {}", uid, meta),
+            (Dialect::LilaWs, LilaIn::Disconnect(uid)) => format!("disconnect/users {}", uid),
+            (Dialect::LilaWs, LilaIn::Notified(uid)) => format!("notified/batch {}", uid),
+            _ => self.to_string(),
+        }
+    }
 }
 
 impl<'a> fmt::Display for LilaIn<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            LilaIn::Connect(uid) => write!(f, "connect {}", uid),
+            LilaIn::Connect(uid, None) => write!(f, "connect {}", uid),
+            LilaIn::Connect(uid, Some(meta)) => write!(f, "connect {} {}", uid, meta),
             LilaIn::Disconnect(uid) => write!(f, "disconnect {}", uid),
             LilaIn::DisconnectAll => write!(f, "disconnect/all"),
             LilaIn::Notified(uid) => write!(f, "notified {}", uid),
+            LilaIn::Away(uid) => write!(f, "away {}", uid),
+            LilaIn::Back(uid) => write!(f, "back {}", uid),
             LilaIn::Watch(game) => write!(f, "watch {}", game),
             LilaIn::Unwatch(game) => write!(f, "unwatch {}", game),
             LilaIn::Connections(n) => write!(f, "connections {}", n),
+            LilaIn::AnonConnections(n) => write!(f, "connections/anon {}", n),
+            LilaIn::Visitors(v) =>
+                write!(f, "visitors {} {} {} {}", v.ips_5m, v.users_5m, v.ips_1h, v.users_1h),
             LilaIn::Lags(lags) => {
                 write!(f, "lags ")?;
-                for (uid, lag) in lags.iter() { 
-                    write!(f, "{}:{},", uid, lag)?;
+                for (uid, lag) in lags.iter() {
+                    write!(f, "{}:{}:{},", uid, lag.p50, lag.p95)?;
+                }
+                Ok(())
+            }
+            LilaIn::RoundTrips(rtts) => {
+                write!(f, "rtts ")?;
+                for (uid, rtt) in rtts.iter() {
+                    write!(f, "{}:{}:{},", uid, rtt.p50, rtt.p95)?;
+                }
+                Ok(())
+            }
+            LilaIn::TopGames(games) => {
+                write!(f, "top/games ")?;
+                for (game, watchers) in games.iter() {
+                    write!(f, "{}:{},", game, watchers)?;
                 }
                 Ok(())
             }
             LilaIn::Friends(uid) => write!(f, "friends {}", uid),
+            LilaIn::Resync(uid) => write!(f, "resync {}", uid),
             LilaIn::TellSri(sri, uid, payload) =>
-                write!(f, "tell/sri {} {} {}", sri, uid.map_or("-", |u| u.as_str()), payload),
+                write!(f, "tell/sri {} {} {}", sri, uid.map_or("-", |u| u.as_str()), single_line(payload)),
+            LilaIn::Abuse(kind, ip, uid) => {
+                write!(f, "abuse {} ", kind)?;
+                match ip {
+                    Some(ip) => write!(f, "{} ", ip)?,
+                    None => f.write_str("- ")?,
+                }
+                f.write_str(uid.map_or("-", |u| u.as_str()))
+            }
+            LilaIn::Online(id, users) => {
+                write!(f, "online/answer {} ", id)?;
+                for (i, uid) in users.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    f.write_str(uid.as_str())?;
+                }
+                Ok(())
+            }
+            LilaIn::PresenceSnapshot(n) => write!(f, "presence/snapshot {}", n),
+            LilaIn::WatcherTalk(game, uid, text) => write!(f, "watcher/talk {} {} {}", game, uid, escape(text)),
+            LilaIn::Signals(ip, uid, report) => {
+                f.write_str("signals ")?;
+                match ip {
+                    Some(ip) => write!(f, "{} ", ip)?,
+                    None => f.write_str("- ")?,
+                }
+                write!(f, "{} ", uid.map_or("-", |u| u.as_str()))?;
+                f.write_str(&serde_json::to_string(report).map_err(|_| fmt::Error)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use smallvec::smallvec;
+
+    const SITE_OUT: &str = include_str!("../tests/fixtures/site-out.txt");
+    const SITE_OUT_INVALID: &str = include_str!("../tests/fixtures/site-out-invalid.txt");
+    const SITE_IN: &str = include_str!("../tests/fixtures/site-in.txt");
+
+    fn uid(name: &str) -> UserId {
+        UserId::new(name).unwrap()
+    }
+
+    #[test]
+    fn test_site_out() {
+        let expected = vec![
+            LilaOut::Move {
+                game: "5iL3vzAw".parse().unwrap(),
+                last_uci: "e2e4",
+                fen: "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR",
+                meta: None,
+            },
+            LilaOut::Move {
+                game: "Kn8YNzSq".parse().unwrap(),
+                last_uci: "e1g1",
+                fen: "r1bqk2r/pppp1ppp/2n2n2/2b1p3/2B1P3/5N2/PPPP1PPP/RNBQ1RK1",
+                meta: None,
+            },
+            LilaOut::Move {
+                game: "Kn8YNzSq".parse().unwrap(),
+                last_uci: "f8c5",
+                fen: "r1bqk2r/pppp1ppp/2n2n2/2b1p3/2B1P3/5N2/PPPP1PPP/RNBQK2R",
+                meta: Some(MoveMeta {
+                    ply: 8,
+                    turn: Color::White,
+                    variant: VariantKey::Standard,
+                    pockets: None,
+                }),
+            },
+            LilaOut::Move {
+                game: "Hn4s0Qa1".parse().unwrap(),
+                last_uci: "e4d5",
+                fen: "rnb1kbnr/ppp1pppp/8/3P4/8/8/PPPP1PPP/RNBQKBNR",
+                meta: Some(MoveMeta {
+                    ply: 4,
+                    turn: Color::Black,
+                    variant: VariantKey::Crazyhouse,
+                    pockets: Some("PQ".parse().unwrap()),
+                }),
+            },
+            LilaOut::Move {
+                game: "Hn4s0Qa1".parse().unwrap(),
+                last_uci: "P@e6",
+                fen: "rnb1kbnr/ppp1pppp/4P3/3P4/8/8/PPPP1PPP/RNBQKBNR",
+                meta: Some(MoveMeta {
+                    ply: 5,
+                    turn: Color::White,
+                    variant: VariantKey::Crazyhouse,
+                    pockets: Some("-".parse().unwrap()),
+                }),
+            },
+            LilaOut::Finish {
+                game: "5iL3vzAw".parse().unwrap(),
+                winner: Some(Color::White),
+            },
+            LilaOut::Finish {
+                game: "Kn8YNzSq".parse().unwrap(),
+                winner: None,
+            },
+            LilaOut::TellUsers {
+                users: smallvec![uid("thibault")],
+                payload: r#"{"t":"notifications","d":3}"#,
+            },
+            LilaOut::TellUsers {
+                users: smallvec![uid("thibault"), uid("neio"), uid("revoof")],
+                payload: r#"{"t":"following_enters","d":"thibault"}"#,
+            },
+            LilaOut::TellAll {
+                payload: r#"{"t":"reload"}"#,
+            },
+            LilaOut::TellAnon {
+                payload: r#"{"t":"signupPrompt"}"#,
+            },
+            LilaOut::TellAuth {
+                payload: r#"{"t":"reload"}"#,
+            },
+            LilaOut::TellFlag {
+                flag: Flag::Tournament,
+                payload: r#"{"t":"reload"}"#,
+            },
+            LilaOut::TellFlag {
+                flag: Flag::Simul,
+                payload: r#"{"t":"reload"}"#,
+            },
+            LilaOut::TellFlag {
+                flag: Flag::Streamer,
+                payload: r#"{"t":"streams","d":3}"#,
+            },
+            LilaOut::TellSri {
+                sri: "8j6e6kbwxhsv".parse().unwrap(),
+                payload: r#"{"t":"evalHit","d":{"fen":"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1","knodes":1,"depth":20,"pvs":[]}}"#,
+            },
+            LilaOut::TellGame {
+                game: "5iL3vzAw".parse().unwrap(),
+                payload: r#"{"t":"message","d":{"u":"thibault","t":"gg"}}"#,
+            },
+            LilaOut::TellRole {
+                role: Role::Mod,
+                payload: r#"{"t":"modAlert"}"#,
+            },
+            LilaOut::UserRoles {
+                uid: uid("thibault"),
+                roles: smallvec![Role::Mod, Role::Patron],
+            },
+            LilaOut::UserRoles {
+                uid: uid("revoof"),
+                roles: smallvec![],
+            },
+            LilaOut::UserRoles {
+                uid: uid("neio"),
+                roles: smallvec![Role::Troll],
+            },
+            LilaOut::DisconnectUser {
+                uid: uid("thibault"),
+            },
+            LilaOut::MoveLatency(42),
+            LilaOut::Counts(Counts { connections: 52000, members: 31000, rounds: 12000 }),
+            LilaOut::DeployPre,
+            LilaOut::DeployPost,
+            LilaOut::Boot,
+            LilaOut::OnlineQuery {
+                id: 7,
+                users: vec![uid("thibault"), uid("neio"), uid("revoof")],
+            },
+            LilaOut::PresenceDump { counts: false },
+            LilaOut::PresenceDump { counts: true },
+            LilaOut::TraceUser { uid: uid("thibault"), seconds: Some(600) },
+            LilaOut::TraceUser { uid: uid("thibault"), seconds: None },
+            LilaOut::TellRoom {
+                room: "team:lichess-swiss".parse().unwrap(),
+                payload: r#"{"t":"chat","d":{"u":"thibault","t":"hi"}}"#,
+            },
+            LilaOut::UserRooms {
+                uid: uid("thibault"),
+                rooms: vec!["team:lichess-swiss".parse().unwrap(), "team:coders".parse().unwrap()],
+            },
+            LilaOut::UserRooms {
+                uid: uid("revoof"),
+                rooms: vec![],
+            },
+            LilaOut::TellSris {
+                sris: smallvec!["8j6e6kbwxhsv".parse().unwrap(), "t3st".parse().unwrap()],
+                payload: r#"{"t":"reload"}"#,
+            },
+            LilaOut::TellRooms {
+                rooms: smallvec!["team:lichess-swiss".parse().unwrap(), "team:coders".parse().unwrap()],
+                payload: r#"{"t":"reload"}"#,
+            },
+            LilaOut::TellVersion {
+                room: "team:coders".parse().unwrap(),
+                version: 12,
+                troll: false,
+                payload: r#"{"t":"message","d":"hi"}"#,
+            },
+            LilaOut::TellRelay {
+                relay: "Qa1bR2c3".parse().unwrap(),
+                payload: r#"{"t":"addChapter","d":{"id":"kN8BVgDS"}}"#,
+            },
+            LilaOut::TellChapter {
+                chapter: "kN8BVgDS".parse().unwrap(),
+                payload: r#"{"t":"evalHit","d":{"path":""}}"#,
+            },
+            LilaOut::ChapterViewer {
+                sri: "t3st".parse().unwrap(),
+                chapter: "kN8BVgDS".parse().unwrap(),
+            },
+            LilaOut::RelayFens {
+                relay: "Qa1bR2c3".parse().unwrap(),
+                fens: vec![
+                    RelayFen { id: "kN8BVgDS".parse().unwrap(), fen: "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR", lm: "e2e4" },
+                    RelayFen { id: "Xf9a0Lq2".parse().unwrap(), fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR", lm: "" },
+                ],
+            },
+            LilaOut::TellSwiss {
+                swiss: "w5XbKq1Z".parse().unwrap(),
+                payload: r#"{"t":"reload"}"#,
+            },
+            LilaOut::SwissRound {
+                swiss: "w5XbKq1Z".parse().unwrap(),
+                round: 3,
+                spread: 2000,
+            },
+        ];
+
+        let lines: Vec<&str> = SITE_OUT.lines().collect();
+        assert_eq!(lines.len(), expected.len());
+        for (line, expected) in lines.into_iter().zip(expected) {
+            assert_eq!(LilaOut::parse(line).expect(line), expected);
+        }
+    }
+
+    #[test]
+    fn test_with_version() {
+        assert_eq!(with_version(r#"{"t":"message","d":"hi"}"#, 12).unwrap(), r#"{"v":12,"t":"message","d":"hi"}"#);
+        assert_eq!(with_version("{ }", 3).unwrap(), r#"{"v":3}"#);
+        assert_eq!(with_version("[]", 3), None);
+    }
+
+    #[test]
+    fn test_site_in_lila_ws() {
+        let user = uid("thibault");
+        let game: GameId = "5iL3vzAw".parse().unwrap();
+        let meta = ConnectMeta {
+            ip: Some("127.0.0.1".parse().unwrap()),
+            sri: Some("t3st".parse().unwrap()),
+            user_agent: None,
+        };
+        assert_eq!(LilaIn::Connect(&user, Some(&meta)).to_string_in(Dialect::LilaWs), "connect/user thibault 127.0.0.1 t3st -");
+        assert_eq!(LilaIn::Connect(&user, None).to_string_in(Dialect::LilaWs), "connect/user thibault");
+        assert_eq!(LilaIn::Disconnect(&user).to_string_in(Dialect::LilaWs), "disconnect/users thibault");
+        assert_eq!(LilaIn::Notified(&user).to_string_in(Dialect::LilaWs), "notified/batch thibault");
+        assert_eq!(LilaIn::Watch(&game).to_string_in(Dialect::LilaWs), "watch 5iL3vzAw");
+        assert_eq!(LilaIn::Connect(&user, None).to_string_in(Dialect::Legacy), "connect thibault");
+    }
+
+    #[test]
+    fn test_site_out_invalid() {
+        for line in SITE_OUT_INVALID.lines() {
+            assert!(LilaOut::parse(line).is_err(), "should be rejected: {}", line);
+        }
+    }
+
+    #[test]
+    fn test_online_query_size() {
+        let users = vec!["thibault"; MAX_ONLINE_QUERY_USERS].join(",");
+        assert!(LilaOut::parse(&format!("online/query 1 {}", users)).is_ok());
+        assert!(LilaOut::parse(&format!("online/query 1 {},neio", users)).is_err());
+    }
+
+    #[test]
+    fn test_decompress() {
+        const SITE_OUT_COMPRESSED: &str = include_str!("../tests/fixtures/site-out-compressed.txt");
+        let msg = decompress(SITE_OUT_COMPRESSED.trim_end()).unwrap();
+        assert_eq!(msg.len(), 922);
+        match LilaOut::parse(&msg).unwrap() {
+            LilaOut::TellFlag { flag: Flag::Tournament, payload } => {
+                let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+                assert_eq!(payload["d"]["standing"]["players"][9]["name"], "player9");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Uncompressed messages are passed through.
+        assert!(matches!(decompress("mlat 42").unwrap(), Cow::Borrowed("mlat 42")));
+
+        assert!(decompress("lz4 3 MGFiYw==").is_ok());
+        assert!(decompress("lz4 4 MGFiYw==").is_err());
+        assert!(decompress("lz4 3 not base64").is_err());
+        assert!(decompress("lz4 99999999999 MGFiYw==").is_err());
+        assert!(decompress("lz4 MGFiYw==").is_err());
+        assert!(LilaOut::parse("lz4 3 MGFiYw==").is_err());
+    }
+
+    #[test]
+    fn test_site_out_unknown() {
+        assert_eq!(LilaOut::parse("unknown/message 1 2 3").unwrap(), LilaOut::Unknown { tag: "unknown/message", args: Some("1 2 3") });
+        assert_eq!(LilaOut::parse("tell/new").unwrap(), LilaOut::Unknown { tag: "tell/new", args: None });
+
+        let counts = UnknownCounts::default();
+        assert!(counts.record("tell/new"));
+        assert!(!counts.record("tell/new"));
+        assert!(counts.record("r/ver"));
+        for i in 0..MAX_UNKNOWN_TAGS {
+            counts.record(&format!("t{}", i));
+        }
+        assert!(!counts.record("tell/newer"));
+        assert!(counts.report().starts_with("unknown lila messages: other=3 r/ver=1"), "{}", counts.report());
+        assert!(counts.report().contains(" tell/new=2"));
+    }
+
+    /// Deterministic pseudo random numbers for property tests.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn text(&mut self) -> String {
+            const ALPHABET: &[char] = &[' ', '\n', '\r', '\\', '-', 'n', 'r', '"', '{', 'é', '\t'];
+            (0..self.below(12)).map(|_| ALPHABET[self.below(ALPHABET.len())]).collect()
+        }
+
+        fn json(&mut self, depth: u32) -> serde_json::Value {
+            match self.below(if depth > 0 { 5 } else { 3 }) {
+                0 => serde_json::Value::from(self.below(1000)),
+                1 => serde_json::Value::from(self.text()),
+                2 => serde_json::Value::Null,
+                3 => (0..self.below(4)).map(|_| self.json(depth - 1)).collect(),
+                _ => serde_json::Value::Object((0..self.below(4)).map(|_| (self.text(), self.json(depth - 1))).collect()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_escape_roundtrip() {
+        let user = uid("thibault");
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let text = rng.text();
+            let escaped = escape(&text);
+            assert!(!escaped.contains(['\n', '\r']) && escaped != "-", "{:?}", escaped);
+            assert_eq!(unescape(&escaped).unwrap(), text);
+
+            let meta = ConnectMeta {
+                ip: None,
+                sri: None,
+                user_agent: Some(text.clone()),
+            };
+            let msg = LilaIn::Connect(&user, Some(&meta)).to_string();
+            assert_eq!(msg.lines().count(), 1, "{:?}", msg);
+            assert_eq!(unescape(msg.splitn(5, ' ').nth(4).unwrap()).unwrap(), text);
+        }
+
+        assert_eq!(LilaIn::Connect(&user, Some(&ConnectMeta::default())).to_string(), "connect thibault - - -");
+        assert!(unescape("\\x").is_err());
+        assert!(unescape("a\\-").is_err());
+        assert!(unescape("trailing\\").is_err());
+    }
+
+    #[test]
+    fn test_json_payload_roundtrip() {
+        let sri: Sri = "8j6e6kbwxhsv".parse().unwrap();
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let value = rng.json(3);
+            let pretty = serde_json::to_string_pretty(&value).unwrap();
+
+            // To lila on a single line.
+            let msg = LilaIn::TellSri(&sri, None, &pretty).to_string();
+            assert_eq!(msg.lines().count(), 1, "{:?}", msg);
+            let payload = msg.splitn(4, ' ').nth(3).unwrap();
+            assert_eq!(serde_json::from_str::<serde_json::Value>(payload).unwrap(), value);
+
+            // From lila verbatim.
+            let msg = format!("tell/sri {} {}", sri, pretty);
+            match LilaOut::parse(&msg).unwrap() {
+                LilaOut::TellSri { payload, .. } => assert_eq!(serde_json::from_str::<serde_json::Value>(payload).unwrap(), value),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_site_in() {
+        let user = uid("thibault");
+        let game: GameId = "5iL3vzAw".parse().unwrap();
+        let sri: Sri = "8j6e6kbwxhsv".parse().unwrap();
+        let meta = ConnectMeta {
+            ip: Some("203.0.113.7".parse().unwrap()),
+            sri: Some(sri.clone()),
+            user_agent: Some("Mozilla/5.0 (X11; Linux x86_64)".to_owned()),
+        };
+        let no_meta = ConnectMeta::default();
+        let lags = [(user.clone(), LagPercentiles { p50: 120, p95: 350 })];
+        let rtts = [(user.clone(), LagPercentiles { p50: 45, p95: 80 })];
+        let top_games = [(game.clone(), 120), ("Kn8YNzSq".parse().unwrap(), 40)];
+        let neio = uid("neio");
+        let online = [&user, &neio];
+        let signals = SignalReport { messages: 40, timing_cv: Some(0.01), analysis_in_game: 3, impossible_lags: 0 };
+
+        let msgs = vec![
+            LilaIn::Connect(&user, None),
+            LilaIn::Connect(&user, Some(&meta)),
+            LilaIn::Connect(&user, Some(&no_meta)),
+            LilaIn::Disconnect(&user),
+            LilaIn::DisconnectAll,
+            LilaIn::Notified(&user),
+            LilaIn::Away(&user),
+            LilaIn::Back(&user),
+            LilaIn::Watch(&game),
+            LilaIn::Unwatch(&game),
+            LilaIn::Connections(31337),
+            LilaIn::AnonConnections(20000),
+            LilaIn::Visitors(VisitorCounts { ips_5m: 2000, users_5m: 1500, ips_1h: 9000, users_1h: 7000 }),
+            LilaIn::Lags(&lags),
+            LilaIn::RoundTrips(&rtts),
+            LilaIn::TopGames(&top_games),
+            LilaIn::Friends(&user),
+            LilaIn::Resync(&user),
+            LilaIn::TellSri(&sri, Some(&user), r#"{"t":"evalGet","d":{"fen":"8/8/8/8/8/8/8/8 w - -"}}"#),
+            LilaIn::TellSri(&sri, None, r#"{"t":"evalPut","d":{}}"#),
+            LilaIn::Abuse(AbuseKind::Banned, Some("203.0.113.7".parse().unwrap()), Some(&user)),
+            LilaIn::Abuse(AbuseKind::TooManyGames, None, None),
+            LilaIn::Abuse(AbuseKind::ImpossibleLag, None, Some(&user)),
+            LilaIn::Abuse(AbuseKind::InvalidGameId, None, None),
+            LilaIn::Online(7, &online),
+            LilaIn::Online(8, &[]),
+            LilaIn::PresenceSnapshot(31000),
+            LilaIn::Signals(Some("203.0.113.7".parse().unwrap()), Some(&user), &signals),
+            LilaIn::WatcherTalk(&game, &user, "good luck\nhave fun"),
+        ];
+
+        let lines: Vec<&str> = SITE_IN.lines().collect();
+        assert_eq!(lines.len(), msgs.len());
+        for (line, msg) in lines.into_iter().zip(msgs) {
+            assert_eq!(msg.to_string(), line);
         }
     }
 }
@@ -0,0 +1,69 @@
+/// Number of recent samples kept per user.
+const WINDOW: usize = 16;
+
+/// Recent lag samples of a user.
+#[derive(Debug, Default)]
+pub struct LagWindow {
+    samples: [u32; WINDOW],
+    len: usize,
+    next: usize,
+    fresh: bool, // new samples since last report
+}
+
+/// Lag distribution as reported to lila, in milliseconds.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LagPercentiles {
+    pub p50: u32,
+    pub p95: u32,
+}
+
+impl LagWindow {
+    pub fn push(&mut self, lag: u32) {
+        self.samples[self.next] = lag;
+        self.next = (self.next + 1) % WINDOW;
+        self.len = (self.len + 1).min(WINDOW);
+        self.fresh = true;
+    }
+
+    /// Percentiles of the recent samples, if there were new samples since
+    /// the last call.
+    pub fn report(&mut self) -> Option<LagPercentiles> {
+        if !self.fresh {
+            return None;
+        }
+        self.fresh = false;
+
+        let mut sorted = self.samples;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable();
+        Some(LagPercentiles {
+            p50: percentile(sorted, 50),
+            p95: percentile(sorted, 95),
+        })
+    }
+}
+
+fn percentile(sorted: &[u32], p: usize) -> u32 {
+    sorted[(sorted.len() * p / 100).min(sorted.len() - 1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lag_window() {
+        let mut window = LagWindow::default();
+        assert_eq!(window.report(), None);
+
+        window.push(100);
+        assert_eq!(window.report(), Some(LagPercentiles { p50: 100, p95: 100 }));
+        assert_eq!(window.report(), None);
+
+        for lag in 1..=40 {
+            window.push(lag * 10);
+        }
+        // Only the last 16 samples (250..=400) are considered.
+        assert_eq!(window.report(), Some(LagPercentiles { p50: 330, p95: 400 }));
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+
+/// Invalid or oversized LZ4 block.
+#[derive(Debug, Eq, PartialEq)]
+pub struct InvalidBlock;
+
+impl fmt::Display for InvalidBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid lz4 block")
+    }
+}
+
+/// Reads a length that continues with extra bytes while they are 255.
+fn read_length(input: &[u8], pos: &mut usize, mut len: usize) -> Result<usize, InvalidBlock> {
+    if len == 15 {
+        loop {
+            let byte = *input.get(*pos).ok_or(InvalidBlock)?;
+            *pos += 1;
+            len = len.checked_add(usize::from(byte)).ok_or(InvalidBlock)?;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Decompresses a raw LZ4 block (without frame) that decompresses to
+/// exactly `size` bytes.
+pub fn decompress_block(input: &[u8], size: usize) -> Result<Vec<u8>, InvalidBlock> {
+    let mut output = Vec::with_capacity(size);
+    let mut pos = 0;
+
+    loop {
+        let token = *input.get(pos).ok_or(InvalidBlock)?;
+        pos += 1;
+
+        // Literals.
+        let literals = read_length(input, &mut pos, usize::from(token >> 4))?;
+        let end = pos.checked_add(literals).filter(|&end| end <= input.len()).ok_or(InvalidBlock)?;
+        if output.len() + literals > size {
+            return Err(InvalidBlock);
+        }
+        output.extend_from_slice(&input[pos..end]);
+        pos = end;
+
+        // The last sequence has only literals.
+        if pos == input.len() {
+            break;
+        }
+
+        // Match, which may overlap with its own output.
+        let offset = match input.get(pos..pos + 2) {
+            Some(&[lo, hi]) => usize::from(u16::from_le_bytes([lo, hi])),
+            _ => return Err(InvalidBlock),
+        };
+        pos += 2;
+        if offset == 0 || offset > output.len() {
+            return Err(InvalidBlock);
+        }
+        let len = read_length(input, &mut pos, usize::from(token & 0xf))? + 4;
+        if output.len() + len > size {
+            return Err(InvalidBlock);
+        }
+        let start = output.len() - offset;
+        for i in start..start + len {
+            output.push(output[i]);
+        }
+    }
+
+    if output.len() == size {
+        Ok(output)
+    } else {
+        Err(InvalidBlock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress_block() {
+        // Literals only.
+        assert_eq!(decompress_block(b"\x30abc", 3).unwrap(), b"abc");
+
+        // Overlapping match, then final literals.
+        assert_eq!(decompress_block(b"\x16a\x01\x00\x10b", 12).unwrap(), b"aaaaaaaaaaab");
+
+        // Long literals with extra length bytes.
+        let mut block = vec![0xf0, 255, 1];
+        block.extend(std::iter::repeat_n(b'x', 15 + 255 + 1));
+        assert_eq!(decompress_block(&block, 271).unwrap().len(), 271);
+
+        assert_eq!(decompress_block(b"", 0), Err(InvalidBlock));
+        assert_eq!(decompress_block(b"\x30abc", 2), Err(InvalidBlock)); // too large
+        assert_eq!(decompress_block(b"\x30abc", 4), Err(InvalidBlock)); // too small
+        assert_eq!(decompress_block(b"\x40abc", 4), Err(InvalidBlock)); // truncated
+        assert_eq!(decompress_block(b"\x10a\x02\x00", 5), Err(InvalidBlock)); // offset out of range
+        assert_eq!(decompress_block(b"\x10a\x00\x00", 5), Err(InvalidBlock)); // zero offset
+    }
+}
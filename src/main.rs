@@ -1,42 +1,115 @@
-use mongodb::ThreadedClient as _;
-use mongodb::db::ThreadedDatabase as _;
-use mongodb::coll::options::FindOptions;
-use bson::{doc, bson};
-
-use redis::Commands as _;
-
-use cookie::Cookie;
 use serde::{Serialize, Deserialize};
 
-use ws::{Handshake, Handler, Sender, Message, CloseCode};
-use ws::util::Token;
-use mio_extras::timer::Timeout;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Notify, Semaphore};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, Instant};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::protocol::{self, CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use futures_util::{SinkExt as _, StreamExt as _};
 
 use structopt::StructOpt;
 
+use std::fmt;
+use std::io;
+use std::panic;
+use std::process;
+use std::thread;
 use std::str;
 use std::mem;
-use std::cmp::max;
+use std::iter;
+use std::cmp::{max, min, Reverse};
 use std::convert::TryInto;
 use std::net::IpAddr;
 use std::num::NonZeroU32;
 use std::time::Duration;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher as _, Hash, Hasher};
 use smallvec::SmallVec;
+use once_cell::unsync;
 
-use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
-use once_cell::sync::OnceCell;
-use parking_lot::RwLock;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
+use parking_lot::{Mutex, RwLock};
 use crossbeam::channel;
-use ratelimit_meter::KeyedRateLimiter;
+use ratelimit_meter::{KeyedRateLimiter, NonConformance as _};
 
 mod model;
 mod ipc;
 mod util;
 mod analysis;
+mod pgn;
+mod bench;
+mod perft;
+mod backend;
+mod check;
+mod geoip;
+mod visitors;
+mod lag;
+mod penalty;
+mod blocklist;
+mod stats;
+mod audit;
+mod cache;
+mod msgpack;
+mod lz4;
+mod memory;
+mod alloc;
+mod profile;
+mod watchdog;
+mod alert;
+mod echo;
+mod trace;
+mod debug;
+mod signals;
+mod fen_json;
+mod fxhash;
+mod snapshot;
+mod session;
+mod dump;
+mod budget;
+mod retry;
+mod warnings;
+mod v2;
+mod explorer;
+mod admin;
+#[cfg(test)]
+mod integration_tests;
 
-use crate::model::{Flag, GameId, Sri, UserId};
-use crate::ipc::{LilaOut, LilaIn};
+use crate::model::{ChapterId, Flag, GameId, RelayId, Role, RoomId, Sri, SwissId, UserId};
+use crate::fen_json::fen_json;
+use crate::fxhash::{FxHashMap, FxHashSet};
+use crate::snapshot::SnapshotSet;
+use crate::session::{InvalidSessionCookie, SessionCookie};
+use crate::ipc::{AbuseKind, ConnectMeta, Counts, Dialect, LilaOut, LilaIn, RelayFen, UnknownCounts};
+use crate::backend::{BackendError, LilaBus, MongoSessionStore, RedisBus, SessionStore};
+use crate::geoip::{GeoConnections, GeoInfo, GeoIp};
+use crate::lag::LagWindow;
+use crate::penalty::Penalties;
+use crate::blocklist::Blocklist;
+use crate::stats::SocketStats;
+use crate::v2::{RelayFenV2, Shape, SocketInV2};
+use crate::budget::Budget;
+use crate::retry::Retries;
+use crate::warnings::{Warning, Warnings};
+use crate::audit::{CloseAudit, CloseReason};
+use crate::cache::{GameCache, WatchedGame};
+use crate::memory::{MapUsage, MemoryReport};
+use crate::dump::{QueueDepth, StateDump};
+use crate::watchdog::Watchdog;
+use crate::debug::{DebugTarget, DebugTargets, Debugging};
+use crate::trace::{now_ms, TraceFlag};
+use crate::signals::{Lag, Signals};
+use crate::visitors::Visitors;
+use crate::explorer::Explorer;
 
 #[derive(StructOpt, Clone)]
 struct Opt {
@@ -52,39 +125,353 @@ struct Opt {
     /// Hard limit for maximum number of simultaneous Websocket connections
     #[structopt(long = "max-connections", default_value = "40000")]
     max_connections: usize,
+    /// How many Websocket connections beyond the limit may wait briefly for
+    /// a free slot
+    #[structopt(long = "admission-queue", default_value = "1000")]
+    admission_queue: usize,
     /// How many messages to accept, per IP, per 10s
     #[structopt(long = "rate-limiter-credits", default_value = "40")]
     rate_limiter_credits: u32,
+    /// Maximum number of messages waiting to be published to lila, per
+    /// priority class
+    #[structopt(long = "redis-queue-size", default_value = "10000")]
+    redis_queue_size: usize,
+    /// Maximum number of messages to buffer for lila during redis outages
+    #[structopt(long = "redis-buffer-size", default_value = "50000")]
+    redis_buffer_size: usize,
+    /// Maximum number of pending session lookups
+    #[structopt(long = "auth-queue-size", default_value = "5000")]
+    auth_queue_size: usize,
+    /// Path of MaxMind GeoIP2/GeoLite2 country database
+    #[structopt(long = "geoip-country")]
+    geoip_country: Option<String>,
+    /// Path of MaxMind GeoLite2 ASN database
+    #[structopt(long = "geoip-asn")]
+    geoip_asn: Option<String>,
+    /// File with CIDR ranges of client addresses to refuse, one per line.
+    /// Reloaded on SIGHUP
+    #[structopt(long = "ip-blocklist")]
+    ip_blocklist: Option<String>,
+    /// Binding address of HTTP admin interface (disabled if not given)
+    #[structopt(long = "admin-bind")]
+    admin_bind: Option<String>,
+    /// CIDR range allowed to use the admin interface (may be repeated,
+    /// defaults to everyone)
+    #[structopt(long = "admin-allow")]
+    admin_allow: Vec<String>,
+    /// Bearer token required for the admin interface
+    #[structopt(long = "admin-token")]
+    admin_token: Option<String>,
+    /// Number of most watched games to report to lila on every tick (0 to
+    /// disable)
+    #[structopt(long = "top-games", default_value = "0")]
+    top_games: usize,
+    /// Maximum number of watched games to cache the latest position of (0
+    /// to disable)
+    #[structopt(long = "game-cache-size", default_value = "5000")]
+    game_cache_size: usize,
+    /// Origin that browsers may connect from (may be repeated, defaults to
+    /// any). API clients with a bearer token are exempt
+    #[structopt(long = "allowed-origin")]
+    allowed_origins: Vec<String>,
+    /// Base URL of the opening explorer to proxy requests to, like
+    /// http://127.0.0.1:9002 (disabled if not given)
+    #[structopt(long = "explorer")]
+    explorer: Option<explorer::Endpoint>,
+    /// Maximum number of explorer responses to cache
+    #[structopt(long = "explorer-cache-size", default_value = "10000")]
+    explorer_cache_size: usize,
+    /// Number of threads making requests to the explorer
+    #[structopt(long = "explorer-workers", default_value = "4")]
+    explorer_workers: usize,
+    /// Interval for measuring round trip times of connections with
+    /// Websocket pings, in seconds
+    #[structopt(long = "rtt-probe-interval", default_value = "15")]
+    rtt_probe_interval: u64,
+    /// Close connections that sent nothing but pings for this long, in
+    /// seconds (0 to disable). Pings still keep the connection itself alive
+    #[structopt(long = "user-idle-timeout", default_value = "0")]
+    user_idle_timeout: u64,
+    /// Default duration of tracing requested by lila for a user, in
+    /// seconds
+    #[structopt(long = "trace-duration", default_value = "600")]
+    trace_duration: u64,
+    /// Log all messages of unknown types from lila in full, not just the
+    /// first of each type
+    #[structopt(long = "pass-unknown-messages")]
+    pass_unknown_messages: bool,
+    /// Dialect of messages to lila: legacy or lila-ws. Messages from lila
+    /// are understood in either
+    #[structopt(long = "lila-protocol", default_value = "legacy")]
+    lila_protocol: ipc::Dialect,
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt, Clone)]
+enum Command {
+    /// Run a load test against a running server
+    #[structopt(name = "bench")]
+    Bench(bench::BenchOpt),
+    /// Validate configuration and connectivity to redis and mongodb
+    #[structopt(name = "check")]
+    Check,
+    /// Verify move generation of all variants against known node counts
+    #[structopt(name = "perft")]
+    Perft(perft::PerftOpt),
+}
+
+/// Health of this server, sent along with mlat.
+#[derive(Serialize, Debug)]
+struct ServerLoad {
+    connections: u32,
+    load: LoadLevel,
+}
+
+/// Coarse summary of queue depths.
+#[derive(Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum LoadLevel {
+    Normal,
+    Busy,
+    Overloaded,
 }
 
 /// Messages we send to Websocket clients.
 #[derive(Serialize)]
 #[serde(tag = "t", content = "d")]
 enum SocketIn<'a> {
+    /// Formatted by `fen_json::fen_json()` in the fanout path. Kept as the
+    /// reference for its output.
+    #[cfg(test)]
     #[serde(rename = "fen")]
     Fen {
         id: &'a GameId,
         fen: &'a str,
         lm: &'a str,
+        #[serde(flatten)]
+        meta: Option<ipc::MoveMeta>,
+    },
+    #[serde(rename = "finish")]
+    Finish {
+        id: &'a GameId,
+        win: Option<char>,
+    },
+    /// Acknowledges `startWatching`, so that clients can show placeholders
+    /// for games that could not be subscribed. Invalid ids are only
+    /// counted, not echoed back.
+    #[serde(rename = "watching")]
+    Watching {
+        accepted: &'a [AcceptedGame],
+        rejected: &'a [RejectedGame<'a>],
+        #[serde(skip_serializing_if = "util::is_zero_usize")]
+        invalid: usize,
+    },
+    /// Positions of many boards of a broadcast at once.
+    #[serde(rename = "fens")]
+    Fens(&'a [RelayFen<'a>]),
+    /// Pairings of a new swiss round are ready. Clients reload after
+    /// `delay` milliseconds, so that not all viewers do at once.
+    #[serde(rename = "swissRound")]
+    SwissRound {
+        round: u32,
+        delay: u32,
     },
     #[serde(rename = "mlat")]
     MoveLatency(u32),
+    #[serde(rename = "mlatHistory")]
+    MoveLatencyHistory(&'a VecDeque<u32>),
+    #[serde(rename = "serverLoad")]
+    ServerLoad(ServerLoad),
+    #[serde(rename = "counts")]
+    Counts(Counts),
+    #[serde(rename = "deployPre")]
+    DeployPre {
+        #[serde(rename = "reconnectIn")]
+        reconnect_in: u64,
+    },
+    #[serde(rename = "deployPost")]
+    DeployPost {
+        #[serde(rename = "reconnectIn")]
+        reconnect_in: u64,
+    },
     #[serde(rename = "opening")]
     Opening(analysis::OpeningResponse),
     #[serde(rename = "destsFailure")]
     DestsFailure,
     #[serde(rename = "dests")]
     Dests(analysis::DestsResponse),
+    #[serde(rename = "destsBatch")]
+    DestsBatch(Vec<analysis::BatchedDests>),
     #[serde(rename = "stepFailure")]
     StepFailure,
     #[serde(rename = "node")]
     Node(Box<analysis::Node>),
+    #[serde(rename = "line")]
+    Line(analysis::Line),
+    #[serde(rename = "explorer")]
+    Explorer {
+        path: &'a str,
+        db: explorer::Db,
+        data: &'a serde_json::Value,
+    },
+    #[serde(rename = "explorerFailure")]
+    ExplorerFailure {
+        path: &'a str,
+    },
+    #[serde(rename = "importedPgn")]
+    ImportedPgn(analysis::ImportedPgn),
+    #[serde(rename = "importPgnFailure")]
+    ImportPgnFailure,
+    /// Current state of all subscriptions of the client at once, in
+    /// response to `resync`.
+    #[serde(rename = "state")]
+    State {
+        games: &'a [GameState<'a>],
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mlat: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        counts: Option<Counts>,
+    },
+    /// The client missed versioned messages and needs to reload, like when
+    /// lila tells everyone to.
+    #[serde(rename = "reload")]
+    Reload,
+    /// Estimate of the rate limit credits left, restored over `interval`
+    /// milliseconds.
+    #[serde(rename = "rateLimit")]
+    RateLimit {
+        remaining: u32,
+        interval: u64,
+    },
+    #[serde(rename = "error")]
+    Error {
+        code: ErrorCode,
+        reason: &'static str,
+        /// Milliseconds until requests will be accepted again, if known.
+        #[serde(rename = "retryIn", skip_serializing_if = "Option::is_none")]
+        retry_in: Option<u64>,
+    },
+}
+
+/// Machine readable reasons for `SocketIn::Error`.
+#[derive(Serialize, Copy, Clone, Debug)]
+enum ErrorCode {
+    #[serde(rename = "rateLimited")]
+    RateLimited,
+    #[serde(rename = "tooManyGames")]
+    TooManyGames,
+    #[serde(rename = "sriRequired")]
+    SriRequired,
+    #[serde(rename = "overloaded")]
+    Overloaded,
+}
+
+/// Subscribed game, and whether its current position was sent right away.
+#[derive(Serialize, Debug)]
+struct AcceptedGame {
+    id: GameId,
+    cached: bool,
+}
+
+/// Cached state of a watched game, in `SocketIn::State`.
+#[derive(Serialize, Debug)]
+struct GameState<'a> {
+    id: &'a GameId,
+    fen: &'a str,
+    lm: &'a str,
+    #[serde(flatten)]
+    meta: Option<ipc::MoveMeta>,
+    #[serde(skip_serializing_if = "util::is_false")]
+    finished: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    win: Option<char>,
+}
+
+/// Game of `startWatching` that was not subscribed.
+#[derive(Serialize, Debug)]
+struct RejectedGame<'a> {
+    id: &'a GameId,
+    reason: ErrorCode,
 }
 
 impl<'a> SocketIn<'a> {
     fn to_json_string(&self) -> String {
         serde_json::to_string(self).expect("serialize for socket")
     }
+
+    /// Serializes the message in the shape of the recipient. Only few of
+    /// our messages differ.
+    fn to_json_string_in(&self, shape: Shape) -> String {
+        match (shape, self) {
+            (Shape::Legacy, _) => self.to_json_string(),
+            (Shape::V2, SocketIn::Finish { id, win }) => SocketInV2::Finish { id, winner: *win }.to_json_string(),
+            (Shape::V2, SocketIn::Fens(fens)) => SocketInV2::Fens(fens.iter().map(RelayFenV2::from).collect()).to_json_string(),
+            (Shape::V2, SocketIn::MoveLatency(mlat)) => SocketInV2::MoveLatency(*mlat).to_json_string(),
+            (Shape::V2, SocketIn::MoveLatencyHistory(history)) => SocketInV2::MoveLatencyHistory(history).to_json_string(),
+            (Shape::V2, SocketIn::DestsFailure) => SocketInV2::DestsFailure.to_json_string(),
+            (Shape::V2, SocketIn::StepFailure) => SocketInV2::StepFailure.to_json_string(),
+            (Shape::V2, _) => self.to_json_string(),
+        }
+    }
+}
+
+/// A message for many clients, serialized once in each shape and encoded
+/// at most once for each protocol, so that fanout only clones the one
+/// matching the recipient.
+struct Broadcast {
+    legacy: Encoded,
+    v2: Option<Encoded>, // same as legacy if none
+}
+
+impl Broadcast {
+    fn new(serialize: impl Fn(Shape) -> String) -> Broadcast {
+        let legacy = serialize(Shape::Legacy);
+        let v2 = serialize(Shape::V2);
+        Broadcast {
+            v2: if v2 != legacy { Some(Encoded::new(v2)) } else { None },
+            legacy: Encoded::new(legacy),
+        }
+    }
+
+    /// A message that is the same in all shapes, like an opaque payload
+    /// from lila.
+    fn same(msg: impl Into<tungstenite::Utf8Bytes>) -> Broadcast {
+        Broadcast {
+            legacy: Encoded::new(msg),
+            v2: None,
+        }
+    }
+
+    fn get(&self, shape: Shape, protocol: Protocol) -> Message {
+        match (shape, &self.v2) {
+            (Shape::V2, Some(v2)) => v2.get(protocol),
+            _ => self.legacy.get(protocol),
+        }
+    }
+}
+
+/// A JSON message, and its MessagePack encoding once the first recipient
+/// needs it.
+struct Encoded {
+    json: Message,
+    msgpack: unsync::OnceCell<Message>,
+}
+
+impl Encoded {
+    fn new(json: impl Into<tungstenite::Utf8Bytes>) -> Encoded {
+        Encoded {
+            json: Message::text(json),
+            msgpack: unsync::OnceCell::new(),
+        }
+    }
+
+    fn get(&self, protocol: Protocol) -> Message {
+        match protocol {
+            Protocol::Json => self.json.clone(),
+            Protocol::Msgpack => self.msgpack.get_or_init(|| protocol.encode(self.json.clone())).clone(),
+        }
+    }
 }
 
 /// Messages we receive from Websocket clients.
@@ -98,19 +485,31 @@ enum SocketOut {
     #[serde(rename = "startWatching")]
     StartWatching {
         #[serde(deserialize_with = "util::space_separated")]
-        d: SmallVec<[GameId; 1]>
+        d: SmallVec<[String; 1]>, // validated one by one
     },
     #[serde(rename = "moveLat")]
     MoveLatency { d: bool },
+    #[serde(rename = "counts")]
+    Counts { d: bool },
     #[serde(rename = "following_onlines")]
     FollowingOnlines,
+    #[serde(rename = "resync")]
+    Resync,
+    #[serde(rename = "singleTab")]
+    SingleTab { d: bool },
+    #[serde(rename = "rateLimit")]
+    RateLimit,
+    #[serde(rename = "bg")]
+    Background,
+    #[serde(rename = "fg")]
+    Foreground,
     #[serde(rename = "opening")]
     Opening {
         d: analysis::GetOpening,
     },
     #[serde(rename = "anaDests")]
     AnaDests {
-        d: analysis::GetDests,
+        d: analysis::DestsRequest,
     },
     #[serde(rename = "anaMove")]
     AnaMove {
@@ -120,10 +519,53 @@ enum SocketOut {
     AnaDrop {
         d: analysis::PlayDrop,
     },
+    #[serde(rename = "anaLine")]
+    AnaLine {
+        d: analysis::PlayLine,
+    },
+    #[serde(rename = "explorer")]
+    Explorer {
+        d: explorer::GetExplorer,
+    },
+    #[serde(rename = "importPgn")]
+    ImportPgn {
+        d: analysis::ImportPgn,
+    },
     #[serde(rename = "evalGet")]
-    EvalGet, // opaque
+    EvalGet {
+        #[serde(default)]
+        d: EvalMeta,
+    }, // otherwise opaque
     #[serde(rename = "evalPut")]
-    EvalPut, // opaque
+    EvalPut {
+        #[serde(default)]
+        d: EvalMeta,
+    }, // otherwise opaque
+    #[serde(rename = "talk")]
+    Talk {
+        d: Talk,
+    },
+    #[serde(rename = "startRelay")]
+    StartRelay {
+        d: RelayId,
+    },
+    #[serde(rename = "stopRelay")]
+    StopRelay,
+    #[serde(rename = "startSwiss")]
+    StartSwiss {
+        d: SwissId,
+    },
+    #[serde(rename = "stopSwiss")]
+    StopSwiss,
+    #[serde(rename = "joinRoom")]
+    JoinRoom {
+        d: RoomId,
+        v: Option<u32>, // last seen version of a versioned room
+    },
+    #[serde(rename = "leaveRoom")]
+    LeaveRoom {
+        d: RoomId,
+    },
     #[serde(alias = "ping")]
     #[serde(alias = "join")]
     #[serde(alias = "cancel")]
@@ -138,706 +580,3544 @@ enum SocketOut {
     UnexpectedMessage,
 }
 
-/// Session cookie from Play framework.
-#[derive(Debug, Deserialize)]
-struct SessionCookie {
-    #[serde(rename = "sessionId")]
-    session_id: String,
+/// Chat line of a spectator of a game.
+#[derive(Deserialize)]
+struct Talk {
+    id: GameId,
+    text: String,
+}
+
+/// Maximum length of spectator chat lines, in characters.
+const MAX_TALK_LENGTH: usize = 140;
+
+/// Maximum number of team and private channel rooms a single socket can
+/// join, including pending requests.
+const MAX_ROOMS: usize = 20;
+
+/// Study chapter that an eval request is about, if any.
+#[derive(Deserialize, Default)]
+struct EvalMeta {
+    ch: Option<ChapterId>,
 }
 
 /// Query string of Websocket requests.
 #[derive(Deserialize, Debug)]
 struct QueryString {
-    flag: Option<Flag>,
-    sri: Sri,
+    #[serde(default, deserialize_with = "util::comma_separated")]
+    flag: SmallVec<[Flag; 2]>,
+    sri: Option<Sri>,
+    v: Option<u32>, // last seen version of room, like lila-ws
+    room: Option<RoomId>, // to join right away
+    proto: Option<u32>, // version of the protocol
+    #[serde(default, deserialize_with = "util::truthy")]
+    mobile: bool,
+    #[serde(default, deserialize_with = "util::truthy")]
+    batch: bool,
 }
 
-/// Timeout that's used to close Websockets after some time of inactivity.
-const IDLE_TIMEOUT_TOKEN: Token = Token(1);
-const IDLE_TIMEOUT_MS: u64 = 15_000;
-
-/// Shared state of this Websocket server.
-struct App {
-    by_user: RwLock<HashMap::<UserId, Vec<Sender>>>,
-    by_game: RwLock<HashMap::<GameId, Vec<Sender>>>,
-    by_sri: RwLock<HashMap::<Sri, Vec<Sender>>>,
-    by_id: RwLock<HashMap::<SocketId, UserSocket>>,
-    watched_games: RwLock<HashMap<GameId, WatchedGame>>,
-    flags: [RwLock<HashSet<Sender>>; 2],
-    lags: RwLock<HashMap::<UserId, u32>>, // buffer of user lags, to send several at once
-    mlat: AtomicU32,
-    watching_mlat: RwLock<HashSet<Sender>>,
-    redis_sink: channel::Sender<String>,
-    sid_sink: channel::Sender<(SocketId, SessionCookie)>,
-    broadcaster: OnceCell<Sender>,
-    connection_count: AtomicI32, // signed to allow relaxed writes with underflow
+/// What a client tells about itself in the handshake.
+#[derive(Debug, Default, Copy, Clone)]
+struct ClientInfo {
+    version: Option<u32>, // of the protocol
+    mobile: bool, // from query string or user agent
+    protocol: Protocol,
+    batch: bool, // accepts frames with an array of messages
+    shape: Shape, // of outgoing messages, by protocol version
 }
 
-#[derive(Debug)]
-struct WatchedGame {
-    fen: String,
-    lm: String,
+/// Websocket subprotocols. The messages are the same, only the encoding
+/// differs.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+enum Protocol {
+    #[default]
+    Json,
+    Msgpack,
 }
 
-impl App {
-    fn new(redis_sink: channel::Sender<String>, sid_sink: channel::Sender<(SocketId, SessionCookie)>) -> App {
-        App {
-            by_user: RwLock::new(HashMap::new()),
-            by_game: RwLock::new(HashMap::new()),
-            by_sri: RwLock::new(HashMap::new()),
-            by_id: RwLock::new(HashMap::new()),
-            watched_games: RwLock::new(HashMap::new()),
-            flags: [RwLock::new(HashSet::new()), RwLock::new(HashSet::new())],
-            lags: RwLock::new(HashMap::new()),
-            redis_sink,
-            sid_sink,
-            broadcaster: OnceCell::new(),
-            connection_count: AtomicI32::new(0),
-            mlat: AtomicU32::new(u32::max_value()),
-            watching_mlat: RwLock::new(HashSet::new()),
+impl Protocol {
+    fn from_name(name: &str) -> Option<Protocol> {
+        match name {
+            "lichess-v2-json" => Some(Protocol::Json),
+            "lichess-v2-msgpack" => Some(Protocol::Msgpack),
+            _ => None,
         }
     }
 
-    fn publish<'a>(&self, msg: LilaIn<'a>) {
-        self.redis_sink.send(msg.to_string()).expect("redis sink");
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Json => "lichess-v2-json",
+            Protocol::Msgpack => "lichess-v2-msgpack",
+        }
     }
 
-    fn received(&self, msg: LilaOut) {
-        match msg {
-            LilaOut::TellUsers { users, payload } => {
-                let by_user = self.by_user.read();
-                for user in users {
-                    if let Some(entry) = by_user.get(&user) {
-                        for sender in entry {
-                            if let Err(err) = sender.send(Message::text(payload.to_string())) {
-                                log::error!("failed to tell {}: {:?}", user, err);
-                            }
-                        }
-                    }
-                }
-            }
-            LilaOut::TellAll { payload } => {
-                let msg = Message::text(payload.to_string());
-                if let Err(err) = self.broadcaster.get().expect("broadcaster").send(msg) {
-                    log::error!("failed to broadcast: {:?}", err);
-                }
-            }
-            LilaOut::Move { game, fen, last_uci } => {
-                self.watched_games.write().insert(game.clone(), WatchedGame {
-                    fen: fen.to_owned(),
-                    lm: last_uci.to_owned()
-                });
-
-                let by_game = self.by_game.read();
-                if let Some(entry) = by_game.get(&game) {
-                    let msg = Message::text(SocketIn::Fen {
-                        id: &game,
-                        fen,
-                        lm: last_uci,
-                    }.to_json_string());
-
-                    for sender in entry {
-                        if let Err(err) = sender.send(msg.clone()) {
-                            log::error!("failed to send fen: {:?}", err);
-                        }
-                    }
-                }
-            }
-            LilaOut::MoveLatency(mlat) => {
-                // Respond with our stats (connection count).
-                self.publish(LilaIn::Connections(
-                    max(0, self.connection_count.load(Ordering::Relaxed)) as u32
-                ));
-                // publish the buffered lags and clear them
-                let mut lags = self.lags.write();
-                self.publish(LilaIn::Lags(&lags));
-                lags.clear();
-
-                // Update stats.
-                self.mlat.store(mlat, Ordering::Relaxed);
-
-                // Update watching clients.
-                let msg = SocketIn::MoveLatency(mlat).to_json_string();
-                for sender in self.watching_mlat.read().iter() {
-                    if let Err(err) = sender.send(msg.clone()) {
-                        log::error!("failed to send mlat: {:?}", err);
-                    }
+    /// Encodes an outgoing message. Messages that are not JSON, like
+    /// close frames, are passed through.
+    fn encode(self, msg: Message) -> Message {
+        match (self, &msg) {
+            (Protocol::Msgpack, Message::Text(text)) => match serde_json::from_str(text.as_str()) {
+                Ok(value) => {
+                    let mut buf = Vec::with_capacity(text.len());
+                    msgpack::encode(&value, &mut buf);
+                    Message::binary(buf)
                 }
-            }
-            LilaOut::TellFlag { flag, payload } => {
-                let watching_flag = self.flags[flag as usize].read();
-                let msg = payload.to_string();
-                for sender in watching_flag.iter() {
-                    if let Err(err) = sender.send(msg.clone()) {
-                        log::error!("failed to send to flag ({:?}): {:?}", flag, err);
-                    }
-                }
-            }
-            LilaOut::TellSri { sri, payload } => {
-                if let Some(entry) = self.by_sri.read().get(&sri) {
-                    for sender in entry {
-                        if let Err(err) = sender.send(payload) {
-                            log::error!("failed to send to sri: {:?}", err);
-                        }
-                    }
-                }
-            }
-            LilaOut::DisconnectUser { uid } => {
-                let senders = {
-                    let by_user = self.by_user.read();
-                    let senders = by_user.get(&uid);
-                    senders.cloned()
-                };
-                if let Some(senders) = senders {
-                    for sender in senders {
-                        if let Err(err) = sender.close(CloseCode::Normal) {
-                            log::error!("failed to disconnect user: {:?}", err);
-                        }
-                    }
-                }
-            }
+                Err(_) => msg,
+            },
+            _ => msg,
         }
     }
 }
 
-/// A Websocket client connection.
-struct Socket {
-    app: &'static App,
-    socket_id: SocketId,
-    rate_limiter: KeyedRateLimiter<IpAddr>,
-    client_addr: Option<IpAddr>,
-    user_agent: Option<String>,
-    rate_limited_once: bool,
-    sender: Sender,
-    watching: HashSet<GameId>,
-    flag: Option<Flag>,
-    sri: Option<Sri>,
-    idle_timeout: Option<Timeout>,
-    log_ignore: bool // stop logging errors from this client
+impl Serialize for Protocol {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
 }
 
-/// Uniquely identifies a socket connection over the entire runtime of the
-/// application.
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
-struct SocketId(pub u64);
-
-enum SocketAuth {
-    Requested,
-    Authenticated(UserId),
-    Anonymous,
+fn is_mobile_user_agent(ua: &str) -> bool {
+    ua.contains("Lichess Mobile") || ua.contains("lichobile")
 }
 
-struct UserSocket {
-    app: &'static App,
-    sender: Sender,
-    auth: SocketAuth,
-    pending_notified: bool,
-    pending_following_onlines: bool,
+/// Checks the type of a message that is too long to be anything but a PGN
+/// import.
+fn is_import_pgn(msg: &str) -> bool {
+    #[derive(Deserialize)]
+    struct MessageType<'a> {
+        t: &'a str,
+    }
+    serde_json::from_str::<MessageType<'_>>(msg).is_ok_and(|m| m.t == "importPgn")
 }
 
-impl UserSocket {
-    fn set_user(&mut self, maybe_uid: Option<UserId>) {
-        // Connected.
-        let auth = match maybe_uid {
-            Some(uid) => {
-                self.app.by_user.write()
-                    .entry(uid.clone())
-                    .and_modify(|v| v.push(self.sender.clone()))
-                    .or_insert_with(|| {
-                        log::debug!("first open: {}", uid);
-                        self.app.publish(LilaIn::Connect(&uid));
-                        vec![self.sender.clone()]
-                    });
+/// Websockets are closed after some time of inactivity.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15);
 
-                SocketAuth::Authenticated(uid)
-            },
-            None => SocketAuth::Anonymous,
-        };
+/// Mobile apps stop sending pings while in the background, so they get more
+/// time before being closed.
+const MOBILE_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
 
-        match mem::replace(&mut self.auth, auth) {
-            // Disconnected.
-            SocketAuth::Authenticated(uid) => {
-                let mut by_user = self.app.by_user.write();
-                let entry = by_user.get_mut(&uid).expect("uid in by_user");
-                let idx = entry.iter().position(|s| s.token() == self.sender.token()).expect("sender in by_user entry");
-                entry.swap_remove(idx);
-
-                // Last remaining connection closed.
-                if entry.is_empty() {
-                    by_user.remove(&uid);
-                    log::debug!("last close: {}", uid);
-                    self.app.publish(LilaIn::Disconnect(&uid));
-                }
-            },
-            // Authentication request finished.
-            SocketAuth::Requested => {
-                if self.pending_notified {
-                    self.on_notified();
-                }
+/// How often to log metrics.
+const METRICS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
-                if self.pending_following_onlines {
-                    self.on_following_onlines();
-                }
-            },
-            SocketAuth::Anonymous => (),
-        }
-    }
+/// Interval for broadcasting online counts to subscribed clients.
+const COUNTS_BROADCAST_INTERVAL: Duration = Duration::from_secs(3);
 
-    fn on_ping(&self, lag: u32) {
-        if let SocketAuth::Authenticated(ref uid) = self.auth {
-            self.app.lags.write().insert(uid.clone(), lag);
-        }
-    }
+/// Window over which clients are asked to spread their reconnects around a
+/// deploy of lila.
+const DEPLOY_RECONNECT_WINDOW: Duration = Duration::from_secs(30);
 
-    fn on_notified(&mut self) {
-        self.pending_notified = false;
-        match &self.auth {
-            SocketAuth::Requested => self.pending_notified = true,
-            SocketAuth::Authenticated(uid) => self.app.publish(LilaIn::Notified(uid)),
-            SocketAuth::Anonymous => log::warn!("anon notified"),
-        }
-    }
+/// Time for clients to complete the close handshake when the server is
+/// shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    fn on_following_onlines(&mut self) {
-        self.pending_following_onlines = false;
-        match &self.auth {
-            SocketAuth::Requested => self.pending_following_onlines = true,
-            SocketAuth::Authenticated(uid) => self.app.publish(LilaIn::Friends(uid)),
-            SocketAuth::Anonymous => log::debug!("anon following_onlines"),
-        }
-    }
+/// Number of recent mlat samples sent to clients that start watching mlat.
+const MLAT_HISTORY_SIZE: usize = 60;
 
-    fn user_id(&self) -> Option<&UserId> {
-        match self.auth {
-            SocketAuth::Authenticated(ref uid) => Some(uid),
-            _ => None,
-        }
-    }
-}
+/// Users are considered away if none of their clients sent anything but
+/// pings for this long.
+const AWAY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
-impl Handler for Socket {
-    fn on_open(&mut self, handshake: Handshake) -> ws::Result<()> {
-        // Update connection count.
-        self.app.connection_count.fetch_add(1, Ordering::Relaxed);
+/// Sockets are closed after this many rate limited messages within
+/// `RATE_LIMITED_WINDOW`.
+const MAX_RATE_LIMITED: u32 = 50;
+const RATE_LIMITED_WINDOW: Duration = Duration::from_secs(10);
 
-        // Get client address.
-        self.client_addr = handshake.request.client_addr()?.and_then(|ip| ip.parse().ok());
+/// Retry session lookups after this long if their queue is full.
+const SESSION_LOOKUP_RETRY_INTERVAL: Duration = Duration::from_millis(100);
 
-        // Get user agent.
-        self.user_agent = handshake.request.header("user-agent")
-            .and_then(|h| str::from_utf8(h).ok())
-            .map(|h| h.to_owned());
+/// Check the session store if there were no lookups for this long.
+const SESSION_STORE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 
-        // Parse session cookie.
-        let maybe_cookie = handshake.request.header("cookie")
-            .and_then(|h| str::from_utf8(h).ok())
-            .and_then(|h| {
-                h.split(';')
-                    .map(|p| p.trim())
-                    .find(|p| p.starts_with("lila2="))
-            })
-            .and_then(|h| Cookie::parse(h).ok())
-            .and_then(|c| {
-                let s = c.value();
-                let idx = s.find('-').map_or(0, |n| n + 1);
-                serde_urlencoded::from_str::<SessionCookie>(&s[idx..]).ok()
-            });
+/// How long to keep retrying important messages that lila missed, and how
+/// often.
+const MISSED_RETRY_WINDOW: Duration = Duration::from_secs(10);
+const MISSED_RETRY_INTERVAL: Duration = Duration::from_millis(500);
 
-        // Update by_id.
-        self.app.by_id.write().insert(self.socket_id, UserSocket {
-            app: self.app,
-            auth: if maybe_cookie.is_some() { SocketAuth::Requested } else { SocketAuth::Anonymous },
-            pending_notified: false,
-            pending_following_onlines: false,
-            sender: self.sender.clone(),
-        });
+/// Interval for shrinking maps, if the server is not busy.
+const SHRINK_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-        // Request authentication.
-        if let Some(cookie) = maybe_cookie {
-            self.app.sid_sink.send((self.socket_id, cookie)).expect("auth request");
-        }
+/// Interval for checking internal queues for overload.
+const LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-        // Parse query string.
-        let mut uri = handshake.request.resource().splitn(2, '?');
-        if let (_, Some(query_string)) = (uri.next().unwrap(), uri.next()) {
-            match serde_urlencoded::from_str::<QueryString>(query_string) {
-                Ok(QueryString { flag, sri }) => {
-                    // Subscribe to flag.
-                    self.flag = flag;
-                    if let Some(flag) = flag {
-                        self.app.flags[flag as usize].write().insert(self.sender.clone());
-                    }
+/// Start shedding load when any internal queue is filled to this fraction,
+/// and stop when all queues are back below the lower threshold.
+const OVERLOAD_ENTER: f64 = 0.8;
+const OVERLOAD_LEAVE: f64 = 0.5;
 
-                    // Add sri.
-                    self.sri = Some(sri.clone());
-                    self.app.by_sri.write()
-                        .entry(sri)
-                        .and_modify(|v| v.push(self.sender.clone()))
-                        .or_insert_with(|| vec![self.sender.clone()]);
-                },
-                Err(err) => {
-                    log::warn!("invalid query string ({:?}): {}", err, query_string);
+/// Maximum number of pending requests to the opening explorer.
+const EXPLORER_QUEUE_SIZE: usize = 1000;
+
+/// Rate limiter credits are restored over this interval.
+const RATE_LIMITER_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Rate limiter credits consumed by analysis requests while overloaded.
+const OVERLOAD_ANALYSIS_COST: u32 = 5;
+
+/// Watchers of finished games stay subscribed for this long, in case of late
+/// messages like rematch offers.
+const FINISHED_GAME_RETENTION: Duration = Duration::from_secs(60);
+
+/// Identical consecutive messages to a flag within this window are dropped.
+const FLAG_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Only one `notified` per user is forwarded to lila within this window.
+const NOTIFIED_DEBOUNCE: Duration = Duration::from_secs(5);
+
+/// Clients may ask for their current state again at most this often.
+const RESYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maximum number of games a single Websocket client can watch.
+const MAX_WATCHED_GAMES: usize = 50;
+
+/// Lila may ask about this many users per interval in online queries.
+/// Queries beyond that are dropped, leaving it to lila to time out.
+const ONLINE_QUERY_CREDITS: u32 = 10_000;
+const ONLINE_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Behavioral signals of each connection are reported to lila at most
+/// this often, if they stand out.
+const SIGNALS_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Maximum number of messages queued for a single Websocket client. Large
+/// enough for the cached positions and results of all games at once, when
+/// starting to watch as many as allowed.
+const QUEUE_SIZE: usize = 2 * MAX_WATCHED_GAMES + 20;
+
+/// Maximum number of queued messages sent together in a single frame, for
+/// clients that support it.
+const MAX_BATCH_SIZE: usize = QUEUE_SIZE;
+
+/// Maximum size of messages from clients. Bigger ones are abuse.
+const MAX_CLIENT_MESSAGE_SIZE: usize = 2048;
+
+/// Maximum size of `importPgn` messages. Pasted games with comments easily
+/// exceed the limit for other messages.
+const MAX_IMPORT_PGN_SIZE: usize = 32 * 1024;
+
+/// Maximum size of incoming Websocket messages. Anything bigger than the
+/// application level limit is rejected by the protocol layer already.
+const MAX_MESSAGE_SIZE: usize = MAX_IMPORT_PGN_SIZE;
+
+/// Shared state of this Websocket server.
+struct App {
+    by_user: RwLock<HashMap<UserId, Vec<Sender>>>, // SipHash is as fast for these
+    senders: RwLock<FxHashMap<SocketId, Sender>>, // of open connections
+    by_game: RwLock<FxHashMap<GameId, FxHashSet<SocketId>>>,
+    by_chapter: RwLock<HashMap<ChapterId, ChapterViewers>>,
+    by_topic: RwLock<HashMap<Topic, HashSet<Sender>>>,
+    by_sri: RwLock<HashMap::<Sri, Vec<Sender>>>,
+    room_versions: RwLock<HashMap<RoomId, u32>>, // latest seen, kept when rooms empty
+    by_id: RwLock<FxHashMap<SocketId, UserSocket>>,
+    watched_games: RwLock<GameCache>,
+    finished_games: Mutex<VecDeque<(Instant, GameId)>>, // pending cleanup
+    flags: [SnapshotSet<Sender>; Flag::ALL.len()],
+    last_flag_message: [Mutex<Option<(Instant, String)>>; Flag::ALL.len()], // for debouncing
+    roles: [RwLock<HashSet<UserId>>; Role::ALL.len()], // of connected users, as pushed by lila
+    user_rooms: RwLock<HashMap<UserId, HashSet<RoomId>>>, // of connected users, as pushed by lila
+    away: RwLock<HashSet<UserId>>,
+    last_notified: Mutex<HashMap<UserId, Instant>>,
+    lags: RwLock<HashMap::<UserId, LagWindow>>, // recent lags of connected users
+    rtts: RwLock<HashMap::<UserId, LagWindow>>, // recent round trip times of connected users
+    mlat: AtomicU32,
+    mlat_history: Mutex<VecDeque<u32>>,
+    watching_mlat: SnapshotSet<Sender>,
+    lila_counts: RwLock<Counts>, // totals lila knows, excluding this server
+    watching_counts: SnapshotSet<Sender>,
+    redis_sink: RedisSink,
+    sid_sink: channel::Sender<(SocketId, SessionCookie)>,
+    connection_count: AtomicI32, // signed to allow relaxed writes with underflow
+    overloaded: AtomicBool,
+    shutting_down: AtomicBool,
+    subscribed: AtomicBool, // to messages from lila
+    session_store_ok: AtomicBool, // as of the last query
+    geoip: GeoIp,
+    geo_connections: GeoConnections,
+    visitors: Mutex<Visitors>,
+    penalties: Penalties,
+    blocklist: Blocklist,
+    close_audit: CloseAudit,
+    report_top_games: usize,
+    allowed_origins: Vec<String>, // any if empty
+    explorer: Option<Explorer>,
+    unknown_messages: UnknownCounts, // from lila
+    unknown_handler: Option<UnknownHandler>,
+    watchdog: Watchdog, // of the event loop
+    online_budget: Mutex<Budget>, // for online queries from lila
+    online_dropped: AtomicU32, // online queries over budget
+    session_deferred: AtomicU32, // session lookups while the queue was full
+    invalid_cookies: AtomicU32, // present, but not understood
+    warnings: Warnings, // caused by clients
+    bus: Option<&'static dyn LilaBus>, // for presence snapshots
+    debug: DebugTargets, // connections mirrored to the log or traced
+    trace_duration: Duration, // if lila does not specify
+    rtt_probe_interval: Duration,
+    user_idle_timeout: Option<Duration>, // if closing idle users
+    dialect: Dialect, // of messages to lila
+}
+
+/// Viewers of a study chapter that asked about evals. Only those that lila
+/// confirmed get the evals that lila accepted from others.
+#[derive(Default)]
+struct ChapterViewers {
+    pending: HashSet<Sender>,
+    confirmed: HashSet<Sender>,
+}
+
+impl ChapterViewers {
+    fn remove(&mut self, sender: &Sender) {
+        self.pending.remove(sender);
+        self.confirmed.remove(sender);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.confirmed.is_empty()
+    }
+}
+
+/// Something that lila tells all its subscribers about at once, like a
+/// broadcast.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+enum Topic {
+    Relay(RelayId),
+    Swiss(SwissId),
+    Room(RoomId),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum TopicKind {
+    Relay,
+    Swiss,
+    Room,
+}
+
+impl Topic {
+    fn kind(&self) -> TopicKind {
+        match self {
+            Topic::Relay(_) => TopicKind::Relay,
+            Topic::Swiss(_) => TopicKind::Swiss,
+            Topic::Room(_) => TopicKind::Room,
+        }
+    }
+}
+
+/// Handles messages of unknown types from lila, given the tag and
+/// arguments.
+type UnknownHandler = Box<dyn Fn(&str, Option<&str>) + Send + Sync>;
+
+/// A message for lila, formatted with `App::defer()`.
+struct Deferred {
+    msg: String,
+    low_priority: bool,
+    retried: bool,
+}
+
+/// Messages for lila that are published once the lock on `by_id` is
+/// released, so that a full queue to lila does not stall every connection.
+type Outbox = SmallVec<[Deferred; 2]>;
+
+/// Messages waiting to be published to lila. High priority messages block
+/// when the queue is full, low priority messages (statistics) replace the
+/// oldest queued low priority message.
+///
+/// State changes that lila would otherwise lose track of (see
+/// `LilaIn::is_retried()`) are retried if no one was subscribed when they
+/// were published, every `MISSED_RETRY_INTERVAL` for up to
+/// `MISSED_RETRY_WINDOW`. They stay in order: while some are waiting for a
+/// retry, further ones queue up behind them, so that for example a
+/// disconnect does not overtake the connect before it. Other messages are
+/// published right away and may overtake them.
+struct RedisSink {
+    high: channel::Sender<(bool, String)>, // (retried, message)
+    low: channel::Sender<String>,
+    low_overflow: channel::Receiver<String>, // to drop the oldest when full
+    dropped: AtomicU32, // low priority messages replaced by newer ones
+    missed: AtomicU32, // retried messages lila never received
+}
+
+impl RedisSink {
+    fn new(high: channel::Sender<(bool, String)>, low: channel::Sender<String>, low_overflow: channel::Receiver<String>) -> RedisSink {
+        RedisSink {
+            high,
+            low,
+            low_overflow,
+            dropped: AtomicU32::new(0),
+            missed: AtomicU32::new(0),
+        }
+    }
+
+    /// Queues a message, blocking while the queue is full. If `retried`,
+    /// the message is retried in case lila misses it.
+    fn send_high(&self, retried: bool, msg: String) {
+        self.high.send((retried, msg)).expect("redis sink");
+    }
+
+    /// Queues a message, making room by dropping the oldest queued low
+    /// priority message if needed. Never blocks and never retried.
+    fn send_low(&self, mut msg: String) {
+        loop {
+            match self.low.try_send(msg) {
+                Ok(()) => break,
+                Err(channel::TrySendError::Full(rejected)) => {
+                    if self.low_overflow.try_recv().is_ok() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    msg = rejected;
+                }
+                Err(channel::TrySendError::Disconnected(_)) => panic!("redis sink"),
+            }
+        }
+    }
+}
+
+/// Messages that could not yet be published to lila, for example during a
+/// redis outage. They are replayed in order after reconnecting.
+struct PublishBuffer {
+    queue: VecDeque<(bool, String)>, // (low priority, message)
+    capacity: usize,
+}
+
+impl PublishBuffer {
+    fn new(capacity: usize) -> PublishBuffer {
+        PublishBuffer {
+            queue: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, low_priority: bool, msg: String) {
+        if self.queue.len() >= self.capacity {
+            // Make room by dropping the oldest low priority message, or the
+            // oldest message if there is none.
+            let idx = self.queue.iter().position(|&(low, _)| low).unwrap_or(0);
+            if let Some((_, dropped)) = self.queue.remove(idx) {
+                log::warn!("publish buffer full, dropping: {}", dropped);
+            }
+        }
+        self.queue.push_back((low_priority, msg));
+    }
+
+    /// Moves all messages currently waiting in the channels into the buffer,
+    /// to avoid blocking publishers during outages.
+    fn drain(&mut self, high: &channel::Receiver<(bool, String)>, low: &channel::Receiver<String>) {
+        while let Ok((_, msg)) = high.try_recv() {
+            self.push(false, msg);
+        }
+        while let Ok(msg) = low.try_recv() {
+            self.push(true, msg);
+        }
+    }
+}
+
+impl App {
+    #[allow(clippy::too_many_arguments)]
+    fn new(redis_sink: RedisSink, sid_sink: channel::Sender<(SocketId, SessionCookie)>, geoip: GeoIp, blocklist: Blocklist, report_top_games: usize, game_cache_size: usize, allowed_origins: Vec<String>, explorer: Option<Explorer>) -> App {
+        App {
+            by_user: RwLock::new(HashMap::new()),
+            senders: RwLock::new(FxHashMap::default()),
+            by_game: RwLock::new(FxHashMap::default()),
+            by_chapter: RwLock::new(HashMap::new()),
+            by_topic: RwLock::new(HashMap::new()),
+            by_sri: RwLock::new(HashMap::new()),
+            room_versions: RwLock::new(HashMap::new()),
+            by_id: RwLock::new(FxHashMap::default()),
+            watched_games: RwLock::new(GameCache::new(game_cache_size)),
+            finished_games: Mutex::new(VecDeque::new()),
+            flags: Default::default(),
+            last_flag_message: Default::default(),
+            roles: Default::default(),
+            user_rooms: RwLock::new(HashMap::new()),
+            away: RwLock::new(HashSet::new()),
+            last_notified: Mutex::new(HashMap::new()),
+            lags: RwLock::new(HashMap::new()),
+            rtts: RwLock::new(HashMap::new()),
+            redis_sink,
+            sid_sink,
+            connection_count: AtomicI32::new(0),
+            overloaded: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            subscribed: AtomicBool::new(false),
+            session_store_ok: AtomicBool::new(true),
+            mlat: AtomicU32::new(u32::MAX),
+            mlat_history: Mutex::new(VecDeque::with_capacity(MLAT_HISTORY_SIZE)),
+            watching_mlat: SnapshotSet::default(),
+            lila_counts: RwLock::new(Counts::default()),
+            watching_counts: SnapshotSet::default(),
+            geoip,
+            geo_connections: GeoConnections::default(),
+            visitors: Mutex::new(Visitors::default()),
+            penalties: Penalties::default(),
+            blocklist,
+            close_audit: CloseAudit::default(),
+            report_top_games,
+            allowed_origins,
+            explorer,
+            unknown_messages: UnknownCounts::default(),
+            unknown_handler: None,
+            watchdog: Watchdog::default(),
+            online_budget: Mutex::new(Budget::new(ONLINE_QUERY_CREDITS, ONLINE_QUERY_INTERVAL)),
+            online_dropped: AtomicU32::new(0),
+            session_deferred: AtomicU32::new(0),
+            invalid_cookies: AtomicU32::new(0),
+            warnings: Warnings::default(),
+            bus: None,
+            debug: DebugTargets::default(),
+            trace_duration: Duration::from_secs(600),
+            rtt_probe_interval: Duration::from_secs(15),
+            user_idle_timeout: None,
+            dialect: Dialect::Legacy,
+        }
+    }
+
+    fn subscribe(&self, topic: Topic, sender: &Sender) {
+        self.by_topic.write().entry(topic).or_default().insert(sender.clone());
+    }
+
+    fn unsubscribe(&self, topic: &Topic, sender: &Sender) {
+        let mut by_topic = self.by_topic.write();
+        if let Some(subscribers) = by_topic.get_mut(topic) {
+            subscribers.remove(sender);
+            if subscribers.is_empty() {
+                by_topic.remove(topic);
+            }
+        }
+    }
+
+    /// Passes an opaque payload from lila to all subscribers of a topic.
+    fn tell(&self, topic: &Topic, payload: &str) {
+        if let Some(subscribers) = self.by_topic.read().get(topic) {
+            let msg = Broadcast::same(payload);
+            for sender in subscribers {
+                if let Err(err) = sender.send_broadcast(Priority::Normal, &msg) {
+                    log::error!("failed to send to subscriber of {:?}: {:?}", topic, err);
+                }
+            }
+        }
+    }
+
+    /// Games with the most watchers, in descending order.
+    fn top_games(&self, n: usize) -> Vec<(GameId, usize)> {
+        let mut games: Vec<(GameId, usize)> = self.by_game.read().iter()
+            .map(|(game, watchers)| (game.clone(), watchers.len()))
+            .collect();
+        games.sort_by_key(|&(_, watchers)| Reverse(watchers));
+        games.truncate(n);
+        games
+    }
+
+    /// Online counts of this server merged with the totals from lila.
+    fn counts(&self) -> Counts {
+        let lila = *self.lila_counts.read();
+        Counts {
+            connections: lila.connections + max(0, self.connection_count.load(Ordering::Relaxed)) as u32,
+            members: lila.members + self.by_user.read().len() as u32,
+            rounds: lila.rounds,
+        }
+    }
+
+    /// Fill level of the fullest internal queue, between 0 and 1.
+    fn queue_fill(&self) -> f64 {
+        fn fill<T>(sender: &channel::Sender<T>) -> f64 {
+            sender.capacity().map_or(0.0, |cap| sender.len() as f64 / cap.max(1) as f64)
+        }
+
+        fill(&self.redis_sink.high).max(fill(&self.redis_sink.low)).max(fill(&self.sid_sink))
+    }
+
+    /// Enters or leaves overload mode, depending on the fill level of the
+    /// queues to lila and the session store.
+    fn update_load(&self) {
+        let load = self.queue_fill();
+        let overloaded = self.overloaded.load(Ordering::Relaxed);
+        if !overloaded && load >= OVERLOAD_ENTER {
+            log::warn!("overloaded (queues {:.0}% full), shedding load", load * 100.0);
+            self.overloaded.store(true, Ordering::Relaxed);
+        } else if overloaded && load < OVERLOAD_LEAVE {
+            log::warn!("no longer overloaded");
+            self.overloaded.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Browsers must connect from an allowed origin. API clients
+    /// authenticate with a token instead.
+    fn check_client(&self, hs: &Handshake) -> Result<(), &'static str> {
+        if hs.is_api_client() {
+            return Ok(());
+        }
+        if !self.allowed_origins.is_empty() &&
+           !hs.header("origin").is_some_and(|origin| self.allowed_origins.iter().any(|o| o == origin))
+        {
+            return Err("origin not allowed");
+        }
+        Ok(())
+    }
+
+    /// Session cookie of the request, if any. Signed cookies without a
+    /// session belong to anonymous visitors. Cookies that are unsigned or
+    /// garbled are counted, and their clients connect anonymously, too.
+    fn session_cookie(&self, hs: &Handshake) -> Result<Option<SessionCookie>, InvalidSessionCookie> {
+        let res = session::extract(hs.headers.get_all("cookie").iter().filter_map(|h| h.to_str().ok()));
+        if res.is_err() {
+            self.invalid_cookies.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    /// Entries and capacity of the maps that grow with traffic.
+    fn map_usage(&self) -> BTreeMap<&'static str, MapUsage> {
+        let mut maps = BTreeMap::new();
+        maps.insert("by_user", MapUsage::of(&self.by_user.read()));
+        maps.insert("by_game", MapUsage::of(&self.by_game.read()));
+        maps.insert("by_chapter", MapUsage::of(&self.by_chapter.read()));
+        maps.insert("by_topic", MapUsage::of(&self.by_topic.read()));
+        maps.insert("by_sri", MapUsage::of(&self.by_sri.read()));
+        maps.insert("room_versions", MapUsage::of(&self.room_versions.read()));
+        maps.insert("by_id", MapUsage::of(&self.by_id.read()));
+        maps.insert("senders", MapUsage::of(&self.senders.read()));
+        maps.insert("lags", MapUsage::of(&self.lags.read()));
+        maps.insert("rtts", MapUsage::of(&self.rtts.read()));
+        maps.insert("user_rooms", MapUsage::of(&self.user_rooms.read()));
+        maps.insert("last_notified", MapUsage::of(&self.last_notified.lock()));
+        maps
+    }
+
+    fn memory_report(&self) -> MemoryReport {
+        MemoryReport::new(self.map_usage())
+    }
+
+    fn state_dump(&self) -> StateDump {
+        fn depth<T>(sender: &channel::Sender<T>) -> QueueDepth {
+            QueueDepth { len: sender.len(), capacity: sender.capacity() }
+        }
+
+        let mut dump = StateDump::new(self.server_load(), self.by_user.read().len(), self.map_usage());
+        dump.largest_games = dump::largest(self.by_game.read().iter().map(|(id, sockets)| (id, sockets.len())));
+        dump.largest_rooms = dump::largest(self.by_topic.read().iter().filter_map(|(topic, senders)| match topic {
+            Topic::Room(id) => Some((id, senders.len())),
+            _ => None,
+        }));
+        dump.penalties = self.penalties.counts();
+        dump.queues.insert("redis_high", depth(&self.redis_sink.high));
+        dump.queues.insert("redis_low", depth(&self.redis_sink.low));
+        dump.queues.insert("session_store", depth(&self.sid_sink));
+        for sender in self.senders.read().values() {
+            let queued = sender.queued();
+            dump.client_queues.queued += queued;
+            dump.client_queues.fullest = max(dump.client_queues.fullest, queued);
+            if sender.is_slow_consumer() {
+                dump.client_queues.slow_consumers += 1;
+            }
+        }
+        dump
+    }
+
+    /// Releases capacity that maps kept after a traffic spike. Returns the
+    /// number of maps that were shrunk.
+    fn shrink_maps(&self) -> usize {
+        // One map at a time, to keep locks short.
+        let shrunk = [
+            memory::shrink(&mut self.by_user.write()),
+            memory::shrink(&mut self.by_game.write()),
+            memory::shrink(&mut self.by_chapter.write()),
+            memory::shrink(&mut self.by_topic.write()),
+            memory::shrink(&mut self.by_sri.write()),
+            memory::shrink(&mut self.room_versions.write()),
+            memory::shrink(&mut self.by_id.write()),
+            memory::shrink(&mut self.senders.write()),
+            memory::shrink(&mut self.lags.write()),
+            memory::shrink(&mut self.rtts.write()),
+            memory::shrink(&mut self.user_rooms.write()),
+            memory::shrink(&mut self.last_notified.lock()),
+        ].iter().filter(|shrunk| **shrunk).count();
+        if shrunk > 0 {
+            memory::trim();
+        }
+        shrunk
+    }
+
+    fn is_overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    fn server_load(&self) -> ServerLoad {
+        ServerLoad {
+            connections: max(0, self.connection_count.load(Ordering::Relaxed)) as u32,
+            load: if self.is_overloaded() {
+                LoadLevel::Overloaded
+            } else if self.queue_fill() >= OVERLOAD_LEAVE {
+                LoadLevel::Busy
+            } else {
+                LoadLevel::Normal
+            },
+        }
+    }
+
+    fn broadcast_counts(&self) {
+        if self.is_overloaded() {
+            return;
+        }
+        let msg = Broadcast::same(SocketIn::Counts(self.counts()).to_json_string());
+        for sender in self.watching_counts.snapshot().iter() {
+            if let Err(err) = sender.send_broadcast(Priority::Low, &msg) {
+                log::error!("failed to send counts: {:?}", err);
+            }
+        }
+    }
+
+    /// Tells all clients about a deploy of lila, each with a different
+    /// suggested reconnect delay.
+    fn broadcast_deploy(&self, post: bool) {
+        let jitter = RandomState::new();
+        let window = DEPLOY_RECONNECT_WINDOW.as_millis() as u64;
+        for (socket_id, user_socket) in self.by_id.read().iter() {
+            let reconnect_in = jitter.hash_one(socket_id) % window;
+            let msg = if post {
+                SocketIn::DeployPost { reconnect_in }
+            } else {
+                SocketIn::DeployPre { reconnect_in }
+            };
+            if let Err(err) = user_socket.sender.send_with(Priority::Normal, msg.to_json_string()) {
+                log::error!("failed to announce deploy: {:?}", err);
+            }
+        }
+    }
+
+    /// Closes all connections, so that clients reconnect to another
+    /// instance right away, and waits a little while for the close
+    /// handshakes to complete.
+    async fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        for sender in self.senders.read().values() {
+            if let Err(err) = sender.close(CloseCode::Away) {
+                log::debug!("failed to close connection for shutdown: {:?}", err);
+            }
+        }
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while self.connection_count.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Marks users as away if all of their clients have been inactive.
+    fn detect_away(&self) {
+        let newly_away: Vec<UserId> = {
+            let away_since = now_ms().saturating_sub(AWAY_TIMEOUT.as_millis() as u64);
+            let by_user = self.by_user.read();
+            let mut away = self.away.write();
+            by_user.iter()
+                .filter(|(_, senders)| senders.iter().all(|sender| sender.last_activity_ms() <= away_since))
+                .filter(|(uid, _)| away.insert((*uid).clone()))
+                .map(|(uid, _)| uid.clone())
+                .collect()
+        };
+        for uid in &newly_away {
+            self.publish(LilaIn::Away(uid));
+        }
+    }
+
+    /// Unsubscribes all watchers of games that finished a while ago.
+    fn cleanup_finished_games(&self) {
+        let mut due: Vec<GameId> = Vec::new();
+        {
+            let mut finished_games = self.finished_games.lock();
+            while let Some((finished_at, _)) = finished_games.front() {
+                if finished_at.elapsed() < FINISHED_GAME_RETENTION {
+                    break;
+                }
+                due.push(finished_games.pop_front().expect("finished game").1);
+            }
+        }
+        if due.is_empty() {
+            return;
+        }
+
+        // Watchers forget these games on their next startWatching.
+        let unwatched: Vec<&GameId> = {
+            let mut by_game = self.by_game.write();
+            due.iter().filter(|game| by_game.remove(*game).is_some()).collect()
+        };
+        {
+            let mut watched_games = self.watched_games.write();
+            for game in &due {
+                watched_games.remove(game);
+            }
+        }
+        for game in unwatched {
+            log::debug!("unwatching finished game {:?}", game);
+            self.publish(LilaIn::Unwatch(game));
+        }
+    }
+
+    /// Connected users with their number of connections.
+    fn presence(&self) -> Vec<(UserId, usize)> {
+        self.by_user.read().iter().map(|(uid, senders)| (uid.clone(), senders.len())).collect()
+    }
+
+    /// Stores all connected users for lila, as `thibault,neio` or with
+    /// connection counts as `thibault:2,neio:1`, and tells lila once the
+    /// snapshot is ready.
+    fn dump_presence(&self, counts: bool) {
+        let bus = match self.bus {
+            Some(bus) => bus,
+            None => return,
+        };
+        let presence = self.presence();
+        let snapshot = presence.iter().map(|(uid, n)| {
+            if counts { format!("{}:{}", uid, n) } else { uid.to_string() }
+        }).collect::<Vec<_>>().join(",");
+        match bus.store_presence(&snapshot) {
+            Ok(()) => {
+                log::info!("stored presence snapshot of {} users", presence.len());
+                self.publish(LilaIn::PresenceSnapshot(presence.len()));
+            }
+            Err(err) => log::error!("failed to store presence snapshot: {}", err),
+        }
+    }
+
+    /// Starts or stops mirroring messages of the connections of a user or
+    /// sri to the log. Returns the number of affected connections that
+    /// are currently open.
+    fn set_echo(&self, target: DebugTarget, enabled: bool) -> usize {
+        self.debug.set_echo(target.clone(), enabled);
+        let senders = match target {
+            DebugTarget::User(ref uid) => self.by_user.read().get(uid).cloned(),
+            DebugTarget::Sri(ref sri) => self.by_sri.read().get(sri).cloned(),
+        }.unwrap_or_default();
+        for sender in &senders {
+            sender.set_echo(enabled);
+        }
+        log::info!("echo {} for {:?} ({} connections)", if enabled { "enabled" } else { "disabled" }, target, senders.len());
+        senders.len()
+    }
+
+    /// Starts or stops verbose tracing of the auth flow, subscription
+    /// changes and fanout decisions for all connections of a user.
+    fn set_trace(&self, uid: UserId, duration: Duration) {
+        let until_ms = self.debug.set_trace(DebugTarget::User(uid.clone()), duration);
+        let senders = self.by_user.read().get(&uid).cloned().unwrap_or_default();
+        for sender in &senders {
+            sender.health.trace.set_until(until_ms);
+        }
+        log::info!("tracing {} for {:?} ({} connections)", uid, duration, senders.len());
+    }
+
+    /// Tells lila again about all games with watchers, in case it lost
+    /// track of them.
+    fn republish_watches(&self) {
+        // Publishing may block, so do not hold the lock meanwhile.
+        let games: Vec<GameId> = self.by_game.read().iter()
+            .filter(|(_, watchers)| !watchers.is_empty())
+            .map(|(game, _)| game.clone())
+            .collect();
+        for game in &games {
+            self.publish(LilaIn::Watch(game));
+        }
+
+        // Unwatches in the meantime may have been overtaken. Repeat them.
+        let unwatched: Vec<&GameId> = {
+            let by_game = self.by_game.read();
+            games.iter().filter(|game| !by_game.contains_key(*game)).collect()
+        };
+        for game in &unwatched {
+            self.publish(LilaIn::Unwatch(game));
+        }
+        log::info!("republished {} watched games ({} unwatched meanwhile)", games.len(), unwatched.len());
+    }
+
+    fn publish<'a>(&self, msg: LilaIn<'a>) {
+        self.publish_deferred(iter::once(self.defer(msg)));
+    }
+
+    /// Formats a message for lila, to publish it only after releasing
+    /// locks, because publishing may block.
+    fn defer<'a>(&self, msg: LilaIn<'a>) -> Deferred {
+        Deferred {
+            low_priority: msg.is_low_priority(),
+            retried: msg.is_retried(),
+            msg: msg.to_string_in(self.dialect),
+        }
+    }
+
+    fn publish_deferred(&self, outbox: impl IntoIterator<Item = Deferred>) {
+        for deferred in outbox {
+            if deferred.low_priority {
+                self.redis_sink.send_low(deferred.msg);
+            } else {
+                self.redis_sink.send_high(deferred.retried, deferred.msg);
+            }
+        }
+    }
+
+    fn received(&self, msg: LilaOut) {
+        match msg {
+            LilaOut::Finish { game, winner } => {
+                if let Some(state) = self.watched_games.write().get_mut(&game) {
+                    state.finished = true;
+                    state.win = winner.map(|c| c.char());
+                }
+
+                if let Some(entry) = self.by_game.read().get(&game) {
+                    let msg = SocketIn::Finish {
+                        id: &game,
+                        win: winner.map(|c| c.char()),
+                    };
+                    let msg = Broadcast::new(|shape| msg.to_json_string_in(shape));
+
+                    let senders = self.senders.read();
+                    for sender in entry.iter().filter_map(|id| senders.get(id)) {
+                        if let Err(err) = sender.send_broadcast(Priority::Normal, &msg) {
+                            log::error!("failed to send finish: {:?}", err);
+                        }
+                    }
+                }
+                self.finished_games.lock().push_back((Instant::now(), game));
+            }
+            LilaOut::TellUsers { users, payload } => {
+                let by_id = self.by_id.read();
+                let by_user = self.by_user.read();
+                for user in users {
+                    if let Some(entry) = by_user.get(&user) {
+                        // Of all tabs in single tab mode, only the most
+                        // recently active one gets the message.
+                        let single_tab = entry.iter()
+                            .filter_map(|s| by_id.get(&s.token()))
+                            .filter(|s| s.single_tab)
+                            .max_by_key(|s| s.sender.last_activity_ms())
+                            .map(|s| s.sender.token());
+
+                        for sender in entry {
+                            let is_single_tab = by_id.get(&sender.token()).is_some_and(|s| s.single_tab);
+                            if is_single_tab && single_tab != Some(sender.token()) {
+                                sender.trace(format_args!("skipped message to {}: other tab active", user));
+                                continue;
+                            }
+                            if let Err(err) = sender.send(payload) {
+                                log::error!("failed to tell {}: {:?}", user, err);
+                            }
+                        }
+                    }
+                }
+            }
+            LilaOut::TellAll { payload } => {
+                let msg = Broadcast::same(payload);
+                for user_socket in self.by_id.read().values() {
+                    if let Err(err) = user_socket.sender.send_broadcast(Priority::Normal, &msg) {
+                        log::error!("failed to broadcast: {:?}", err);
+                    }
+                }
+            }
+            LilaOut::TellAnon { payload } => {
+                let msg = Broadcast::same(payload);
+                for user_socket in self.by_id.read().values() {
+                    if let SocketAuth::Anonymous = user_socket.auth {
+                        if let Err(err) = user_socket.sender.send_broadcast(Priority::Normal, &msg) {
+                            log::error!("failed to broadcast to anon: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::TellAuth { payload } => {
+                let msg = Broadcast::same(payload);
+                for user_socket in self.by_id.read().values() {
+                    if let SocketAuth::Authenticated(_) = user_socket.auth {
+                        if let Err(err) = user_socket.sender.send_broadcast(Priority::Normal, &msg) {
+                            log::error!("failed to broadcast to auth: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::Move { game, fen, last_uci, meta } => {
+                self.watched_games.write().insert(game.clone(), WatchedGame {
+                    fen: fen.to_owned(),
+                    lm: last_uci.to_owned(),
+                    meta,
+                    finished: false,
+                    win: None,
+                });
+
+                let by_game = self.by_game.read();
+                if let Some(entry) = by_game.get(&game) {
+                    let msg = Broadcast::new(|shape| fen_json(&game, fen, last_uci, meta.as_ref(), shape));
+
+                    let senders = self.senders.read();
+                    for sender in entry.iter().filter_map(|id| senders.get(id)) {
+                        if let Err(err) = sender.send_broadcast(Priority::Normal, &msg) {
+                            log::error!("failed to send fen: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::MoveLatency(mlat) => {
+                // Respond with our stats (connection count).
+                self.publish(LilaIn::Connections(
+                    max(0, self.connection_count.load(Ordering::Relaxed)) as u32
+                ));
+                self.publish(LilaIn::AnonConnections(
+                    self.by_id.read().values().filter(|s| matches!(s.auth, SocketAuth::Anonymous)).count() as u32
+                ));
+                self.publish(LilaIn::Visitors(self.visitors.lock().counts()));
+                // Publish lag percentiles of users with new samples.
+                let lags: Vec<_> = self.lags.write().iter_mut()
+                    .filter_map(|(uid, window)| window.report().map(|lag| (uid.clone(), lag)))
+                    .collect();
+                self.publish(LilaIn::Lags(&lags));
+                // Round trip times as measured by us, which unlike lags
+                // reported by clients can not be faked.
+                let rtts: Vec<_> = self.rtts.write().iter_mut()
+                    .filter_map(|(uid, window)| window.report().map(|rtt| (uid.clone(), rtt)))
+                    .collect();
+                if !rtts.is_empty() {
+                    self.publish(LilaIn::RoundTrips(&rtts));
+                }
+                self.detect_away();
+                if self.report_top_games > 0 {
+                    self.publish(LilaIn::TopGames(&self.top_games(self.report_top_games)));
+                }
+
+                // Report dropped statistics.
+                let dropped = self.redis_sink.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    log::warn!("dropped {} low priority messages to lila", dropped);
+                }
+                let dropped = self.online_dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    log::warn!("dropped {} online queries over budget", dropped);
+                }
+                let deferred = self.session_deferred.swap(0, Ordering::Relaxed);
+                if deferred > 0 {
+                    log::warn!("deferred {} session lookups, queue full", deferred);
+                }
+
+                // Update stats.
+                self.mlat.store(mlat, Ordering::Relaxed);
+                self.penalties.cleanup();
+                self.cleanup_finished_games();
+                let mut mlat_history = self.mlat_history.lock();
+                if mlat_history.len() >= MLAT_HISTORY_SIZE {
+                    mlat_history.pop_front();
+                }
+                mlat_history.push_back(mlat);
+                drop(mlat_history);
+
+                // Update watching clients, unless shedding load.
+                if self.is_overloaded() {
+                    return;
+                }
+                let mlat_msg = Broadcast::new(|shape| SocketIn::MoveLatency(mlat).to_json_string_in(shape));
+                let load_msg = Broadcast::same(SocketIn::ServerLoad(self.server_load()).to_json_string());
+                for sender in self.watching_mlat.snapshot().iter() {
+                    if let Err(err) = sender.send_broadcast(Priority::Low, &mlat_msg) {
+                        log::error!("failed to send mlat: {:?}", err);
+                    }
+                    if let Err(err) = sender.send_broadcast(Priority::Low, &load_msg) {
+                        log::error!("failed to send server load: {:?}", err);
+                    }
+                }
+            }
+            LilaOut::Counts(counts) => {
+                *self.lila_counts.write() = counts;
+            }
+            LilaOut::DeployPre => {
+                log::info!("lila deploy announced");
+                self.broadcast_deploy(false);
+            }
+            LilaOut::DeployPost => {
+                log::info!("lila deploy finished");
+                self.broadcast_deploy(true);
+            }
+            LilaOut::Boot => {
+                log::info!("lila booted");
+                self.republish_watches();
+            }
+            LilaOut::OnlineQuery { id, users } => {
+                {
+                    let now = std::time::Instant::now();
+                    let mut budget = self.online_budget.lock();
+                    if budget.remaining(now) < users.len() as u32 {
+                        self.online_dropped.fetch_add(1, Ordering::Relaxed);
+                        return;
+                    }
+                    budget.spend(users.len() as u32, now);
+                }
+
+                let online: Vec<&UserId> = {
+                    let by_user = self.by_user.read();
+                    users.iter().filter(|uid| by_user.contains_key(uid)).collect()
+                };
+                self.publish(LilaIn::Online(id, &online));
+            }
+            LilaOut::PresenceDump { counts } => {
+                self.dump_presence(counts);
+            }
+            LilaOut::TraceUser { uid, seconds } => {
+                self.set_trace(uid, seconds.map_or(self.trace_duration, Duration::from_secs));
+            }
+            LilaOut::Unknown { tag, args } => {
+                if self.unknown_messages.record(tag) {
+                    log::warn!("unknown message type from lila: {}", tag);
+                }
+                if let Some(ref handler) = self.unknown_handler {
+                    handler(tag, args);
+                }
+            }
+            LilaOut::TellFlag { flag, payload } => {
+                {
+                    let mut last = self.last_flag_message[flag as usize].lock();
+                    if let Some((at, ref last_payload)) = *last {
+                        if last_payload == payload && at.elapsed() < FLAG_DEBOUNCE {
+                            log::debug!("skipping duplicate message to flag ({:?})", flag);
+                            return;
+                        }
+                    }
+                    *last = Some((Instant::now(), payload.to_owned()));
+                }
+
+                let msg = Broadcast::same(payload);
+                for sender in self.flags[flag as usize].snapshot().iter() {
+                    if let Err(err) = sender.send_broadcast(Priority::Normal, &msg) {
+                        log::error!("failed to send to flag ({:?}): {:?}", flag, err);
+                    }
+                }
+            }
+            LilaOut::TellSri { sri, payload } => {
+                if let Some(entry) = self.by_sri.read().get(&sri) {
+                    for sender in entry {
+                        if let Err(err) = sender.send(payload) {
+                            log::error!("failed to send to sri: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::TellSris { sris, payload } => {
+                let by_sri = self.by_sri.read();
+                for sender in sris.iter().filter_map(|sri| by_sri.get(sri)).flatten() {
+                    if let Err(err) = sender.send(payload) {
+                        log::error!("failed to send to sri: {:?}", err);
+                    }
+                }
+            }
+            LilaOut::TellGame { game, payload } => {
+                if let Some(entry) = self.by_game.read().get(&game) {
+                    let msg = Broadcast::same(payload);
+                    let senders = self.senders.read();
+                    for sender in entry.iter().filter_map(|id| senders.get(id)) {
+                        if let Err(err) = sender.send_broadcast(Priority::Normal, &msg) {
+                            log::error!("failed to send to game watcher: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::TellRole { role, payload } => {
+                let msg = Broadcast::same(payload);
+                let by_user = self.by_user.read();
+                for uid in self.roles[role as usize].read().iter() {
+                    for sender in by_user.get(uid).into_iter().flatten() {
+                        if let Err(err) = sender.send_broadcast(Priority::Critical, &msg) {
+                            log::error!("failed to send to role ({:?}): {:?}", role, err);
+                        }
+                    }
+                }
+            }
+            LilaOut::UserRoles { uid, roles } => {
+                // Ignore roles of users that are no longer connected.
+                let by_user = self.by_user.read();
+                if let Some(senders) = by_user.get(&uid) {
+                    for role in Role::ALL.iter() {
+                        let mut users = self.roles[*role as usize].write();
+                        if roles.contains(role) {
+                            users.insert(uid.clone());
+                        } else {
+                            users.remove(&uid);
+                        }
+                    }
+                    let troll = roles.contains(&Role::Troll);
+                    for sender in senders {
+                        sender.set_troll(troll);
+                    }
+                }
+            }
+            LilaOut::TellRoom { room, payload } => {
+                self.tell(&Topic::Room(room), payload);
+            }
+            LilaOut::TellRooms { rooms, payload } => {
+                for room in rooms {
+                    self.tell(&Topic::Room(room), payload);
+                }
+            }
+            LilaOut::TellVersion { room, version, troll, payload } => {
+                // Versions are recorded even if no one is in the room, so
+                // that clients joining later know whether they missed
+                // some. Joining sockets check under the by_topic lock.
+                let by_topic = self.by_topic.read();
+                {
+                    let mut room_versions = self.room_versions.write();
+                    let latest = room_versions.entry(room.clone()).or_insert(version);
+                    *latest = max(*latest, version);
+                }
+                if let Some(members) = by_topic.get(&Topic::Room(room)) {
+                    let msg = match ipc::with_version(payload, version) {
+                        Some(msg) => Broadcast::same(msg),
+                        None => {
+                            log::error!("versioned payload is not an object: {}", payload);
+                            return;
+                        }
+                    };
+                    // Messages only for trolls are delivered to trolls. Other
+                    // clients skip the version.
+                    for sender in members.iter().filter(|sender| !troll || sender.is_troll()) {
+                        if let Err(err) = sender.send_broadcast(Priority::Normal, &msg) {
+                            log::error!("failed to send to room member: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::UserRooms { uid, rooms } => {
+                // Ignore rooms of users that are no longer connected.
+                let senders = match self.by_user.read().get(&uid) {
+                    Some(senders) => senders.clone(),
+                    None => return,
+                };
+                self.user_rooms.write().insert(uid, rooms.into_iter().collect());
+                let mut by_id = self.by_id.write();
+                for sender in senders {
+                    if let Some(user_socket) = by_id.get_mut(&sender.token()) {
+                        user_socket.sync_rooms();
+                    }
+                }
+            }
+            LilaOut::TellChapter { chapter, payload } => {
+                if let Some(viewers) = self.by_chapter.read().get(&chapter) {
+                    let msg = Broadcast::same(payload);
+                    for viewer in &viewers.confirmed {
+                        if let Err(err) = viewer.send_broadcast(Priority::Low, &msg) {
+                            log::debug!("failed to send to chapter viewer: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::ChapterViewer { sri, chapter } => {
+                let by_sri = self.by_sri.read();
+                let mut by_chapter = self.by_chapter.write();
+                if let (Some(senders), Some(viewers)) = (by_sri.get(&sri), by_chapter.get_mut(&chapter)) {
+                    for sender in senders {
+                        if viewers.pending.remove(sender) {
+                            viewers.confirmed.insert(sender.clone());
+                        }
+                    }
+                }
+            }
+            LilaOut::TellRelay { relay, payload } => {
+                self.tell(&Topic::Relay(relay), payload);
+            }
+            LilaOut::RelayFens { relay, fens } => {
+                if let Some(viewers) = self.by_topic.read().get(&Topic::Relay(relay)) {
+                    let msg = Broadcast::new(|shape| SocketIn::Fens(&fens).to_json_string_in(shape));
+                    for sender in viewers {
+                        if let Err(err) = sender.send_broadcast(Priority::Normal, &msg) {
+                            log::error!("failed to send relay fens: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::TellSwiss { swiss, payload } => {
+                self.tell(&Topic::Swiss(swiss), payload);
+            }
+            LilaOut::SwissRound { swiss, round, spread } => {
+                if let Some(viewers) = self.by_topic.read().get(&Topic::Swiss(swiss)) {
+                    let n = viewers.len() as u64;
+                    for (i, sender) in viewers.iter().enumerate() {
+                        let delay = (u64::from(spread) * i as u64 / n) as u32;
+                        if let Err(err) = sender.send_with(Priority::Normal, SocketIn::SwissRound { round, delay }.to_json_string()) {
+                            log::error!("failed to send swiss round: {:?}", err);
+                        }
+                    }
+                }
+            }
+            LilaOut::DisconnectUser { uid } => {
+                let senders = {
+                    let by_user = self.by_user.read();
+                    let senders = by_user.get(&uid);
+                    senders.cloned()
+                };
+                if let Some(senders) = senders {
+                    for sender in senders {
+                        if let Err(err) = sender.close(CloseCode::Normal) {
+                            log::error!("failed to disconnect user: {:?}", err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A Websocket client connection.
+struct Socket {
+    app: &'static App,
+    socket_id: SocketId,
+    user: watch::Receiver<Option<UserId>>, // authenticated user, as in by_id
+    rate_limiter: KeyedRateLimiter<IpAddr>,
+    budget: Budget,
+    client_addr: Option<IpAddr>,
+    user_agent: Option<String>,
+    rate_limit_notified: bool,
+    rate_limited: u32, // messages within the current window
+    rate_limited_since: Instant,
+    sender: Sender,
+    watching: FxHashSet<GameId>,
+    chapter: Option<ChapterId>, // study chapter with evals of interest
+    topics: SmallVec<[Topic; 2]>, // at most one of each kind
+    flags: SmallVec<[Flag; 2]>,
+    sri: Option<Sri>,
+    client: ClientInfo,
+    idle_deadline: Instant, // reset by any message
+    user_idle_deadline: Option<Instant>, // reset by messages other than pings
+    geo: GeoInfo,
+    close_reason: Option<CloseReason>, // if closing
+    last_resync: Option<Instant>,
+    signals: Signals,
+    signals_reported: Instant,
+}
+
+/// Uniquely identifies a socket connection over the entire runtime of the
+/// application.
+#[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
+struct SocketId(pub u64);
+
+/// Handle to queue messages for a Websocket client. Cheap to clone.
+#[derive(Clone)]
+struct Sender {
+    socket_id: SocketId,
+    tx: mpsc::Sender<Message>,
+    health: Arc<SenderHealth>,
+}
+
+/// Importance of messages to Websocket clients. When the queue of a client
+/// fills up, less important messages are dropped first.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Priority {
+    /// Statistics, like mlat and online counts.
+    Low,
+    /// Updates for spectators and broadcasts.
+    Normal,
+    /// Responses and messages addressed to the client or its user.
+    Critical,
+}
+
+impl Priority {
+    /// Number of queue slots kept free for more important messages.
+    fn reserved(self) -> usize {
+        match self {
+            Priority::Low => QUEUE_SIZE / 2,
+            Priority::Normal => 2,
+            Priority::Critical => 0,
+        }
+    }
+}
+
+/// Connections are considered stale after this many consecutive failures
+/// to queue a message.
+const MAX_SEND_FAILURES: u32 = 10;
+
+/// Connections are closed as slow consumers when messages are dropped
+/// because of a full queue for this long, without the queue draining below
+/// half in between.
+const SLOW_CONSUMER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Close code for slow consumers, from the range for private use.
+const SLOW_CONSUMER: u16 = 4008;
+
+/// Close code for connections that are alive, but where the user has been
+/// idle for too long, from the range for private use. Clients should not
+/// reconnect until the user is back.
+const USER_IDLE: u16 = 4009;
+
+/// Time to deliver the close frame to a slow consumer, before dropping it
+/// anyway.
+const SLOW_CONSUMER_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shared between all clones of a sender, to detect wedged connections.
+#[derive(Default)]
+struct SenderHealth {
+    failures: AtomicU32,
+    full_since: Mutex<Option<Instant>>, // first dropped message since the queue drained
+    slow: AtomicBool,
+    stale: Notify,
+    stats: SocketStats,
+    background: AtomicBool, // app is in the background, skip low priority messages
+    echo: AtomicBool, // mirror messages to the log
+    troll: AtomicBool, // gets versioned room messages only for trolls
+    v2: AtomicBool, // gets messages in the v2 shape
+    last_activity_ms: AtomicU64, // last message other than a ping, see trace::now_ms()
+    msgpack: AtomicBool, // gets broadcasts encoded as MessagePack
+    trace: TraceFlag,
+}
+
+#[derive(Debug)]
+enum SendError {
+    QueueFull,
+    Closed,
+}
+
+impl Sender {
+    fn new(socket_id: SocketId, tx: mpsc::Sender<Message>) -> Sender {
+        Sender {
+            socket_id,
+            tx,
+            health: Arc::new(SenderHealth {
+                last_activity_ms: AtomicU64::new(now_ms()),
+                ..SenderHealth::default()
+            }),
+        }
+    }
+
+    fn touch(&self) {
+        self.health.last_activity_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn last_activity_ms(&self) -> u64 {
+        self.health.last_activity_ms.load(Ordering::Relaxed)
+    }
+
+    fn send<M: Into<Message>>(&self, msg: M) -> Result<(), SendError> {
+        self.send_with(Priority::Critical, msg)
+    }
+
+    fn send_with<M: Into<Message>>(&self, priority: Priority, msg: M) -> Result<(), SendError> {
+        if priority == Priority::Low && self.health.background.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        // Dropping less important messages to keep slots free is routine on
+        // a busy connection, and does not count as a failed send. But a
+        // queue that does not drain for too long still makes the
+        // connection a slow consumer, even if it only gets messages that
+        // are dropped before it is completely full.
+        let capacity = self.tx.capacity();
+        if capacity > 0 && capacity <= priority.reserved() {
+            self.health.stats.send_failed();
+            self.trace(format_args!("dropped {:?} priority message: reserved", priority));
+            self.queue_full(Instant::now());
+            return Ok(());
+        }
+
+        match self.tx.try_send(msg.into()) {
+            Ok(()) => {
+                if self.health.failures.load(Ordering::Relaxed) != 0 {
+                    self.health.failures.store(0, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.health.stats.send_failed();
+                self.trace(format_args!("dropped {:?} priority message: {:?}", priority, err));
+                if let mpsc::error::TrySendError::Full(_) = err {
+                    self.queue_full(Instant::now());
+                }
+                if self.health.failures.fetch_add(1, Ordering::Relaxed) + 1 == MAX_SEND_FAILURES {
+                    log::warn!("connection {:?} is stale after {} failed sends", self.socket_id, MAX_SEND_FAILURES);
+                    self.health.stale.notify_one();
+                }
+                Err(match err {
+                    mpsc::error::TrySendError::Full(_) => SendError::QueueFull,
+                    mpsc::error::TrySendError::Closed(_) => SendError::Closed,
+                })
+            }
+        }
+    }
+
+    /// Records that a message was dropped because the queue is full, and
+    /// flags the connection as a slow consumer if that has been going on
+    /// for too long.
+    fn queue_full(&self, now: Instant) {
+        let since = *self.health.full_since.lock().get_or_insert(now);
+        if now.duration_since(since) >= SLOW_CONSUMER_TIMEOUT && !self.health.slow.swap(true, Ordering::Relaxed) {
+            log::warn!("connection {:?} is a slow consumer, queue full for {:?}", self.socket_id, now.duration_since(since));
+            self.health.stale.notify_one();
+        }
+    }
+
+    /// Called by the writer whenever the queue is less than half full.
+    fn queue_drained(&self) {
+        let mut full_since = self.health.full_since.lock();
+        if full_since.is_some() {
+            *full_since = None;
+        }
+    }
+
+    /// Whether the connection is considered stale because it is not
+    /// keeping up with its messages.
+    fn is_slow_consumer(&self) -> bool {
+        self.health.slow.load(Ordering::Relaxed)
+    }
+
+    fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    fn stats(&self) -> &SocketStats {
+        &self.health.stats
+    }
+
+    fn set_echo(&self, enabled: bool) {
+        self.health.echo.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Sends the message in the shape and encoding of this client.
+    fn send_broadcast(&self, priority: Priority, msg: &Broadcast) -> Result<(), SendError> {
+        self.send_with(priority, msg.get(self.shape(), self.protocol()))
+    }
+
+    fn set_protocol(&self, protocol: Protocol) {
+        self.health.msgpack.store(protocol == Protocol::Msgpack, Ordering::Relaxed);
+    }
+
+    /// Protocol of the client. Until known, JSON, which the writer
+    /// encodes as needed.
+    fn protocol(&self) -> Protocol {
+        if self.health.msgpack.load(Ordering::Relaxed) { Protocol::Msgpack } else { Protocol::Json }
+    }
+
+    fn set_shape(&self, shape: Shape) {
+        self.health.v2.store(shape == Shape::V2, Ordering::Relaxed);
+    }
+
+    fn shape(&self) -> Shape {
+        if self.health.v2.load(Ordering::Relaxed) { Shape::V2 } else { Shape::Legacy }
+    }
+
+    /// Enables what is selected for a user or sri of the connection.
+    fn debug(&self, debugging: Debugging) {
+        if debugging.echo {
+            self.set_echo(true);
+        }
+        if debugging.trace_until_ms != 0 {
+            self.health.trace.set_until(debugging.trace_until_ms);
+        }
+    }
+
+    fn set_troll(&self, troll: bool) {
+        self.health.troll.store(troll, Ordering::Relaxed);
+    }
+
+    fn is_troll(&self) -> bool {
+        self.health.troll.load(Ordering::Relaxed)
+    }
+
+    /// Logs a decision about the connection if it is traced.
+    fn trace(&self, args: fmt::Arguments<'_>) {
+        if self.health.trace.is_active() {
+            log::info!(target: "trace", "{} {}", self.socket_id.0, args);
+        }
+    }
+
+    /// Logs the message if the connection is selected for debugging.
+    fn echo(&self, direction: &str, msg: &str) {
+        if self.health.echo.load(Ordering::Relaxed) {
+            log::info!(target: "echo", "{} {} {}", self.socket_id.0, direction, echo::truncate(msg));
+        }
+    }
+
+    /// Logs a MessagePack message as JSON if the connection is selected
+    /// for debugging.
+    fn echo_msgpack(&self, direction: &str, msg: &[u8]) {
+        if self.health.echo.load(Ordering::Relaxed) {
+            if let Ok(value) = msgpack::decode(msg) {
+                self.echo(direction, &value.to_string());
+            }
+        }
+    }
+
+    /// Resolves when the connection is considered stale and should be
+    /// dropped.
+    async fn stale(&self) {
+        self.health.stale.notified().await
+    }
+
+    fn close(&self, code: CloseCode) -> Result<(), SendError> {
+        self.send(Message::Close(Some(CloseFrame {
+            code,
+            reason: "".into(),
+        })))
+    }
+
+    fn token(&self) -> SocketId {
+        self.socket_id
+    }
+}
+
+impl PartialEq for Sender {
+    fn eq(&self, other: &Sender) -> bool {
+        self.socket_id == other.socket_id
+    }
+}
+
+impl Eq for Sender {}
+
+impl Hash for Sender {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.socket_id.hash(state);
+    }
+}
+
+/// Parts of the Websocket upgrade request we are interested in.
+struct Handshake {
+    resource: String,
+    headers: HeaderMap,
+}
+
+impl Handshake {
+    fn new(req: &Request) -> Handshake {
+        Handshake {
+            resource: req.uri().to_string(),
+            headers: req.headers().clone(),
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|h| h.to_str().ok())
+    }
+
+    /// API clients authenticate with a bearer token rather than a session
+    /// cookie.
+    fn is_api_client(&self) -> bool {
+        self.header("authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|token| !token.trim().is_empty())
+    }
+
+    /// First supported subprotocol offered by the client, if any.
+    fn protocol(&self) -> Option<Protocol> {
+        self.header("sec-websocket-protocol")
+            .and_then(|h| h.split(',').find_map(|p| Protocol::from_name(p.trim())))
+    }
+
+    /// Client address as reported by the reverse proxy.
+    fn client_addr(&self) -> Option<&str> {
+        if let Some(x_forwarded_for) = self.header("x-forwarded-for") {
+            return x_forwarded_for.split(',').next().map(|p| p.trim());
+        }
+
+        self.header("forwarded")
+            .and_then(|h| h.split(';').find(|p| p.trim().starts_with("for")))
+            .and_then(|p| p.trim().split(',').next())
+            .and_then(|p| p.split_once('=').map(|(_, addr)| addr))
+    }
+}
+
+enum SocketAuth {
+    Requested,
+    Authenticated(UserId),
+    Anonymous,
+}
+
+struct UserSocket {
+    app: &'static App,
+    sender: Sender,
+    auth: SocketAuth,
+    pending_notified: bool,
+    pending_following_onlines: bool,
+    pending_resync: bool,
+    meta: ConnectMeta, // reported to lila for security
+    single_tab: bool, // share messages to the user with other tabs in this mode
+    client: ClientInfo,
+    user_tx: watch::Sender<Option<UserId>>, // to the socket, to skip lookups
+    rooms: SmallVec<[RoomId; 2]>,
+    pending_rooms: SmallVec<[(RoomId, Option<u32>); 2]>, // with last seen version, until membership is known
+}
+
+impl UserSocket {
+    /// Returns messages for lila, to be published after releasing the lock
+    /// on `by_id`.
+    #[must_use]
+    fn set_user(&mut self, maybe_uid: Option<UserId>) -> Outbox {
+        let mut outbox = Outbox::new();
+
+        // Connected.
+        let auth = match maybe_uid {
+            Some(uid) => {
+                self.app.visitors.lock().user(&uid);
+                let mut first = false;
+                self.app.by_user.write()
+                    .entry(uid.clone())
+                    .and_modify(|v| v.push(self.sender.clone()))
+                    .or_insert_with(|| {
+                        first = true;
+                        vec![self.sender.clone()]
+                    });
+
+                if first {
+                    log::debug!("first open: {}", uid);
+                    outbox.push(self.app.defer(LilaIn::Connect(&uid, Some(&self.meta))));
+                }
+
+                self.sender.debug(self.app.debug.get(&DebugTarget::User(uid.clone())));
+                if self.app.roles[Role::Troll as usize].read().contains(&uid) {
+                    self.sender.set_troll(true);
+                }
+                self.sender.trace(format_args!("authenticated as {}", uid));
+
+                SocketAuth::Authenticated(uid)
+            },
+            None => SocketAuth::Anonymous,
+        };
+
+        let previous = mem::replace(&mut self.auth, auth);
+        self.user_tx.send_replace(self.user_id().cloned());
+
+        match previous {
+            // Disconnected.
+            SocketAuth::Authenticated(uid) => {
+                self.pending_rooms.clear();
+                for room in self.rooms.clone() {
+                    self.leave_room(&room);
+                }
+
+                let last = {
+                    let mut by_user = self.app.by_user.write();
+                    let entry = by_user.get_mut(&uid).expect("uid in by_user");
+                    let idx = entry.iter().position(|s| s.token() == self.sender.token()).expect("sender in by_user entry");
+                    entry.swap_remove(idx);
+
+                    // Last remaining connection closed.
+                    let last = entry.is_empty();
+                    if last {
+                        by_user.remove(&uid);
+                        self.app.away.write().remove(&uid);
+                        self.app.last_notified.lock().remove(&uid);
+                        self.app.lags.write().remove(&uid);
+                        self.app.rtts.write().remove(&uid);
+                        self.app.user_rooms.write().remove(&uid);
+                        for users in self.app.roles.iter() {
+                            users.write().remove(&uid);
+                        }
+                    }
+                    last
+                };
+
+                if last {
+                    log::debug!("last close: {}", uid);
+                    self.sender.trace(format_args!("last connection of {} closed", uid));
+                    outbox.push(self.app.defer(LilaIn::Disconnect(&uid)));
+                }
+            },
+            // Authentication request finished.
+            SocketAuth::Requested => {
+                self.sender.trace(format_args!("auth finished (pending notified: {}, following onlines: {})", self.pending_notified, self.pending_following_onlines));
+                if self.pending_notified {
+                    outbox.extend(self.on_notified());
+                }
+
+                if self.pending_following_onlines {
+                    outbox.extend(self.on_following_onlines());
+                }
+
+                if self.pending_resync {
+                    outbox.extend(self.on_resync());
+                }
+
+                self.sync_rooms();
+            },
+            SocketAuth::Anonymous => (),
+        }
+
+        outbox
+    }
+
+    #[must_use]
+    fn on_notified(&mut self) -> Option<Deferred> {
+        self.pending_notified = false;
+        match &self.auth {
+            SocketAuth::Requested => self.pending_notified = true,
+            SocketAuth::Authenticated(uid) => {
+                // Other tabs of the user may already have acknowledged the
+                // same notifications.
+                let mut last_notified = self.app.last_notified.lock();
+                if last_notified.get(uid).is_none_or(|at| at.elapsed() >= NOTIFIED_DEBOUNCE) {
+                    last_notified.insert(uid.clone(), Instant::now());
+                    return Some(self.app.defer(LilaIn::Notified(uid)));
+                }
+                self.sender.trace(format_args!("notified debounced"));
+            }
+            SocketAuth::Anonymous => self.app.warnings.log(Warning::AnonNotified, format_args!("anon notified")),
+        }
+        None
+    }
+
+    #[must_use]
+    fn on_following_onlines(&mut self) -> Option<Deferred> {
+        self.pending_following_onlines = false;
+        match &self.auth {
+            SocketAuth::Requested => self.pending_following_onlines = true,
+            SocketAuth::Authenticated(uid) => return Some(self.app.defer(LilaIn::Friends(uid))),
+            SocketAuth::Anonymous => log::debug!("anon following_onlines"),
+        }
+        None
+    }
+
+    /// Asks lila to send the unread notification count again.
+    #[must_use]
+    fn on_resync(&mut self) -> Option<Deferred> {
+        self.pending_resync = false;
+        match &self.auth {
+            SocketAuth::Requested => self.pending_resync = true,
+            SocketAuth::Authenticated(uid) => return Some(self.app.defer(LilaIn::Resync(uid))),
+            SocketAuth::Anonymous => (),
+        }
+        None
+    }
+
+    /// Subscribes to a team or private channel room, if lila asserted that
+    /// the user is a member. Requests are kept pending until lila pushed the
+    /// rooms of the user. `version` is the last one the client has seen, if
+    /// the room is versioned.
+    fn on_join_room(&mut self, room: RoomId, version: Option<u32>) {
+        if self.rooms.contains(&room) || self.pending_rooms.iter().any(|(r, _)| *r == room) {
+            return;
+        }
+        if self.rooms.len() + self.pending_rooms.len() >= MAX_ROOMS {
+            log::debug!("too many rooms, ignoring {}", room);
+            return;
+        }
+        match self.auth {
+            SocketAuth::Requested => self.pending_rooms.push((room, version)),
+            SocketAuth::Authenticated(_) => {
+                self.pending_rooms.push((room, version));
+                self.sync_rooms();
+            }
+            SocketAuth::Anonymous => log::debug!("anon join room {}", room),
+        }
+    }
+
+    fn on_leave_room(&mut self, room: &RoomId) {
+        self.pending_rooms.retain(|(r, _)| r != room);
+        self.leave_room(room);
+    }
+
+    /// Joins pending rooms and leaves rooms according to the memberships
+    /// lila pushed for the user, if any yet.
+    fn sync_rooms(&mut self) {
+        let (leave, join) = {
+            let user_rooms = self.app.user_rooms.read();
+            let member_of = match self.auth {
+                SocketAuth::Authenticated(ref uid) => match user_rooms.get(uid) {
+                    Some(member_of) => member_of,
+                    None => return,
+                },
+                SocketAuth::Requested => return,
+                SocketAuth::Anonymous => {
+                    self.pending_rooms.clear();
+                    return;
+                }
+            };
+            let leave: SmallVec<[RoomId; 2]> = self.rooms.iter().filter(|r| !member_of.contains(r)).cloned().collect();
+            let join: SmallVec<[(RoomId, Option<u32>); 2]> = mem::take(&mut self.pending_rooms).into_iter().filter(|(r, _)| member_of.contains(r)).collect();
+            (leave, join)
+        };
+        for room in &leave {
+            self.sender.trace(format_args!("left room {} (no longer a member)", room));
+            self.leave_room(room);
+        }
+        if !join.is_empty() {
+            // Versions only change under the by_topic read lock, so the
+            // client either missed a version already, or will receive it.
+            let mut by_topic = self.app.by_topic.write();
+            let room_versions = self.app.room_versions.read();
+            let mut missed = false;
+            for (room, version) in join {
+                self.sender.trace(format_args!("joined room {} (version {:?})", room, version));
+                missed |= version.is_some_and(|v| room_versions.get(&room).is_some_and(|&latest| latest > v));
+                by_topic.entry(Topic::Room(room.clone())).or_default().insert(self.sender.clone());
+                self.rooms.push(room);
+            }
+
+            // Clients that missed versions of a room can not catch up and
+            // need to reload.
+            if missed {
+                if let Err(err) = self.sender.send(SocketIn::Reload.to_json_string()) {
+                    log::error!("failed to send reload: {:?}", err);
+                }
+            }
+        }
+    }
+
+    fn leave_room(&mut self, room: &RoomId) {
+        if let Some(idx) = self.rooms.iter().position(|r| r == room) {
+            let room = self.rooms.swap_remove(idx);
+            self.app.unsubscribe(&Topic::Room(room), &self.sender);
+        }
+    }
+
+    fn user_id(&self) -> Option<&UserId> {
+        match self.auth {
+            SocketAuth::Authenticated(ref uid) => Some(uid),
+            _ => None,
+        }
+    }
+}
+
+impl Socket {
+    fn on_open(&mut self, handshake: &Handshake) {
+        // Update connection count.
+        self.app.connection_count.fetch_add(1, Ordering::Relaxed);
+        self.app.senders.write().insert(self.socket_id, self.sender.clone());
+
+        // Get client address.
+        self.client_addr = handshake.client_addr().and_then(|ip| ip.parse().ok());
+
+        // Get user agent.
+        self.user_agent = handshake.header("user-agent").map(|h| h.to_owned());
+        self.client.mobile = self.user_agent.as_deref().is_some_and(is_mobile_user_agent);
+        self.client.protocol = handshake.protocol().unwrap_or_default();
+        self.sender.set_protocol(self.client.protocol);
+
+        // Get origin of client.
+        if let Some(client_addr) = self.client_addr {
+            self.geo = self.app.geoip.lookup(client_addr);
+            self.app.visitors.lock().ip(&client_addr);
+        }
+        self.app.geo_connections.connected(&self.geo);
+        log::info!(target: "access", "open {} {} {:?} (ua: {:?})",
+                   self.socket_id.0, self.geo, self.client_addr, self.user_agent);
+
+        // Parse session cookie.
+        let maybe_cookie = self.app.session_cookie(handshake).unwrap_or_default();
+
+        // Parse query string.
+        let mut join_room = None;
+        let mut uri = handshake.resource.splitn(2, '?');
+        if let (_, Some(query_string)) = (uri.next().unwrap(), uri.next()) {
+            match serde_urlencoded::from_str::<QueryString>(query_string) {
+                Ok(QueryString { flag, sri, v, room, proto, mobile, batch }) => {
+                    join_room = room.map(|room| (room, v));
+
+                    self.client.version = proto;
+                    self.client.mobile |= mobile;
+                    self.client.shape = Shape::from_version(proto);
+                    self.sender.set_shape(self.client.shape);
+                    self.client.batch = batch || self.client.shape == Shape::V2;
+
+                    // Subscribe to flags.
+                    for flag in flag {
+                        if !self.flags.contains(&flag) {
+                            self.app.flags[flag as usize].insert(self.sender.clone());
+                            self.flags.push(flag);
+                        }
+                    }
+
+                    // Add sri.
+                    if let Some(sri) = sri {
+                        self.sender.debug(self.app.debug.get(&DebugTarget::Sri(sri.clone())));
+                        self.sri = Some(sri.clone());
+                        self.app.by_sri.write()
+                            .entry(sri)
+                            .and_modify(|v| v.push(self.sender.clone()))
+                            .or_insert_with(|| vec![self.sender.clone()]);
+                    }
+                },
+                Err(err) => {
+                    self.app.warnings.log(Warning::InvalidQueryString, format_args!("invalid query string ({:?}): {}", err, query_string));
+                }
+            }
+        }
+
+        // Update by_id.
+        let (user_tx, user) = watch::channel(None);
+        self.user = user;
+        self.app.by_id.write().insert(self.socket_id, UserSocket {
+            app: self.app,
+            auth: if maybe_cookie.is_some() { SocketAuth::Requested } else { SocketAuth::Anonymous },
+            pending_notified: false,
+            pending_following_onlines: false,
+            pending_resync: false,
+            meta: ConnectMeta {
+                ip: self.client_addr,
+                sri: self.sri.clone(),
+                user_agent: self.user_agent.clone(),
+            },
+            single_tab: false,
+            client: self.client,
+            sender: self.sender.clone(),
+            user_tx,
+            rooms: SmallVec::new(),
+            pending_rooms: SmallVec::new(),
+        });
+        if let Some((room, version)) = join_room {
+            self.app.by_id.write().get_mut(&self.socket_id).expect("user socket").on_join_room(room, version);
+        }
+
+        // Request authentication. While the session store is backed up,
+        // the lookup is retried in the background rather than holding up
+        // the event loop. The client waits as not yet authenticated.
+        if let Some(cookie) = maybe_cookie {
+            match self.app.sid_sink.try_send((self.socket_id, cookie)) {
+                Ok(()) => (),
+                Err(channel::TrySendError::Full(req)) => {
+                    self.app.session_deferred.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(retry_session_lookup(self.app, req));
+                }
+                Err(channel::TrySendError::Disconnected(_)) => panic!("auth request"),
+            }
+        }
+
+        // Start idle timeouts.
+        self.idle_deadline = Instant::now() + self.idle_timeout();
+        self.user_idle_deadline = self.app.user_idle_timeout.map(|t| Instant::now() + t);
+    }
+
+    fn on_close(&mut self, reason: CloseReason) {
+        self.report_signals();
+
+        // Update connection count. (Due to relaxed ordering this can
+        // temporarily be less than 0).
+        self.app.connection_count.fetch_sub(1, Ordering::Relaxed);
+        self.app.geo_connections.disconnected(&self.geo);
+        log::info!(target: "access", "close {} {} {:?} ({})", self.socket_id.0, self.geo, self.client_addr, reason);
+        self.sender.trace(format_args!("close ({})", reason));
+
+        // Update by_sri.
+        if let Some(sri) = self.sri.take() {
+            let mut by_sri = self.app.by_sri.write();
+            let senders = by_sri.get_mut(&sri).expect("sri in by_sri");
+            let our_token = self.sender.token();
+            let idx = senders.iter().position(|s| s.token() == our_token).expect("sender in senders");
+            senders.swap_remove(idx);
+            if senders.is_empty() {
+                by_sri.remove(&sri);
+            }
+        }
+
+        // Update by_id.
+        let mut user_socket = self.app.by_id.write().remove(&self.socket_id).expect("user socket");
+        self.app.close_audit.record(self.socket_id.0, reason, user_socket.user_id().cloned(), self.client_addr);
+        let outbox = user_socket.set_user(None);
+        self.app.publish_deferred(outbox);
+
+        // Update by_game.
+        let mut unwatched: SmallVec<[GameId; 4]> = SmallVec::new();
+        {
+            let mut by_game = self.app.by_game.write();
+            for game in self.watching.drain() {
+                // Watchers of finished games may already have been removed.
+                let watchers = match by_game.get_mut(&game) {
+                    Some(watchers) => watchers,
+                    None => continue,
+                };
+                if !watchers.remove(&self.socket_id) {
+                    continue;
+                }
+                if watchers.is_empty() {
+                    by_game.remove(&game);
+                    self.app.watched_games.write().remove(&game);
+                    unwatched.push(game);
+                }
+            }
+        }
+        for game in &unwatched {
+            log::debug!("no more watchers for {:?}", game);
+            self.app.publish(LilaIn::Unwatch(game));
+        }
+
+        self.leave_chapter();
+        for topic in self.topics.drain() {
+            self.app.unsubscribe(&topic, &self.sender);
+        }
+
+        // Unsubscribe from flags.
+        for flag in self.flags.drain() {
+            self.app.flags[flag as usize].remove(&self.sender);
+        }
+
+        // Unsubscribe from statistics.
+        self.app.watching_mlat.remove(&self.sender);
+        self.app.watching_counts.remove(&self.sender);
+
+        self.app.senders.write().remove(&self.socket_id);
+    }
+
+    /// Asks for evals of other viewers of a study chapter. Kept pending
+    /// until lila confirms that the client may see the chapter.
+    fn join_chapter(&mut self, chapter: &ChapterId) {
+        if self.chapter.as_ref() == Some(chapter) {
+            return;
+        }
+        self.leave_chapter();
+        self.app.by_chapter.write().entry(chapter.clone()).or_default().pending.insert(self.sender.clone());
+        self.chapter = Some(chapter.clone());
+    }
+
+    fn leave_chapter(&mut self) {
+        if let Some(chapter) = self.chapter.take() {
+            let mut by_chapter = self.app.by_chapter.write();
+            if let Some(viewers) = by_chapter.get_mut(&chapter) {
+                viewers.remove(&self.sender);
+                if viewers.is_empty() {
+                    by_chapter.remove(&chapter);
+                }
+            }
+        }
+    }
+
+    /// Subscribes to a topic, like the updates of a broadcast. Only one
+    /// topic of each kind at a time.
+    fn join_topic(&mut self, topic: Topic) {
+        if self.topics.contains(&topic) {
+            return;
+        }
+        self.leave_topic(topic.kind());
+        self.app.subscribe(topic.clone(), &self.sender);
+        self.topics.push(topic);
+    }
+
+    fn leave_topic(&mut self, kind: TopicKind) {
+        if let Some(idx) = self.topics.iter().position(|topic| topic.kind() == kind) {
+            let topic = self.topics.swap_remove(idx);
+            self.app.unsubscribe(&topic, &self.sender);
+        }
+    }
+
+    /// Sends the cached state of a game, if any. Returns whether there was
+    /// one.
+    fn send_cached_game(&self, game: &GameId) -> Result<bool, SendError> {
+        if let Some(state) = self.app.watched_games.read().get(game) {
+            self.sender.send(fen_json(game, &state.fen, &state.lm, state.meta.as_ref(), self.client.shape))?;
+
+            if state.finished {
+                self.sender.send(SocketIn::Finish {
+                    id: game,
+                    win: state.win,
+                }.to_json_string_in(self.client.shape))?;
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Sends the current state of all subscriptions again, for clients
+    /// recovering from suspend. Everything but the opaque flag messages is
+    /// sent in a single message, and clients may not ask too often.
+    fn on_resync(&mut self) -> Result<(), SendError> {
+        if self.last_resync.is_some_and(|at| at.elapsed() < RESYNC_INTERVAL) {
+            self.sender.trace(format_args!("resync too soon, ignored"));
+            return Ok(());
+        }
+        self.last_resync = Some(Instant::now());
+        self.sender.trace(format_args!("resync ({} games, flags: {:?})", self.watching.len(), self.flags));
+
+        let mlat = Some(self.app.mlat.load(Ordering::Relaxed))
+            .filter(|_| self.app.watching_mlat.snapshot().contains(&self.sender));
+        let counts = Some(&self.app.watching_counts)
+            .filter(|watching| watching.snapshot().contains(&self.sender))
+            .map(|_| self.app.counts());
+        let msg = {
+            let watched_games = self.app.watched_games.read();
+            let games: Vec<GameState<'_>> = self.watching.iter()
+                .filter_map(|game| watched_games.get(game).map(|state| GameState {
+                    id: game,
+                    fen: &state.fen,
+                    lm: &state.lm,
+                    meta: state.meta,
+                    finished: state.finished,
+                    win: state.win,
+                }))
+                .collect();
+            SocketIn::State { games: &games, mlat, counts }.to_json_string()
+        };
+        self.sender.send(msg)?;
+
+        for &flag in &self.flags {
+            let last = self.app.last_flag_message[flag as usize].lock().as_ref().map(|(_, payload)| payload.clone());
+            if let Some(payload) = last {
+                self.sender.send(payload)?;
+            }
+        }
+
+        let deferred = self.app.by_id.write().get_mut(&self.socket_id).expect("user socket").on_resync();
+        self.app.publish_deferred(deferred);
+        Ok(())
+    }
+
+    /// Relays eval requests and evals to lila. Viewers of a study chapter
+    /// get the evals of others once lila has checked both.
+    fn on_eval(&mut self, msg: &str, meta: EvalMeta) -> Result<(), SendError> {
+        let sri = match self.sri {
+            Some(ref sri) => sri,
+            None => {
+                self.app.warnings.log(Warning::SriRequired, format_args!("sri required for: {}", msg));
+                return self.sender.send(SocketIn::Error {
+                    code: ErrorCode::SriRequired,
+                    reason: "sri required in query string",
+                    retry_in: None,
+                }.to_json_string());
+            }
+        };
+
+        self.app.publish(LilaIn::TellSri(sri, self.user.borrow().as_ref(), msg));
+
+        if let Some(chapter) = meta.ch {
+            self.join_chapter(&chapter);
+        }
+        Ok(())
+    }
+
+    /// Publishes behavioral signals of the current window, if they stand
+    /// out, and starts a new window.
+    fn report_signals(&mut self) {
+        self.signals_reported = Instant::now();
+        if let Some(report) = self.signals.report() {
+            self.sender.trace(format_args!("signals: {:?}", report));
+            self.app.publish(LilaIn::Signals(self.client_addr, self.user.borrow().as_ref(), &report));
+        }
+    }
+
+    /// Notes analysis requests for the current position of a game in
+    /// progress that the client is watching.
+    fn check_analysis(&mut self, fen: &str) {
+        if self.watching.is_empty() {
+            return;
+        }
+        let board = fen.split(' ').next().unwrap_or(fen);
+        let watched_games = self.app.watched_games.read();
+        if self.watching.iter().any(|game| watched_games.get(game).is_some_and(|state| !state.finished && state.fen == board)) {
+            self.signals.analysis_in_game();
+        }
+    }
+
+    /// Forwards a chat line of a spectator to lila, which relays accepted
+    /// lines to the watchers of the game with `tell/game`.
+    fn on_talk(&self, talk: Talk) {
+        if !self.watching.contains(&talk.id) {
+            log::debug!("talk to unwatched game {}", talk.id);
+            return;
+        }
+        let text = talk.text.trim();
+        if text.is_empty() || text.chars().count() > MAX_TALK_LENGTH {
+            log::debug!("invalid talk length ({} bytes)", text.len());
+            return;
+        }
+        match *self.user.borrow() {
+            Some(ref uid) => {
+                self.sender.trace(format_args!("talk to {}", talk.id));
+                self.app.publish(LilaIn::WatcherTalk(&talk.id, uid, text));
+            }
+            None => log::debug!("anonymous talk to {}", talk.id),
+        }
+    }
+
+    fn report_abuse(&self, kind: AbuseKind) {
+        self.app.publish(LilaIn::Abuse(kind, self.client_addr, self.user.borrow().as_ref()));
+    }
+
+    fn on_ping(&self, lag: u32) {
+        if let Some(ref uid) = *self.user.borrow() {
+            self.app.lags.write().entry(uid.clone()).or_default().push(lag);
+        }
+    }
+
+    fn on_round_trip(&self, rtt: u32) {
+        if let Some(ref uid) = *self.user.borrow() {
+            self.app.rtts.write().entry(uid.clone()).or_default().push(rtt);
+        }
+    }
+
+    /// Records activity without taking the lock on `by_id`, and tells lila
+    /// when an away user is back.
+    fn on_activity(&self) {
+        self.sender.touch();
+        // Most users are not away. Check that first with a shared lock.
+        let away = match *self.user.borrow() {
+            Some(ref uid) if self.app.away.read().contains(uid) => Some(uid.clone()),
+            _ => None,
+        };
+        if let Some(uid) = away {
+            if self.app.away.write().remove(&uid) {
+                self.app.publish(LilaIn::Back(&uid));
+            }
+        }
+    }
+
+    fn on_message(&mut self, msg: &str) -> Result<(), SendError> {
+        self.sender.echo("in", msg);
+
+        if self.signals_reported.elapsed() >= SIGNALS_REPORT_INTERVAL {
+            self.report_signals();
+        }
+
+        if let Some(client_addr) = self.client_addr {
+            if let Err(not_until) = self.rate_limiter.check(client_addr) {
+                self.sender.stats().rate_limited();
+                let now = std::time::Instant::now();
+                let retry_in = not_until.wait_time_from(now);
+                self.budget.exhaust(retry_in, now);
+
+                // Escalate for clients that do not back off.
+                if self.rate_limited_since.elapsed() >= RATE_LIMITED_WINDOW {
+                    self.rate_limited = 0;
+                    self.rate_limited_since = Instant::now();
+                }
+                self.rate_limited += 1;
+                if self.rate_limited >= MAX_RATE_LIMITED {
+                    if self.app.penalties.offense(client_addr) {
+                        log::warn!("banning client {} ({}) for repeatedly exceeding rate limit", client_addr, self.geo);
+                        self.report_abuse(AbuseKind::Banned);
+                    } else {
+                        log::info!("closing socket of client {} ({}) for exceeding rate limit", client_addr, self.geo);
+                        self.report_abuse(AbuseKind::RateLimited);
+                    }
+                    return self.close(CloseCode::Policy, CloseReason::RateLimited);
+                }
+
+                // Notify once, so that the client can tell the connection is
+                // still alive and back off. The notice itself does not count
+                // towards the limit.
+                if !mem::replace(&mut self.rate_limit_notified, true) {
+                    self.app.warnings.log(Warning::RateLimited, format_args!("socket of client {} ({}) rate limited", client_addr, self.geo));
+                    return self.sender.send(SocketIn::Error {
+                        code: ErrorCode::RateLimited,
+                        reason: "too many messages, ignoring some",
+                        retry_in: Some(retry_in.as_millis() as u64),
+                    }.to_json_string());
+                }
+                return Ok(()); // ignore message
+            }
+            self.budget.spend(1, std::time::Instant::now());
+        }
+
+        self.idle_deadline = Instant::now() + self.idle_timeout();
+
+        // Fast path for ping.
+        if msg == "null" {
+            return self.sender.send("0");
+        }
+
+        // Limit message size. Only PGN imports may be bigger.
+        if msg.len() > MAX_CLIENT_MESSAGE_SIZE {
+            if msg.len() > MAX_IMPORT_PGN_SIZE || !is_import_pgn(msg) {
+                self.app.warnings.log(Warning::OversizedMessage, format_args!("very long message ({} bytes): {}", msg.len(), echo::truncate(msg)));
+                self.report_abuse(AbuseKind::Oversized);
+                return self.close(CloseCode::Size, CloseReason::Oversized);
+            }
+        } else if msg.len() > 1024 {
+            self.app.warnings.log(Warning::LongMessage, format_args!("long message ({} bytes): {}", msg.len(), msg));
+        }
+
+        let parsed = serde_json::from_str(msg);
+        if !matches!(parsed, Ok(SocketOut::Ping { .. })) {
+            self.on_activity();
+            self.signals.message(std::time::Instant::now());
+            if let Some(timeout) = self.app.user_idle_timeout {
+                self.user_idle_deadline = Some(Instant::now() + timeout);
+            }
+        }
+
+        // Analysis is expensive and can wait while overloaded.
+        if let (true, Some(client_addr)) = (self.app.is_overloaded(), self.client_addr) {
+            if matches!(parsed, Ok(SocketOut::Opening { .. }) | Ok(SocketOut::AnaDests { .. }) | Ok(SocketOut::AnaMove { .. }) | Ok(SocketOut::AnaDrop { .. }) | Ok(SocketOut::AnaLine { .. }) | Ok(SocketOut::ImportPgn { .. }) | Ok(SocketOut::Explorer { .. })) {
+                if self.rate_limiter.check_n(client_addr, OVERLOAD_ANALYSIS_COST).is_err() {
+                    return self.sender.send(SocketIn::Error {
+                        code: ErrorCode::Overloaded,
+                        reason: "server overloaded, try again later",
+                        retry_in: None,
+                    }.to_json_string());
+                }
+                self.budget.spend(OVERLOAD_ANALYSIS_COST, std::time::Instant::now());
+            }
+        }
+
+        match parsed {
+            Ok(SocketOut::Ping { l }) => {
+                match l.map(|lag| self.signals.lag(lag.into())) {
+                    Some(Lag::Valid(lag)) => self.on_ping(lag),
+                    Some(Lag::Suspicious) => {
+                        log::info!("client {:?} ({}) persistently reports impossible lag (ua: {:?})", self.client_addr, self.geo, self.user_agent);
+                        self.report_abuse(AbuseKind::ImpossibleLag);
+                    }
+                    Some(Lag::Ignored) | None => (),
+                }
+                self.sender.send("0")
+            }
+            Ok(SocketOut::RateLimit) => {
+                self.sender.send(SocketIn::RateLimit {
+                    remaining: self.budget.remaining(std::time::Instant::now()),
+                    interval: self.budget.interval().as_millis() as u64,
+                }.to_json_string())
+            }
+            Ok(SocketOut::Notified) => {
+                let deferred = self.app.by_id.write().get_mut(&self.socket_id).expect("user socket").on_notified();
+                self.app.publish_deferred(deferred);
+                Ok(())
+            }
+            Ok(SocketOut::FollowingOnlines) => {
+                let deferred = self.app.by_id.write().get_mut(&self.socket_id).expect("user socket").on_following_onlines();
+                self.app.publish_deferred(deferred);
+                Ok(())
+            }
+            Ok(SocketOut::Resync) => self.on_resync(),
+            Ok(SocketOut::SingleTab { d }) => {
+                self.sender.trace(format_args!("single tab: {}", d));
+                self.app.by_id.write().get_mut(&self.socket_id).expect("user socket").single_tab = d;
+                Ok(())
+            }
+            Ok(SocketOut::Background) => {
+                self.sender.health.background.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            Ok(SocketOut::Foreground) => {
+                self.sender.health.background.store(false, Ordering::Relaxed);
+                Ok(())
+            }
+            Ok(SocketOut::StartWatching { d }) => {
+                // Well-behaved clients never ask for more games than they
+                // may watch at once.
+                if d.len() > MAX_WATCHED_GAMES {
+                    self.app.warnings.log(Warning::ProtocolViolation, format_args!("startWatching with {} ids (ua: {:?})", d.len(), self.user_agent));
+                    self.report_abuse(AbuseKind::TooManyGames);
+                    return self.close(CloseCode::Protocol, CloseReason::Protocol);
+                }
+
+                // Forget finished games that have been cleaned up, even if
+                // others started watching them again since. Finished games
+                // that are not cleaned up yet do not count towards the
+                // limit.
+                let mut active = {
+                    let by_game = self.app.by_game.read();
+                    let socket_id = self.socket_id;
+                    self.watching.retain(|game| by_game.get(game).is_some_and(|watchers| watchers.contains(&socket_id)));
+                    let watched_games = self.app.watched_games.read();
+                    self.watching.iter().filter(|game| !watched_games.get(game).is_some_and(|state| state.finished)).count()
+                };
+
+                let mut accepted = Vec::with_capacity(d.len());
+                let mut too_many = Vec::new();
+                let mut invalid = None;
+                let mut invalid_count = 0;
+                for id in &d {
+                    let game = match id.parse::<GameId>() {
+                        Ok(game) => game,
+                        Err(_) => {
+                            self.sender.trace(format_args!("not watching {:?}: invalid game id", id));
+                            invalid.get_or_insert(id);
+                            invalid_count += 1;
+                            continue;
+                        }
+                    };
+
+                    if active >= MAX_WATCHED_GAMES && !self.watching.contains(&game) {
+                        self.sender.trace(format_args!("not watching {}: too many games", game));
+                        too_many.push(game);
+                        continue;
+                    }
+
+                    if !self.watching.insert(game.clone()) {
+                        let cached = self.app.watched_games.read().get(&game).is_some();
+                        accepted.push(AcceptedGame { id: game, cached });
+                    } else {
+                        active += 1;
+                        self.sender.trace(format_args!("watching {}", game));
+
+                        // If cached, send current game state immediately.
+                        let cached = self.send_cached_game(&game)?;
+
+                        // Subscribe to updates.
+                        let mut first = false;
+                        self.app.by_game.write()
+                            .entry(game.clone())
+                            .and_modify(|v| {
+                                v.insert(self.socket_id);
+                                log::debug!("also watching {:?} ({} watchers)", game, v.len());
+                            })
+                            .or_insert_with(|| {
+                                first = true;
+                                std::iter::once(self.socket_id).collect()
+                            });
+                        if first {
+                            log::debug!("start watching: {:?}", game);
+                            self.app.publish(LilaIn::Watch(&game));
+                        }
+                        accepted.push(AcceptedGame { id: game, cached });
+                    }
+                }
+                self.sender.stats().set_watching(self.watching.len());
+                if self.watching.len() > 20 {
+                    log::info!("client is watching many games: {}", self.watching.len());
+                }
+
+                if let Some(invalid) = invalid {
+                    self.app.warnings.log(Warning::InvalidGameId, format_args!("invalid game id {:?} (ua: {:?})", invalid, self.user_agent));
+                    self.report_abuse(AbuseKind::InvalidGameId);
+                }
+                if !too_many.is_empty() {
+                    log::info!("client is watching too many games (ua: {:?})", self.user_agent);
+                    self.report_abuse(AbuseKind::TooManyGames);
+                    self.sender.send(SocketIn::Error {
+                        code: ErrorCode::TooManyGames,
+                        reason: "watching too many games",
+                        retry_in: None,
+                    }.to_json_string())?;
+                }
+                let rejected: Vec<RejectedGame<'_>> = too_many.iter()
+                    .map(|id| RejectedGame { id, reason: ErrorCode::TooManyGames })
+                    .collect();
+                self.sender.send(SocketIn::Watching {
+                    accepted: &accepted,
+                    rejected: &rejected,
+                    invalid: invalid_count,
+                }.to_json_string())
+            },
+            Ok(SocketOut::MoveLatency { d }) => {
+                self.sender.trace(format_args!("move latency subscription: {}", d));
+                if d {
+                    if self.app.watching_mlat.insert(self.sender.clone()) {
+                        self.sender.send(SocketIn::MoveLatencyHistory(
+                            &self.app.mlat_history.lock()
+                        ).to_json_string_in(self.client.shape))?;
+                        self.sender.send(SocketIn::MoveLatency(
+                            self.app.mlat.load(Ordering::Relaxed)
+                        ).to_json_string_in(self.client.shape))?;
+                        if !self.app.is_overloaded() {
+                            self.sender.send(SocketIn::ServerLoad(
+                                self.app.server_load()
+                            ).to_json_string())?;
+                        }
+                    }
+                } else {
+                    self.app.watching_mlat.remove(&self.sender);
+                }
+                Ok(())
+            },
+            Ok(SocketOut::Counts { d }) => {
+                self.sender.trace(format_args!("counts subscription: {}", d));
+                if d {
+                    if self.app.watching_counts.insert(self.sender.clone()) {
+                        self.sender.send(SocketIn::Counts(self.app.counts()).to_json_string())?;
+                    }
+                } else {
+                    self.app.watching_counts.remove(&self.sender);
+                }
+                Ok(())
+            },
+            Ok(SocketOut::Opening { d }) => {
+                if let Some(response) = d.respond() {
+                    self.sender.send(SocketIn::Opening(response).to_json_string())?;
+                }
+                Ok(())
+            }
+            Ok(SocketOut::AnaDests { d: analysis::DestsRequest::Single(d) }) => {
+                self.check_analysis(d.fen());
+                self.sender.send(match d.respond() {
+                    Ok(res) => SocketIn::Dests(res),
+                    Err(err) => {
+                        self.app.warnings.log(Warning::AnalysisFailure, format_args!("analysis dests failure {:?}: {}", err, msg));
+                        SocketIn::DestsFailure
+                    },
+                }.to_json_string_in(self.client.shape))
+            }
+            Ok(SocketOut::AnaDests { d: analysis::DestsRequest::Batch(batch) }) => {
+                if batch.len() > analysis::MAX_DESTS_BATCH {
+                    log::warn!("dests batch too large ({} positions)", batch.len());
+                    return self.sender.send(SocketIn::DestsFailure.to_json_string_in(self.client.shape));
+                }
+
+                // Each additional position costs a credit.
+                if let (Some(client_addr), Some(extra)) = (self.client_addr, NonZeroU32::new(batch.len().saturating_sub(1) as u32)) {
+                    if self.rate_limiter.check_n(client_addr, extra.get()).is_err() {
+                        return self.sender.send(SocketIn::Error {
+                            code: ErrorCode::RateLimited,
+                            reason: "too many positions, try again later",
+                            retry_in: None,
+                        }.to_json_string());
+                    }
+                    self.budget.spend(extra.get(), std::time::Instant::now());
+                }
+
+                // Large batches would hold up the connection.
+                let sender = self.sender.clone();
+                tokio::task::spawn_blocking(move || {
+                    let res = SocketIn::DestsBatch(analysis::respond_batch(batch)).to_json_string();
+                    if let Err(err) = sender.send(res) {
+                        log::debug!("failed to send dests batch: {:?}", err);
+                    }
+                });
+                Ok(())
+            }
+            Ok(SocketOut::AnaMove { d }) => {
+                let step = analysis::PlayStep::from(d);
+                self.check_analysis(step.fen());
+                self.sender.send(match step.respond() {
+                    Ok(res) => SocketIn::Node(Box::new(res)),
+                    Err(err) => {
+                        self.app.warnings.log(Warning::AnalysisFailure, format_args!("analysis step failure {:?}: {}", err, msg));
+                        SocketIn::StepFailure
+                    }
+                }.to_json_string_in(self.client.shape))
+            }
+            Ok(SocketOut::AnaDrop { d }) => {
+                let step = analysis::PlayStep::from(d);
+                self.check_analysis(step.fen());
+                self.sender.send(match step.respond() {
+                    Ok(res) => SocketIn::Node(Box::new(res)),
+                    Err(err) => {
+                        self.app.warnings.log(Warning::AnalysisFailure, format_args!("analysis step failure {:?}: {}", err, msg));
+                        SocketIn::StepFailure
+                    }
+                }.to_json_string_in(self.client.shape))
+            }
+            Ok(SocketOut::AnaLine { d }) => {
+                self.sender.send(match d.respond() {
+                    Ok(res) => SocketIn::Line(res),
+                    Err(err) => {
+                        self.app.warnings.log(Warning::AnalysisFailure, format_args!("analysis line failure {:?}: {}", err, msg));
+                        SocketIn::StepFailure
+                    }
+                }.to_json_string_in(self.client.shape))
+            }
+            Ok(SocketOut::Explorer { d }) => {
+                let explorer = match self.app.explorer {
+                    Some(ref explorer) => explorer,
+                    None => return self.sender.send(SocketIn::ExplorerFailure { path: d.path() }.to_json_string()),
+                };
+                let sender = self.sender.clone();
+                let (path, db) = (d.path().to_owned(), d.db());
+                let callback: explorer::Callback = Box::new(move |data| {
+                    let msg = match data {
+                        Some(ref data) => SocketIn::Explorer { path: &path, db, data },
+                        None => SocketIn::ExplorerFailure { path: &path },
+                    };
+                    if let Err(err) = sender.send(msg.to_json_string()) {
+                        log::debug!("failed to send explorer response: {:?}", err);
+                    }
+                });
+                if let Err(callback) = explorer.query(&d, callback) {
+                    callback(None);
                 }
+                Ok(())
+            }
+            Ok(SocketOut::ImportPgn { d }) => {
+                // Long games would hold up the connection.
+                let sender = self.sender.clone();
+                tokio::task::spawn_blocking(move || {
+                    let res = match d.respond() {
+                        Ok(res) => SocketIn::ImportedPgn(res),
+                        Err(err) => {
+                            // Pasted games are often broken.
+                            log::debug!("pgn import failure: {:?}", err);
+                            SocketIn::ImportPgnFailure
+                        }
+                    }.to_json_string();
+                    if let Err(err) = sender.send(res) {
+                        log::debug!("failed to send imported pgn: {:?}", err);
+                    }
+                });
+                Ok(())
+            }
+            Ok(SocketOut::EvalGet { d }) | Ok(SocketOut::EvalPut { d }) => self.on_eval(msg, d),
+            Ok(SocketOut::Talk { d }) => {
+                self.on_talk(d);
+                Ok(())
+            }
+            Ok(SocketOut::StartRelay { d }) => {
+                self.join_topic(Topic::Relay(d));
+                Ok(())
+            }
+            Ok(SocketOut::StopRelay) => {
+                self.leave_topic(TopicKind::Relay);
+                Ok(())
+            }
+            Ok(SocketOut::StartSwiss { d }) => {
+                self.join_topic(Topic::Swiss(d));
+                Ok(())
+            }
+            Ok(SocketOut::StopSwiss) => {
+                self.leave_topic(TopicKind::Swiss);
+                Ok(())
+            }
+            Ok(SocketOut::JoinRoom { d, v }) => {
+                self.app.by_id.write().get_mut(&self.socket_id).expect("user socket").on_join_room(d, v);
+                Ok(())
+            }
+            Ok(SocketOut::LeaveRoom { d }) => {
+                self.app.by_id.write().get_mut(&self.socket_id).expect("user socket").on_leave_room(&d);
+                Ok(())
+            }
+            Ok(SocketOut::UnexpectedMessage) => {
+                self.app.warnings.log(Warning::UnexpectedMessage, format_args!("unexpected message (ua: {:?}): {}", self.user_agent, msg));
+                Ok(())
+            }
+            Err(err) => {
+                self.app.warnings.log(Warning::ProtocolViolation, format_args!("protocol violation of client (ua: {:?}): ({:?}): {}", self.user_agent, err, msg));
+                self.close(CloseCode::Protocol, CloseReason::Protocol)
             }
         }
+    }
+
+    fn close(&mut self, code: CloseCode, reason: CloseReason) -> Result<(), SendError> {
+        self.close_reason.get_or_insert(reason);
+        self.sender.close(code)
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        if self.client.mobile { MOBILE_IDLE_TIMEOUT } else { IDLE_TIMEOUT }
+    }
+
+    fn on_timeout(&mut self) -> Result<(), SendError> {
+        log::debug!("closing socket due to timeout");
+        self.idle_deadline = Instant::now() + self.idle_timeout();
+        self.close(CloseCode::Away, CloseReason::IdleTimeout)
+    }
 
-        // Start idle timeout.
-        self.sender.timeout(IDLE_TIMEOUT_MS, IDLE_TIMEOUT_TOKEN)
+    fn on_user_idle(&mut self) -> Result<(), SendError> {
+        log::debug!("closing socket of idle user");
+        self.user_idle_deadline = None;
+        self.close(CloseCode::from(USER_IDLE), CloseReason::UserIdle)
     }
+}
 
-    fn on_close(&mut self, _: CloseCode, _: &str) {
-        // Update connection count. (Due to relaxed ordering this can
-        // temporarily be less than 0).
-        self.app.connection_count.fetch_sub(1, Ordering::Relaxed);
+/// Queues a session lookup once the session store catches up. If the socket
+/// is closed meanwhile, the result is ignored.
+async fn retry_session_lookup(app: &'static App, mut req: (SocketId, SessionCookie)) {
+    loop {
+        time::sleep(SESSION_LOOKUP_RETRY_INTERVAL).await;
+        match app.sid_sink.try_send(req) {
+            Ok(()) => break,
+            Err(channel::TrySendError::Full(rejected)) => req = rejected,
+            Err(channel::TrySendError::Disconnected(_)) => panic!("auth request"),
+        }
+    }
+}
 
-        // Clear timeout.
-        if let Some(timeout) = self.idle_timeout.take() {
-            if let Err(err) = self.sender.cancel(timeout) {
-                log::error!("failed to clear timeout: {:?}", err);
+/// Takes further queued messages for a client that supports batches, to
+/// send them as a single frame with a JSON array. A single message is sent
+/// as it is, unless the client gets the v2 shape. Also returns a message
+/// that can not be batched, like a close frame, if one was taken from the
+/// queue.
+fn batch(first: tungstenite::Utf8Bytes, rx: &mut mpsc::Receiver<Message>, shape: Shape) -> (Message, Option<Message>) {
+    let mut batch: Option<String> = None;
+    let mut held = None;
+    for _ in 1..MAX_BATCH_SIZE {
+        match rx.try_recv() {
+            Ok(Message::Text(next)) => {
+                let batch = batch.get_or_insert_with(|| {
+                    let mut batch = String::with_capacity(2 * (first.len() + next.len()));
+                    batch.push('[');
+                    batch.push_str(first.as_str());
+                    batch
+                });
+                batch.push(',');
+                batch.push_str(next.as_str());
             }
+            Ok(msg) => {
+                held = Some(msg);
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+    match (batch, shape) {
+        (Some(mut batch), _) => {
+            batch.push(']');
+            (Message::text(batch), held)
         }
+        (None, Shape::Legacy) => (Message::Text(first), held),
+        (None, Shape::V2) => (Message::text(format!("[{}]", first.as_str())), held),
+    }
+}
 
-        // Update by_sri.
-        if let Some(sri) = self.sri.take() {
-            let mut by_sri = self.app.by_sri.write();
-            let senders = by_sri.get_mut(&sri).expect("sri in by_sri");
-            let our_token = self.sender.token();
-            let idx = senders.iter().position(|s| s.token() == our_token).expect("sender in senders");
-            senders.swap_remove(idx);
-            if senders.is_empty() {
-                by_sri.remove(&sri);
+/// Like `batch()`, but for clients that get MessagePack. Broadcasts are
+/// queued already encoded, other messages are encoded here, and a batch is
+/// sent as a MessagePack array.
+fn batch_msgpack(first: Message, rx: &mut mpsc::Receiver<Message>, shape: Shape) -> (Message, Option<Message>) {
+    let first = match Protocol::Msgpack.encode(first) {
+        Message::Binary(first) => first,
+        msg => return (msg, None),
+    };
+    let mut len = 1;
+    let mut rest = Vec::new();
+    let mut held = None;
+    for _ in 1..MAX_BATCH_SIZE {
+        match rx.try_recv() {
+            Ok(msg @ (Message::Text(_) | Message::Binary(_))) => match Protocol::Msgpack.encode(msg) {
+                Message::Binary(next) => {
+                    rest.extend_from_slice(&next);
+                    len += 1;
+                }
+                msg => {
+                    held = Some(msg);
+                    break;
+                }
+            },
+            Ok(msg) => {
+                held = Some(msg);
+                break;
             }
+            Err(_) => break,
         }
+    }
+    if len == 1 && shape == Shape::Legacy {
+        return (Message::Binary(first), held);
+    }
+    let mut batch = Vec::with_capacity(5 + first.len() + rest.len());
+    msgpack::encode_array_header(len, &mut batch);
+    batch.extend_from_slice(&first);
+    batch.extend_from_slice(&rest);
+    (Message::binary(batch), held)
+}
 
-        // Update by_id.
-        let mut user_socket = self.app.by_id.write().remove(&self.socket_id).expect("user socket");
-        user_socket.set_user(None);
+/// Performs the Websocket handshake, refusing clients that are not welcome.
+async fn accept(app: &'static App, stream: TcpStream) -> Result<(WebSocketStream<TcpStream>, Handshake), tungstenite::Error> {
+    stream.set_nodelay(true)?;
 
-        // Update by_game.
-        let mut by_game = self.app.by_game.write();
-        let our_token = self.sender.token();
-        for game in self.watching.drain() {
-            let watchers = by_game.get_mut(&game).expect("game in by_game");
-            let idx = watchers.iter().position(|s| s.token() == our_token).expect("sender in watchers");
-            watchers.swap_remove(idx);
-            if watchers.is_empty() {
-                by_game.remove(&game);
-                self.app.watched_games.write().remove(&game);
-                log::debug!("no more watchers for {:?}", game);
-                self.app.publish(LilaIn::Unwatch(&game));
+    let mut handshake = None;
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(MAX_MESSAGE_SIZE))
+        .max_frame_size(Some(MAX_MESSAGE_SIZE));
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, mut res: Response| {
+        let hs = Handshake::new(req);
+        if app.is_overloaded() && !hs.header("cookie").is_some_and(|c| c.contains("lila2=")) {
+            let mut err = ErrorResponse::new(Some("overloaded".to_owned()));
+            *err.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Err(err);
+        }
+        if let Some(ip) = hs.client_addr().and_then(|ip| ip.parse().ok()) {
+            if app.blocklist.contains(ip) {
+                log::debug!("refusing blocklisted client {}", ip);
+                let mut err = ErrorResponse::new(Some("blocked".to_owned()));
+                *err.status_mut() = StatusCode::FORBIDDEN;
+                return Err(err);
             }
+            if app.penalties.is_banned(ip) {
+                let mut err = ErrorResponse::new(Some("banned for exceeding rate limit".to_owned()));
+                *err.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                return Err(err);
+            }
+        }
+        if let Some(protocol) = hs.protocol() {
+            res.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol.name()));
         }
+        if let Err(reason) = app.check_client(&hs) {
+            log::debug!("refusing client: {}", reason);
+            let mut err = ErrorResponse::new(Some(reason.to_owned()));
+            *err.status_mut() = StatusCode::FORBIDDEN;
+            return Err(err);
+        }
+        handshake = Some(hs);
+        Ok(res)
+    };
+    let ws = tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config)).await?;
+    Ok((ws, handshake.expect("handshake")))
+}
+
+/// Drives an accepted connection until it is closed.
+async fn handle_connection(app: &'static App,
+                           mut ws: WebSocketStream<TcpStream>,
+                           handshake: Handshake,
+                           socket_id: SocketId,
+                           rate_limiter: KeyedRateLimiter<IpAddr>,
+                           budget: Budget) -> Result<(), tungstenite::Error> {
+    let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+
+    let mut socket = Socket {
+        app,
+        sender: Sender::new(socket_id, tx),
+        user: watch::channel(None).1, // set during handshake
+        rate_limiter,
+        budget,
+        socket_id,
+        client_addr: None, // set during handshake
+        user_agent: None, // set during handshake
+        rate_limit_notified: false,
+        rate_limited: 0,
+        rate_limited_since: Instant::now(),
+        sri: None, // set during handshake
+        client: ClientInfo::default(), // set during handshake
+        flags: SmallVec::new(), // set during handshake
+        watching: FxHashSet::default(),
+        chapter: None,
+        topics: SmallVec::new(),
+        idle_deadline: Instant::now(), // set during handshake
+        user_idle_deadline: None, // set during handshake
+        geo: GeoInfo::default(), // set during handshake
+        close_reason: None,
+        last_resync: None,
+        signals: Signals::default(),
+        signals_reported: Instant::now(),
+    };
+
+    socket.on_open(&handshake);
+
+    let sender = socket.sender.clone();
 
-        // Unsubscribe from flag.
-        if let Some(flag) = self.flag.take() {
-            self.app.flags[flag as usize].write().remove(&self.sender);
+    let mut rtt_probe = time::interval_at(Instant::now() + app.rtt_probe_interval, app.rtt_probe_interval);
+    let mut pending_probe: Option<(u64, Instant)> = None; // payload and time sent
+    let mut probe_seq: u64 = 0;
+
+    let connection = async {
+        'connection: loop {
+            tokio::select! {
+                Some(msg) = rx.recv() => {
+                    let (msg, held) = match msg {
+                        Message::Text(first) if socket.client.batch && socket.client.protocol == Protocol::Json => batch(first, &mut rx, socket.client.shape),
+                        msg @ (Message::Text(_) | Message::Binary(_)) if socket.client.batch => batch_msgpack(msg, &mut rx, socket.client.shape),
+                        msg => (msg, None),
+                    };
+                    for msg in iter::once(msg).chain(held) {
+                        let close = msg.is_close();
+                        if close {
+                            socket.close_reason.get_or_insert(if socket.app.shutting_down.load(Ordering::Relaxed) {
+                                CloseReason::Shutdown
+                            } else {
+                                CloseReason::Kicked
+                            });
+                        }
+                        match msg {
+                            Message::Text(ref text) => sender.echo("out", text.as_str()),
+                            Message::Binary(ref data) => sender.echo_msgpack("out", data),
+                            _ => (),
+                        }
+                        let msg = socket.client.protocol.encode(msg);
+                        sender.stats().sent(msg.len());
+                        ws.send(msg).await?;
+                        if rx.len() < QUEUE_SIZE / 2 {
+                            sender.queue_drained();
+                        }
+                        if close {
+                            break 'connection Ok(());
+                        }
+                    }
+                }
+                incoming = ws.next() => match incoming {
+                    Some(Ok(Message::Text(msg))) => {
+                        sender.stats().received(msg.len());
+                        if let Err(err) = socket.on_message(msg.as_str()) {
+                            log::debug!("failed to respond: {:?}", err);
+                            socket.close_reason.get_or_insert(CloseReason::Stale);
+                            break Ok(());
+                        }
+                    }
+                    Some(Ok(Message::Binary(msg))) if socket.client.protocol == Protocol::Msgpack => {
+                        sender.stats().received(msg.len());
+                        let msg = match msgpack::decode(&msg) {
+                            Ok(value) => value.to_string(),
+                            Err(err) => {
+                                socket.app.warnings.log(Warning::ProtocolViolation, format_args!("{} from client (ua: {:?})", err, socket.user_agent));
+                                socket.close_reason.get_or_insert(CloseReason::Protocol);
+                                break Ok(());
+                            }
+                        };
+                        if let Err(err) = socket.on_message(&msg) {
+                            log::debug!("failed to respond: {:?}", err);
+                            socket.close_reason.get_or_insert(CloseReason::Stale);
+                            break Ok(());
+                        }
+                    }
+                    Some(Ok(Message::Binary(_))) => {
+                        socket.app.warnings.log(Warning::BinaryMessage, format_args!("binary message from client (ua: {:?})", socket.user_agent));
+                        socket.close_reason.get_or_insert(CloseReason::Protocol);
+                        break Ok(());
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        // The close handshake is completed by the protocol layer.
+                        socket.close_reason.get_or_insert(CloseReason::Client(frame.map(|f| f.code.into())));
+                    }
+                    Some(Ok(Message::Pong(payload))) => {
+                        if let Some((seq, sent)) = pending_probe {
+                            if payload[..] == seq.to_be_bytes() {
+                                pending_probe = None;
+                                let rtt = sent.elapsed().as_millis().try_into().unwrap_or(u32::MAX);
+                                socket.on_round_trip(rtt);
+                            }
+                        }
+                    }
+                    Some(Ok(_)) => (), // pings are answered by the protocol layer
+                    Some(Err(err)) => break Err(err),
+                    None => break Ok(()),
+                },
+                _ = rtt_probe.tick() => {
+                    // Probes that are not answered before the next one are
+                    // abandoned.
+                    probe_seq += 1;
+                    pending_probe = Some((probe_seq, Instant::now()));
+                    ws.send(Message::Ping(probe_seq.to_be_bytes().to_vec().into())).await?;
+                }
+                _ = time::sleep_until(socket.idle_deadline) => {
+                    if let Err(err) = socket.on_timeout() {
+                        log::debug!("failed to close idle socket: {:?}", err);
+                        socket.close_reason = Some(CloseReason::Stale);
+                        break Ok(());
+                    }
+                }
+                _ = time::sleep_until(socket.user_idle_deadline.unwrap_or(socket.idle_deadline)), if socket.user_idle_deadline.is_some() => {
+                    if let Err(err) = socket.on_user_idle() {
+                        log::debug!("failed to close socket of idle user: {:?}", err);
+                        socket.close_reason = Some(CloseReason::Stale);
+                        break Ok(());
+                    }
+                }
+            }
         }
-    }
+    };
 
-    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
-        if let Some(client_addr) = self.client_addr {
-            if self.rate_limiter.check(client_addr).is_err() {
-                if !mem::replace(&mut self.rate_limited_once, true) {
-                    log::warn!("socket of client {} rate limited (will log only once)", client_addr);
+    // Stale connections are dropped without waiting for the client, so that
+    // they are removed from all registries. Slow consumers get a close frame
+    // past their full queue, if it can still be delivered.
+    let res = tokio::select! {
+        res = connection => res,
+        _ = sender.stale() => {
+            if sender.is_slow_consumer() {
+                socket.close_reason = Some(CloseReason::SlowConsumer);
+                let frame = CloseFrame {
+                    code: CloseCode::from(SLOW_CONSUMER),
+                    reason: "slow consumer".into(),
+                };
+                if !matches!(time::timeout(SLOW_CONSUMER_CLOSE_TIMEOUT, ws.send(Message::Close(Some(frame)))).await, Ok(Ok(()))) {
+                    log::debug!("failed to deliver close frame to slow consumer {:?}", socket_id);
                 }
-                return Ok(()); // ignore message
+            } else {
+                socket.close_reason = Some(CloseReason::Stale);
             }
+            Ok(())
         }
+    };
 
-        self.sender.timeout(IDLE_TIMEOUT_MS, IDLE_TIMEOUT_TOKEN)?;
+    let reason = socket.close_reason.unwrap_or(if res.is_ok() { CloseReason::Dropped } else { CloseReason::Error });
+    socket.on_close(reason);
+    res
+}
 
-        // Fast path for ping.
-        let msg = msg.as_text()?;
-        if msg == "null" {
-            return self.sender.send(Message::text("0"));
-        }
+/// Clients that connect while the server is full are asked to retry after
+/// this long.
+const FULL_RETRY_AFTER: Duration = Duration::from_secs(10);
 
-        // Limit message size.
-        if msg.len() > 2048 {
-            log::warn!("very long message ({} bytes): {}", msg.len(), msg);
-            return self.sender.close(CloseCode::Size);
-        } else if msg.len() > 1024 {
-            log::info!("long message ({} bytes): {}", msg.len(), msg);
+/// Maximum size of a handshake request.
+const MAX_HANDSHAKE_SIZE: usize = 8192;
+
+/// How long connections in the admission queue wait for a free slot.
+const ADMISSION_WAIT: Duration = Duration::from_secs(2);
+
+/// Time for clients to complete the Websocket handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time for clients to send their request when the server is full.
+const FULL_REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Clients that are told that the server is full at the same time. Further
+/// connections are dropped without a word, so that a reconnect wave can not
+/// pile up rejections.
+const MAX_FULL_REJECTS: usize = 256;
+
+fn full_reason() -> String {
+    format!("server full, retry after {} seconds", FULL_RETRY_AFTER.as_secs())
+}
+
+/// Closes an accepted Websocket connection, because the server is full.
+async fn close_full(mut ws: WebSocketStream<TcpStream>) -> Result<(), tungstenite::Error> {
+    ws.close(Some(CloseFrame {
+        code: CloseCode::Again,
+        reason: full_reason().into(),
+    })).await
+}
+
+/// Tells a client that the server is full and when to retry. Websocket
+/// clients cannot see the HTTP status of a failed handshake, so they get an
+/// immediate close frame instead.
+async fn reject_full(mut stream: TcpStream) -> Result<(), tungstenite::Error> {
+    let mut buf = Vec::with_capacity(1024);
+    let key = loop {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
         }
+        buf.extend_from_slice(&chunk[..n]);
 
-        match serde_json::from_str(msg) {
-            Ok(SocketOut::Ping { l }) => {
-                if let Some(lag) = l {
-                    if let Ok(lag) = lag.try_into() {
-                        self.app.by_id.read().get(&self.socket_id).expect("user socket").on_ping(lag);
-                    } else {
-                        log::warn!("negative lag: {}, user-agent: {:?}", lag, self.user_agent);
-                    }
-                }
-                self.sender.send(Message::text("0"))
-            }
-            Ok(SocketOut::Notified) => {
-                let mut write_guard = self.app.by_id.write();
-                write_guard.get_mut(&self.socket_id)
-                    .expect("user socket")
-                    .on_notified();
-                Ok(())
-            }
-            Ok(SocketOut::FollowingOnlines) => {
-                let mut write_guard = self.app.by_id.write();
-                write_guard.get_mut(&self.socket_id)
-                    .expect("user socket")
-                    .on_following_onlines();
-                Ok(())
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                let is_upgrade = req.headers.iter().any(|h| h.name.eq_ignore_ascii_case("upgrade") && h.value.eq_ignore_ascii_case(b"websocket"));
+                break req.headers.iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("sec-websocket-key"))
+                    .filter(|_| is_upgrade)
+                    .map(|h| derive_accept_key(h.value));
             }
-            Ok(SocketOut::StartWatching { d }) => {
-                for game in d {
-                    if self.watching.insert(game.clone()) {
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HANDSHAKE_SIZE => continue,
+            _ => return Ok(()),
+        }
+    };
 
-                        // If cached, send current game state immediately.
-                        if let Some(state) = self.app.watched_games.read().get(&game) {
-                            self.sender.send(SocketIn::Fen {
-                                id: &game,
-                                fen: &state.fen,
-                                lm: &state.lm,
-                            }.to_json_string())?;
-                        }
+    match key {
+        Some(key) => {
+            let head = format!("HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n\r\n", key);
+            stream.write_all(head.as_bytes()).await?;
+            close_full(WebSocketStream::from_raw_socket(stream, protocol::Role::Server, None).await).await
+        }
+        None => {
+            let reason = full_reason();
+            let head = format!("HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                               FULL_RETRY_AFTER.as_secs(), reason.len());
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(reason.as_bytes()).await?;
+            Ok(stream.shutdown().await?)
+        }
+    }
+}
 
-                        // Subscribe to updates.
-                        self.app.by_game.write()
-                            .entry(game.clone())
-                            .and_modify(|v| {
-                                v.push(self.sender.clone());
-                                log::debug!("also watching {:?} ({} watchers)", game, v.len());
-                            })
-                            .or_insert_with(|| {
-                                log::debug!("start watching: {:?}", game);
-                                self.app.publish(LilaIn::Watch(&game));
-                                vec![self.sender.clone()]
-                            });
-                    }
+/// Accepts Websocket connections until the listener fails.
+async fn serve(app: &'static App, listener: TcpListener, opt: &Opt, rate_limiter: KeyedRateLimiter<IpAddr>) -> io::Result<()> {
+    let connection_limit = Arc::new(Semaphore::new(opt.max_connections));
+    let admission_queue = Arc::new(Semaphore::new(opt.admission_queue));
+    let full_rejects = Arc::new(Semaphore::new(MAX_FULL_REJECTS));
+
+    let mut socket_id = 0;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        socket_id += 1;
+        let socket_id = SocketId(socket_id);
+        let rate_limiter = rate_limiter.clone();
+        let budget = Budget::new(opt.rate_limiter_credits, RATE_LIMITER_INTERVAL);
+
+        // Hard limit for the number of simultaneous connections. Connections
+        // beyond the limit may wait in the admission queue for a little
+        // while, to smooth out reconnect waves. If both are already
+        // exhausted, reject right away.
+        let connection_limit = connection_limit.clone();
+        let admission_queue = admission_queue.clone();
+        let max_connections = opt.max_connections;
+        if connection_limit.available_permits() == 0 && admission_queue.available_permits() == 0 {
+            log::warn!("too many connections ({}), rejecting", max_connections);
+            let permit = match full_rejects.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    log::debug!("too many pending rejections, dropping connection");
+                    continue;
                 }
-                if self.watching.len() > 20 {
-                    log::info!("client is watching many games: {}", self.watching.len());
+            };
+            tokio::spawn(async move {
+                if let Err(err) = time::timeout(FULL_REJECT_TIMEOUT, reject_full(stream)).await {
+                    log::debug!("failed to reject connection: {:?}", err);
                 }
-                Ok(())
-            },
-            Ok(SocketOut::MoveLatency { d }) => {
-                let mut watching_mlat = self.app.watching_mlat.write();
-                if d {
-                    if watching_mlat.insert(self.sender.clone()) {
-                        self.sender.send(SocketIn::MoveLatency(
-                            self.app.mlat.load(Ordering::Relaxed)
-                        ).to_json_string())?;
-                    }
-                } else {
-                    watching_mlat.remove(&self.sender);
+                drop(permit);
+            });
+            continue;
+        }
+
+        tokio::spawn(async move {
+            let (ws, handshake) = match time::timeout(HANDSHAKE_TIMEOUT, accept(app, stream)).await {
+                Ok(Ok(accepted)) => accepted,
+                Ok(Err(err)) => {
+                    log::debug!("handshake failed: {:?}", err);
+                    return;
                 }
-                Ok(())
-            },
-            Ok(SocketOut::Opening { d }) => {
-                if let Some(response) = d.respond() {
-                    self.sender.send(SocketIn::Opening(response).to_json_string())?;
+                Err(_) => {
+                    log::debug!("handshake timed out");
+                    return;
                 }
-                Ok(())
+            };
+
+            // Only established connections take a slot.
+            let permit = match connection_limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => match admission_queue.try_acquire_owned() {
+                    Ok(_queued) => time::timeout(ADMISSION_WAIT, connection_limit.acquire_owned()).await.ok().and_then(Result::ok),
+                    Err(_) => None,
+                },
+            };
+
+            let permit = match permit {
+                Some(permit) => permit,
+                None => {
+                    log::warn!("too many connections ({}), rejecting", max_connections);
+                    if let Err(err) = time::timeout(FULL_REJECT_TIMEOUT, close_full(ws)).await {
+                        log::debug!("failed to reject connection: {:?}", err);
+                    }
+                    return;
+                }
+            };
+
+            if let Err(err) = handle_connection(app, ws, handshake, socket_id, rate_limiter, budget).await {
+                log::debug!("connection error: {:?}", err);
             }
-            Ok(SocketOut::AnaDests { d }) => {
-                self.sender.send(match d.respond() {
-                    Ok(res) => SocketIn::Dests(res),
-                    Err(err) => {
-                        log::warn!("analysis dests failure {:?}: {}", err, msg);
-                        SocketIn::DestsFailure
-                    },
-                }.to_json_string())
+            drop(permit);
+        });
+    }
+}
+
+/// Worker backoff after the first failure.
+const WORKER_MIN_BACKOFF: Duration = Duration::from_millis(100);
+/// Maximum worker backoff after repeated failures.
+const WORKER_MAX_BACKOFF: Duration = Duration::from_secs(10);
+/// Reset backoff if the worker did not fail for this long.
+const WORKER_STABLE: Duration = Duration::from_secs(60);
+
+/// Runs a worker loop forever, restarting it with exponential backoff
+/// whenever it returns an error or panics.
+fn supervise<F, E>(name: &str, mut worker: F)
+where
+    F: FnMut() -> Result<(), E>,
+    E: fmt::Debug,
+{
+    let mut backoff = WORKER_MIN_BACKOFF;
+
+    loop {
+        let started = std::time::Instant::now();
+
+        match panic::catch_unwind(panic::AssertUnwindSafe(&mut worker)) {
+            Ok(Ok(())) => log::error!("{} worker exited", name),
+            Ok(Err(err)) => log::error!("{} worker failed: {:?}", name, err),
+            Err(_) => log::error!("{} worker panicked", name),
+        }
+
+        if started.elapsed() > WORKER_STABLE {
+            backoff = WORKER_MIN_BACKOFF;
+        }
+
+        log::warn!("restarting {} worker in {:?}", name, backoff);
+        thread::sleep(backoff);
+        backoff = min(backoff * 2, WORKER_MAX_BACKOFF);
+    }
+}
+
+/// Creates the shared state and starts worker threads for communication with
+/// lila and the session store.
+fn start(opt: &Opt, bus: &'static dyn LilaBus, session_store: &'static dyn SessionStore) -> (&'static App, KeyedRateLimiter<IpAddr>) {
+    let (redis_sink, redis_recv) = channel::bounded(opt.redis_queue_size);
+    let (redis_low_sink, redis_low_recv) = channel::bounded(opt.redis_queue_size);
+    let (sid_sink, sid_recv) = channel::bounded::<(SocketId, SessionCookie)>(opt.auth_queue_size);
+    let redis_sink = RedisSink::new(redis_sink, redis_low_sink, redis_low_recv.clone());
+    let geoip = GeoIp::open(opt.geoip_country.as_deref(), opt.geoip_asn.as_deref()).expect("open geoip database");
+    let blocklist = Blocklist::open(opt.ip_blocklist.as_deref()).expect("open ip blocklist");
+    let explorer = opt.explorer.clone().map(|endpoint| Explorer::new(endpoint, opt.explorer_cache_size, EXPLORER_QUEUE_SIZE));
+    let mut app = App::new(redis_sink, sid_sink, geoip, blocklist, opt.top_games, opt.game_cache_size, opt.allowed_origins.clone(), explorer);
+    app.bus = Some(bus);
+    app.trace_duration = Duration::from_secs(opt.trace_duration);
+    app.rtt_probe_interval = Duration::from_secs(opt.rtt_probe_interval.max(1));
+    app.user_idle_timeout = Some(Duration::from_secs(opt.user_idle_timeout)).filter(|t| !t.is_zero());
+    app.dialect = opt.lila_protocol;
+    if opt.pass_unknown_messages {
+        app.unknown_handler = Some(Box::new(|tag, args| log::info!("unknown message from lila: {} {}", tag, args.unwrap_or(""))));
+    }
+    let app: &'static App = Box::leak(Box::new(app));
+
+    // Threads for requests to the opening explorer.
+    if let Some(ref explorer) = app.explorer {
+        for _ in 0..opt.explorer_workers {
+            thread::Builder::new().name("explorer".to_owned()).spawn(move || supervise("explorer", || explorer.work())).expect("spawn explorer");
+        }
+    }
+
+    let rate_limiter = KeyedRateLimiter::<IpAddr>::new(
+        NonZeroU32::new(opt.rate_limiter_credits).expect("non-zero credits"),
+        RATE_LIMITER_INTERVAL);
+
+    // Thread for outgoing messages to lila.
+    let mut buffer = PublishBuffer::new(opt.redis_buffer_size);
+    let mut retries = Retries::new(opt.redis_buffer_size, MISSED_RETRY_WINDOW, MISSED_RETRY_INTERVAL);
+    thread::Builder::new().name("redis sink".to_owned()).spawn(move || supervise("redis sink", || {
+        let mut publisher = match bus.publisher() {
+            Ok(publisher) => publisher,
+            Err(err) => {
+                buffer.drain(&redis_recv, &redis_low_recv);
+                return Err(err);
             }
-            Ok(SocketOut::AnaMove { d }) => {
-                self.sender.send(match analysis::PlayStep::from(d).respond() {
-                    Ok(res) => SocketIn::Node(Box::new(res)),
-                    Err(err) => {
-                        log::warn!("analysis step failure {:?}: {}", err, msg);
-                        SocketIn::StepFailure
-                    }
-                }.to_json_string())
+        };
+
+        // Replay messages buffered during outage.
+        if !buffer.queue.is_empty() {
+            log::info!("replaying {} buffered messages", buffer.queue.len());
+        }
+        while let Some((_, msg)) = buffer.queue.front() {
+            publisher.publish(msg)?;
+            buffer.queue.pop_front();
+        }
+
+        loop {
+            // Retry messages that lila missed, if due.
+            let now = std::time::Instant::now();
+            let missed = retries.expire(now);
+            app.redis_sink.missed.fetch_add(missed, Ordering::Relaxed);
+            let retry_at = retries.next_attempt();
+            if retry_at.is_some_and(|at| at <= now) {
+                if let Err(err) = retries.retry(now, |msg| publisher.publish(msg)) {
+                    buffer.drain(&redis_recv, &redis_low_recv);
+                    return Err(err);
+                }
+                continue;
             }
-            Ok(SocketOut::AnaDrop { d }) => {
-                self.sender.send(match analysis::PlayStep::from(d).respond() {
-                    Ok(res) => SocketIn::Node(Box::new(res)),
-                    Err(err) => {
-                        log::warn!("analysis step failure {:?}: {}", err, msg);
-                        SocketIn::StepFailure
+            let retry_tick = retry_at.map_or_else(channel::never, |at| channel::after(at - now));
+
+            // Prefer high priority messages.
+            let (low_priority, retried, msg) = match redis_recv.try_recv() {
+                Ok((retried, msg)) => (false, retried, msg),
+                Err(_) => channel::select! {
+                    recv(redis_recv) -> msg => {
+                        let (retried, msg) = msg.expect("redis recv");
+                        (false, retried, msg)
                     }
-                }.to_json_string())
+                    recv(redis_low_recv) -> msg => (true, false, msg.expect("redis recv")),
+                    recv(retry_tick) -> _ => continue,
+                },
+            };
+            log::trace!("site-in: {}", msg);
+
+            // Do not let important messages overtake those waiting for a
+            // retry.
+            if retried && !retries.is_empty() {
+                let missed = retries.push(msg, std::time::Instant::now());
+                app.redis_sink.missed.fetch_add(missed, Ordering::Relaxed);
+                continue;
             }
-            Ok(SocketOut::EvalGet) | Ok(SocketOut::EvalPut) => {
-                if let Some(ref sri) = self.sri {
-                    let by_id = self.app.by_id.read();
-                    let uid = by_id.get(&self.socket_id).expect("user socket").user_id();
-                    self.app.publish(LilaIn::TellSri(sri, uid, msg));
-                } else {
-                    log::warn!("sri required for: {}", msg);
+
+            match publisher.publish(&msg) {
+                Ok(0) if retried => {
+                    log::warn!("lila missed a message, retrying: {}", msg);
+                    let missed = retries.push(msg, std::time::Instant::now());
+                    app.redis_sink.missed.fetch_add(missed, Ordering::Relaxed);
                 }
-                Ok(())
-            }
-            Ok(SocketOut::UnexpectedMessage) => {
-                if !mem::replace(&mut self.log_ignore, true) {
-                    log::warn!("unexpected message (ua: {:?}): {}", self.user_agent, msg);
+                Ok(0) => log::error!("lila missed a message"),
+                Ok(_) => (),
+                Err(err) => {
+                    buffer.push(low_priority, msg);
+                    buffer.drain(&redis_recv, &redis_low_recv);
+                    return Err(err);
                 }
-                Ok(())
-            }
-            Err(err) => {
-                log::warn!("protocol violation of client (ua: {:?}): ({:?}): {}", self.user_agent, err, msg);
-                self.sender.close(CloseCode::Protocol)
             }
         }
-    }
+    })).expect("spawn redis sink");
+
+    // Thread for session id lookups.
+    thread::Builder::new().name("session lookup".to_owned()).spawn(move || supervise("session lookup", || -> Result<(), BackendError> {
+        loop {
+            let (socket_id, cookie) = match sid_recv.recv_timeout(SESSION_STORE_CHECK_INTERVAL) {
+                Ok(req) => req,
+                Err(channel::RecvTimeoutError::Timeout) => {
+                    // Keep track of the health of the session store while idle.
+                    let res = session_store.check();
+                    if let Err(ref err) = res {
+                        log::error!("session store check failed: {}", err);
+                    }
+                    app.session_store_ok.store(res.is_ok(), Ordering::Relaxed);
+                    continue;
+                }
+                Err(channel::RecvTimeoutError::Disconnected) => panic!("socket id recv"),
+            };
+
+            // Unknown sessions are an answer, too. Only errors count
+            // against the health of the session store.
+            let res = session_store.user_id(&cookie.session_id);
+            app.session_store_ok.store(res.is_ok(), Ordering::Relaxed);
+            let maybe_uid = match res {
+                Ok(Some(uid)) => Some(uid),
+                Ok(None) => {
+                    log::debug!("session store does not have sid: {}", cookie.session_id);
+                    None
+                },
+                Err(err) => {
+                    log::error!("session store query failed: {:?}", err);
+                    None
+                },
+            };
 
-    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> ws::Result<()> {
-        assert_eq!(event, IDLE_TIMEOUT_TOKEN);
-        if let Some(old_timeout) = self.idle_timeout.take() {
-            self.sender.cancel(old_timeout)?;
+            let outbox = match app.by_id.write().get_mut(&socket_id) {
+                Some(user_socket) => user_socket.set_user(maybe_uid),
+                None => continue, // closed meanwhile
+            };
+            app.publish_deferred(outbox);
         }
-        self.idle_timeout = Some(timeout);
-        Ok(())
+    })).expect("spawn session lookup");
+
+    // Thread for incoming messages from lila.
+    let mut rate_limiter_inner = rate_limiter.clone();
+    let mut resubscribing = false;
+    thread::Builder::new().name("redis source".to_owned()).spawn(move || supervise("redis source", || {
+        let mut subscribed = || {
+            app.subscribed.store(true, Ordering::Relaxed);
+
+            // Messages from lila may have been lost in the meantime.
+            if resubscribing {
+                app.republish_watches();
+            }
+            resubscribing = true;
+        };
+        let res = bus.subscribe(&mut subscribed, &mut |msg| {
+            // Decompress once, before fanning out.
+            let msg = match ipc::decompress(msg) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    log::error!("invalid compressed message from lila ({} bytes)", msg.len());
+                    return;
+                }
+            };
+
+            match LilaOut::parse(&msg) {
+                Ok(msg) => {
+                    // Abuse this message as a tick, and stop tracking
+                    // IPs not seen for 60 seconds.
+                    if let LilaOut::MoveLatency(_) = msg {
+                        rate_limiter_inner.cleanup(Duration::from_secs(60));
+                    }
+
+                    app.received(msg);
+                },
+                Err(_) => log::error!("invalid message from lila: {}", msg),
+            }
+        });
+        app.subscribed.store(false, Ordering::Relaxed);
+        res
+    })).expect("spawn redis source");
+
+    (app, rate_limiter)
+}
+
+/// Blocks until messages from lila are received, and then resets connections
+/// and subscriptions from the previous process. Connections should not be
+/// accepted before.
+fn await_lila(app: &App, bus: &dyn LilaBus) {
+    log::info!("waiting for subscription to lila");
+    while !app.subscribed.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(50));
     }
 
-    fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
-        assert_eq!(event, IDLE_TIMEOUT_TOKEN);
-        log::debug!("closing socket due to timeout");
-        self.sender.close(CloseCode::Away)
+    loop {
+        match bus.publisher().and_then(|mut publisher| publisher.publish(&LilaIn::DisconnectAll.to_string())) {
+            Ok(_) => break,
+            Err(err) => {
+                log::error!("failed to reset connections on lila: {}", err);
+                thread::sleep(WORKER_MAX_BACKOFF);
+            }
+        }
     }
 }
 
 fn main() {
     env_logger::init();
 
-    crossbeam::scope(|s| {
-        let opt = Opt::from_args();
+    let opt = Opt::from_args();
 
-        let (redis_sink, redis_recv) = channel::unbounded();
-        let (sid_sink, sid_recv) = channel::unbounded();
-        let app: &'static App = Box::leak(Box::new(App::new(redis_sink, sid_sink)));
+    match opt.cmd {
+        Some(Command::Bench(bench_opt)) => return bench::run(bench_opt),
+        Some(Command::Check) => process::exit(if check::run(&opt) { 0 } else { 1 }),
+        Some(Command::Perft(ref perft_opt)) => process::exit(if perft::run(perft_opt) { 0 } else { 1 }),
+        None => (),
+    }
 
-        let rate_limiter = KeyedRateLimiter::<IpAddr>::new(
-            NonZeroU32::new(opt.rate_limiter_credits).expect("non-zero credits"),
-            Duration::from_secs(10));
+    let bus: &'static RedisBus = Box::leak(Box::new(RedisBus::new(&opt.redis).expect("redis uri")));
+    alert::install(bus);
+    let session_store = MongoSessionStore::new(&opt.mongodb).expect("mongodb uri");
+    let (app, rate_limiter) = start(&opt, bus, Box::leak(Box::new(session_store)));
 
-        // Clear connections and subscriptions from previous process.
-        app.publish(LilaIn::DisconnectAll);
+    // Start websocket server.
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
 
-        // Thread for outgoing messages to lila.
-        let opt_inner = opt.clone();
-        s.builder().name("redis sink".to_owned()).spawn(move |_| {
-            let mut redis = redis::Client::open(opt_inner.redis.as_str())
-                .expect("redis open for publish")
-                .get_connection()
-                .expect("redis connection for publish");
+    runtime.block_on(async {
+        // Watch responsiveness of the event loop from outside.
+        let handle = tokio::runtime::Handle::current();
+        thread::Builder::new().name("watchdog".to_owned()).spawn(move || supervise("watchdog", || app.watchdog.run(&handle))).expect("spawn watchdog");
 
+        // Periodically report metrics.
+        let geoip_enabled = opt.geoip_country.is_some() || opt.geoip_asn.is_some();
+        tokio::spawn(async move {
+            let mut interval = time::interval(METRICS_REPORT_INTERVAL);
             loop {
-                let msg: String = redis_recv.recv().expect("redis recv");
-                log::trace!("site-in: {}", msg);
-                let ret: u32 = redis.publish("site-in", msg).expect("publish site-in");
-                if ret == 0 {
-                    log::error!("lila missed a message");
+                interval.tick().await;
+                log::info!(target: "metrics", "{:?}", app.visitors.lock().counts());
+                log::info!(target: "metrics", "overloaded: {}", app.is_overloaded());
+                log::info!(target: "metrics", "{}", app.close_audit.report());
+                log::info!(target: "metrics", "{}", app.unknown_messages.report());
+                log::info!(target: "metrics", "{}", app.memory_report().summary());
+                log::info!(target: "metrics", "{}", app.watchdog.report());
+                log::info!(target: "metrics", "{}", app.watched_games.read().stats());
+                log::info!(target: "metrics", "invalid session cookies: {}", app.invalid_cookies.swap(0, Ordering::Relaxed));
+                log::info!(target: "metrics", "{}", app.warnings.report());
+                log::info!(target: "metrics", "messages missed by lila: {}", app.redis_sink.missed.swap(0, Ordering::Relaxed));
+                if geoip_enabled {
+                    log::info!(target: "metrics", "{}", app.geo_connections.report(10));
                 }
             }
-        }).unwrap();
-
-        // Thread for session id lookups.
-        let opt_inner = opt.clone();
-        s.builder().name("session lookup".to_owned()).spawn(move |_| {
-            let session_store = mongodb::Client::with_uri(opt_inner.mongodb.as_str())
-                .expect("mongodb connect")
-                .db("lichess")
-                .collection("security");
-
-            loop {
-                let (socket_id, cookie) = sid_recv.recv().expect("socket id recv");
-
-                let query = doc! { "_id": &cookie.session_id, "up": true, };
-                let mut opts = FindOptions::new();
-                opts.projection = Some(doc! { "user": true });
+        });
 
-                let maybe_uid = match session_store.find_one(Some(query), Some(opts)) {
-                    Ok(Some(doc)) => doc.get_str("user").ok().and_then(|s| UserId::new(s).ok()),
-                    Ok(None) => {
-                        log::debug!("session store does not have sid: {}", cookie.session_id);
-                        None
-                    },
-                    Err(err) => {
-                        log::error!("session store query failed: {:?}", err);
-                        None
-                    },
-                };
+        if let Some(ref admin_bind) = opt.admin_bind {
+            let access = admin::Access {
+                allow: opt.admin_allow.iter().map(|cidr| cidr.parse().expect("admin allow cidr")).collect(),
+                token: opt.admin_token.clone(),
+            };
+            let admin_listener = TcpListener::bind(admin_bind).await?;
+            tokio::spawn(async move {
+                if let Err(err) = admin::serve(app, admin_listener, access).await {
+                    log::error!("admin listener failed: {:?}", err);
+                }
+            });
+        }
 
-                let mut write_guard = app.by_id.write();
-                if let Some(user_socket) = write_guard.get_mut(&socket_id) {
-                    user_socket.set_user(maybe_uid);
+        // Reload blocklist on SIGHUP.
+        if app.blocklist.is_enabled() {
+            let mut hangup = signal(SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    match app.blocklist.reload() {
+                        Ok(n) => log::info!("reloaded ip blocklist ({} ranges)", n),
+                        Err(err) => log::error!("failed to reload ip blocklist: {}", err),
+                    }
                 }
+            });
+        }
+
+        // Dump state to the log on SIGUSR1.
+        let mut user_defined1 = signal(SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while user_defined1.recv().await.is_some() {
+                log::info!(target: "state", "{}", serde_json::to_string(&app.state_dump()).expect("serialize state dump"));
             }
-        }).unwrap();
+        });
 
-        // Thread for incoming messages from lila.
-        let opt_inner = opt.clone();
-        let rate_limiter_inner = rate_limiter.clone();
-        s.builder().name("redis source".to_owned()).spawn(move |_| {
-            let mut rate_limiter = rate_limiter_inner;
+        // Wait for lila, while already answering health checks.
+        tokio::task::spawn_blocking(move || await_lila(app, bus)).await.expect("await lila");
 
-            let mut redis = redis::Client::open(opt_inner.redis.as_str())
-                .expect("redis open for subscribe")
-                .get_connection()
-                .expect("redis connection for subscribe");
+        // Watch for overload.
+        tokio::spawn(async move {
+            let mut interval = time::interval(LOAD_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                app.update_load();
+            }
+        });
 
-            let mut incoming = redis.as_pubsub();
-            incoming.subscribe("site-out").expect("subscribe site-out");
+        // Shrink maps after traffic spikes, while not busy.
+        tokio::spawn(async move {
+            let mut interval = time::interval(SHRINK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if app.server_load().load == LoadLevel::Normal {
+                    let shrunk = app.shrink_maps();
+                    if shrunk > 0 {
+                        log::info!("shrunk {} maps", shrunk);
+                    }
+                }
+            }
+        });
 
+        // Periodically broadcast online counts.
+        tokio::spawn(async move {
+            let mut interval = time::interval(COUNTS_BROADCAST_INTERVAL);
             loop {
-                let msg = incoming.get_message()
-                    .expect("get message")
-                    .get_payload::<String>()
-                    .expect("get payload");
-
-                match LilaOut::parse(&msg) {
-                    Ok(msg) => {
-                        // Abuse this message as a tick, and stop tracking
-                        // IPs not seen for 60 seconds.
-                        if let LilaOut::MoveLatency(_) = msg {
-                            rate_limiter.cleanup(Duration::from_secs(60));
-                        }
+                interval.tick().await;
+                app.broadcast_counts();
+            }
+        });
 
-                        app.received(msg);
-                    },
-                    Err(_) => log::error!("invalid message from lila: {}", msg),
-                }
-            }
-        }).unwrap();
-
-        // Start websocket server.
-        let mut settings = ws::Settings::default();
-        settings.max_connections = opt.max_connections;
-        settings.queue_size = 10;
-        settings.tcp_nodelay = true;
-        settings.in_buffer_grow = false;
-
-        let mut socket_id = 0;
-
-        let server = ws::Builder::new()
-            .with_settings(settings)
-            .build(move |sender| {
-                socket_id += 1;
-                Socket {
-                    app,
-                    sender,
-                    rate_limiter: rate_limiter.clone(),
-                    socket_id: SocketId(socket_id),
-                    client_addr: None, // set during handshake
-                    user_agent: None, // set during handshake
-                    rate_limited_once: false,
-                    sri: None, // set during handshake
-                    flag: None, // set during handshake
-                    watching: HashSet::new(),
-                    idle_timeout: None, // set during handshake
-                    log_ignore: false
-                }
-            })
-            .expect("valid settings");
-
-        app.broadcaster.set(server.broadcaster()).expect("set broadcaster");
-
-        server.listen(&opt.bind).expect("ws listen");
-    }).expect("scope");
+        // Stop accepting connections and close the open ones on SIGTERM.
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        let listener = TcpListener::bind(&opt.bind).await?;
+        tokio::select! {
+            res = serve(app, listener, &opt, rate_limiter) => res,
+            _ = terminate.recv() => {
+                log::info!("shutting down, closing {} connections", app.connection_count.load(Ordering::Relaxed));
+                app.shutdown().await;
+                Ok(())
+            }
+        }
+    }).expect("ws listen");
 }
@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::{BuildHasher, Hash};
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+use std::mem;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+use once_cell::sync::Lazy;
+
+use serde::Serialize;
+
+use crate::alloc::{self, HeapStats};
+
+/// Maps are only shrunk if they could hold this many more entries than
+/// they have.
+const MIN_WASTED_CAPACITY: usize = 1024;
+
+/// Entries and capacity of a map.
+#[derive(Serialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MapUsage {
+    pub len: usize,
+    pub capacity: usize,
+}
+
+impl MapUsage {
+    pub fn of<K, V, S>(map: &HashMap<K, V, S>) -> MapUsage {
+        MapUsage {
+            len: map.len(),
+            capacity: map.capacity(),
+        }
+    }
+
+    /// Capacity left behind by a past traffic spike.
+    fn is_wasteful(&self) -> bool {
+        self.capacity > 4 * self.len && self.capacity - self.len > MIN_WASTED_CAPACITY
+    }
+}
+
+/// Shrinks the map if most of its capacity is unused. Returns `true` if
+/// it was shrunk.
+pub fn shrink<K: Eq + Hash, V, S: BuildHasher>(map: &mut HashMap<K, V, S>) -> bool {
+    if MapUsage::of(map).is_wasteful() {
+        map.shrink_to_fit();
+        true
+    } else {
+        false
+    }
+}
+
+/// Statistics of the system allocator.
+#[derive(Serialize, Debug, Copy, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AllocatorStats {
+    /// Bytes obtained from the operating system.
+    pub total: usize,
+    /// Bytes in allocated blocks.
+    pub in_use: usize,
+}
+
+/// Looked up at runtime, because mallinfo2 is only available since glibc
+/// 2.33, and linking it would keep the server from starting on older
+/// systems. There are no stats on those.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+static MALLINFO2: Lazy<Option<extern "C" fn() -> libc::mallinfo2>> = Lazy::new(|| {
+    // SAFETY: The name is nul terminated, and the symbol, if any, is the
+    // function with this signature.
+    unsafe {
+        let sym = libc::dlsym(libc::RTLD_DEFAULT, b"mallinfo2\0".as_ptr().cast());
+        if sym.is_null() {
+            None
+        } else {
+            Some(mem::transmute::<*mut libc::c_void, extern "C" fn() -> libc::mallinfo2>(sym))
+        }
+    }
+});
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    let info = (*MALLINFO2)?();
+    Some(AllocatorStats {
+        total: info.arena + info.hblkhd,
+        in_use: info.uordblks + info.hblkhd,
+    })
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
+
+/// Returns free memory at the top of the heap to the operating system.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub fn trim() {
+    // SAFETY: malloc_trim has no preconditions.
+    unsafe {
+        libc::malloc_trim(0);
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub fn trim() {}
+
+/// Resident set size of this process in bytes, where available.
+pub fn resident_size() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line["VmRSS:".len()..].trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Memory usage of this process, for metrics and the admin interface.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryReport {
+    pub maps: BTreeMap<&'static str, MapUsage>,
+    pub allocator: Option<AllocatorStats>,
+    pub heap: Option<HeapStats>, // with the alloc-stats feature
+    pub resident: Option<u64>,
+}
+
+impl MemoryReport {
+    pub fn new(maps: BTreeMap<&'static str, MapUsage>) -> MemoryReport {
+        MemoryReport {
+            maps,
+            allocator: allocator_stats(),
+            heap: alloc::heap_stats(),
+            resident: resident_size(),
+        }
+    }
+
+    /// Like `memory: by_game=0/0 by_user=12/28 resident=4096 allocated=2048
+    /// inUse=1024 live=1000 peak=1500 allocations=20`, with entries and
+    /// capacity of each map and sizes in bytes.
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = self.maps.iter().map(|(name, usage)| format!("{}={}/{}", name, usage.len, usage.capacity)).collect();
+        if let Some(resident) = self.resident {
+            parts.push(format!("resident={}", resident));
+        }
+        if let Some(allocator) = self.allocator {
+            parts.push(format!("allocated={} inUse={}", allocator.total, allocator.in_use));
+        }
+        if let Some(heap) = self.heap {
+            parts.push(format!("live={} peak={} allocations={}", heap.live, heap.peak, heap.allocations));
+        }
+        format!("memory: {}", parts.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shrink() {
+        let mut map: HashMap<u32, u32> = (0..10_000).map(|i| (i, i)).collect();
+        assert!(!shrink(&mut map));
+
+        map.retain(|&k, _| k < 10);
+        let before = MapUsage::of(&map);
+        assert!(before.is_wasteful());
+        assert!(shrink(&mut map));
+        let after = MapUsage::of(&map);
+        assert_eq!(after.len, 10);
+        assert!(after.capacity < before.capacity);
+        assert!(!shrink(&mut map));
+    }
+
+    #[test]
+    fn test_allocator_stats() {
+        // Without mallinfo2 in the C library, there are just no stats.
+        if let Some(stats) = allocator_stats() {
+            assert!(stats.in_use <= stats.total);
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let report = MemoryReport {
+            maps: vec![("by_user", MapUsage { len: 12, capacity: 28 }), ("by_game", MapUsage { len: 0, capacity: 0 })].into_iter().collect(),
+            allocator: Some(AllocatorStats { total: 2048, in_use: 1024 }),
+            heap: Some(HeapStats { live: 1000, peak: 1500, allocations: 20 }),
+            resident: Some(4096),
+        };
+        assert_eq!(report.summary(), "memory: by_game=0/0 by_user=12/28 resident=4096 allocated=2048 inUse=1024 live=1000 peak=1500 allocations=20");
+
+        #[cfg(target_os = "linux")]
+        assert!(resident_size().is_some_and(|rss| rss > 0));
+    }
+}
@@ -5,57 +5,89 @@ use std::fmt;
 
 use serde::{Deserialize, Serialize, Serializer, Deserializer};
 
-/// An 8 character game id.
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
-pub struct GameId(ArrayString<[u8; 8]>);
+/// Declares an id type of exactly 8 ASCII letters and digits.
+macro_rules! short_id {
+    ($(#[$attr:meta])* $name:ident, $invalid:ident, $what:literal) => {
+        $(#[$attr])*
+        #[derive(Eq, PartialEq, Hash, Clone, Debug)]
+        pub struct $name(ArrayString<[u8; 8]>);
 
-#[derive(Debug)]
-pub struct InvalidGameId;
+        #[derive(Debug)]
+        pub struct $invalid;
 
-impl fmt::Display for InvalidGameId {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("invalid game id")
-    }
-}
+        impl fmt::Display for $invalid {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(concat!("invalid ", $what))
+            }
+        }
 
-impl GameId {
-    pub fn new(inner: ArrayString<[u8; 8]>) -> Result<GameId, InvalidGameId> {
-        if inner.chars().all(|c| c.is_ascii_alphanumeric()) && inner.len() == 8 {
-            Ok(GameId(inner))
-        } else {
-            Err(InvalidGameId)
+        impl $name {
+            pub fn new(inner: ArrayString<[u8; 8]>) -> Result<$name, $invalid> {
+                if inner.chars().all(|c| c.is_ascii_alphanumeric()) && inner.len() == 8 {
+                    Ok($name(inner))
+                } else {
+                    Err($invalid)
+                }
+            }
         }
-    }
-}
 
-impl Serialize for GameId {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        self.0.serialize(serializer)
-    }
-}
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                self.0.serialize(serializer)
+            }
+        }
 
-impl fmt::Display for GameId {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
-    }
-}
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
 
-impl<'de> Deserialize<'de> for GameId {
-    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        let inner = ArrayString::deserialize(deserializer)?;
-        GameId::new(inner).map_err(|_| serde::de::Error::custom("invalid game id"))
-    }
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let inner = ArrayString::deserialize(deserializer)?;
+                $name::new(inner).map_err(|_| serde::de::Error::custom(concat!("invalid ", $what)))
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = $invalid;
+
+            fn from_str(s: &str) -> Result<$name, $invalid> {
+                $name::new(ArrayString::from(s).map_err(|_| $invalid)?)
+            }
+        }
+    };
 }
 
-impl FromStr for GameId {
-    type Err = InvalidGameId;
+short_id!(
+    /// An 8 character game id.
+    GameId, InvalidGameId, "game id"
+);
 
-    fn from_str(s: &str) -> Result<GameId, InvalidGameId> {
-        GameId::new(ArrayString::from(s).map_err(|_| InvalidGameId)?)
+impl GameId {
+    pub fn as_str(&self) -> &str {
+        &self.0
     }
 }
 
-/// Username, normalized to lowercase.
+short_id!(
+    /// An 8 character study chapter id.
+    ChapterId, InvalidChapterId, "chapter id"
+);
+
+short_id!(
+    /// An 8 character broadcast relay id.
+    RelayId, InvalidRelayId, "relay id"
+);
+
+short_id!(
+    /// An 8 character swiss tournament id.
+    SwissId, InvalidSwissId, "swiss id"
+);
+
+/// Username, normalized to lowercase. Between 2 and 30 ASCII letters,
+/// digits, `-` or `_`.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct UserId(String);
 
@@ -64,7 +96,7 @@ pub struct InvalidUserId;
 
 impl UserId {
     pub fn new(inner: &str) -> Result<UserId, InvalidUserId> {
-        if !inner.is_empty() && inner.len() <= 30 &&
+        if (2..=30).contains(&inner.len()) &&
            inner.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
         {
             Ok(UserId(inner.to_lowercase()))
@@ -115,6 +147,12 @@ impl Sri {
     }
 }
 
+impl Serialize for Sri {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
 impl<'de> Deserialize<'de> for Sri {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         let inner = ArrayString::deserialize(deserializer)?;
@@ -136,18 +174,77 @@ impl fmt::Display for Sri {
     }
 }
 
+/// Room of a team or another private channel, like `team:lichess-swiss`.
+/// Between 1 and 64 ASCII letters, digits, `-`, `_` or `:`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct RoomId(String);
+
+#[derive(Debug)]
+pub struct InvalidRoomId;
+
+impl RoomId {
+    pub fn new(inner: &str) -> Result<RoomId, InvalidRoomId> {
+        if (1..=64).contains(&inner.len()) &&
+           inner.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ':')
+        {
+            Ok(RoomId(inner.to_owned()))
+        } else {
+            Err(InvalidRoomId)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RoomId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let inner = String::deserialize(deserializer)?;
+        RoomId::new(&inner).map_err(|_| serde::de::Error::custom("invalid room id"))
+    }
+}
+
+impl FromStr for RoomId {
+    type Err = InvalidRoomId;
+
+    fn from_str(s: &str) -> Result<RoomId, InvalidRoomId> {
+        RoomId::new(s)
+    }
+}
+
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Serialize for RoomId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
 /// Channels for server sent updates.
-#[derive(Deserialize, Debug, Copy, Clone)]
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Flag {
     #[serde(rename = "simul")]
     Simul = 0,
     #[serde(rename = "tournament")]
     Tournament = 1,
+    #[serde(rename = "streamer")]
+    Streamer = 2,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 3] = [Flag::Simul, Flag::Tournament, Flag::Streamer];
 }
 
 #[derive(Debug)]
 pub struct UnknownFlag;
 
+impl fmt::Display for UnknownFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown flag")
+    }
+}
+
 impl FromStr for Flag {
     type Err = UnknownFlag;
 
@@ -155,7 +252,193 @@ impl FromStr for Flag {
         Ok(match s {
             "tournament" => Flag::Tournament,
             "simul" => Flag::Simul,
+            "streamer" => Flag::Streamer,
             _ => return Err(UnknownFlag),
         })
     }
 }
+
+/// Roles of users that lila can address collectively. Trolls also get
+/// the versioned room messages that only they can see.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Role {
+    Mod = 0,
+    Patron = 1,
+    Troll = 2,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Mod, Role::Patron, Role::Troll];
+}
+
+#[derive(Debug)]
+pub struct UnknownRole;
+
+impl FromStr for Role {
+    type Err = UnknownRole;
+
+    fn from_str(s: &str) -> Result<Role, UnknownRole> {
+        Ok(match s {
+            "mod" => Role::Mod,
+            "patron" => Role::Patron,
+            "troll" => Role::Troll,
+            _ => return Err(UnknownRole),
+        })
+    }
+}
+
+/// Variant of a game, as named by lila.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VariantKey {
+    #[serde(rename = "standard")]
+    Standard,
+    #[serde(rename = "fromPosition")]
+    FromPosition,
+    #[serde(rename = "chess960")]
+    Chess960,
+    #[serde(rename = "antichess")]
+    Antichess,
+    #[serde(rename = "kingOfTheHill")]
+    KingOfTheHill,
+    #[serde(rename = "threeCheck")]
+    ThreeCheck,
+    #[serde(rename = "atomic")]
+    Atomic,
+    #[serde(rename = "horde")]
+    Horde,
+    #[serde(rename = "racingKings")]
+    RacingKings,
+    #[serde(rename = "crazyhouse")]
+    Crazyhouse,
+}
+
+#[derive(Debug)]
+pub struct UnknownVariant;
+
+impl fmt::Display for UnknownVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown variant")
+    }
+}
+
+impl VariantKey {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VariantKey::Standard => "standard",
+            VariantKey::FromPosition => "fromPosition",
+            VariantKey::Chess960 => "chess960",
+            VariantKey::Antichess => "antichess",
+            VariantKey::KingOfTheHill => "kingOfTheHill",
+            VariantKey::ThreeCheck => "threeCheck",
+            VariantKey::Atomic => "atomic",
+            VariantKey::Horde => "horde",
+            VariantKey::RacingKings => "racingKings",
+            VariantKey::Crazyhouse => "crazyhouse",
+        }
+    }
+}
+
+impl FromStr for VariantKey {
+    type Err = UnknownVariant;
+
+    fn from_str(s: &str) -> Result<VariantKey, UnknownVariant> {
+        Ok(match s {
+            "standard" => VariantKey::Standard,
+            "fromPosition" => VariantKey::FromPosition,
+            "chess960" => VariantKey::Chess960,
+            "antichess" => VariantKey::Antichess,
+            "kingOfTheHill" => VariantKey::KingOfTheHill,
+            "threeCheck" => VariantKey::ThreeCheck,
+            "atomic" => VariantKey::Atomic,
+            "horde" => VariantKey::Horde,
+            "racingKings" => VariantKey::RacingKings,
+            "crazyhouse" => VariantKey::Crazyhouse,
+            _ => return Err(UnknownVariant),
+        })
+    }
+}
+
+/// Pieces in hand in crazyhouse, like `QNpp`, with white pieces in
+/// uppercase.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Pockets(ArrayString<[u8; 32]>);
+
+#[derive(Debug)]
+pub struct InvalidPockets;
+
+impl FromStr for Pockets {
+    type Err = InvalidPockets;
+
+    fn from_str(s: &str) -> Result<Pockets, InvalidPockets> {
+        if s == "-" {
+            return Ok(Pockets(ArrayString::new()));
+        }
+        if s.is_empty() || !s.chars().all(|c| "PNBRQpnbrq".contains(c)) {
+            return Err(InvalidPockets);
+        }
+        Ok(Pockets(ArrayString::from(s).map_err(|_| InvalidPockets)?))
+    }
+}
+
+impl Pockets {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Serialize for Pockets {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_game_id() {
+        assert!("5iL3vzAw".parse::<GameId>().is_ok());
+        assert!("5iL3vzA".parse::<GameId>().is_err());
+        assert!("5iL3vzAwx".parse::<GameId>().is_err());
+        assert!("5iL3vzA ".parse::<GameId>().is_err());
+        assert!("5iL3vz-w".parse::<GameId>().is_err());
+        assert!("5iL3vzä".parse::<GameId>().is_err());
+        assert!(serde_json::from_str::<GameId>(r#""5iL3vz,w""#).is_err());
+    }
+
+    #[test]
+    fn test_chapter_id() {
+        assert!(serde_json::from_str::<ChapterId>(r#""kN8BVgDS""#).is_ok());
+        assert!(serde_json::from_str::<ChapterId>(r#""kN8BVgD""#).is_err());
+        assert!(serde_json::from_str::<ChapterId>(r#""kN8BVg/S""#).is_err());
+        assert!("Qa1bR2c3".parse::<RelayId>().is_ok());
+        assert!("Qa1bR2c".parse::<RelayId>().is_err());
+        assert!("Qa1bR2c3d".parse::<RelayId>().is_err());
+        assert!(serde_json::from_str::<SwissId>(r#""w5XbKq1Z""#).is_ok());
+        assert!(serde_json::from_str::<SwissId>(r#""w5XbKq1""#).is_err());
+    }
+
+    #[test]
+    fn test_room_id() {
+        assert_eq!("team:lichess-swiss".parse::<RoomId>().unwrap().to_string(), "team:lichess-swiss");
+        assert!("".parse::<RoomId>().is_err());
+        assert!("team:a,b".parse::<RoomId>().is_err());
+        assert!("team lichess".parse::<RoomId>().is_err());
+        assert!("a".repeat(65).parse::<RoomId>().is_err());
+        assert!(serde_json::from_str::<RoomId>(r#""team:lichess-swiss""#).is_ok());
+    }
+
+    #[test]
+    fn test_user_id() {
+        assert_eq!(UserId::new("Thibault").unwrap().as_str(), "thibault");
+        assert_eq!(UserId::new("Some-User_42").unwrap().as_str(), "some-user_42");
+        assert!(UserId::new("").is_err());
+        assert!(UserId::new("t").is_err());
+        assert!(UserId::new(&"a".repeat(31)).is_err());
+        assert!(UserId::new("thi bault").is_err());
+        assert!(UserId::new("thibault,").is_err());
+        assert!(UserId::new("thibaült").is_err());
+        assert!(serde_json::from_str::<UserId>(r#""thibault:""#).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+/// Maximum nesting of decoded arrays and maps.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct InvalidMsgpack;
+
+impl fmt::Display for InvalidMsgpack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid msgpack")
+    }
+}
+
+/// Encodes a JSON value as MessagePack.
+pub fn encode(value: &Value, buf: &mut Vec<u8>) {
+    match value {
+        Value::Null => buf.push(0xc0),
+        Value::Bool(false) => buf.push(0xc2),
+        Value::Bool(true) => buf.push(0xc3),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                encode_uint(n, buf);
+            } else if let Some(n) = n.as_i64() {
+                encode_int(n, buf);
+            } else {
+                buf.push(0xcb);
+                buf.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            encode_len(s.len(), [0xa0, 0xd9, 0xda, 0xdb], 31, buf);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(values) => {
+            encode_array_header(values.len(), buf);
+            for value in values {
+                encode(value, buf);
+            }
+        }
+        Value::Object(map) => {
+            encode_len(map.len(), [0x80, 0, 0xde, 0xdf], 15, buf);
+            for (key, value) in map {
+                encode_len(key.len(), [0xa0, 0xd9, 0xda, 0xdb], 31, buf);
+                buf.extend_from_slice(key.as_bytes());
+                encode(value, buf);
+            }
+        }
+    }
+}
+
+/// Encodes the header of an array, to be followed by its `len` encoded
+/// elements.
+pub fn encode_array_header(len: usize, buf: &mut Vec<u8>) {
+    encode_len(len, [0x90, 0, 0xdc, 0xdd], 15, buf);
+}
+
+fn encode_uint(n: u64, buf: &mut Vec<u8>) {
+    if n <= 0x7f {
+        buf.push(n as u8);
+    } else if n <= u64::from(u8::MAX) {
+        buf.push(0xcc);
+        buf.push(n as u8);
+    } else if n <= u64::from(u16::MAX) {
+        buf.push(0xcd);
+        buf.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u64::from(u32::MAX) {
+        buf.push(0xce);
+        buf.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        buf.push(0xcf);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn encode_int(n: i64, buf: &mut Vec<u8>) {
+    if n >= -32 {
+        buf.push(n as u8);
+    } else if n >= i64::from(i8::MIN) {
+        buf.push(0xd0);
+        buf.push(n as u8);
+    } else if n >= i64::from(i16::MIN) {
+        buf.push(0xd1);
+        buf.extend_from_slice(&(n as i16).to_be_bytes());
+    } else if n >= i64::from(i32::MIN) {
+        buf.push(0xd2);
+        buf.extend_from_slice(&(n as i32).to_be_bytes());
+    } else {
+        buf.push(0xd3);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Writes a length with the fix, 8, 16 or 32 bit marker (0 if there is no 8
+/// bit form).
+fn encode_len(len: usize, markers: [u8; 4], fix_max: usize, buf: &mut Vec<u8>) {
+    if len <= fix_max {
+        buf.push(markers[0] | len as u8);
+    } else if len <= usize::from(u8::MAX) && markers[1] != 0 {
+        buf.push(markers[1]);
+        buf.push(len as u8);
+    } else if len <= usize::from(u16::MAX) {
+        buf.push(markers[2]);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(markers[3]);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+/// Decodes a single MessagePack value. Binary and extension types have no
+/// JSON equivalent and are rejected.
+pub fn decode(bytes: &[u8]) -> Result<Value, InvalidMsgpack> {
+    let mut reader = Reader { bytes };
+    let value = reader.value(0)?;
+    if reader.bytes.is_empty() {
+        Ok(value)
+    } else {
+        Err(InvalidMsgpack)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], InvalidMsgpack> {
+        if self.bytes.len() < n {
+            return Err(InvalidMsgpack);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn be<const N: usize>(&mut self) -> Result<[u8; N], InvalidMsgpack> {
+        Ok(self.take(N)?.try_into().expect("length checked"))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, InvalidMsgpack> {
+        if depth > MAX_DEPTH {
+            return Err(InvalidMsgpack);
+        }
+
+        let marker = self.take(1)?[0];
+        Ok(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.map(usize::from(marker & 0x0f), depth)?,
+            0x90..=0x9f => self.array(usize::from(marker & 0x0f), depth)?,
+            0xa0..=0xbf => self.str(usize::from(marker & 0x1f))?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => float(f64::from(f32::from_be_bytes(self.be()?)))?,
+            0xcb => float(f64::from_be_bytes(self.be()?))?,
+            0xcc => Value::from(self.be::<1>()?[0]),
+            0xcd => Value::from(u16::from_be_bytes(self.be()?)),
+            0xce => Value::from(u32::from_be_bytes(self.be()?)),
+            0xcf => Value::from(u64::from_be_bytes(self.be()?)),
+            0xd0 => Value::from(self.be::<1>()?[0] as i8),
+            0xd1 => Value::from(i16::from_be_bytes(self.be()?)),
+            0xd2 => Value::from(i32::from_be_bytes(self.be()?)),
+            0xd3 => Value::from(i64::from_be_bytes(self.be()?)),
+            0xd9 => {
+                let len = self.be::<1>()?[0];
+                self.str(usize::from(len))?
+            }
+            0xda => {
+                let len = u16::from_be_bytes(self.be()?);
+                self.str(usize::from(len))?
+            }
+            0xdb => {
+                let len = u32::from_be_bytes(self.be()?);
+                self.str(len as usize)?
+            }
+            0xdc => {
+                let len = u16::from_be_bytes(self.be()?);
+                self.array(usize::from(len), depth)?
+            }
+            0xdd => {
+                let len = u32::from_be_bytes(self.be()?);
+                self.array(len as usize, depth)?
+            }
+            0xde => {
+                let len = u16::from_be_bytes(self.be()?);
+                self.map(usize::from(len), depth)?
+            }
+            0xdf => {
+                let len = u32::from_be_bytes(self.be()?);
+                self.map(len as usize, depth)?
+            }
+            0xe0..=0xff => Value::from(marker as i8),
+            _ => return Err(InvalidMsgpack), // bin, ext, reserved
+        })
+    }
+
+    fn str(&mut self, len: usize) -> Result<Value, InvalidMsgpack> {
+        let bytes = self.take(len)?;
+        Ok(Value::String(String::from_utf8(bytes.to_vec()).map_err(|_| InvalidMsgpack)?))
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> Result<Value, InvalidMsgpack> {
+        // Every element takes at least one byte.
+        let mut values = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            values.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> Result<Value, InvalidMsgpack> {
+        let mut map = Map::new();
+        for _ in 0..len {
+            let key = match self.value(depth + 1)? {
+                Value::String(key) => key,
+                _ => return Err(InvalidMsgpack),
+            };
+            map.insert(key, self.value(depth + 1)?);
+        }
+        Ok(Value::Object(map))
+    }
+}
+
+fn float(f: f64) -> Result<Value, InvalidMsgpack> {
+    Number::from_f64(f).map(Value::Number).ok_or(InvalidMsgpack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn roundtrip(value: Value) {
+        let mut buf = Vec::new();
+        encode(&value, &mut buf);
+        assert_eq!(decode(&buf), Ok(value));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut buf = Vec::new();
+        encode(&json!({"t": "mlat", "d": 42}), &mut buf);
+        assert_eq!(buf, b"\x82\xa1t\xa4mlat\xa1d\x2a");
+
+        buf.clear();
+        encode(&json!([null, true, -1, -200, 300, 1.5]), &mut buf);
+        assert_eq!(buf, b"\x96\xc0\xc3\xff\xd1\xff\x38\xcd\x01\x2c\xcb\x3f\xf8\0\0\0\0\0\0");
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(json!({"t": "fen", "d": {"id": "5iL3vzAw", "fen": "8/8/8/8/8/8/8/8", "ply": 1}}));
+        roundtrip(json!([0, 127, 128, 255, 256, 65535, 65536, u64::from(u32::MAX) + 1, u64::MAX]));
+        roundtrip(json!([-32, -33, -128, -129, -32768, -32769, i64::from(i32::MIN) - 1, i64::MIN]));
+        roundtrip(json!("x".repeat(31)));
+        roundtrip(json!("x".repeat(32)));
+        roundtrip(json!("x".repeat(256)));
+        roundtrip(json!("x".repeat(65536)));
+        roundtrip(json!(vec![1; 16]));
+        roundtrip(json!(vec![1; 65536]));
+        roundtrip(json!(0.25));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert_eq!(decode(b""), Err(InvalidMsgpack));
+        assert_eq!(decode(b"\xa4mla"), Err(InvalidMsgpack)); // truncated
+        assert_eq!(decode(b"\xc0\xc0"), Err(InvalidMsgpack)); // trailing
+        assert_eq!(decode(b"\xc4\x01x"), Err(InvalidMsgpack)); // bin
+        assert_eq!(decode(b"\x81\x01\x02"), Err(InvalidMsgpack)); // non-string key
+        assert_eq!(decode(b"\xa1\xff"), Err(InvalidMsgpack)); // not utf-8
+        assert_eq!(decode(b"\xdd\xff\xff\xff\xff"), Err(InvalidMsgpack)); // huge array
+        assert_eq!(decode(&[0x91; MAX_DEPTH + 2]), Err(InvalidMsgpack)); // deep
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// Offenses are forgotten after this long.
+const OFFENSE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Number of offenses within the window that get an address banned.
+const OFFENSES_BEFORE_BAN: u32 = 3;
+
+/// How long banned addresses are refused.
+const BAN_DURATION: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+struct Offender {
+    offenses: u32,
+    last_offense: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Number of tracked addresses.
+#[derive(Serialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PenaltyCounts {
+    pub offenders: usize,
+    pub banned: usize,
+}
+
+/// Addresses whose sockets had to be closed for persistently exceeding the
+/// rate limit, and temporary bans for repeat offenders.
+#[derive(Default)]
+pub struct Penalties {
+    offenders: Mutex<HashMap<IpAddr, Offender>>,
+}
+
+impl Penalties {
+    /// Records an offense. Returns true if the address is now banned.
+    pub fn offense(&self, ip: IpAddr) -> bool {
+        self.offense_at(ip, Instant::now())
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    /// Forgets offenses and bans that have expired.
+    pub fn cleanup(&self) {
+        self.cleanup_at(Instant::now())
+    }
+
+    pub fn counts(&self) -> PenaltyCounts {
+        let now = Instant::now();
+        let offenders = self.offenders.lock();
+        PenaltyCounts {
+            offenders: offenders.len(),
+            banned: offenders.values().filter(|o| o.banned_until.is_some_and(|until| now < until)).count(),
+        }
+    }
+
+    fn offense_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut offenders = self.offenders.lock();
+        let offender = offenders.entry(ip).or_insert(Offender {
+            offenses: 0,
+            last_offense: now,
+            banned_until: None,
+        });
+        if now.duration_since(offender.last_offense) >= OFFENSE_WINDOW {
+            offender.offenses = 0;
+        }
+        offender.offenses += 1;
+        offender.last_offense = now;
+        if offender.offenses >= OFFENSES_BEFORE_BAN {
+            offender.offenses = 0;
+            offender.banned_until = Some(now + BAN_DURATION);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        self.offenders.lock().get(&ip)
+            .and_then(|o| o.banned_until)
+            .is_some_and(|until| now < until)
+    }
+
+    fn cleanup_at(&self, now: Instant) {
+        self.offenders.lock().retain(|_, o| {
+            now.duration_since(o.last_offense) < OFFENSE_WINDOW ||
+            o.banned_until.is_some_and(|until| now < until)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_penalties() {
+        let penalties = Penalties::default();
+        let ip = "203.0.113.7".parse().unwrap();
+        let now = Instant::now();
+
+        // Offenses far apart are forgiven.
+        assert!(!penalties.offense_at(ip, now));
+        assert!(!penalties.offense_at(ip, now + OFFENSE_WINDOW));
+        assert!(!penalties.offense_at(ip, now + 2 * OFFENSE_WINDOW));
+        assert!(!penalties.is_banned_at(ip, now + 2 * OFFENSE_WINDOW));
+
+        // Repeat offenders are banned for a while.
+        let later = now + 2 * OFFENSE_WINDOW + Duration::from_secs(1);
+        assert!(!penalties.offense_at(ip, later));
+        assert!(penalties.offense_at(ip, later));
+        assert!(penalties.is_banned_at(ip, later + BAN_DURATION - Duration::from_secs(1)));
+        assert!(!penalties.is_banned_at(ip, later + BAN_DURATION));
+        assert_eq!(penalties.counts(), PenaltyCounts { offenders: 1, banned: 1 });
+
+        penalties.cleanup_at(later + BAN_DURATION + OFFENSE_WINDOW);
+        assert!(penalties.offenders.lock().is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Instant;
+
+use shakmaty::perft;
+use shakmaty::variants::Variant;
+use structopt::StructOpt;
+
+use crate::analysis;
+use crate::model::VariantKey;
+
+#[derive(StructOpt, Clone)]
+pub struct PerftOpt {
+    /// Maximum depth
+    #[structopt(long = "depth", default_value = "3")]
+    depth: u32,
+    /// Variant of the given positions
+    #[structopt(long = "variant", default_value = "standard")]
+    variant: VariantKey,
+    /// Position to count from (may be repeated). Runs positions with known
+    /// node counts for all variants if not given
+    #[structopt(long = "fen")]
+    fens: Vec<String>,
+}
+
+struct Known {
+    variant: VariantKey,
+    fen: &'static str,
+    nodes: &'static [u64], // by depth, starting at 1
+}
+
+const KNOWN: &[Known] = &[
+    Known {
+        variant: VariantKey::Standard,
+        fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        nodes: &[20, 400, 8902, 197281],
+    },
+    Known {
+        variant: VariantKey::Standard,
+        fen: "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        nodes: &[48, 2039, 97862],
+    },
+    Known {
+        variant: VariantKey::Chess960,
+        fen: "bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9",
+        nodes: &[21, 528, 12189],
+    },
+    Known {
+        variant: VariantKey::Antichess,
+        fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1",
+        nodes: &[20, 400, 8067],
+    },
+    Known {
+        variant: VariantKey::Atomic,
+        fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        nodes: &[20, 400, 8902],
+    },
+    Known {
+        variant: VariantKey::Crazyhouse,
+        fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[] w KQkq - 0 1",
+        nodes: &[20, 400, 8902, 197281],
+    },
+    Known {
+        variant: VariantKey::Horde,
+        fen: "rnbqkbnr/pppppppp/8/1PP2PP1/PPPPPPPP/PPPPPPPP/PPPPPPPP/PPPPPPPP w kq - 0 1",
+        nodes: &[8, 128, 1274],
+    },
+    Known {
+        variant: VariantKey::KingOfTheHill,
+        fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        nodes: &[20, 400, 8902, 197281],
+    },
+    Known {
+        variant: VariantKey::RacingKings,
+        fen: "8/8/8/8/8/8/krbnNBRK/qrbnNBRQ w - - 0 1",
+        nodes: &[21, 421, 11264],
+    },
+    Known {
+        variant: VariantKey::ThreeCheck,
+        fen: "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 3+3 0 1",
+        nodes: &[20, 400, 8902, 197281],
+    },
+];
+
+/// Counts nodes at each depth from the given position, and compares with
+/// the known counts, if any. Returns `false` on mismatches or invalid
+/// positions.
+fn run_position(variant: VariantKey, fen: &str, depth: u32, known: &[u64]) -> bool {
+    let pos = match analysis::position(Variant::from(variant), fen) {
+        Ok(pos) => pos,
+        Err(err) => {
+            println!("FAILED  {:?} {}: {:?}", variant, fen, err);
+            return false;
+        }
+    };
+
+    let mut ok = true;
+    for d in 1..=depth {
+        let started = Instant::now();
+        let nodes = perft(&pos, d);
+        let elapsed = started.elapsed();
+        match known.get(d as usize - 1) {
+            Some(&expected) if expected != nodes => {
+                println!("FAILED  {:?} {} depth {}: {} nodes, expected {}", variant, fen, d, nodes, expected);
+                ok = false;
+            }
+            Some(_) => println!("ok      {:?} {} depth {}: {} nodes ({:?})", variant, fen, d, nodes, elapsed),
+            None => println!("        {:?} {} depth {}: {} nodes ({:?})", variant, fen, d, nodes, elapsed),
+        }
+    }
+    ok
+}
+
+/// Verifies move generation by counting the leaf nodes of the move tree.
+/// Returns `false` if any count did not match.
+pub fn run(opt: &PerftOpt) -> bool {
+    let mut ok = true;
+    if opt.fens.is_empty() {
+        for known in KNOWN {
+            let depth = opt.depth.min(known.nodes.len() as u32);
+            ok &= run_position(known.variant, known.fen, depth, known.nodes);
+        }
+    } else {
+        for fen in &opt.fens {
+            ok &= run_position(opt.variant, fen, opt.depth, &[]);
+        }
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known() {
+        for known in KNOWN {
+            assert!(run_position(known.variant, known.fen, 2, known.nodes), "{}", known.fen);
+        }
+    }
+}
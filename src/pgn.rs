@@ -0,0 +1,118 @@
+use std::fmt;
+
+/// Tag pairs and mainline moves of a single PGN game. Comments, variations,
+/// NAGs, move numbers and annotation symbols are skipped.
+#[derive(Debug)]
+pub struct Pgn<'a> {
+    pub tags: Vec<(&'a str, &'a str)>,
+    pub sans: Vec<&'a str>,
+}
+
+impl<'a> Pgn<'a> {
+    pub fn tag(&self, name: &str) -> Option<&'a str> {
+        self.tags.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum InvalidPgn {
+    UnterminatedTag,
+    UnterminatedComment,
+    UnbalancedVariation,
+}
+
+impl fmt::Display for InvalidPgn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InvalidPgn::UnterminatedTag => "unterminated tag",
+            InvalidPgn::UnterminatedComment => "unterminated comment",
+            InvalidPgn::UnbalancedVariation => "unbalanced variation",
+        })
+    }
+}
+
+/// Parses the first game of a PGN snippet. Stops at the game termination
+/// marker, if any.
+pub fn parse(pgn: &str) -> Result<Pgn<'_>, InvalidPgn> {
+    let mut tags = Vec::new();
+    let mut sans = Vec::new();
+    let mut depth = 0u32; // of variations
+    let mut rest = pgn;
+
+    loop {
+        rest = rest.trim_start();
+        let mut chars = rest.chars();
+        match chars.next() {
+            None => break,
+            Some('[') => {
+                let end = rest.find(']').ok_or(InvalidPgn::UnterminatedTag)?;
+                let tag = rest[1..end].trim();
+                let (name, value) = tag.split_at(tag.find(char::is_whitespace).unwrap_or(tag.len()));
+                tags.push((name, value.trim().trim_matches('"')));
+                rest = &rest[end + 1..];
+            }
+            Some('{') => {
+                let end = rest.find('}').ok_or(InvalidPgn::UnterminatedComment)?;
+                rest = &rest[end + 1..];
+            }
+            Some(';') | Some('%') => {
+                rest = rest.find('\n').map_or("", |end| &rest[end..]);
+            }
+            Some('(') => {
+                depth += 1;
+                rest = chars.as_str();
+            }
+            Some(')') => {
+                depth = depth.checked_sub(1).ok_or(InvalidPgn::UnbalancedVariation)?;
+                rest = chars.as_str();
+            }
+            Some(_) => {
+                let end = rest.find(|c: char| c.is_whitespace() || "[]{}();".contains(c)).unwrap_or(rest.len());
+                let token = &rest[..end];
+                rest = &rest[end..];
+
+                if depth == 0 && matches!(token, "1-0" | "0-1" | "1/2-1/2" | "*") {
+                    break;
+                }
+
+                // Strip move numbers like 12. or 12... and annotations.
+                let san = token.trim_start_matches(|c: char| c.is_ascii_digit());
+                let san = if san.len() < token.len() && san.starts_with('.') { san.trim_start_matches('.') } else { token };
+                let san = san.trim_end_matches(['!', '?']);
+                if depth == 0 && !san.is_empty() && !san.starts_with('$') {
+                    sans.push(san);
+                }
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err(InvalidPgn::UnbalancedVariation);
+    }
+
+    Ok(Pgn { tags, sans })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let pgn = parse(r#"[Event "Casual game"]
+[Variant "King of the Hill"]
+
+1. e4 {best by test} e5 2.Nf3 $1 (2. f4 exf4 (2... d5)) 2... Nc6!? ; comment
+3. Bb5 a6 1-0 4. Ba4"#).unwrap();
+        assert_eq!(pgn.tag("Event"), Some("Casual game"));
+        assert_eq!(pgn.tag("Variant"), Some("King of the Hill"));
+        assert_eq!(pgn.tag("FEN"), None);
+        assert_eq!(pgn.sans, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6"]);
+
+        assert_eq!(parse("").unwrap().sans, Vec::<&str>::new());
+        assert_eq!(parse("[Event \"x").unwrap_err(), InvalidPgn::UnterminatedTag);
+        assert_eq!(parse("1. e4 { e5").unwrap_err(), InvalidPgn::UnterminatedComment);
+        assert_eq!(parse("1. e4 (1. d4").unwrap_err(), InvalidPgn::UnbalancedVariation);
+        assert_eq!(parse("1. e4 )").unwrap_err(), InvalidPgn::UnbalancedVariation);
+    }
+}
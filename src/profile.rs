@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use pprof::protos::Message as _;
+use serde::Deserialize;
+use tokio::time;
+
+/// Stack samples per second. Not a multiple of common timer frequencies,
+/// to avoid sampling in lockstep with periodic work.
+const SAMPLE_FREQUENCY: i32 = 99;
+
+/// Output format of a CPU profile.
+#[derive(Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// SVG for looking at right away, with a tower per thread name.
+    #[default]
+    Flamegraph,
+    /// Protobuf for `go tool pprof`.
+    Pprof,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Flamegraph => "image/svg+xml",
+            Format::Pprof => "application/octet-stream",
+        }
+    }
+}
+
+/// Samples the stacks of all threads over the given duration. Only one
+/// profile can be captured at a time.
+pub async fn capture(duration: Duration, format: Format) -> pprof::Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLE_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    time::sleep(duration).await;
+    let report = guard.report().build()?;
+
+    let mut body = Vec::new();
+    match format {
+        Format::Flamegraph => report.flamegraph(&mut body)?,
+        Format::Pprof => report.pprof()?.write_to_vec(&mut body).map_err(|err| pprof::Error::IoError(err.into()))?,
+    }
+    Ok(body)
+}
+
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Important messages that lila missed, because no one was subscribed to
+/// site-in when they were published. They are retried in order until lila
+/// is back, or given up on after a while.
+pub struct Retries {
+    queue: VecDeque<(Instant, String)>, // (first published, message)
+    capacity: usize,
+    window: Duration,
+    interval: Duration,
+    last_attempt: Instant,
+}
+
+impl Retries {
+    pub fn new(capacity: usize, window: Duration, interval: Duration) -> Retries {
+        Retries {
+            queue: VecDeque::new(),
+            capacity,
+            window,
+            interval,
+            last_attempt: Instant::now(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queues a missed message, or a message that must not overtake the
+    /// missed messages. Returns the number of messages given up on to make
+    /// room.
+    pub fn push(&mut self, msg: String, now: Instant) -> u32 {
+        if self.queue.is_empty() {
+            self.last_attempt = now;
+        }
+        let mut given_up = 0;
+        while self.queue.len() >= self.capacity.max(1) {
+            if let Some((_, msg)) = self.queue.pop_front() {
+                log::warn!("retry queue full, lila missed: {}", msg);
+                given_up += 1;
+            }
+        }
+        self.queue.push_back((now, msg));
+        given_up
+    }
+
+    /// When the next retry is due, if any.
+    pub fn next_attempt(&self) -> Option<Instant> {
+        if self.queue.is_empty() {
+            None
+        } else {
+            Some(self.last_attempt + self.interval)
+        }
+    }
+
+    /// Gives up on messages older than the window. Returns how many.
+    pub fn expire(&mut self, now: Instant) -> u32 {
+        let mut given_up = 0;
+        while let Some((since, msg)) = self.queue.front() {
+            if now.saturating_duration_since(*since) < self.window {
+                break;
+            }
+            log::warn!("lila missed a message for {:?}, giving up: {}", self.window, msg);
+            self.queue.pop_front();
+            given_up += 1;
+        }
+        given_up
+    }
+
+    /// Publishes queued messages in order, until one is missed again.
+    pub fn retry<E>(&mut self, now: Instant, mut publish: impl FnMut(&str) -> Result<u32, E>) -> Result<(), E> {
+        self.last_attempt = now;
+        while let Some((_, msg)) = self.queue.front() {
+            if publish(msg)? == 0 {
+                break;
+            }
+            self.queue.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retries() {
+        let now = Instant::now();
+        let mut retries = Retries::new(2, Duration::from_secs(5), Duration::from_millis(500));
+        assert_eq!(retries.next_attempt(), None);
+
+        assert_eq!(retries.push("connect a".to_owned(), now), 0);
+        assert_eq!(retries.push("watch 00000000".to_owned(), now), 0);
+        assert_eq!(retries.next_attempt(), Some(now + Duration::from_millis(500)));
+
+        // Still no subscribers.
+        let mut published = Vec::new();
+        retries.retry::<()>(now, |msg| {
+            published.push(msg.to_owned());
+            Ok(0)
+        }).unwrap();
+        assert_eq!(published, ["connect a"]);
+        assert!(!retries.is_empty());
+
+        // Full, so the oldest message is given up on.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(retries.push("disconnect a".to_owned(), later), 1);
+
+        // Messages expire after the window.
+        assert_eq!(retries.expire(now + Duration::from_secs(5)), 1);
+
+        // Lila is back.
+        published.clear();
+        retries.retry::<()>(later, |msg| {
+            published.push(msg.to_owned());
+            Ok(1)
+        }).unwrap();
+        assert_eq!(published, ["disconnect a"]);
+        assert!(retries.is_empty());
+        assert_eq!(retries.next_attempt(), None);
+    }
+}
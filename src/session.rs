@@ -0,0 +1,159 @@
+use serde::Deserialize;
+
+/// Name of the session cookie of lila.
+const COOKIE_NAME: &str = "lila2";
+
+/// Session cookie from Play framework.
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+pub struct SessionCookie {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+}
+
+/// Data of a Play cookie. Anonymous visitors have a session without
+/// `sessionId`.
+#[derive(Deserialize)]
+struct SessionData {
+    #[serde(rename = "sessionId")]
+    session_id: Option<String>,
+}
+
+impl SessionData {
+    fn into_cookie(self) -> Option<SessionCookie> {
+        self.session_id.filter(|s| !s.is_empty()).map(|session_id| SessionCookie { session_id })
+    }
+}
+
+/// A session cookie is present, but none of its values can be understood.
+#[derive(Debug, Eq, PartialEq)]
+pub struct InvalidSessionCookie;
+
+/// Finds the session among all `Cookie` headers of a request. Duplicate
+/// session cookies (for example set for different paths or domains) are
+/// tried in order. Well-formed cookies without a session are anonymous.
+pub fn extract<'a>(headers: impl Iterator<Item = &'a str>) -> Result<Option<SessionCookie>, InvalidSessionCookie> {
+    let mut present = false;
+    let mut anonymous = false;
+    for pair in headers.flat_map(|h| h.split(';')) {
+        let value = match pair.trim().split_once('=') {
+            Some((name, value)) if name.trim() == COOKIE_NAME => value.trim(),
+            _ => continue,
+        };
+        present = true;
+        match parse_value(value) {
+            Some(Some(session)) => return Ok(Some(session)),
+            Some(None) => anonymous = true,
+            None => (),
+        }
+    }
+    if present && !anonymous {
+        Err(InvalidSessionCookie)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Play cookies are either signed url encoded data like
+/// `signature-sessionId=abc&sid=def` or JWTs with the data in the
+/// `data` claim. Values may be quoted or url encoded as a whole. Returns
+/// `None` if the value is unsigned or garbled, and `Some(None)` for
+/// anonymous sessions.
+fn parse_value(value: &str) -> Option<Option<SessionCookie>> {
+    let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+    let decoded;
+    let value = if value.contains('%') && !value.contains('=') {
+        decoded = percent_decode(value)?;
+        decoded.as_str()
+    } else {
+        value
+    };
+    if value.starts_with("eyJ") {
+        return parse_jwt(value);
+    }
+    let (signature, data) = value.split_once('-')?;
+    if signature.is_empty() || !signature.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    serde_urlencoded::from_str::<SessionData>(data).ok().map(SessionData::into_cookie)
+}
+
+fn parse_jwt(value: &str) -> Option<Option<SessionCookie>> {
+    #[derive(Deserialize)]
+    struct Claims {
+        data: SessionData,
+    }
+
+    let mut parts = value.split('.');
+    let (_header, payload, _signature) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice::<Claims>(&payload).ok().map(|c| c.data.into_cookie())
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(session_id: &str) -> Result<Option<SessionCookie>, InvalidSessionCookie> {
+        Ok(Some(SessionCookie { session_id: session_id.to_owned() }))
+    }
+
+    #[test]
+    fn test_extract() {
+        assert_eq!(extract(["lila2=s1gn4ture-sessionId=s3ss10n"].iter().copied()), session("s3ss10n"));
+        assert_eq!(extract(["theme=dark; lila2=s1gn4ture-sid=x&sessionId=s3ss10n; bg=light"].iter().copied()), session("s3ss10n"));
+        assert_eq!(extract(["theme=dark", "lila2=s1gn4ture-sessionId=s3ss10n"].iter().copied()), session("s3ss10n"));
+        assert_eq!(extract([].iter().copied()), Ok(None));
+        assert_eq!(extract(["theme=dark"].iter().copied()), Ok(None));
+        assert_eq!(extract(["lila2x=s1gn4ture-sessionId=s3ss10n"].iter().copied()), Ok(None));
+
+        // Duplicates, the first of which is stale.
+        assert_eq!(extract(["lila2=; lila2=s1gn4ture-sessionId=s3ss10n"].iter().copied()), session("s3ss10n"));
+
+        // Anonymous sessions.
+        assert_eq!(extract(["lila2=s1gn4ture-sid=x"].iter().copied()), Ok(None));
+        assert_eq!(extract(["lila2=garbage; lila2=s1gn4ture-sid=x"].iter().copied()), Ok(None));
+        assert_eq!(extract(["lila2=s1gn4ture-sessionId="].iter().copied()), Ok(None));
+
+        // Present, but not understood.
+        assert_eq!(extract(["lila2=garbage"].iter().copied()), Err(InvalidSessionCookie));
+        assert_eq!(extract(["lila2=-sessionId=s3ss10n"].iter().copied()), Err(InvalidSessionCookie));
+        assert_eq!(extract(["lila2=-sid=x"].iter().copied()), Err(InvalidSessionCookie));
+    }
+
+    #[test]
+    fn test_encoded() {
+        assert_eq!(extract([r#"lila2="s1gn4ture-sessionId=s3ss10n""#].iter().copied()), session("s3ss10n"));
+        assert_eq!(extract(["lila2=s1gn4ture-sessionId%3Ds3ss10n%26sid%3Dx"].iter().copied()), session("s3ss10n"));
+        assert_eq!(extract(["lila2=s1gn4ture-sessionId=s3ss%2D10n"].iter().copied()), session("s3ss-10n"));
+        assert_eq!(extract(["lila2=s1gn4ture-sessionId%3"].iter().copied()), Err(InvalidSessionCookie));
+    }
+
+    #[test]
+    fn test_jwt() {
+        let payload = base64::encode_config(r#"{"data":{"sessionId":"s3ss10n"},"nbf":1,"iat":1}"#, base64::URL_SAFE_NO_PAD);
+        let jwt = format!("lila2=eyJhbGciOiJIUzI1NiJ9.{}.s1gn4ture", payload);
+        assert_eq!(extract([jwt.as_str()].iter().copied()), session("s3ss10n"));
+        assert_eq!(extract(["lila2=eyJhbGciOiJIUzI1NiJ9.garbage.s1gn4ture"].iter().copied()), Err(InvalidSessionCookie));
+
+        let payload = base64::encode_config(r#"{"data":{"sid":"x"}}"#, base64::URL_SAFE_NO_PAD);
+        let jwt = format!("lila2=eyJhbGciOiJIUzI1NiJ9.{}.s1gn4ture", payload);
+        assert_eq!(extract([jwt.as_str()].iter().copied()), Ok(None));
+    }
+}
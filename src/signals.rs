@@ -0,0 +1,186 @@
+use std::time::Instant;
+
+use serde::Serialize;
+
+/// Number of intervals between messages before their regularity is
+/// considered meaningful.
+const MIN_INTERVALS: u64 = 20;
+
+/// Intervals between messages with a coefficient of variation below this
+/// are more regular than humans manage.
+const MAX_HUMAN_REGULARITY: f64 = 0.05;
+
+/// Lag reported by clients beyond this is not plausible, in milliseconds.
+const MAX_PLAUSIBLE_LAG: i64 = 60_000;
+
+/// Plausible lag is clamped to this for statistics, in milliseconds, so
+/// that a few stalled clients do not dominate them.
+const MAX_REPORTED_LAG: u32 = 10_000;
+
+/// Clients that report this many impossible lags over the lifetime of the
+/// connection are most likely broken or spoofed. Their lag is ignored from
+/// then on.
+const SUSPICIOUS_IMPOSSIBLE_LAGS: u32 = 10;
+
+/// Behavioral signals of a single connection, for lila's security
+/// pipeline. Pings are sent on a timer by the client, so only other
+/// messages count towards timing.
+#[derive(Debug, Default)]
+pub struct Signals {
+    last_message: Option<Instant>,
+    messages: u64,
+    // Running mean and sum of squared deviations of the intervals
+    // between messages in milliseconds (Welford).
+    intervals: u64,
+    mean: f64,
+    m2: f64,
+    analysis_in_game: u32,
+    impossible_lags: u32,
+    total_impossible_lags: u32, // not reset by reports
+}
+
+/// Outcome of recording a lag value reported by the client.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Lag {
+    /// Plausible lag, clamped for statistics.
+    Valid(u32),
+    /// Impossible lag, or any lag of a suspicious client.
+    Ignored,
+    /// Impossible lag that just made the client suspicious.
+    Suspicious,
+}
+
+/// Signals of a connection within a report window.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SignalReport {
+    pub messages: u64,
+    /// Coefficient of variation of the intervals between messages.
+    pub timing_cv: Option<f64>,
+    /// Analysis requests for the current position of a game in progress.
+    pub analysis_in_game: u32,
+    /// Negative or excessive lag values reported by the client.
+    pub impossible_lags: u32,
+}
+
+impl Signals {
+    /// Records a message other than a ping.
+    pub fn message(&mut self, now: Instant) {
+        self.messages += 1;
+        if let Some(last) = self.last_message.replace(now) {
+            let interval = now.duration_since(last).as_secs_f64() * 1000.0;
+            self.intervals += 1;
+            let delta = interval - self.mean;
+            self.mean += delta / self.intervals as f64;
+            self.m2 += delta * (interval - self.mean);
+        }
+    }
+
+    /// Records a lag value reported by the client.
+    pub fn lag(&mut self, lag: i64) -> Lag {
+        if self.total_impossible_lags >= SUSPICIOUS_IMPOSSIBLE_LAGS {
+            Lag::Ignored
+        } else if (0..=MAX_PLAUSIBLE_LAG).contains(&lag) {
+            Lag::Valid((lag as u32).min(MAX_REPORTED_LAG))
+        } else {
+            self.impossible_lags += 1;
+            self.total_impossible_lags += 1;
+            if self.total_impossible_lags == SUSPICIOUS_IMPOSSIBLE_LAGS {
+                Lag::Suspicious
+            } else {
+                Lag::Ignored
+            }
+        }
+    }
+
+    /// Records an analysis request for a position of a game in progress.
+    pub fn analysis_in_game(&mut self) {
+        self.analysis_in_game += 1;
+    }
+
+    fn timing_cv(&self) -> Option<f64> {
+        if self.intervals < MIN_INTERVALS || self.mean <= 0.0 {
+            return None;
+        }
+        let variance = self.m2 / self.intervals as f64;
+        Some(variance.sqrt() / self.mean)
+    }
+
+    fn is_anomalous(&self) -> bool {
+        self.analysis_in_game > 0 ||
+        self.impossible_lags > 0 ||
+        self.timing_cv().is_some_and(|cv| cv < MAX_HUMAN_REGULARITY)
+    }
+
+    /// Returns the signals of the current window, if anything stands out,
+    /// and starts a new window.
+    pub fn report(&mut self) -> Option<SignalReport> {
+        let signals = std::mem::take(self);
+        self.last_message = signals.last_message;
+        self.total_impossible_lags = signals.total_impossible_lags;
+        signals.is_anomalous().then(|| SignalReport {
+            messages: signals.messages,
+            timing_cv: signals.timing_cv(),
+            analysis_in_game: signals.analysis_in_game,
+            impossible_lags: signals.impossible_lags,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_regular_timing() {
+        let start = Instant::now();
+        let mut signals = Signals::default();
+        for i in 0..=MIN_INTERVALS {
+            signals.message(start + Duration::from_millis(500 * i));
+        }
+        let report = signals.report().expect("anomalous");
+        assert_eq!(report.messages, MIN_INTERVALS + 1);
+        assert!(report.timing_cv.unwrap() < 1e-6);
+
+        // Human timing.
+        let mut at = start + Duration::from_secs(60);
+        for i in 0..=MIN_INTERVALS {
+            at += Duration::from_millis(200 + (i * 7919) % 3000);
+            signals.message(at);
+        }
+        assert_eq!(signals.report(), None);
+    }
+
+    #[test]
+    fn test_impossible_lag() {
+        let mut signals = Signals::default();
+        assert_eq!(signals.lag(120), Lag::Valid(120));
+        assert_eq!(signals.lag(45_000), Lag::Valid(MAX_REPORTED_LAG));
+        assert_eq!(signals.report(), None);
+        assert_eq!(signals.lag(-5), Lag::Ignored);
+        assert_eq!(signals.lag(3_600_000), Lag::Ignored);
+        assert_eq!(signals.report(), Some(SignalReport {
+            messages: 0,
+            timing_cv: None,
+            analysis_in_game: 0,
+            impossible_lags: 2,
+        }));
+        assert_eq!(signals.report(), None);
+    }
+
+    #[test]
+    fn test_suspicious_lag() {
+        let mut signals = Signals::default();
+        for _ in 1..SUSPICIOUS_IMPOSSIBLE_LAGS {
+            assert_eq!(signals.lag(-1), Lag::Ignored);
+            signals.report(); // does not forget
+        }
+        assert_eq!(signals.lag(-1), Lag::Suspicious);
+
+        // Reported only once, and plausible values are no longer trusted.
+        assert_eq!(signals.lag(-1), Lag::Ignored);
+        assert_eq!(signals.lag(120), Lag::Ignored);
+    }
+}
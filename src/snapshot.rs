@@ -0,0 +1,62 @@
+use std::hash::Hash;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::fxhash::FxHashSet;
+
+/// Set that is read far more often than it changes, like the subscribers
+/// of a flag. Readers take a snapshot and iterate it without holding a
+/// lock, so that broadcasts never block subscriptions. Changes copy the
+/// set only while a snapshot is still in use.
+pub struct SnapshotSet<T> {
+    current: RwLock<Arc<FxHashSet<T>>>,
+}
+
+impl<T> Default for SnapshotSet<T> {
+    fn default() -> SnapshotSet<T> {
+        SnapshotSet {
+            current: RwLock::new(Arc::new(FxHashSet::default())),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> SnapshotSet<T> {
+    pub fn snapshot(&self) -> Arc<FxHashSet<T>> {
+        self.current.read().clone()
+    }
+
+    pub fn insert(&self, value: T) -> bool {
+        let mut current = self.current.write();
+        !current.contains(&value) && Arc::make_mut(&mut current).insert(value)
+    }
+
+    pub fn remove(&self, value: &T) -> bool {
+        let mut current = self.current.write();
+        current.contains(value) && Arc::make_mut(&mut current).remove(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_set() {
+        let set = SnapshotSet::default();
+        assert!(set.insert(1));
+        assert!(!set.insert(1));
+
+        // Snapshots are not affected by later changes.
+        let snapshot = set.snapshot();
+        assert!(set.insert(2));
+        assert!(set.remove(&1));
+        assert!(!set.remove(&3));
+        assert_eq!(snapshot.len(), 1);
+        assert!(snapshot.contains(&1));
+
+        let snapshot = set.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert!(snapshot.contains(&2));
+    }
+}
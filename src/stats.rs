@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// Counters for a single Websocket connection, for debugging reports about
+/// specific users.
+pub struct SocketStats {
+    connected_at: SystemTime,
+    connected: Instant,
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    send_failures: AtomicU64,
+    rate_limited: AtomicU64,
+    watching: AtomicU32,
+}
+
+impl Default for SocketStats {
+    fn default() -> SocketStats {
+        SocketStats {
+            connected_at: SystemTime::now(),
+            connected: Instant::now(),
+            messages_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            watching: AtomicU32::new(0),
+        }
+    }
+}
+
+/// Snapshot of `SocketStats`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SocketStatsSnapshot {
+    pub connected_at: u64,
+    pub connected_secs: u64,
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    pub send_failures: u64,
+    pub rate_limited: u64,
+    pub watching: u32,
+}
+
+impl SocketStats {
+    pub fn received(&self, bytes: usize) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn send_failed(&self) {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_watching(&self, games: usize) {
+        self.watching.store(games as u32, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> SocketStatsSnapshot {
+        SocketStatsSnapshot {
+            connected_at: self.connected_at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            connected_secs: self.connected.elapsed().as_secs(),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            send_failures: self.send_failures.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            watching: self.watching.load(Ordering::Relaxed),
+        }
+    }
+}
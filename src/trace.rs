@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Upper bound for tracing durations requested by lila.
+pub const MAX_TRACE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Milliseconds since the Unix epoch, which fit into an atomic.
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Whether a connection is traced, until when.
+#[derive(Default)]
+pub struct TraceFlag {
+    until_ms: AtomicU64, // 0 if not traced
+}
+
+impl TraceFlag {
+    pub fn set_until(&self, until_ms: u64) {
+        self.until_ms.store(until_ms, Ordering::Relaxed);
+    }
+
+    pub fn is_active(&self) -> bool {
+        let until_ms = self.until_ms.load(Ordering::Relaxed);
+        until_ms != 0 && now_ms() < until_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_flag() {
+        let flag = TraceFlag::default();
+        assert!(!flag.is_active());
+        flag.set_until(now_ms() + 60_000);
+        assert!(flag.is_active());
+
+        // Expired.
+        flag.set_until(now_ms() - 1);
+        assert!(!flag.is_active());
+    }
+}
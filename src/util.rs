@@ -6,6 +6,7 @@ use std::marker::PhantomData;
 use serde::{Deserializer, de};
 
 // adapted from: https://github.com/serde-rs/serde/issues/581#issuecomment-253626616
+// also accepts a sequence of strings, like ["abcd1234", "efgh5678"]
 pub fn space_separated<'de, V, T, D>(deserializer: D) -> Result<V, D::Error>
 where
     V: FromIterator<T>,
@@ -13,31 +14,64 @@ where
     T::Err: Display,
     D: Deserializer<'de>,
 {
-    struct SpaceSeparated<V, T>(PhantomData<V>, PhantomData<T>);
+    deserializer.deserialize_any(Separated::new(' '))
+}
 
-    impl<'de, V, T> de::Visitor<'de> for SpaceSeparated<V, T>
-    where
-        V: FromIterator<T>,
-        T: FromStr,
-        T::Err: Display,
-    {
-        type Value = V;
+pub fn comma_separated<'de, V, T, D>(deserializer: D) -> Result<V, D::Error>
+where
+    V: FromIterator<T>,
+    T: FromStr,
+    T::Err: Display,
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(Separated::new(','))
+}
 
-        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            f.write_str("string containing space-separated elements")
-        }
+struct Separated<V, T> {
+    separator: char,
+    marker: PhantomData<(V, T)>,
+}
 
-        fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            let iter = s.split(' ').map(FromStr::from_str);
-            Result::from_iter(iter).map_err(de::Error::custom)
+impl<V, T> Separated<V, T> {
+    fn new(separator: char) -> Separated<V, T> {
+        Separated {
+            separator,
+            marker: PhantomData,
         }
     }
+}
 
-    let visitor = SpaceSeparated(PhantomData, PhantomData);
-    deserializer.deserialize_str(visitor)
+impl<'de, V, T> de::Visitor<'de> for Separated<V, T>
+where
+    V: FromIterator<T>,
+    T: FromStr,
+    T::Err: Display,
+{
+    type Value = V;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "string containing {:?}-separated elements, or sequence of strings", self.separator)
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let iter = s.split(self.separator).map(FromStr::from_str);
+        Result::from_iter(iter).map_err(de::Error::custom)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let iter = std::iter::from_fn(|| match seq.next_element::<String>() {
+            Ok(Some(s)) => Some(s.parse().map_err(de::Error::custom)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        });
+        Result::from_iter(iter)
+    }
 }
 
 pub fn parsable<'de, T, D>(deserializer: D) -> Result<T, D::Error>
@@ -71,6 +105,36 @@ where
     deserializer.deserialize_str(visitor)
 }
 
+/// Boolean query string parameter, like `mobile=1`, `mobile=true` or just
+/// `mobile`.
+pub fn truthy<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct Truthy;
+
+    impl<'de> de::Visitor<'de> for Truthy {
+        type Value = bool;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("boolean")
+        }
+
+        fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            match s {
+                "" | "1" | "true" => Ok(true),
+                "0" | "false" => Ok(false),
+                _ => Err(de::Error::invalid_value(de::Unexpected::Str(s), &self)),
+            }
+        }
+    }
+
+    deserializer.deserialize_str(Truthy)
+}
+
 #[allow(clippy::trivially_copy_pass_by_ref)]
 pub fn is_false(v: &bool) -> bool {
     !*v
@@ -80,3 +144,90 @@ pub fn is_false(v: &bool) -> bool {
 pub fn is_zero_u8(v: &u8) -> bool {
     *v == 0
 }
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+pub fn is_zero_usize(v: &usize) -> bool {
+    *v == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    use crate::model::{Flag, GameId};
+
+    #[derive(Deserialize)]
+    struct Games {
+        #[serde(deserialize_with = "space_separated")]
+        d: Vec<GameId>,
+    }
+
+    #[test]
+    fn test_space_separated() {
+        let games: Games = serde_json::from_str(r#"{"d":"abcd1234 efgh5678"}"#).unwrap();
+        assert_eq!(games.d, vec!["abcd1234".parse().unwrap(), "efgh5678".parse::<GameId>().unwrap()]);
+
+        let games: Games = serde_json::from_str(r#"{"d":["abcd1234","efgh5678"]}"#).unwrap();
+        assert_eq!(games.d, vec!["abcd1234".parse().unwrap(), "efgh5678".parse::<GameId>().unwrap()]);
+
+        let games: Games = serde_json::from_str(r#"{"d":[]}"#).unwrap();
+        assert!(games.d.is_empty());
+
+        assert!(serde_json::from_str::<Games>(r#"{"d":"abcd1234 nope"}"#).is_err());
+        assert!(serde_json::from_str::<Games>(r#"{"d":["abcd1234","nope"]}"#).is_err());
+        assert!(serde_json::from_str::<Games>(r#"{"d":["abcd1234",1]}"#).is_err());
+        assert!(serde_json::from_str::<Games>(r#"{"d":42}"#).is_err());
+
+        // Also within internally tagged enums, like client messages.
+        #[derive(Deserialize)]
+        #[serde(tag = "t")]
+        enum Tagged {
+            #[serde(rename = "startWatching")]
+            StartWatching {
+                #[serde(deserialize_with = "space_separated")]
+                d: Vec<GameId>,
+            },
+        }
+        let Tagged::StartWatching { d } = serde_json::from_str(r#"{"t":"startWatching","d":["abcd1234"]}"#).unwrap();
+        assert_eq!(d, vec!["abcd1234".parse::<GameId>().unwrap()]);
+    }
+
+    #[derive(Deserialize)]
+    struct Flags {
+        #[serde(default, deserialize_with = "comma_separated")]
+        flag: Vec<Flag>,
+    }
+
+    #[test]
+    fn test_comma_separated() {
+        let flags: Flags = serde_urlencoded::from_str("flag=simul,tournament").unwrap();
+        assert_eq!(flags.flag, vec![Flag::Simul, Flag::Tournament]);
+
+        let flags: Flags = serde_urlencoded::from_str("flag=streamer").unwrap();
+        assert_eq!(flags.flag, vec![Flag::Streamer]);
+
+        let flags: Flags = serde_urlencoded::from_str("sri=t3st").unwrap();
+        assert!(flags.flag.is_empty());
+
+        assert!(serde_urlencoded::from_str::<Flags>("flag=simul,team").is_err());
+    }
+
+    #[derive(Deserialize)]
+    struct Mobile {
+        #[serde(default, deserialize_with = "truthy")]
+        mobile: bool,
+    }
+
+    #[test]
+    fn test_truthy() {
+        let parse = |s| serde_urlencoded::from_str::<Mobile>(s).map(|m| m.mobile).ok();
+        assert_eq!(parse("mobile=1"), Some(true));
+        assert_eq!(parse("mobile=true"), Some(true));
+        assert_eq!(parse("mobile"), Some(true));
+        assert_eq!(parse("mobile=0"), Some(false));
+        assert_eq!(parse("sri=t3st"), Some(false));
+        assert_eq!(parse("mobile=maybe"), None);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+
+use serde::Serialize;
+
+#[cfg(test)]
+use crate::ipc::MoveMeta;
+use crate::ipc::RelayFen;
+use crate::model::{ChapterId, GameId};
+
+/// Clients announcing at least this protocol version with `proto` in the
+/// query string get messages in the v2 shape.
+pub const MIN_VERSION: u32 = 6;
+
+/// Shape of the messages sent to a client, selected per connection, so that
+/// the website and the mobile app can migrate independently. V2 also sends
+/// every frame as a batch, even if it contains only a single message.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Shape {
+    #[default]
+    Legacy,
+    V2,
+}
+
+impl Shape {
+    pub fn from_version(version: Option<u32>) -> Shape {
+        match version {
+            Some(v) if v >= MIN_VERSION => Shape::V2,
+            _ => Shape::Legacy,
+        }
+    }
+}
+
+/// Our messages with types or fields that are renamed in v2. Messages of
+/// other types, including opaque messages from lila, are the same in both
+/// shapes.
+#[derive(Serialize)]
+#[serde(tag = "t", content = "d")]
+pub enum SocketInV2<'a> {
+    /// Formatted by `fen_json::fen_json()`. Kept as the reference for its
+    /// output.
+    #[cfg(test)]
+    #[serde(rename = "fen")]
+    Fen {
+        id: &'a GameId,
+        fen: &'a str,
+        #[serde(rename = "lastMove")]
+        last_move: &'a str,
+        #[serde(flatten)]
+        meta: Option<MoveMeta>,
+    },
+    #[serde(rename = "fens")]
+    Fens(Vec<RelayFenV2<'a>>),
+    #[serde(rename = "finish")]
+    Finish {
+        id: &'a GameId,
+        winner: Option<char>,
+    },
+    #[serde(rename = "moveLatency")]
+    MoveLatency(u32),
+    #[serde(rename = "moveLatencyHistory")]
+    MoveLatencyHistory(&'a VecDeque<u32>),
+    #[serde(rename = "dests/failure")]
+    DestsFailure,
+    #[serde(rename = "step/failure")]
+    StepFailure,
+}
+
+impl<'a> SocketInV2<'a> {
+    pub fn to_json_string(&self) -> String {
+        serde_json::to_string(self).expect("serialize for socket")
+    }
+}
+
+/// Position of a board of a broadcast, in `SocketInV2::Fens`.
+#[derive(Serialize)]
+pub struct RelayFenV2<'a> {
+    id: &'a ChapterId,
+    fen: &'a str,
+    #[serde(rename = "lastMove")]
+    last_move: &'a str,
+}
+
+impl<'a> From<&'a RelayFen<'a>> for RelayFenV2<'a> {
+    fn from(relay_fen: &'a RelayFen<'a>) -> RelayFenV2<'a> {
+        RelayFenV2 {
+            id: &relay_fen.id,
+            fen: relay_fen.fen,
+            last_move: relay_fen.lm,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shape() {
+        assert_eq!(Shape::from_version(None), Shape::Legacy);
+        assert_eq!(Shape::from_version(Some(5)), Shape::Legacy);
+        assert_eq!(Shape::from_version(Some(MIN_VERSION)), Shape::V2);
+    }
+
+    #[test]
+    fn test_v2() {
+        let fens = [RelayFen { id: "kN8BVgDS".parse().unwrap(), fen: "8/8/8/8/8/8/8/8", lm: "" }];
+        assert_eq!(SocketInV2::Fens(fens.iter().map(RelayFenV2::from).collect()).to_json_string(),
+                   r#"{"t":"fens","d":[{"id":"kN8BVgDS","fen":"8/8/8/8/8/8/8/8","lastMove":""}]}"#);
+        let game: GameId = "5iL3vzAw".parse().unwrap();
+        assert_eq!(SocketInV2::Finish { id: &game, winner: None }.to_json_string(),
+                   r#"{"t":"finish","d":{"id":"5iL3vzAw","winner":null}}"#);
+        assert_eq!(SocketInV2::MoveLatency(42).to_json_string(), r#"{"t":"moveLatency","d":42}"#);
+        assert_eq!(SocketInV2::DestsFailure.to_json_string(), r#"{"t":"dests/failure"}"#);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::VecDeque;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
+
+/// Number of index bits. 2^12 registers give a standard error of about 1.6%.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// HyperLogLog cardinality estimator.
+#[derive(Clone)]
+pub struct HyperLogLog {
+    registers: Box<[u8; REGISTERS]>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> HyperLogLog {
+        HyperLogLog {
+            registers: Box::new([0; REGISTERS]),
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
+        let mut hasher = DefaultHasher::new();
+        item.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let idx = (hash >> (64 - PRECISION)) as usize;
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() + 1;
+        self.registers[idx] = self.registers[idx].max(rank as u8);
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (r, o) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *r = (*r).max(*o);
+        }
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-i32::from(r))).sum();
+        let raw = alpha * m * m / sum;
+
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting for small cardinalities.
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            raw.round() as u64
+        }
+    }
+}
+
+/// HyperLogLog over a sliding window, in buckets of one minute.
+#[derive(Default)]
+pub struct SlidingHyperLogLog {
+    buckets: VecDeque<(u64, HyperLogLog)>,
+}
+
+impl SlidingHyperLogLog {
+    pub fn insert<T: Hash + ?Sized>(&mut self, minute: u64, item: &T) {
+        match self.buckets.back_mut() {
+            Some((m, hll)) if *m == minute => hll.insert(item),
+            _ => {
+                let mut hll = HyperLogLog::default();
+                hll.insert(item);
+                self.buckets.push_back((minute, hll));
+            }
+        }
+    }
+
+    /// Forgets buckets that are older than the given number of minutes.
+    pub fn expire(&mut self, minute: u64, keep: u64) {
+        while self.buckets.front().is_some_and(|&(m, _)| m + keep <= minute) {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// Estimates the number of distinct items in the last `window` minutes.
+    pub fn estimate(&self, minute: u64, window: u64) -> u64 {
+        let mut merged = HyperLogLog::default();
+        for (_, hll) in self.buckets.iter().filter(|&&(m, _)| m + window > minute) {
+            merged.merge(hll);
+        }
+        merged.estimate()
+    }
+}
+
+/// Estimated number of unique visitors in the recent past.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct VisitorCounts {
+    pub ips_5m: u64,
+    pub users_5m: u64,
+    pub ips_1h: u64,
+    pub users_1h: u64,
+}
+
+/// Unique IP addresses and users over sliding windows of 5 minutes and 1
+/// hour.
+pub struct Visitors {
+    started: Instant,
+    ips: SlidingHyperLogLog,
+    users: SlidingHyperLogLog,
+}
+
+impl Default for Visitors {
+    fn default() -> Visitors {
+        Visitors {
+            started: Instant::now(),
+            ips: SlidingHyperLogLog::default(),
+            users: SlidingHyperLogLog::default(),
+        }
+    }
+}
+
+impl Visitors {
+    fn minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / 60
+    }
+
+    pub fn ip<T: Hash + ?Sized>(&mut self, ip: &T) {
+        let minute = self.minute();
+        self.ips.insert(minute, ip);
+    }
+
+    pub fn user<T: Hash + ?Sized>(&mut self, uid: &T) {
+        let minute = self.minute();
+        self.users.insert(minute, uid);
+    }
+
+    pub fn counts(&mut self) -> VisitorCounts {
+        let minute = self.minute();
+        self.ips.expire(minute, 60);
+        self.users.expire(minute, 60);
+        VisitorCounts {
+            ips_5m: self.ips.estimate(minute, 5),
+            users_5m: self.users.estimate(minute, 5),
+            ips_1h: self.ips.estimate(minute, 60),
+            users_1h: self.users.estimate(minute, 60),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(estimate: u64, actual: u64) {
+        let error = (estimate as f64 - actual as f64).abs() / actual as f64;
+        assert!(error < 0.05, "estimate {} too far from {}", estimate, actual);
+    }
+
+    #[test]
+    fn test_hyperloglog() {
+        let mut hll = HyperLogLog::default();
+        assert_eq!(hll.estimate(), 0);
+
+        for i in 0..100 {
+            hll.insert(&i);
+            hll.insert(&i);
+        }
+        assert_close(hll.estimate(), 100);
+
+        for i in 0..100_000 {
+            hll.insert(&i);
+        }
+        assert_close(hll.estimate(), 100_000);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut sliding = SlidingHyperLogLog::default();
+        for minute in 0..10 {
+            for i in 0..1000 {
+                sliding.insert(minute, &(minute * 1000 + i));
+            }
+        }
+        assert_close(sliding.estimate(9, 1), 1000);
+        assert_close(sliding.estimate(9, 5), 5000);
+        assert_close(sliding.estimate(9, 60), 10_000);
+
+        sliding.expire(9, 2);
+        assert_close(sliding.estimate(9, 60), 2000);
+        assert_eq!(sliding.estimate(20, 5), 0);
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of lines logged per category between metrics reports. Further
+/// occurrences are only counted, so that misbehaving clients can not flood
+/// the logs.
+const MAX_LOGGED: u64 = 20;
+
+/// Categories of log lines caused by clients.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Warning {
+    RateLimited,
+    LongMessage,
+    OversizedMessage,
+    UnexpectedMessage,
+    ProtocolViolation,
+    BinaryMessage,
+    InvalidQueryString,
+    SriRequired,
+    AnonNotified,
+    AnalysisFailure,
+    InvalidGameId,
+}
+
+impl Warning {
+    pub const ALL: [Warning; 11] = [
+        Warning::RateLimited,
+        Warning::LongMessage,
+        Warning::OversizedMessage,
+        Warning::UnexpectedMessage,
+        Warning::ProtocolViolation,
+        Warning::BinaryMessage,
+        Warning::InvalidQueryString,
+        Warning::SriRequired,
+        Warning::AnonNotified,
+        Warning::AnalysisFailure,
+        Warning::InvalidGameId,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Warning::RateLimited => "rate_limited",
+            Warning::LongMessage => "long_message",
+            Warning::OversizedMessage => "oversized_message",
+            Warning::UnexpectedMessage => "unexpected_message",
+            Warning::ProtocolViolation => "protocol_violation",
+            Warning::BinaryMessage => "binary_message",
+            Warning::InvalidQueryString => "invalid_query_string",
+            Warning::SriRequired => "sri_required",
+            Warning::AnonNotified => "anon_notified",
+            Warning::AnalysisFailure => "analysis_failure",
+            Warning::InvalidGameId => "invalid_game_id",
+        }
+    }
+
+    fn level(self) -> log::Level {
+        match self {
+            Warning::LongMessage => log::Level::Info,
+            _ => log::Level::Warn,
+        }
+    }
+}
+
+/// Throttled logging with occurrence counters per category.
+#[derive(Default)]
+pub struct Warnings {
+    counts: [AtomicU64; Warning::ALL.len()], // since the last report
+}
+
+impl Warnings {
+    /// Counts an occurrence, and logs it unless the category already logged
+    /// too much since the last report.
+    pub fn log(&self, warning: Warning, args: fmt::Arguments<'_>) {
+        let n = self.counts[warning as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        if n <= MAX_LOGGED {
+            log::log!(warning.level(), "{}", args);
+        }
+        if n == MAX_LOGGED {
+            log::log!(warning.level(), "{} logged {} times, suppressing until next report", warning.name(), n);
+        }
+    }
+
+    /// Occurrences since the last report, like
+    /// `warnings: rate_limited=3 protocol_violation=1`.
+    pub fn report(&self) -> String {
+        let counts: Vec<String> = Warning::ALL.iter()
+            .map(|&warning| (warning, self.counts[warning as usize].swap(0, Ordering::Relaxed)))
+            .filter(|&(_, n)| n > 0)
+            .map(|(warning, n)| format!("{}={}", warning.name(), n))
+            .collect();
+        format!("warnings: {}", counts.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings() {
+        let warnings = Warnings::default();
+        for _ in 0..(MAX_LOGGED + 5) {
+            warnings.log(Warning::ProtocolViolation, format_args!("protocol violation"));
+        }
+        warnings.log(Warning::RateLimited, format_args!("rate limited"));
+        assert_eq!(warnings.report(), "warnings: rate_limited=1 protocol_violation=25");
+
+        // Counters and throttling start over after each report.
+        assert_eq!(warnings.report(), "warnings: ");
+        warnings.log(Warning::ProtocolViolation, format_args!("protocol violation"));
+        assert_eq!(warnings.report(), "warnings: protocol_violation=1");
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel;
+use tokio::runtime::Handle;
+
+/// How often to probe the event loop.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Probes that take longer than this are considered stalls.
+const STALL_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// Watches the responsiveness of the event loop by regularly scheduling a
+/// no-op task from outside and measuring how long it takes to run. A stalled
+/// loop would otherwise look just like a lack of traffic.
+#[derive(Default)]
+pub struct Watchdog {
+    latency_ms: AtomicU64, // of the last probe
+    max_latency_ms: AtomicU64, // since the last report
+    stalls: AtomicU64,
+    stalled: AtomicBool, // waiting for a probe past the threshold
+}
+
+impl Watchdog {
+    fn record(&self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        self.latency_ms.store(ms, Ordering::Relaxed);
+        self.max_latency_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    /// Waits for a single probe to run on the event loop.
+    pub fn probe(&self, handle: &Handle) {
+        let started = Instant::now();
+        let (done, probed) = channel::bounded(1);
+        handle.spawn(async move {
+            let _ = done.send(());
+        });
+
+        if probed.recv_timeout(STALL_THRESHOLD).is_err() {
+            self.stalled.store(true, Ordering::Relaxed);
+            self.stalls.fetch_add(1, Ordering::Relaxed);
+            log::error!("event loop stalled: no response to probe within {:?}", STALL_THRESHOLD);
+            if probed.recv().is_err() {
+                log::error!("event loop dropped probe");
+                return;
+            }
+            log::error!("event loop recovered after {:?}", started.elapsed());
+            self.stalled.store(false, Ordering::Relaxed);
+        }
+
+        self.record(started.elapsed());
+    }
+
+    /// Probes the event loop forever. Run on a dedicated thread.
+    pub fn run(&self, handle: &Handle) -> Result<(), ()> {
+        loop {
+            thread::sleep(PROBE_INTERVAL);
+            self.probe(handle);
+        }
+    }
+
+    /// Whether a probe is overdue right now. The latency of a probe is
+    /// only known once it ran, which may be never.
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.load(Ordering::Relaxed)
+    }
+
+    /// Like `event loop: latency=1ms max=15ms stalls=0`, with the maximum
+    /// since the previous report.
+    pub fn report(&self) -> String {
+        format!("event loop: latency={}ms max={}ms stalls={}",
+                self.latency_ms.load(Ordering::Relaxed),
+                self.max_latency_ms.swap(0, Ordering::Relaxed),
+                self.stalls.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let watchdog = Watchdog::default();
+
+        // Blocking the event loop delays the probe.
+        thread::scope(|s| {
+            s.spawn(|| watchdog.probe(runtime.handle()));
+            runtime.block_on(async {
+                thread::sleep(STALL_THRESHOLD + Duration::from_millis(500));
+                assert!(watchdog.is_stalled());
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            });
+        });
+        assert!(!watchdog.is_stalled());
+        assert!(watchdog.report().ends_with(" stalls=1"));
+
+        thread::scope(|s| {
+            s.spawn(|| watchdog.probe(runtime.handle()));
+            runtime.block_on(async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            });
+        });
+        assert!(!watchdog.is_stalled());
+        assert!(watchdog.report().ends_with(" stalls=1"));
+    }
+}